@@ -0,0 +1,809 @@
+//! Computer players. Anything that can choose a move, the built-in [`AlphaBeta`] search, an
+//! engine from another crate or one running on another machine, implements [`Engine`], so the
+//! rest of the crate can use them interchangeably.
+//!
+//! [`AlphaBeta`] is an iterative-deepening alpha-beta search scored by
+//! [`eval::evaluate_position`](crate::logic::eval::evaluate_position). It runs on a bare
+//! [`Board`] with [`Board::make_move`] and ignores the draw rules, like
+//! [`perft`](crate::logic::perft). Past the depth limit it keeps following loud moves, ones whose
+//! shot destroys a king or that leave a king in the next player's beam, so a king exposed one ply
+//! past the limit isn't missed. Four-player games are searched "paranoid": every opponent is
+//! assumed to play against the engine's player, whatever that costs them. To play less than
+//! perfectly, it can add noise to its evaluation and sometimes play a random move; the
+//! [`Difficulty`] presets set both along with how deep to search.
+//!
+//! [`SelfPlay`] has an engine play itself, producing games and positions labelled with the
+//! engine's scores and the games' results, and [`Arena`] plays two engines against each other to
+//! measure which is stronger.
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use bevy_math::USizeVec2;
+
+use crate::logic::{
+    Board, GameResult, GameState, Move, OpeningBook, Outcome, PieceKind, Player, RulesConfig,
+    Tablebase,
+    eval::{WIN_SCORE, evaluate_position, static_exchange},
+    splitmix64,
+};
+
+mod arena;
+mod selfplay;
+
+pub use arena::{Arena, MatchResult};
+pub use selfplay::{LabeledPosition, SelfPlay, SelfPlayGame};
+
+/// Above any score a position can get, for the initial alpha-beta window.
+const INFINITY: i32 = WIN_SCORE + 1;
+
+/// Wins found this many plies or fewer from the root are scored above this, so there's no point
+/// searching any deeper.
+const MAX_PLY: i32 = 1_000;
+
+/// How deep [`AlphaBeta`] searches when nothing else stops it.
+const MAX_DEPTH: u32 = 64;
+
+/// How many loud moves the quiescence search follows past the depth limit.
+const QUIESCENCE_DEPTH: u32 = 4;
+
+/// How much material a move may look to lose by [`static_exchange`] and still be searched one
+/// ply from the depth limit: a mirror's worth, since the estimate misses some counterplay.
+const EXCHANGE_PRUNING_MARGIN: i32 = 300;
+
+/// Something that picks moves.
+pub trait Engine {
+    /// Chooses a move for the player to move in `state`, staying within `limits`.
+    fn best_move(&mut self, state: &GameState, limits: SearchLimits) -> SearchResult;
+
+    /// Thinks on the opponent's time. Called with the position after the engine's own move and
+    /// the reply it expects, usually the second move of its principal variation, and runs until
+    /// `stop` is set, typically by another thread once the opponent has moved. Engines that
+    /// ponder keep what they find for the next [`Engine::best_move`]; by default this returns
+    /// straight away.
+    fn ponder(&mut self, state: &GameState, predicted: Move, stop: &AtomicBool) {
+        let _ = (state, predicted, stop);
+    }
+}
+
+/// How much searching an [`Engine`] may do for one move. Limits left as `None` don't apply.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchLimits {
+    /// How many plies deep to look.
+    pub depth: Option<u32>,
+    /// How many positions to look at.
+    pub nodes: Option<u64>,
+    pub time: Option<Duration>,
+}
+
+/// What a search found.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchResult {
+    /// `None` if the game is over or the player to move has no moves.
+    pub best_move: Option<Move>,
+    /// How good the position is for the player to move, like
+    /// [`eval::evaluate`](crate::logic::eval::evaluate). Wins are scored just under
+    /// [`WIN_SCORE`], less the more plies they take.
+    pub score: i32,
+    /// The line the engine expects, starting with `best_move`.
+    pub principal_variation: Vec<Move>,
+    /// How many plies deep the last completed search went, or 0 if the move came from an opening
+    /// book or tablebase, was picked at random or the budget ran out before the first search
+    /// finished.
+    pub depth: u32,
+    /// How many positions were searched, including any searched while pondering.
+    pub nodes: u64,
+}
+
+/// The built-in engine, searching with alpha-beta one ply deeper at a time.
+#[derive(Clone, Debug, Default)]
+pub struct AlphaBeta {
+    book: Option<OpeningBook>,
+    tablebase: Option<Tablebase>,
+    /// How far evaluations may be off either way.
+    noise: i32,
+    /// The chance of playing a random move instead of searching, in percent.
+    random_move_chance: u8,
+    /// Where the noise and random moves come from. Moves on after every search.
+    seed: u64,
+    /// What pondering found, with the hash of the position it was for.
+    pondered: Option<(u64, SearchResult)>,
+    /// Ends searches early when set from outside.
+    stop: Option<Arc<AtomicBool>>,
+}
+
+impl AlphaBeta {
+    /// An engine without an opening book or tablebase that always plays the best move it finds.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Play the book's heaviest move without searching whenever the position is in it.
+    pub fn book(mut self, book: OpeningBook) -> Self {
+        self.book = Some(book);
+        self
+    }
+
+    /// Play perfectly without searching whenever the position is in `tablebase`.
+    pub fn tablebase(mut self, tablebase: Tablebase) -> Self {
+        self.tablebase = Some(tablebase);
+        self
+    }
+
+    /// Shift every evaluation by up to `noise` either way, the same for the same position
+    /// within a search, so the engine misjudges positions the way a person would.
+    pub fn noise(mut self, noise: i32) -> Self {
+        self.noise = noise;
+        self
+    }
+
+    /// Play a random legal move instead of searching `percent` percent of the time.
+    pub fn random_move_chance(mut self, percent: u8) -> Self {
+        self.random_move_chance = percent;
+        self
+    }
+
+    /// Seed the noise and random moves, so games can be played again exactly.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// End any search early as soon as `stop` is set, e.g. from another thread when a user asks
+    /// the engine to move now. The engine never clears the flag, so clear it before searching
+    /// again.
+    pub fn stop_flag(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+}
+
+/// Presets for how well the built-in engine plays.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Difficulty {
+    /// Looks one move ahead, misjudges a lot and blunders now and then.
+    Easy,
+    /// Looks at the reply to each move and makes the odd mistake.
+    #[default]
+    Medium,
+    /// Searches as deep as a few seconds allow, up to 4 plies, and plays its best.
+    Hard,
+}
+
+impl Difficulty {
+    pub const ALL: [Self; 3] = [Self::Easy, Self::Medium, Self::Hard];
+
+    /// The search limits to pass to the engine from [`Difficulty::engine`].
+    pub fn limits(self) -> SearchLimits {
+        match self {
+            Difficulty::Easy => SearchLimits {
+                depth: Some(1),
+                ..SearchLimits::default()
+            },
+            Difficulty::Medium => SearchLimits {
+                depth: Some(2),
+                ..SearchLimits::default()
+            },
+            Difficulty::Hard => SearchLimits {
+                depth: Some(4),
+                time: Some(Duration::from_secs(5)),
+                ..SearchLimits::default()
+            },
+        }
+    }
+
+    /// The built-in engine set up to play at this level, with its randomness seeded by `seed`.
+    pub fn engine(self, seed: u64) -> AlphaBeta {
+        let (noise, random_move_chance) = match self {
+            Difficulty::Easy => (200, 15),
+            Difficulty::Medium => (60, 3),
+            Difficulty::Hard => (0, 0),
+        };
+        AlphaBeta::new()
+            .noise(noise)
+            .random_move_chance(random_move_chance)
+            .seed(seed)
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difficulty::Easy => write!(f, "easy"),
+            Difficulty::Medium => write!(f, "medium"),
+            Difficulty::Hard => write!(f, "hard"),
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|difficulty| difficulty.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown difficulty '{s}' (expected easy, medium or hard)"))
+    }
+}
+
+impl Engine for AlphaBeta {
+    /// Searches one ply deeper at a time until it reaches the depth limit, finds a forced result
+    /// or runs out of budget, and returns the result of the deepest search that finished. If not
+    /// even the first one did, any legal move is returned. Without any limits the search only
+    /// stops at a forced result or after 64 plies, which in practice means never.
+    fn best_move(&mut self, state: &GameState, limits: SearchLimits) -> SearchResult {
+        let moves = state.legal_moves();
+        let mut result = SearchResult {
+            best_move: moves.first().copied(),
+            score: 0,
+            principal_variation: Vec::new(),
+            depth: 0,
+            nodes: 0,
+        };
+        if moves.is_empty() {
+            return result;
+        }
+        let seed = self.seed ^ state.position_hash();
+        self.seed = splitmix64(self.seed);
+        if splitmix64(seed) % 100 < u64::from(self.random_move_chance) {
+            let random_move = moves[(splitmix64(seed ^ 1) % moves.len() as u64) as usize];
+            result.best_move = Some(random_move);
+            result.principal_variation = vec![random_move];
+            return result;
+        }
+        if let Some(book_move) = self.book.as_ref().and_then(|book| book.best(state)) {
+            result.best_move = Some(book_move);
+            result.principal_variation = vec![book_move];
+            return result;
+        }
+        if let Some(tablebase) = &self.tablebase
+            && let Some(entry) = tablebase.probe(state)
+            && let Some(tablebase_move) = tablebase.best_move(state)
+        {
+            let plies = i32::from(entry.plies);
+            result.best_move = Some(tablebase_move);
+            result.principal_variation = vec![tablebase_move];
+            result.score = match entry.outcome {
+                Outcome::Win => WIN_SCORE - plies,
+                Outcome::Draw => 0,
+                Outcome::Loss => plies - WIN_SCORE,
+            };
+            return result;
+        }
+        if let Some((hash, pondered)) = self.pondered.take()
+            && hash == state.position_hash()
+        {
+            // The opponent played the predicted move, so carry on from where pondering got to
+            result = pondered;
+        }
+        self.deepen(state, limits, seed, result, self.stop.as_deref())
+    }
+
+    /// Searches the position `predicted` leads to until `stop` is set, and keeps the result. If
+    /// the next call to [`AlphaBeta::best_move`] is for that position, it carries on from there
+    /// instead of starting over.
+    fn ponder(&mut self, state: &GameState, predicted: Move, stop: &AtomicBool) {
+        self.pondered = None;
+        let mut expected = state.clone();
+        if expected.apply(&predicted).is_err() {
+            return;
+        }
+        let moves = expected.legal_moves();
+        if moves.is_empty() {
+            return;
+        }
+        let start = SearchResult {
+            best_move: moves.first().copied(),
+            score: 0,
+            principal_variation: Vec::new(),
+            depth: 0,
+            nodes: 0,
+        };
+        // Same seed best_move will use for this position, so the noise doesn't change on a hit
+        let seed = self.seed ^ expected.position_hash();
+        let result = self.deepen(&expected, SearchLimits::default(), seed, start, Some(stop));
+        self.pondered = Some((expected.position_hash(), result));
+    }
+}
+
+impl AlphaBeta {
+    /// Searches `state` one ply deeper at a time, starting one deeper than `result`, until it
+    /// reaches the depth limit, finds a forced result, runs out of budget or is stopped.
+    fn deepen(
+        &self,
+        state: &GameState,
+        limits: SearchLimits,
+        seed: u64,
+        mut result: SearchResult,
+        stop: Option<&AtomicBool>,
+    ) -> SearchResult {
+        if result.score.abs() >= WIN_SCORE - MAX_PLY {
+            return result;
+        }
+        let mut search = Search {
+            rules: state.rules(),
+            root: state.to_move(),
+            noise: self.noise,
+            seed,
+            ply: 0,
+            nodes: 0,
+            node_limit: limits.nodes,
+            deadline: limits.time.map(|limit| Instant::now() + limit),
+            stop,
+            aborted: false,
+        };
+        let mut board = state.board().clone();
+        for depth in result.depth + 1..=limits.depth.unwrap_or(MAX_DEPTH) {
+            let mut line = Vec::new();
+            let score = search.search(
+                &mut board,
+                state.to_move(),
+                depth,
+                (-INFINITY, INFINITY),
+                &result.principal_variation,
+                &mut line,
+            );
+            if search.aborted {
+                break;
+            }
+            result = SearchResult {
+                best_move: line.first().copied(),
+                score,
+                principal_variation: line,
+                depth,
+                nodes: result.nodes,
+            };
+            if score.abs() >= WIN_SCORE - MAX_PLY {
+                break;
+            }
+        }
+        result.nodes += search.nodes;
+        result
+    }
+}
+
+/// The state of one search, shared by every node.
+struct Search<'a> {
+    rules: &'a RulesConfig,
+    /// The player the engine is choosing a move for.
+    root: Player,
+    noise: i32,
+    seed: u64,
+    /// How many plies below the root the current node is.
+    ply: i32,
+    nodes: u64,
+    node_limit: Option<u64>,
+    deadline: Option<Instant>,
+    /// Set from outside to end the search early.
+    stop: Option<&'a AtomicBool>,
+    /// Set once the budget runs out, after which every score is meaningless.
+    aborted: bool,
+}
+
+impl Search<'_> {
+    /// The score of `board` with `to_move` to play, from the root player's point of view,
+    /// searching `depth` more plies. `window` is the alpha-beta window, `hint` a line to try
+    /// first, usually from the last shallower search, and `line` gets the best line found.
+    fn search(
+        &mut self,
+        board: &mut Board,
+        to_move: Player,
+        depth: u32,
+        (mut alpha, mut beta): (i32, i32),
+        hint: &[Move],
+        line: &mut Vec<Move>,
+    ) -> i32 {
+        line.clear();
+        if let Some(result) = board.result(self.rules) {
+            return self.result_score(result);
+        }
+        if depth == 0 {
+            return self.quiesce(board, to_move, (alpha, beta), QUIESCENCE_DEPTH);
+        }
+        let legal = board.legal_moves(to_move, self.rules);
+        if legal.is_empty() {
+            return self.leaf_score(board, to_move);
+        }
+        // Likely winners of material first, for earlier cutoffs, then the hint ahead of them all
+        let mut moves: Vec<(Move, i32)> = legal
+            .into_iter()
+            .map(|player_move| {
+                // legal_moves only lists moves that can be made
+                let exchange =
+                    static_exchange(board, &player_move, to_move, self.rules).unwrap_or(0);
+                (player_move, exchange)
+            })
+            .collect();
+        moves.sort_by_key(|&(_, exchange)| Reverse(exchange));
+        if let Some(first) = hint.first()
+            && let Some(index) = moves.iter().position(|(m, _)| m == first)
+        {
+            moves[..=index].rotate_right(1);
+        }
+
+        let maximizing = to_move == self.root;
+        let mut best = if maximizing { -INFINITY } else { INFINITY };
+        let mut child_line = Vec::new();
+        for (i, (player_move, exchange)) in moves.into_iter().enumerate() {
+            // One ply from the horizon, don't bother with moves that throw material away. The
+            // root keeps them all, so a lost position still gets a move.
+            if depth == 1 && self.ply > 0 && i > 0 && exchange < -EXCHANGE_PRUNING_MARGIN {
+                continue;
+            }
+            if self.out_of_budget() {
+                self.aborted = true;
+                return 0;
+            }
+            self.nodes += 1;
+            // legal_moves only lists moves that can be made
+            let undo = board.make_move(&player_move, to_move, self.rules).unwrap();
+            self.ply += 1;
+            let next = self.rules.players.next_active(to_move, board);
+            let child_hint = if i == 0 && hint.first() == Some(&player_move) {
+                &hint[1..]
+            } else {
+                &[]
+            };
+            let score = self.search(
+                board,
+                next,
+                depth - 1,
+                (alpha, beta),
+                child_hint,
+                &mut child_line,
+            );
+            self.ply -= 1;
+            board.unmake_move(undo);
+            if self.aborted {
+                return 0;
+            }
+            let better = if maximizing {
+                score > best
+            } else {
+                score < best
+            };
+            if better {
+                best = score;
+                line.clear();
+                line.push(player_move);
+                line.extend_from_slice(&child_line);
+            }
+            if maximizing {
+                alpha = alpha.max(score);
+            } else {
+                beta = beta.min(score);
+            }
+            if alpha >= beta {
+                break;
+            }
+        }
+        best
+    }
+
+    /// Like [`Search::search`] at depth 0, but instead of stopping there, follows loud moves for
+    /// up to `depth` more plies. The player to move can always settle for the static evaluation
+    /// instead of making a loud move.
+    fn quiesce(
+        &mut self,
+        board: &mut Board,
+        to_move: Player,
+        (mut alpha, mut beta): (i32, i32),
+        depth: u32,
+    ) -> i32 {
+        let mut best = self.leaf_score(board, to_move);
+        let maximizing = to_move == self.root;
+        if depth == 0 || (maximizing && best >= beta) || (!maximizing && best <= alpha) {
+            return best;
+        }
+        if maximizing {
+            alpha = alpha.max(best);
+        } else {
+            beta = beta.min(best);
+        }
+        let loud_cells = self.loud_cells(board, to_move);
+        for player_move in board.legal_moves(to_move, self.rules) {
+            if let Some(cells) = &loud_cells {
+                let touches = |cell| cells.contains(&cell);
+                if !touches(player_move.from) && !player_move.to(board.size()).is_some_and(touches)
+                {
+                    continue;
+                }
+            }
+            if self.out_of_budget() {
+                self.aborted = true;
+                return 0;
+            }
+            self.nodes += 1;
+            // legal_moves only lists moves that can be made
+            let undo = board.make_move(&player_move, to_move, self.rules).unwrap();
+            self.ply += 1;
+            let next = self.rules.players.next_active(to_move, board);
+            let score = match board.result(self.rules) {
+                Some(result) => Some(self.result_score(result)),
+                None if self.threatens_king(board, next) => {
+                    Some(self.quiesce(board, next, (alpha, beta), depth - 1))
+                }
+                None => None,
+            };
+            self.ply -= 1;
+            board.unmake_move(undo);
+            if self.aborted {
+                return 0;
+            }
+            let Some(score) = score else {
+                continue;
+            };
+            if maximizing {
+                best = best.max(score);
+                alpha = alpha.max(score);
+            } else {
+                best = best.min(score);
+                beta = beta.min(score);
+            }
+            if alpha >= beta {
+                break;
+            }
+        }
+        best
+    }
+
+    /// The cells a move by `to_move` has to start or end on to be loud, or `None` if every move
+    /// might be. A move anywhere else leaves both their laser and the next player's on the same
+    /// path, so it can only destroy a king if their laser already would, and threatens what the
+    /// next player's threatens now. Saves making every move just to find out it's quiet.
+    ///
+    /// Paths start on the cell in front of the emitter, so the emitters count too: turning one
+    /// changes the whole path.
+    fn loud_cells(&self, board: &Board, to_move: Player) -> Option<HashSet<USizeVec2>> {
+        let next = self.rules.players.next_active(to_move, board);
+        if self.threatens_king(board, to_move) || self.threatens_king(board, next) {
+            return None;
+        }
+        let mine = board.fire_laser(to_move, self.rules);
+        let theirs = board.fire_laser(next, self.rules);
+        let emitters = board
+            .pieces()
+            .filter(|(_, piece)| matches!(piece.kind, PieceKind::Emitter(_)))
+            .map(|(position, _)| position);
+        Some(
+            mine.steps
+                .iter()
+                .chain(&theirs.steps)
+                .map(|step| step.position)
+                .chain(emitters)
+                .collect(),
+        )
+    }
+
+    /// Whether `player`'s laser would destroy a king if they fired it now.
+    fn threatens_king(&self, board: &Board, player: Player) -> bool {
+        board.laser_hits(player, self.rules).iter().any(|hit| {
+            hit.piece.kind == PieceKind::King
+                && hit.piece.allegiance != player
+                && hit.replacement.is_none()
+        })
+    }
+
+    /// Wins and losses sooner are better and worse than ones later.
+    fn result_score(&self, result: GameResult) -> i32 {
+        match result {
+            GameResult::Win { winner, .. } if winner == self.root => WIN_SCORE - self.ply,
+            GameResult::Win { .. } => -WIN_SCORE + self.ply,
+            GameResult::Draw { .. } => 0,
+        }
+    }
+
+    fn leaf_score(&self, board: &Board, to_move: Player) -> i32 {
+        let mut score = evaluate_position(board, to_move, self.rules);
+        if self.noise > 0 {
+            let roll = splitmix64(board.position_hash(to_move) ^ self.seed);
+            score += (roll % (2 * self.noise as u64 + 1)) as i32 - self.noise;
+        }
+        if to_move == self.root { score } else { -score }
+    }
+
+    fn out_of_budget(&self) -> bool {
+        // Checking the clock is slow next to making a move, so only look every so often
+        self.stop.is_some_and(|stop| stop.load(Ordering::Relaxed))
+            || self.node_limit.is_some_and(|limit| self.nodes >= limit)
+            || self.nodes.is_multiple_of(256)
+                && self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+        thread,
+        time::Duration,
+    };
+
+    use bevy_math::{CompassOctant, CompassQuadrant, usizevec2};
+
+    use crate::logic::{
+        Board, GameResult, GameState, Move, MoveKind, OpeningBook, Piece, Player, RulesConfig,
+    };
+
+    use super::{AlphaBeta, Difficulty, Engine, MAX_PLY, SearchLimits, WIN_SCORE};
+
+    /// A 4x4 board where player 1's laser runs up the left edge towards player 2's king, but
+    /// stops on player 1's own block on the way, with `to_move` to play.
+    fn blocked_shot(to_move: Player) -> GameState {
+        let mut board = Board::empty(4, 4);
+        board[usizevec2(0, 0)] = Some(Piece::emitter(Player::Player1, CompassQuadrant::North));
+        board[usizevec2(0, 1)] = Some(Piece::block(Player::Player1));
+        board[usizevec2(3, 0)] = Some(Piece::king(Player::Player1));
+        board[usizevec2(2, 3)] = Some(Piece::emitter(Player::Player2, CompassQuadrant::South));
+        board[usizevec2(0, 3)] = Some(Piece::king(Player::Player2));
+        GameState::with_player_to_move(board, to_move).with_rules(RulesConfig {
+            reserved_squares: Vec::new(),
+            ..RulesConfig::default()
+        })
+    }
+
+    fn depth(depth: u32) -> SearchLimits {
+        SearchLimits {
+            depth: Some(depth),
+            ..SearchLimits::default()
+        }
+    }
+
+    #[test]
+    fn takes_a_win_when_there_is_one() {
+        let mut state = blocked_shot(Player::Player1);
+        let result = AlphaBeta::new().best_move(&state, depth(3));
+        assert!(result.score >= WIN_SCORE - MAX_PLY);
+        assert_eq!(
+            result.principal_variation.first(),
+            result.best_move.as_ref()
+        );
+        state.apply(&result.best_move.unwrap()).unwrap();
+        assert!(matches!(
+            state.result(),
+            Some(GameResult::Win {
+                winner: Player::Player1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn steps_out_of_the_way_of_a_shot() {
+        let mut state = blocked_shot(Player::Player2);
+        let mut board = state.board().clone();
+        board[usizevec2(0, 1)] = None;
+        state = GameState::with_player_to_move(board, Player::Player2)
+            .with_rules(state.rules().clone());
+        let result = AlphaBeta::new().best_move(&state, depth(2));
+        assert!(result.score > MAX_PLY - WIN_SCORE, "{}", result.score);
+        state.apply(&result.best_move.unwrap()).unwrap();
+        let reply = AlphaBeta::new().best_move(&state, depth(1));
+        assert!(reply.score < WIN_SCORE - MAX_PLY, "{}", reply.score);
+    }
+
+    #[test]
+    fn stays_within_its_limits() {
+        let state = GameState::new(Board::classic_setup());
+        let mut engine = AlphaBeta::new();
+        let result = engine.best_move(&state, depth(2));
+        assert_eq!(result.depth, 2);
+        assert_eq!(result.principal_variation.len(), 2);
+
+        let result = engine.best_move(
+            &state,
+            SearchLimits {
+                nodes: Some(10),
+                ..SearchLimits::default()
+            },
+        );
+        assert_eq!(result.depth, 0);
+        assert!(state.legal_moves().contains(&result.best_move.unwrap()));
+        assert!(result.nodes <= 10);
+
+        let stop = Arc::new(AtomicBool::new(true));
+        let result = AlphaBeta::new()
+            .stop_flag(stop)
+            .best_move(&state, SearchLimits::default());
+        assert_eq!(result.depth, 0);
+        assert!(result.best_move.is_some());
+    }
+
+    #[test]
+    fn finished_games_have_no_move() {
+        let mut state = blocked_shot(Player::Player1);
+        state
+            .apply(&Move {
+                from: usizevec2(0, 1),
+                kind: MoveKind::Move(CompassOctant::East),
+            })
+            .unwrap();
+        let result = AlphaBeta::new().best_move(&state, depth(2));
+        assert_eq!(result.best_move, None);
+        assert!(result.principal_variation.is_empty());
+    }
+
+    #[test]
+    fn plays_from_the_book_without_searching() {
+        let state = GameState::new(Board::classic_setup());
+        let book_move = *state.legal_moves().last().unwrap();
+        let mut book = OpeningBook::new();
+        book.add(state.position_hash(), book_move, 1);
+        let result = AlphaBeta::new().book(book).best_move(&state, depth(2));
+        assert_eq!(result.best_move, Some(book_move));
+        assert_eq!((result.depth, result.nodes), (0, 0));
+    }
+
+    #[test]
+    fn pondering_carries_over_when_the_prediction_is_right() {
+        let state = GameState::new(Board::classic_setup());
+        let predicted = state.legal_moves()[0];
+        let mut expected = state.clone();
+        expected.apply(&predicted).unwrap();
+
+        let ponder = |engine: &mut AlphaBeta| {
+            let stop = AtomicBool::new(false);
+            thread::scope(|scope| {
+                scope.spawn(|| {
+                    thread::sleep(Duration::from_millis(200));
+                    stop.store(true, Ordering::Relaxed);
+                });
+                engine.ponder(&state, predicted, &stop);
+            });
+        };
+        let mut engine = AlphaBeta::new();
+        ponder(&mut engine);
+        // Already deeper than asked for, from pondering
+        assert!(engine.best_move(&expected, depth(1)).depth > 1);
+
+        let mut engine = AlphaBeta::new();
+        ponder(&mut engine);
+        let mut other = state.clone();
+        other.apply(&state.legal_moves()[1]).unwrap();
+        assert_eq!(engine.best_move(&other, depth(1)).depth, 1);
+    }
+
+    #[test]
+    fn difficulties_are_named_and_seeded() {
+        for difficulty in Difficulty::ALL {
+            assert_eq!(difficulty.to_string().parse(), Ok(difficulty));
+            let state = blocked_shot(Player::Player2);
+            let play = |seed| {
+                difficulty
+                    .engine(seed)
+                    .best_move(&state, difficulty.limits())
+            };
+            assert_eq!(play(7), play(7));
+        }
+        assert_eq!("HARD".parse(), Ok(Difficulty::Hard));
+        assert!("impossible".parse::<Difficulty>().is_err());
+        // Even the easiest bot doesn't blunder away a win it can see every time
+        let wins = (0..20)
+            .filter(|&seed| {
+                let mut state = blocked_shot(Player::Player1);
+                let mut easy = Difficulty::Easy.engine(seed);
+                let result = easy.best_move(&state, Difficulty::Easy.limits());
+                state.apply(&result.best_move.unwrap()).unwrap();
+                state.result().is_some()
+            })
+            .count();
+        assert!(wins >= 10, "{wins}");
+    }
+}
@@ -0,0 +1,187 @@
+//! Matches between two engines, for telling whether a change to an engine made it stronger.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::logic::{Board, GameRecord, GameResult, Player, RulesConfig, SetupKind};
+
+use super::{Engine, SearchLimits, selfplay::play_game};
+
+/// A match between two engines. Games are played in pairs from the same random opening, each
+/// engine playing player 1 once, and each pair moves on to the next setup in turn, so neither
+/// engine gets the better side of an opening.
+#[derive(Clone, Debug)]
+pub struct Arena {
+    games: usize,
+    setups: Vec<SetupKind>,
+    rules: RulesConfig,
+    opening_plies: usize,
+    max_plies: usize,
+    seed: u64,
+}
+
+/// The score of a match so far, from the first engine's point of view.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchResult {
+    pub wins: u32,
+    /// Including games cut off before they ended.
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Arena {
+    /// A match of `games` games from the official setups under the default rules, with 4 random
+    /// opening plies and games cut off as draws after 300 plies.
+    pub fn new(games: usize) -> Self {
+        Self {
+            games,
+            setups: SetupKind::ALL.to_vec(),
+            rules: RulesConfig::default(),
+            opening_plies: 4,
+            max_plies: 300,
+            seed: 0,
+        }
+    }
+
+    /// Play from these setups in turn. An empty list plays the classic setup.
+    pub fn setups(mut self, setups: Vec<SetupKind>) -> Self {
+        self.setups = setups;
+        self
+    }
+
+    pub fn rules(mut self, rules: RulesConfig) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Start each pair of games with `plies` random moves, none of which end the game.
+    pub fn opening_plies(mut self, plies: usize) -> Self {
+        self.opening_plies = plies;
+        self
+    }
+
+    /// Call games that haven't ended after `plies` moves draws.
+    pub fn max_plies(mut self, plies: usize) -> Self {
+        self.max_plies = plies;
+        self
+    }
+
+    /// Seed the random openings, so the match can be played again with the same ones.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Plays the match, searching each engine's moves within its `limits`, and calls `on_game`
+    /// with each game as it finishes and the score so far. In the records, the first engine is
+    /// named "first" and the second "second". An engine with no move or that picks an illegal
+    /// one loses the game.
+    pub fn play(
+        &self,
+        engines: [&mut dyn Engine; 2],
+        limits: [SearchLimits; 2],
+        mut on_game: impl FnMut(&GameRecord, &MatchResult),
+    ) -> MatchResult {
+        let mut engines = engines;
+        let mut result = MatchResult::default();
+        for game in 0..self.games {
+            let pair = game / 2;
+            let setup = self
+                .setups
+                .get(pair % self.setups.len().max(1))
+                .copied()
+                .unwrap_or_default();
+            // Which engine plays player 1: the first, then the second
+            let first_starts = game % 2 == 0;
+            let engine_for =
+                |player: Player| usize::from((player == Player::Player1) != first_starts);
+            let mut played = play_game(
+                Board::from_setup(setup, &self.rules),
+                &self.rules,
+                self.opening_plies,
+                self.max_plies,
+                self.seed.wrapping_add(pair as u64),
+                |state| {
+                    let engine = engine_for(state.to_move());
+                    engines[engine].best_move(state, limits[engine])
+                },
+            );
+            played.record.players = if first_starts {
+                ["first".into(), "second".into()]
+            } else {
+                ["second".into(), "first".into()]
+            };
+            let winner = match (played.forfeited, played.record.result) {
+                (Some(player), _) => Some(1 - engine_for(player)),
+                (None, Some(GameResult::Win { winner, .. })) => Some(engine_for(winner)),
+                (None, _) => None,
+            };
+            match winner {
+                Some(0) => result.wins += 1,
+                Some(_) => result.losses += 1,
+                None => result.draws += 1,
+            }
+            on_game(&played.record, &result);
+        }
+        result
+    }
+}
+
+impl MatchResult {
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// The first engine's share of the points, with a draw worth half a win, or 0.5 before any
+    /// games.
+    pub fn score(&self) -> f64 {
+        match self.games() {
+            0 => 0.5,
+            games => (f64::from(self.wins) + f64::from(self.draws) / 2.0) / f64::from(games),
+        }
+    }
+
+    /// The range the first engine's true score lies in with 95% confidence, from the spread of
+    /// the results so far.
+    pub fn score_interval(&self) -> (f64, f64) {
+        let games = f64::from(self.games().max(1));
+        let score = self.score();
+        let variance = (f64::from(self.wins) * (1.0 - score).powi(2)
+            + f64::from(self.draws) * (0.5 - score).powi(2)
+            + f64::from(self.losses) * score.powi(2))
+            / games;
+        let margin = 1.96 * (variance / games).sqrt();
+        ((score - margin).max(0.0), (score + margin).min(1.0))
+    }
+
+    /// How much stronger the first engine is in Elo, with the 95% confidence range. A score of
+    /// 0 or 1 is infinitely far apart.
+    pub fn elo_difference(&self) -> (f64, (f64, f64)) {
+        let (low, high) = self.score_interval();
+        (elo(self.score()), (elo(low), elo(high)))
+    }
+}
+
+/// The Elo difference that gives an expected score of `score`.
+fn elo(score: f64) -> f64 {
+    -400.0 * (1.0 / score - 1.0).log10()
+}
+
+impl fmt::Display for MatchResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (low, high) = self.score_interval();
+        let (elo, (elo_low, elo_high)) = self.elo_difference();
+        write!(
+            f,
+            "+{} ={} -{}, score {:.1}% [{:.1}%, {:.1}%], Elo {elo:+.0} \
+             [{elo_low:+.0}, {elo_high:+.0}]",
+            self.wins,
+            self.draws,
+            self.losses,
+            self.score() * 100.0,
+            low * 100.0,
+            high * 100.0,
+        )
+    }
+}
@@ -0,0 +1,194 @@
+//! The engine playing itself, to produce games and labelled positions for training evaluation
+//! functions.
+
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::logic::{
+    Annotation, Board, GameRecord, GameResult, GameState, Move, Player, RulesConfig, SetupKind,
+    TimedMove, splitmix64,
+};
+
+use super::{Engine, SearchLimits, SearchResult};
+
+/// How to play self-play games. Each game starts with a few random moves, so an engine that
+/// always plays the same move in the same position still plays different games, then the engine
+/// plays both sides.
+#[derive(Clone, Debug)]
+pub struct SelfPlay {
+    setup: SetupKind,
+    rules: RulesConfig,
+    limits: SearchLimits,
+    opening_plies: usize,
+    max_plies: usize,
+}
+
+/// One self-play game, with a label for every position the engine searched.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SelfPlayGame {
+    /// The game, with the engine's score for each move it chose as the move's
+    /// [`eval`](Annotation::eval).
+    pub record: GameRecord,
+    pub positions: Vec<LabeledPosition>,
+}
+
+/// A position from a self-play game, with what the engine made of it and how the game went.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabeledPosition {
+    /// The position and player to move, in [notation](crate::logic::GameState::from_notation).
+    /// The rules are the game's.
+    pub position: String,
+    pub best_move: Move,
+    /// The engine's score for the player to move.
+    pub score: i32,
+    /// How deep the search behind `score` went.
+    pub depth: u32,
+    /// How the game ended for the player to move: 1 for a win, -1 for a loss and 0 for a draw or
+    /// a game cut off before it ended.
+    pub outcome: i8,
+}
+
+impl SelfPlay {
+    /// Games from the classic setup under the default rules, searching each move within
+    /// `limits`, with 4 random opening plies and cut off after 300 plies.
+    pub fn new(limits: SearchLimits) -> Self {
+        Self {
+            setup: SetupKind::Classic,
+            rules: RulesConfig::default(),
+            limits,
+            opening_plies: 4,
+            max_plies: 300,
+        }
+    }
+
+    pub fn setup(mut self, setup: SetupKind) -> Self {
+        self.setup = setup;
+        self
+    }
+
+    pub fn rules(mut self, rules: RulesConfig) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Start each game with `plies` random moves, none of which end it. They aren't labelled.
+    pub fn opening_plies(mut self, plies: usize) -> Self {
+        self.opening_plies = plies;
+        self
+    }
+
+    /// Stop games that haven't ended after `plies` moves, leaving them without a result.
+    pub fn max_plies(mut self, plies: usize) -> Self {
+        self.max_plies = plies;
+        self
+    }
+
+    /// Plays one game, with the random opening picked by `seed`. If the engine has no move or
+    /// picks an illegal one, the game stops there without a result.
+    pub fn play(&self, engine: &mut dyn Engine, seed: u64) -> SelfPlayGame {
+        let mut played = play_game(
+            Board::from_setup(self.setup, &self.rules),
+            &self.rules,
+            self.opening_plies,
+            self.max_plies,
+            seed,
+            |state| engine.best_move(state, self.limits),
+        );
+        played.record.players = ["self-play".into(), "self-play".into()];
+        SelfPlayGame {
+            record: played.record,
+            positions: played.positions,
+        }
+    }
+}
+
+/// A game played by [`play_game`].
+pub(super) struct PlayedGame {
+    /// The game, with nobody's name filled in.
+    pub record: GameRecord,
+    pub positions: Vec<LabeledPosition>,
+    /// Whoever had no move or picked an illegal one, which ended the game early.
+    pub forfeited: Option<Player>,
+}
+
+/// Plays a game from `board`, with `opening_plies` random moves picked by `seed` and the rest
+/// chosen by `choose`, until it ends or reaches `max_plies`.
+pub(super) fn play_game(
+    board: Board,
+    rules: &RulesConfig,
+    opening_plies: usize,
+    max_plies: usize,
+    mut seed: u64,
+    mut choose: impl FnMut(&GameState) -> SearchResult,
+) -> PlayedGame {
+    let mut state = GameState::new(board.clone()).with_rules(rules.clone());
+    let mut record = GameRecord::new(Default::default(), board, rules.clone());
+    // Each labelled position with the player it's labelled for
+    let mut positions: Vec<(Player, LabeledPosition)> = Vec::new();
+    let mut forfeited = None;
+    let start = Instant::now();
+    while state.result().is_none() && record.moves.len() < max_plies {
+        let mut annotation = Annotation::default();
+        let player_move = if record.moves.len() < opening_plies {
+            // Only moves that keep the game going, so the opening doesn't decide it
+            let moves: Vec<Move> = state
+                .legal_moves()
+                .into_iter()
+                .filter(|player_move| {
+                    let mut next = state.clone();
+                    next.apply(player_move).is_ok() && next.result().is_none()
+                })
+                .collect();
+            seed = splitmix64(seed);
+            let Some(&random_move) = moves.get((seed % moves.len().max(1) as u64) as usize) else {
+                break;
+            };
+            random_move
+        } else {
+            let result = choose(&state);
+            let Some(best_move) = result.best_move else {
+                forfeited = Some(state.to_move());
+                break;
+            };
+            annotation.eval = Some(result.score);
+            let label = LabeledPosition {
+                position: state.to_string(),
+                best_move,
+                score: result.score,
+                depth: result.depth,
+                outcome: 0,
+            };
+            positions.push((state.to_move(), label));
+            best_move
+        };
+        if state.apply(&player_move).is_err() {
+            // Random moves are always legal, so the label is for the engine's bad move
+            positions.pop();
+            forfeited = Some(state.to_move());
+            break;
+        }
+        record.moves.push(TimedMove {
+            player_move,
+            elapsed: start.elapsed(),
+            annotation,
+        });
+    }
+    record.result = state.result();
+    let positions = positions
+        .into_iter()
+        .map(|(player, mut label)| {
+            label.outcome = match record.result {
+                Some(GameResult::Win { winner, .. }) if winner == player => 1,
+                Some(GameResult::Win { .. }) => -1,
+                Some(GameResult::Draw { .. }) | None => 0,
+            };
+            label
+        })
+        .collect();
+    PlayedGame {
+        record,
+        positions,
+        forfeited,
+    }
+}
@@ -0,0 +1,124 @@
+//! Plays the built-in engine at two settings against each other and reports how the first did,
+//! with 95% confidence intervals on its score and Elo difference. Useful for checking that an
+//! engine change is an improvement and not just noise.
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use clap::Parser;
+use laser_chess::{
+    ai::{Arena, Difficulty, SearchLimits},
+    logic::{GameResult, SetupKind},
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "laser-chess-arena")]
+#[command(about = "Play Laser Chess engine settings against each other", long_about = None)]
+struct Args {
+    /// Level of the first engine
+    #[arg(long, default_value_t = Difficulty::Hard)]
+    first: Difficulty,
+
+    /// Level of the second engine
+    #[arg(long, default_value_t = Difficulty::Medium)]
+    second: Difficulty,
+
+    /// Search depth per move for the first engine, instead of its level's
+    #[arg(long)]
+    first_depth: Option<u32>,
+
+    /// Search depth per move for the second engine, instead of its level's
+    #[arg(long)]
+    second_depth: Option<u32>,
+
+    /// Time per move in milliseconds for both engines, instead of their levels'
+    #[arg(long)]
+    movetime: Option<u64>,
+
+    /// How many games to play. An even number gives both engines each side of every opening
+    #[arg(short = 'n', long, default_value_t = 100)]
+    games: usize,
+
+    /// Opening positions to take turns with
+    #[arg(short, long, value_delimiter = ',', default_values_t = SetupKind::ALL)]
+    setups: Vec<SetupKind>,
+
+    /// Random moves at the start of each pair of games
+    #[arg(long, default_value_t = 4)]
+    opening_plies: usize,
+
+    /// Call games that haven't ended after this many moves draws
+    #[arg(long, default_value_t = 300)]
+    max_plies: usize,
+
+    /// Seed for the random openings and the engines' randomness
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Directory to save the games in, as game records
+    #[arg(long)]
+    save: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let limits = |difficulty: Difficulty, depth: Option<u32>| {
+        let defaults = difficulty.limits();
+        SearchLimits {
+            depth: depth.or(defaults.depth),
+            time: args.movetime.map(Duration::from_millis).or(defaults.time),
+            ..defaults
+        }
+    };
+    let limits = [
+        limits(args.first, args.first_depth),
+        limits(args.second, args.second_depth),
+    ];
+    let mut first = args.first.engine(args.seed);
+    let mut second = args.second.engine(args.seed.wrapping_add(1));
+    if let Some(dir) = &args.save {
+        fs::create_dir_all(dir)?;
+    }
+
+    let arena = Arena::new(args.games)
+        .setups(args.setups.clone())
+        .opening_plies(args.opening_plies)
+        .max_plies(args.max_plies)
+        .seed(args.seed);
+    let mut game = 0;
+    let mut save_error = None;
+    let result = arena.play([&mut first, &mut second], limits, |record, score| {
+        game += 1;
+        let result = match record.result {
+            Some(GameResult::Win { winner, .. }) => format!("{} won", record.player_name(winner)),
+            Some(GameResult::Draw { .. }) => "Draw".to_string(),
+            None => "Unfinished".to_string(),
+        };
+        eprintln!(
+            "Game {game}/{}: {result} after {} plies ({} vs {}). {score}",
+            args.games,
+            record.moves.len(),
+            record.players[0],
+            record.players[1],
+        );
+        if let Some(dir) = &args.save
+            && let Err(e) = fs::write(dir.join(format!("game-{game:04}.txt")), record.to_string())
+        {
+            save_error.get_or_insert(e);
+        }
+    });
+    if let Some(e) = save_error {
+        return Err(e.into());
+    }
+    println!(
+        "{} ({}) vs {} ({}): {result}",
+        args.first,
+        limits[0]
+            .depth
+            .map_or("unlimited".to_string(), |depth| format!("depth {depth}")),
+        args.second,
+        limits[1]
+            .depth
+            .map_or("unlimited".to_string(), |depth| format!("depth {depth}")),
+    );
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! The built-in engine behind a line-based protocol on stdin and stdout, modelled on chess's UCI
+//! so GUIs and tournament managers can drive it like a chess engine. Commands:
+//!
+//! - `uci`: answered with the engine's `id` lines and `uciok`.
+//! - `isready`: answered with `readyok`, even mid-search.
+//! - `ucinewgame`: waits for any search to finish.
+//! - `position startpos [moves ...]`, `position setup <setup> [moves ...]` or
+//!   `position fen <board> <player> [moves ...]`: the classic setup, a named setup such as
+//!   `imhotep` or `random:42`, or a position in the notation from `logic::notation`, followed by
+//!   moves in the same notation.
+//! - `go [depth N] [movetime MS] [nodes N] [infinite]`: searches the position, printing an `info`
+//!   line after each depth and `bestmove <move> [ponder <move>]` at the end, or
+//!   `bestmove (none)` once the game is over.
+//! - `stop`: ends the search early, and `quit` exits.
+//!
+//! Scores in `info` lines are `cp N` for an evaluation, positive when the player to move is
+//! ahead, or `mate N` for a forced win in N plies, negative when the player to move is the one
+//! losing. Anything the engine can't make sense of is reported in an `info string` line and
+//! otherwise ignored.
+
+use std::{
+    fs,
+    io::{self, BufRead},
+    path::PathBuf,
+    str::SplitWhitespace,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use laser_chess::{
+    ai::{AlphaBeta, Engine, SearchLimits, SearchResult},
+    logic::{Board, GameState, Move, OpeningBook, RulesConfig, SetupKind, eval::WIN_SCORE},
+};
+
+/// Scores this close to [`WIN_SCORE`] are forced wins, less one point per ply.
+const MATE_RANGE: i32 = 1_000;
+
+#[derive(Parser, Debug)]
+#[command(name = "laser-chess-engine")]
+#[command(
+    about = "Laser Chess engine speaking a UCI-style protocol on stdin and stdout",
+    long_about = None
+)]
+struct Args {
+    /// Opening book to play from, in the binary format written by OpeningBook::to_bytes
+    #[arg(long)]
+    book: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut engine = AlphaBeta::new().stop_flag(stop.clone());
+    if let Some(path) = args.book {
+        engine = engine.book(OpeningBook::from_bytes(&fs::read(path)?)?);
+    }
+    let mut state = GameState::new(Board::classic_setup());
+    // The engine is handed to the search thread and comes back when it finishes
+    let mut search: Option<JoinHandle<AlphaBeta>> = None;
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        match command {
+            "uci" => {
+                println!("id name laser-chess-engine {}", env!("CARGO_PKG_VERSION"));
+                println!("id author the Laser Chess developers");
+                println!("uciok");
+            }
+            "isready" => println!("readyok"),
+            "ucinewgame" => finish_search(&mut search, &mut engine),
+            "position" => {
+                finish_search(&mut search, &mut engine);
+                match parse_position(words) {
+                    Ok(position) => state = position,
+                    Err(e) => println!("info string {e}"),
+                }
+            }
+            "go" => {
+                finish_search(&mut search, &mut engine);
+                let limits = parse_limits(words);
+                stop.store(false, Ordering::Relaxed);
+                let engine = std::mem::take(&mut engine);
+                let state = state.clone();
+                search = Some(thread::spawn(move || think(engine, &state, limits)));
+            }
+            "stop" => {
+                stop.store(true, Ordering::Relaxed);
+                finish_search(&mut search, &mut engine);
+            }
+            "quit" => {
+                stop.store(true, Ordering::Relaxed);
+                finish_search(&mut search, &mut engine);
+                break;
+            }
+            command => println!("info string Unknown command '{command}'"),
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the search, if one is running, and takes the engine back.
+fn finish_search(search: &mut Option<JoinHandle<AlphaBeta>>, engine: &mut AlphaBeta) {
+    if let Some(handle) = search.take() {
+        *engine = handle.join().expect("Search thread panicked");
+    }
+}
+
+fn parse_position(mut words: SplitWhitespace) -> Result<GameState, String> {
+    let mut state = match words.next() {
+        Some("startpos") => GameState::new(Board::classic_setup()),
+        Some("setup") => {
+            let setup: SetupKind = words.next().ok_or("Missing setup name")?.parse()?;
+            GameState::new(Board::from_setup(setup, &RulesConfig::default()))
+        }
+        Some("fen") => {
+            let board = words.next().ok_or("Missing board")?;
+            let to_move = words.next().ok_or("Missing player to move")?;
+            format!("{board} {to_move}")
+                .parse()
+                .map_err(|e| format!("Invalid position: {e}"))?
+        }
+        Some(other) => return Err(format!("Unknown position '{other}'")),
+        None => return Err("Missing position".into()),
+    };
+    match words.next() {
+        Some("moves") => {}
+        Some(other) => return Err(format!("Expected 'moves', found '{other}'")),
+        None => return Ok(state),
+    }
+    for notation in words {
+        let player_move = Move::parse(notation).map_err(|e| format!("Invalid move: {e}"))?;
+        state
+            .apply(&player_move)
+            .map_err(|e| format!("Illegal move {player_move}: {e}"))?;
+    }
+    Ok(state)
+}
+
+fn parse_limits(mut words: SplitWhitespace) -> SearchLimits {
+    let mut limits = SearchLimits::default();
+    while let Some(word) = words.next() {
+        let mut value = || words.next().and_then(|value| value.parse::<u64>().ok());
+        match word {
+            "depth" => limits.depth = value().map(|depth| depth as u32),
+            "movetime" => limits.time = value().map(Duration::from_millis),
+            "nodes" => limits.nodes = value(),
+            // Searching without limits is the default, `stop` ends it
+            "infinite" => {}
+            other => println!("info string Ignoring '{other}'"),
+        }
+    }
+    limits
+}
+
+/// Searches one depth at a time so each finished depth can be reported, then prints the best
+/// move. Every search starts over from depth 1, which costs little next to the deepest one.
+fn think(mut engine: AlphaBeta, state: &GameState, limits: SearchLimits) -> AlphaBeta {
+    let start = Instant::now();
+    let deadline = limits.time.map(|time| start + time);
+    let mut nodes = 0;
+    let mut best: Option<SearchResult> = None;
+    for depth in 1..=limits.depth.unwrap_or(u32::MAX).max(1) {
+        let result = engine.best_move(
+            state,
+            SearchLimits {
+                depth: Some(depth),
+                nodes: limits.nodes.map(|limit| limit.saturating_sub(nodes)),
+                time: deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            },
+        );
+        nodes += result.nodes;
+        // Stopped, out of budget, a forced result or a move played without searching
+        let finished = result.depth < depth;
+        if best.as_ref().is_none_or(|best| result.depth > best.depth) {
+            if result.depth > 0 {
+                print_info(&result, nodes, start.elapsed());
+            }
+            best = Some(result);
+        }
+        if finished {
+            break;
+        }
+    }
+    let best = best.expect("At least one search runs");
+    match (best.best_move, best.principal_variation.get(1)) {
+        (Some(best_move), Some(reply)) => println!("bestmove {best_move} ponder {reply}"),
+        (Some(best_move), None) => println!("bestmove {best_move}"),
+        (None, _) => println!("bestmove (none)"),
+    }
+    engine
+}
+
+fn print_info(result: &SearchResult, nodes: u64, elapsed: Duration) {
+    let score = if result.score.abs() >= WIN_SCORE - MATE_RANGE {
+        let plies = WIN_SCORE - result.score.abs();
+        format!("mate {}", plies * result.score.signum())
+    } else {
+        format!("cp {}", result.score)
+    };
+    let pv: Vec<String> = result
+        .principal_variation
+        .iter()
+        .map(Move::to_string)
+        .collect();
+    println!(
+        "info depth {} score {score} nodes {nodes} time {} pv {}",
+        result.depth,
+        elapsed.as_millis(),
+        pv.join(" ")
+    );
+}
@@ -0,0 +1,114 @@
+//! Has the built-in engine play itself and writes out the games and labelled positions, for
+//! training evaluation functions. Each game is saved as `game-NNNN.txt` in the game record text
+//! format, and every position the engine searched is appended to `positions.jsonl` as a JSON
+//! object with the number of the game it came from.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::Parser;
+use laser_chess::{
+    ai::{Difficulty, LabeledPosition, SearchLimits, SelfPlay},
+    logic::{GameResult, SetupKind},
+};
+use serde::Serialize;
+
+#[derive(Parser, Debug)]
+#[command(name = "laser-chess-selfplay")]
+#[command(about = "Generate Laser Chess training data from engine self-play", long_about = None)]
+struct Args {
+    /// Directory to write the games and positions to. Created if it doesn't exist
+    #[arg(short, long)]
+    out: PathBuf,
+
+    /// How many games to play
+    #[arg(short = 'n', long, default_value_t = 100)]
+    games: usize,
+
+    /// Engine level to play at. Weaker levels add noise and random moves, which makes for more
+    /// varied games
+    #[arg(short, long, default_value_t = Difficulty::Hard)]
+    difficulty: Difficulty,
+
+    /// Search depth per move, instead of the difficulty's
+    #[arg(long)]
+    depth: Option<u32>,
+
+    /// Time per move in milliseconds, instead of the difficulty's
+    #[arg(long)]
+    movetime: Option<u64>,
+
+    /// Positions to search per move
+    #[arg(long)]
+    nodes: Option<u64>,
+
+    /// Opening position (classic, imhotep, dynasty, random:SEED or handicap:N)
+    #[arg(short, long, default_value_t = SetupKind::Classic)]
+    setup: SetupKind,
+
+    /// Random moves at the start of each game
+    #[arg(long, default_value_t = 4)]
+    opening_plies: usize,
+
+    /// Give up on games that haven't ended after this many moves
+    #[arg(long, default_value_t = 300)]
+    max_plies: usize,
+
+    /// Seed for the random openings and the engine's randomness. The same seed and settings play
+    /// the same games
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// A line of `positions.jsonl`.
+#[derive(Serialize)]
+struct PositionLine<'a> {
+    game: usize,
+    #[serde(flatten)]
+    position: &'a LabeledPosition,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let defaults = args.difficulty.limits();
+    let limits = SearchLimits {
+        depth: args.depth.or(defaults.depth),
+        nodes: args.nodes.or(defaults.nodes),
+        time: args.movetime.map(Duration::from_millis).or(defaults.time),
+    };
+    let self_play = SelfPlay::new(limits)
+        .setup(args.setup)
+        .opening_plies(args.opening_plies)
+        .max_plies(args.max_plies);
+    let mut engine = args.difficulty.engine(args.seed);
+
+    fs::create_dir_all(&args.out)?;
+    let mut positions = BufWriter::new(File::create(args.out.join("positions.jsonl"))?);
+    for game in 1..=args.games {
+        let played = self_play.play(&mut engine, args.seed.wrapping_add(game as u64));
+        fs::write(
+            args.out.join(format!("game-{game:04}.txt")),
+            played.record.to_string(),
+        )?;
+        for position in &played.positions {
+            serde_json::to_writer(&mut positions, &PositionLine { game, position })?;
+            writeln!(positions)?;
+        }
+        let result = match played.record.result {
+            Some(GameResult::Win { winner, .. }) => format!("{winner:?} won"),
+            Some(GameResult::Draw { reason }) => format!("Drawn by {reason:?}"),
+            None => "Unfinished".to_string(),
+        };
+        eprintln!(
+            "Game {game}/{}: {result} after {} plies",
+            args.games,
+            played.record.moves.len()
+        );
+    }
+    positions.flush()?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Generates an endgame tablebase for the engine to play from, e.g.
+//! `laser-chess-tablebase --pieces KkMne --out kings-and-mirror.lctb` solves every position with
+//! the two kings and a player 1 mirror, or fewer pieces, on the classic board's emitters.
+
+use std::{fs, path::PathBuf};
+
+use clap::Parser;
+use laser_chess::logic::{Board, PieceKind, RulesConfig, Tablebase};
+
+#[derive(Parser, Debug)]
+#[command(name = "laser-chess-tablebase")]
+#[command(about = "Generate a Laser Chess endgame tablebase", long_about = None)]
+struct Args {
+    /// The pieces to place, written as one rank in board notation, e.g. KkMne for both kings and
+    /// a player 1 mirror. Orientations don't matter, every one is tried
+    #[arg(short, long)]
+    pieces: String,
+
+    /// Walls and emitters every position has, in board notation. Defaults to the classic board
+    /// with only the emitters left
+    #[arg(short, long)]
+    frame: Option<String>,
+
+    /// JSON file with the rules to solve under, instead of the defaults
+    #[arg(short, long)]
+    rules: Option<PathBuf>,
+
+    /// Where to write the tablebase
+    #[arg(short, long)]
+    out: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let frame = match &args.frame {
+        Some(frame) => frame.parse()?,
+        None => {
+            let mut frame = Board::classic_setup();
+            for (coord, piece) in Board::classic_setup().pieces() {
+                if !matches!(piece.kind, PieceKind::Emitter(_)) {
+                    frame[coord] = None;
+                }
+            }
+            frame
+        }
+    };
+    let pieces: Board = args.pieces.parse()?;
+    let pieces: Vec<_> = pieces.pieces().map(|(_, piece)| piece).collect();
+    let rules = match &args.rules {
+        Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+        None => RulesConfig::default(),
+    };
+
+    let tablebase = Tablebase::generate(&frame, &pieces, &rules);
+    fs::write(&args.out, tablebase.to_bytes())?;
+    println!("Solved {} positions", tablebase.len());
+    Ok(())
+}
@@ -1,257 +1,292 @@
-use axum::{
-    Router,
-    extract::{
-        State,
-        ws::{Message, WebSocket, WebSocketUpgrade},
+use std::{
+    fs, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use clap::Parser;
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    signal::{
+        self,
+        unix::{SignalKind, signal},
     },
-    http::StatusCode,
-    response::Response,
-    routing::get,
+    task::JoinSet,
+    time::timeout,
 };
-use bevy_math::usizevec2;
-use tokio::sync::mpsc::{self, UnboundedSender};
-use tracing::{error, info, warn};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
 
+#[cfg(feature = "sqlite")]
+use std::sync::Arc;
+
+#[cfg(feature = "sqlite")]
+use laser_chess::server::SqliteStore;
 use laser_chess::{
-    ClientRequest, ServerMessage,
-    logic::{Board, Orientation, Piece, Player},
+    ai::Difficulty,
+    logic::TimeControl,
+    server::{IpNetwork, OAuthClient, OAuthProvider, Server, ServerConfig},
 };
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // Initialize tracing subscriber for logging
-    tracing_subscriber::fmt::init();
+/// Every option can also be set with the environment variable named after it, for deployments
+/// that configure containers that way. Flags win over the environment.
+#[derive(Parser, Debug)]
+#[command(name = "laser-chess-server")]
+#[command(about = "Laser Chess game server", long_about = None)]
+struct Args {
+    /// Port to listen on
+    #[arg(short, long, env = "PORT", default_value_t = 10000)]
+    port: u16,
 
-    // Create matchmaking channel
-    let (matchmaking_tx, matchmaking_rx) = mpsc::unbounded_channel::<WebSocket>();
+    /// IPv4 address to listen on. Empty turns IPv4 off
+    #[arg(long, env = "BIND_IPV4", default_value = "0.0.0.0")]
+    bind_ipv4: String,
 
-    // Start the matchmaking task
-    tokio::spawn(matchmaking_loop(matchmaking_rx));
+    /// IPv6 address to listen on, if any. Empty is the same as unset
+    #[arg(long, env = "BIND_IPV6")]
+    bind_ipv6: Option<String>,
 
-    // Build the router
-    let app = Router::new()
-        .route("/game", get(websocket_handler))
-        .with_state(matchmaking_tx);
+    /// Unix socket to listen on, if any, for a reverse proxy on the same host. Empty is the same
+    /// as unset
+    #[arg(long, env = "UNIX_SOCKET")]
+    unix_socket: Option<String>,
 
-    // Get port from environment variable, default to 3000
-    let port = std::env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse::<u16>().ok())
-        .unwrap_or(10000);
+    /// Proxies whose forwarding headers are believed, as a comma-separated list of IPs or CIDR
+    /// ranges
+    #[arg(long, env = "TRUSTED_PROXIES", default_value = "")]
+    trusted_proxies: String,
 
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    info!("Server running on http://{}", addr);
+    /// Give players left waiting this many seconds for an opponent a bot instead. Unset, they
+    /// wait as long as it takes
+    #[arg(long, env = "BOT_TIMEOUT_SECS")]
+    bot_timeout_secs: Option<u64>,
 
-    axum::serve(listener, app).await?;
+    /// How strong those bots are (easy, medium or hard)
+    #[arg(long, env = "BOT_DIFFICULTY", default_value_t = Difficulty::default())]
+    bot_difficulty: Difficulty,
 
-    Ok(())
-}
+    /// Seconds a player who loses their connection mid-game has to resume it before losing. Zero
+    /// makes them lose as soon as their connection does
+    #[arg(long, env = "RECONNECT_GRACE_SECS", default_value_t = 60)]
+    reconnect_grace_secs: u64,
 
-// WebSocket handler that accepts connections and sends them to matchmaking.
-async fn websocket_handler(
-    ws: WebSocketUpgrade,
-    State(matchmaking_tx): State<UnboundedSender<WebSocket>>,
-) -> Result<Response, StatusCode> {
-    Ok(ws.on_upgrade(move |socket| async move {
-        info!("New WebSocket connection established");
-        if let Err(e) = matchmaking_tx.send(socket) {
-            error!("Failed to send connection to matchmaking: {}", e);
-        }
-    }))
-}
+    /// Seconds between pings to players, who are given up on after missing a couple. Zero turns
+    /// pings off
+    #[arg(long, env = "HEARTBEAT_SECS", default_value_t = 15)]
+    heartbeat_secs: u64,
 
-struct ConnectedPlayer {
-    connection: WebSocket,
-    name: String,
-}
+    /// Seconds a new connection has to set up before it's dropped. Zero waits as long as it takes
+    #[arg(long, env = "SETUP_TIMEOUT_SECS", default_value_t = 30)]
+    setup_timeout_secs: u64,
 
-/// Awaits a player connection, awaits a setup packet, then returns either the [`ConnectedPlayer`]
-/// or the setup error.
-async fn connect_player(mut connection: WebSocket) -> anyhow::Result<ConnectedPlayer> {
-    match connection.recv().await {
-        Some(Ok(Message::Text(text))) => {
-            let setup: ClientRequest = serde_json::from_str(&text)?;
-            match setup {
-                ClientRequest::InitialSetup { player_name } => Ok(ConnectedPlayer {
-                    connection,
-                    name: player_name,
-                }),
-                _ => Err(anyhow::anyhow!(
-                    "Expected InitialSetup message, got different message"
-                )),
-            }
-        }
-        Some(Ok(_)) => Err(anyhow::anyhow!(
-            "Expected text message for setup, got different message"
-        )),
-        Some(Err(e)) => Err(anyhow::anyhow!("WebSocket error during setup: {}", e)),
-        None => Err(anyhow::anyhow!("Connection closed during setup")),
-    }
-}
+    /// Time control for games where neither player asks for one, as minutes plus increment in
+    /// seconds (e.g. 10+5). Unset, they're untimed
+    #[arg(long, env = "DEFAULT_TIME_CONTROL")]
+    default_time_control: Option<TimeControl>,
 
-/// Matchmaking loop that pairs up players. When a player opens a connection to the server, it gets
-/// tossed into the channel sender (matchmaking queue -- only two players long). This function just
-/// reads pairs of players and starts a game for them by passing the websocket connections to the
-/// game logic.
-async fn matchmaking_loop(mut matchmaking_rx: mpsc::UnboundedReceiver<WebSocket>) {
-    info!("Matchmaking loop started");
-
-    loop {
-        let player1 = match matchmaking_rx.recv().await {
-            Some(conn) => {
-                info!("Player 1 connected, awaiting setup");
-                // spawn new task to await setup (connect_player)
-                tokio::spawn(connect_player(conn))
-            }
-            None => {
-                warn!("Matchmaking channel closed");
-                break;
-            }
-        };
-        let player2 = match matchmaking_rx.recv().await {
-            Some(conn) => {
-                info!("Player 2 connected, awaiting setup");
-                // spawn new task to await setup (connect_player)
-                tokio::spawn(connect_player(conn))
-            }
-            None => {
-                warn!("Matchmaking channel closed");
-                break;
-            }
-        };
-        // await both
-        let (player1, player2) = tokio::try_join!(player1, player2).unwrap();
-        let (Ok(player1), Ok(player2)) = (player1, player2) else {
-            info!("Player setup failed, restarting matchmaking");
-            continue;
-        };
-
-        tokio::spawn(start_game([player1, player2]));
-    }
+    /// Turn players away while this many live games are being played. Unset, there's no limit
+    #[arg(long, env = "MAX_GAMES")]
+    max_games: Option<usize>,
+
+    /// File to keep logged-in players' ratings in. Unset, they're lost when the server restarts
+    #[arg(long, env = "RATINGS_FILE")]
+    ratings_file: Option<PathBuf>,
+
+    /// SQLite database to keep games in, which needs a server built with the `sqlite` feature.
+    /// Unset, they're not kept
+    #[arg(long, env = "GAMES_DB")]
+    games_db: Option<PathBuf>,
+
+    /// Where players reach the server (e.g. https://lasers.example.com), which logging in with
+    /// GitHub or Google sends them back to
+    #[arg(long, env = "PUBLIC_URL")]
+    public_url: Option<String>,
+
+    /// Client ID of a GitHub OAuth app to let players log in with GitHub, which needs a games
+    /// database and a public URL
+    #[arg(long, env = "GITHUB_CLIENT_ID", requires = "github_client_secret")]
+    github_client_id: Option<String>,
+
+    /// That app's client secret
+    #[arg(long, env = "GITHUB_CLIENT_SECRET", requires = "github_client_id")]
+    github_client_secret: Option<String>,
+
+    /// Client ID of a Google OAuth client to let players log in with Google, which needs a games
+    /// database and a public URL
+    #[arg(long, env = "GOOGLE_CLIENT_ID", requires = "google_client_secret")]
+    google_client_id: Option<String>,
+
+    /// That client's secret
+    #[arg(long, env = "GOOGLE_CLIENT_SECRET", requires = "google_client_id")]
+    google_client_secret: Option<String>,
 
-    info!("Matchmaking loop ended");
+    /// The 32-byte seed of the ed25519 key to sign finished games with, in base64. Unset, a new
+    /// key is made up each time the server starts
+    #[arg(long, env = "SIGNING_KEY")]
+    signing_key: Option<String>,
 }
 
-async fn start_game([mut player1, mut player2]: [ConnectedPlayer; 2]) -> anyhow::Result<()> {
-    info!(
-        "Starting new game between {} and {}",
-        player1.name, player2.name
-    );
+/// How long to wait for games to be wrapped up on shutdown before exiting regardless.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
-    let mut board_state = Board {
-        cell: [[None; 8]; 8],
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Initialize tracing subscriber for logging. RUST_LOG overrides the default level, e.g.
+    // `RUST_LOG=info,laser_chess=trace` to see rule-level events from a `trace` build
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let trusted_proxies = args
+        .trusted_proxies
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(str::parse)
+        .collect::<anyhow::Result<Vec<IpNetwork>>>()?;
+    let reconnect_grace = Duration::from_secs(args.reconnect_grace_secs);
+    let heartbeat_interval = Duration::from_secs(args.heartbeat_secs);
+    let setup_timeout = Duration::from_secs(args.setup_timeout_secs);
+    let oauth_clients = [
+        (
+            OAuthProvider::GitHub,
+            args.github_client_id,
+            args.github_client_secret,
+        ),
+        (
+            OAuthProvider::Google,
+            args.google_client_id,
+            args.google_client_secret,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(provider, client_id, client_secret)| {
+        Some(OAuthClient {
+            provider,
+            client_id: client_id?,
+            client_secret: client_secret?,
+        })
+    })
+    .collect();
+    let signing_key = match args.signing_key.filter(|key| !key.is_empty()) {
+        Some(key) => {
+            let seed = BASE64_STANDARD.decode(key.trim())?;
+            let seed = <[u8; 32]>::try_from(seed)
+                .map_err(|_| anyhow::anyhow!("--signing-key has to be 32 bytes"))?;
+            Some(seed)
+        }
+        None => None,
     };
-    let pieces = {
-        use Orientation::*;
-        use Player::*;
-        [
-            (usizevec2(2, 0), Piece::two_sided(Player1, NW)),
-            (usizevec2(3, 0), Piece::block(Player1)),
-            (usizevec2(4, 0), Piece::king(Player1)),
-            (usizevec2(5, 0), Piece::block(Player1)),
-            (usizevec2(6, 0), Piece::mirror(Player1, NE)),
-            (usizevec2(3, 3), Piece::two_sided(Player1, NW)),
-            (usizevec2(3, 4), Piece::mirror(Player1, SW)),
-            (usizevec2(7, 3), Piece::mirror(Player1, SW)),
-            (usizevec2(7, 4), Piece::mirror(Player1, NW)),
-            (usizevec2(2, 5), Piece::mirror(Player1, NW)),
-            (usizevec2(2, 2), Piece::mirror(Player1, SW)),
-        ]
+    let config = ServerConfig {
+        trusted_proxies,
+        bot_timeout: args.bot_timeout_secs.map(Duration::from_secs),
+        bot_difficulty: args.bot_difficulty,
+        reconnect_grace: (!reconnect_grace.is_zero()).then_some(reconnect_grace),
+        heartbeat_interval: (!heartbeat_interval.is_zero()).then_some(heartbeat_interval),
+        setup_timeout: (!setup_timeout.is_zero()).then_some(setup_timeout),
+        ratings_file: args.ratings_file,
+        default_time_control: args.default_time_control,
+        max_games: args.max_games,
+        oauth_clients,
+        public_url: args.public_url.filter(|url| !url.is_empty()),
+        signing_key,
     };
-    for (coord, piece) in pieces {
-        board_state.cell[coord.y][coord.x] = Some(piece);
-        board_state.cell[7 - coord.y][7 - coord.x] = Some(piece.opposing());
-    }
 
-    let player0_setup = player1.connection.send(Message::text(
-        serde_json::to_string(&ServerMessage::InitialSetup {
-            board: board_state.clone(),
-            player_order: 0,
-            opponent_name: player2.name.clone(),
-        })
-        .unwrap(),
-    ));
-    let player1_setup = player2.connection.send(Message::text(
-        serde_json::to_string(&ServerMessage::InitialSetup {
-            board: board_state,
-            player_order: 1,
-            opponent_name: player1.name.clone(),
-        })
-        .unwrap(),
-    ));
-
-    tokio::try_join!(player0_setup, player1_setup).unwrap();
-
-    // Everything is officially set up!
-
-    while !board_state.game_over() {
-        // await player 1's move
-        let player_move = loop {
-            match client_request(&mut player1).await? {
-                ClientRequest::Move(player_move) => {
-                    match board_state.try_move(&player_move, Player::Player1) {
-                        Ok(()) => break player_move,
-                        Err(e) => {
-                            warn!("Invalid move from player 1: {}", e);
-                        }
-                    }
-                }
-                _ => {
-                    warn!("Expected Move message from player 1, got different message");
-                }
-            }
-        };
-
-        // notify other player, update board state
-        player2
-            .connection
-            .send(Message::text(serde_json::to_string(
-                &ServerMessage::OpponentMoved(player_move),
-            )?))
-            .await?;
-
-        // TODO abstract over the duplicate code here
-        if board_state.game_over() {
-            break;
+    let server = match args.games_db {
+        #[cfg(feature = "sqlite")]
+        Some(path) => {
+            info!("Keeping games in {}", path.display());
+            Server::with_store(config, Arc::new(SqliteStore::open(path)?))?
         }
+        #[cfg(not(feature = "sqlite"))]
+        Some(_) => anyhow::bail!("--games-db needs a server built with the sqlite feature"),
+        None => Server::new(config)?,
+    };
 
-        // await player 2's move
-        let player_move = loop {
-            match client_request(&mut player2).await? {
-                ClientRequest::Move(player_move) => {
-                    match board_state.try_move(&player_move, Player::Player2) {
-                        Ok(()) => break player_move,
-                        Err(e) => {
-                            warn!("Invalid move from player 2: {}", e);
-                        }
-                    }
-                }
-                _ => {
-                    warn!("Expected Move message from player 2, got different message");
+    let port = args.port;
+    let mut listeners = JoinSet::new();
+    if !args.bind_ipv4.is_empty() {
+        let addr = SocketAddr::new(args.bind_ipv4.parse::<Ipv4Addr>()?.into(), port);
+        let listener = TcpListener::bind(addr).await?;
+        info!("Server running on http://{}", addr);
+        listeners.spawn(server.clone().serve(listener));
+    }
+    if let Some(ipv6) = args.bind_ipv6.filter(|ipv6| !ipv6.is_empty()) {
+        let addr = SocketAddr::new(ipv6.parse::<Ipv6Addr>()?.into(), port);
+        let listener = bind_ipv6(addr)?;
+        info!("Server running on http://{}", addr);
+        listeners.spawn(server.clone().serve(listener));
+    }
+    if let Some(unix_socket) = args.unix_socket.filter(|path| !path.is_empty()) {
+        let listener = bind_unix(Path::new(&unix_socket))?;
+        info!("Server running on unix:{}", unix_socket);
+        listeners.spawn(server.clone().serve(listener));
+    }
+    if listeners.is_empty() {
+        anyhow::bail!("No listeners configured");
+    }
+
+    // Listeners only return on error until the server shuts down, so bail out on the first one
+    // that does
+    tokio::select! {
+        Some(result) = listeners.join_next() => result??,
+        result = shutdown_signal() => {
+            result?;
+            info!("Shutting down");
+            // The listeners stop once the server has, with the last requests answered
+            let wrap_up = async {
+                server.shutdown().await;
+                while let Some(result) = listeners.join_next().await {
+                    result??;
                 }
+                anyhow::Ok(())
+            };
+            match timeout(SHUTDOWN_TIMEOUT, wrap_up).await {
+                Ok(result) => result?,
+                Err(_) => warn!("Gave up waiting to wrap up after {:?}", SHUTDOWN_TIMEOUT),
             }
-        };
-
-        // notify other player, update board state
-        player1
-            .connection
-            .send(Message::text(serde_json::to_string(
-                &ServerMessage::OpponentMoved(player_move),
-            )?))
-            .await?;
+            info!("Shut down");
+        }
     }
 
     Ok(())
 }
 
-async fn client_request(player: &mut ConnectedPlayer) -> anyhow::Result<ClientRequest> {
-    match player.connection.recv().await {
-        Some(Ok(Message::Text(text))) => Ok(serde_json::from_str(&text)?),
-        Some(Ok(_)) => Err(anyhow::anyhow!(
-            "Expected text message for move, got different message"
-        )),
-        Some(Err(e)) => Err(anyhow::anyhow!("WebSocket error during game: {}", e)),
-        None => Err(anyhow::anyhow!("Connection closed during game")),
+/// Finishes on SIGTERM, as sent by service managers and container runtimes on redeploys, or on
+/// Ctrl-C.
+async fn shutdown_signal() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        result = signal::ctrl_c() => result,
+    }
+}
+
+/// Binds an IPv6-only TCP listener. Without `IPV6_V6ONLY`, Linux would also claim the IPv4 port
+/// for this socket and collide with the separate IPv4 listener.
+fn bind_ipv6(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, None)?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds a Unix domain socket listener, cleaning up a stale socket file left behind by a previous
+/// run. Anything at `path` that isn't a socket is left alone and the bind fails.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
     }
+    UnixListener::bind(path)
 }
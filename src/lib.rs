@@ -1,21 +1,335 @@
 use serde::{Deserialize, Serialize};
 
-use crate::logic::{Board, Move};
+use crate::{
+    ai::Difficulty,
+    logic::{
+        Board, Clock, GameResult, GameState, InvalidMove, Move, RulesConfig, SetupKind, TimeControl,
+    },
+};
 
+pub mod ai;
 pub mod logic;
+pub mod server;
+
+/// What goes over the WebSocket either way: a [`ClientRequest`] or a [`ServerMessage`], with the
+/// game it's about. Everything about a game says which one, so a connection can have several
+/// going at once, as logged-in players do with their correspondence games. Messages about no game
+/// in particular, like those while you wait for an opponent, leave it out.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game_id: Option<u64>,
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    /// `message`, about game `game_id` if there is one.
+    pub fn new(game_id: Option<u64>, message: T) -> Self {
+        Self { game_id, message }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientRequest {
-    InitialSetup { player_name: String },
+    InitialSetup {
+        player_name: String,
+        /// The opening position this player would like to play, if they care.
+        #[serde(default)]
+        setup: Option<SetupKind>,
+        /// Asks to play the built-in engine at this difficulty instead of another player.
+        #[serde(default)]
+        bot: Option<Difficulty>,
+        /// The time control this player would like to play, if they care. Games are untimed
+        /// unless someone asks for one.
+        #[serde(default)]
+        time_control: Option<TimeControl>,
+        /// The rules this player would like to play by, `None` for the standard ones. Players are
+        /// only paired with players who want the same rules.
+        #[serde(default)]
+        rules: Option<Box<RulesConfig>>,
+        /// Joins the lobby with this name instead of the queue, to pick an opponent from its open
+        /// challenges or post one of your own.
+        #[serde(default)]
+        lobby: Option<String>,
+        /// Asks for a game that counts towards your rating. You're only paired with players who
+        /// asked the same, and games against bots never count. Ratings belong to accounts, so
+        /// this needs a `token`.
+        #[serde(default)]
+        rated: bool,
+        /// The token you [log in](ClientRequest::Login) with, to play as that account. Without
+        /// one you're only known by your connection, since anyone can give any name.
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Leaves the queue or lobby while waiting for an opponent. The server closes the connection.
+    CancelMatchmaking,
+    /// Posts an open challenge in your lobby for anyone there to accept, replacing any you'd
+    /// already posted. `None` leaves it to the server, as in `InitialSetup`. A challenge that
+    /// couldn't be played, say with a setup that doesn't work under the rules you joined with, is
+    /// turned down with a [`ServerMessage::RequestFailed`].
+    PostChallenge {
+        #[serde(default)]
+        setup: Option<SetupKind>,
+        #[serde(default)]
+        time_control: Option<TimeControl>,
+        /// Whether the game counts towards both players' ratings.
+        #[serde(default)]
+        rated: bool,
+    },
+    /// Accepts the open challenge with this id in your lobby, starting the game.
+    AcceptChallenge {
+        id: u64,
+    },
     Move(Move),
+    /// Gives up the game, which the opponent wins.
+    Resign,
+    /// Offers the opponent a draw. It stands until they answer it or make a move. Offering a draw
+    /// when the opponent already has accepts theirs.
+    OfferDraw,
+    /// Agrees to the opponent's draw offer, ending the game.
+    AcceptDraw,
+    /// Turns down the opponent's draw offer.
+    DeclineDraw,
+    /// Says something to your opponent and anyone watching, during the game. Messages over
+    /// [`server::MAX_CHAT_LENGTH`] characters, or sent too quickly one after another, are
+    /// dropped.
+    Chat(String),
+    /// Sent instead of `InitialSetup` on a new connection, to carry on with the game
+    /// `token` was issued for after losing the connection to it.
+    Resume {
+        token: String,
+    },
+    /// Sent instead of `InitialSetup` on a new connection, to play correspondence games: games
+    /// whose players move whenever they next connect instead of both being there at once. Leave
+    /// `token` out the first time to claim `player_name`, and the server sends one back to log in
+    /// with from then on.
+    Login {
+        player_name: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Asks for the correspondence games you're playing, once logged in.
+    ListMyGames,
+    /// Starts a correspondence game against the player with this name, who must have logged in
+    /// before. You play player 1.
+    ChallengePlayer {
+        opponent: String,
+        #[serde(default)]
+        setup: Option<SetupKind>,
+        /// How many days each player has for each move, up to [`server::MAX_DAYS_PER_MOVE`], not
+        /// counting time they're on vacation. Whoever takes longer loses. Leave it out to give
+        /// them as long as they like.
+        #[serde(default)]
+        days_per_move: Option<u32>,
+        /// Makes the game the first of a series, with the next started as each one ends.
+        #[serde(default)]
+        series: Option<SeriesConfig>,
+    },
+    /// Opens the correspondence game with this id, to be sent its state. `Move` and `Resign` about
+    /// it then play in it. Any number of games can be open at once.
+    ResumeGame {
+        id: u64,
+    },
+    /// Leaves moves to be played for you in an open correspondence game while it's your
+    /// opponent's move, replacing any you'd left before: lines of a move they might make then
+    /// your reply, as many times over as you like. When their move starts a line, your reply is
+    /// played straight away, so a forced line doesn't wait a day on each of your moves. If their
+    /// move starts several lines, the first is played. An empty list takes them all back.
+    SetConditionalMoves {
+        lines: Vec<Vec<Move>>,
+    },
+    /// Goes on vacation from your correspondence games, or comes back from one. The clocks of
+    /// your games stop while you're away, for up to 30 days a year, and your opponents are shown
+    /// you're away.
+    SetVacation {
+        on: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
+    /// Sent every so often while you wait for an opponent, and whenever someone joins or leaves
+    /// the queue.
+    QueueStatus {
+        /// How many players are waiting for a game you'd be paired into, you included.
+        waiting_players: usize,
+    },
+    /// The open challenges in your lobby, sent when you join it and whenever they change.
+    LobbyState {
+        lobby: String,
+        challenges: Vec<Challenge>,
+    },
+    /// The game is starting. It comes in an [`Envelope`] with the game's id, as does everything
+    /// else about the game, and requests about it go in one with that id too.
     InitialSetup {
         board: Board,
+        /// The opening position `board` was built from.
+        setup: SetupKind,
+        /// The rules both players are playing by.
+        rules: RulesConfig,
+        /// How much time each player has, or `None` if the game isn't timed.
+        #[serde(default)]
+        time_control: Option<TimeControl>,
         player_order: usize,
         opponent_name: String,
+        /// Your measured round-trip time to the server, in milliseconds.
+        latency_ms: u64,
+        /// Your opponent's measured round-trip time to the server, in milliseconds.
+        opponent_latency_ms: u64,
+        /// Send this in a [`ClientRequest::Resume`] to get back into the game after losing the
+        /// connection. `None` if the server won't hold the game for you.
+        #[serde(default)]
+        session_token: Option<String>,
+        /// Whether the game counts towards both players' ratings.
+        #[serde(default)]
+        rated: bool,
+        /// Your rating going into the game. `None` for bots and players who didn't log in.
+        #[serde(default)]
+        rating: Option<i32>,
+        /// Your opponent's rating going into the game. `None` for bots and players who didn't
+        /// log in.
+        #[serde(default)]
+        opponent_rating: Option<i32>,
     },
-    OpponentMoved(Move),
+    /// Your last move was played.
+    MoveAccepted {
+        /// Both players' time after the move, in a timed game.
+        #[serde(default)]
+        clock: Option<Clock>,
+    },
+    /// Your last move wasn't played. Take it back and try again.
+    MoveRejected { reason: InvalidMove },
+    OpponentMoved {
+        #[serde(rename = "move")]
+        player_move: Move,
+        /// Both players' time after the move, in a timed game.
+        #[serde(default)]
+        clock: Option<Clock>,
+    },
+    /// Your opponent offers a draw. Answer with [`ClientRequest::AcceptDraw`] or
+    /// [`ClientRequest::DeclineDraw`]; making a move declines it too.
+    DrawOffered,
+    /// Your opponent turned down your draw offer.
+    DrawDeclined,
+    /// Your opponent lost their connection. The game carries on if they're back within
+    /// `grace_seconds`, and you win if they aren't.
+    OpponentDisconnected { grace_seconds: u64 },
+    /// Your opponent is back in the game after losing their connection.
+    OpponentReconnected,
+    /// Something a player said. Spectators get both players' messages.
+    Chat { from: String, text: String },
+    /// The game so far, sent to a player who has just resumed it in place of everything they
+    /// missed.
+    StateSync {
+        state: GameState,
+        player_order: usize,
+        opponent_name: String,
+        /// Both players' time as of the last move, in a timed game.
+        #[serde(default)]
+        clock: Option<Clock>,
+    },
+    /// You're logged in for correspondence play.
+    LoggedIn {
+        /// The token to log in with from now on, if you've just claimed your name.
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// The correspondence games you're playing. Sent when you log in, so you know which are
+    /// waiting on you, and whenever you ask.
+    MyGames { games: Vec<CorrespondenceGame> },
+    /// The moves you've left to be played for you in a correspondence game, sent when you leave
+    /// them and when you open a game you've left some in. Lines the game has left behind are
+    /// dropped, and the rest start from where it's at.
+    ConditionalMoves { lines: Vec<Vec<Move>> },
+    /// Whether you're on vacation from your correspondence games, sent when you go on one or come
+    /// back, and when you log in while away.
+    Vacation {
+        on_vacation: bool,
+        /// How much vacation you have left this year, in hours.
+        hours_left: u64,
+    },
+    /// The server couldn't do what you asked.
+    RequestFailed { reason: String },
+    /// The game has ended. Sent to both players after the last move has been relayed.
+    GameOver(GameResult),
+    /// The server is shutting down, so the game stops here unfinished.
+    ShuttingDown {
+        /// The correspondence game it's kept as, to finish by [logging in](ClientRequest::Login)
+        /// under the same name. `None` if it couldn't be kept.
+        #[serde(default)]
+        correspondence_id: Option<u64>,
+    },
+}
+
+/// A game on offer in a lobby, waiting for someone to accept it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Challenge {
+    /// What to send in a [`ClientRequest::AcceptChallenge`] to take it up.
+    pub id: u64,
+    /// Who posted it, and plays player 1.
+    pub player_name: String,
+    pub setup: Option<SetupKind>,
+    pub time_control: Option<TimeControl>,
+    #[serde(default)]
+    pub rated: bool,
+    /// The rules the challenger joined the lobby to play by.
+    #[serde(default)]
+    pub rules: RulesConfig,
+}
+
+/// A correspondence game you're playing.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorrespondenceGame {
+    /// What to send in a [`ClientRequest::ResumeGame`] to play it.
+    pub id: u64,
+    pub opponent_name: String,
+    /// Whether it's waiting on your move.
+    pub your_turn: bool,
+    /// How many moves have been played in it, by both players.
+    pub moves_played: usize,
+    /// How many hours whoever's move it is has left to make it, if the game gives each move a
+    /// number of days. Time on vacation doesn't count.
+    #[serde(default)]
+    pub hours_left: Option<u64>,
+    /// Whether your opponent is away on vacation, with the clock stopped for their moves.
+    #[serde(default)]
+    pub opponent_on_vacation: bool,
+    /// Where the series the game is part of stands, if it's part of one.
+    #[serde(default)]
+    pub series: Option<SeriesStanding>,
+}
+
+/// A match of several correspondence games between the same two players, who take turns to play
+/// player 1. Whoever scores more wins it, a win scoring a point and a draw half.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeriesConfig {
+    /// How many games it's played over, up to [`server::MAX_SERIES_GAMES`]. It ends early once
+    /// one player can't be caught.
+    pub games: u32,
+    /// Settles a series that ends level with one more game, where a draw counts as a win for
+    /// player 2 and player 1 is given more time to make up for it. Without it, a level series is
+    /// drawn.
+    #[serde(default)]
+    pub armageddon: Option<ArmageddonOdds>,
+}
+
+/// The time each player is given in an Armageddon game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArmageddonOdds {
+    /// Days per move for player 1, who has to win.
+    pub must_win_days: u32,
+    /// Days per move for player 2, who only has to draw, fewer than player 1's.
+    pub draw_odds_days: u32,
+}
+
+/// Where a series stands, from your side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeriesStanding {
+    /// Which game of the series this is, counting from 1. The Armageddon game comes after all
+    /// the others.
+    pub game: u32,
+    pub config: SeriesConfig,
+    /// Your score from the games before this one, in half points.
+    pub your_half_points: u32,
+    pub opponent_half_points: u32,
 }
@@ -1,147 +1,1013 @@
-use std::fmt;
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fmt,
+    ops::{Index, IndexMut},
+    str::FromStr,
+};
 
 use bevy_math::{CompassOctant, CompassQuadrant, USizeVec2, usizevec2};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+/// Emits a `tracing` event when the `trace` feature is enabled, and nothing otherwise.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "trace")]
+        tracing::$level!($($arg)*);
+    };
+}
+
+#[cfg(feature = "proptest")]
+pub mod arbitrary;
+mod book;
+mod builder;
+mod clock;
+pub mod eval;
+mod explorer;
+mod game;
+pub mod history;
+mod movegen;
+mod notation;
+mod players;
+mod puzzle;
+mod random;
+mod record;
+mod rules;
+mod symmetry;
+mod tablebase;
+mod zobrist;
+
+pub use book::{BookBuilder, BookError, BookMove, OpeningBook};
+pub use builder::{BoardBuilder, SetupError};
+pub use clock::{Clock, MAX_INCREMENT, MAX_INITIAL_TIME, TimeControl};
+pub use explorer::{Explorer, ExplorerMove, ExplorerPosition};
+pub use game::{DrawReason, GameResult, GameState, ReplayError, WinReason};
+pub use movegen::perft;
+pub use notation::{NotationError, format_coord, format_file, parse_coord};
+pub use players::PlayerSet;
+pub use puzzle::{Puzzle, PuzzleError, find_forced_win};
+pub use record::{Annotation, GameRecord, MoveMark, RecordError, TimedMove};
+pub use rules::{MAX_NO_CAPTURE_DRAW_MOVES, RulesConfig};
+pub use tablebase::{Outcome, Tablebase, TablebaseEntry, TablebaseError};
+pub(crate) use zobrist::splitmix64;
+
+/// A rectangular board of cells that may hold a piece. The standard game is played on 8x8, Khet
+/// on 10x8. Cells are indexed by coordinate, with `(0, 0)` the bottom left corner from player 1's
+/// side. Custom maps can also wall off cells, which nothing can enter and lasers can't cross.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "RawBoard")]
 pub struct Board {
-    pub cell: [[Option<Piece>; 8]; 8],
+    width: usize,
+    height: usize,
+    /// Row by row from the bottom, `width` cells per row.
+    cells: Vec<Option<Piece>>,
+    /// Which cells are walls, laid out like `cells`. Left out when serializing a board without
+    /// any.
+    #[serde(skip_serializing_if = "no_walls")]
+    walls: Vec<bool>,
+}
+
+fn no_walls(walls: &[bool]) -> bool {
+    !walls.contains(&true)
+}
+
+/// A deserialized board that hasn't been checked to have the right number of cells yet.
+#[derive(Deserialize)]
+struct RawBoard {
+    width: usize,
+    height: usize,
+    cells: Vec<Option<Piece>>,
+    #[serde(default)]
+    walls: Vec<bool>,
+}
+
+impl TryFrom<RawBoard> for Board {
+    type Error = String;
+
+    fn try_from(raw: RawBoard) -> Result<Self, Self::Error> {
+        let size = raw.width * raw.height;
+        if raw.cells.len() != size {
+            return Err(format!(
+                "a {}x{} board needs {} cells, found {}",
+                raw.width,
+                raw.height,
+                size,
+                raw.cells.len()
+            ));
+        }
+        let walls = match raw.walls.len() {
+            0 => vec![false; size],
+            len if len == size => raw.walls,
+            len => return Err(format!("a {size}-cell board can't have {len} walls")),
+        };
+        Ok(Self {
+            width: raw.width,
+            height: raw.height,
+            cells: raw.cells,
+            walls,
+        })
+    }
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self::empty(8, 8)
+    }
+}
+
+impl Index<USizeVec2> for Board {
+    type Output = Option<Piece>;
+
+    fn index(&self, coord: USizeVec2) -> &Self::Output {
+        assert!(self.contains(coord), "{coord} is off the board");
+        &self.cells[coord.y * self.width + coord.x]
+    }
+}
+
+impl IndexMut<USizeVec2> for Board {
+    fn index_mut(&mut self, coord: USizeVec2) -> &mut Self::Output {
+        assert!(self.contains(coord), "{coord} is off the board");
+        &mut self.cells[coord.y * self.width + coord.x]
+    }
 }
 
 impl Board {
-    pub fn game_over(&self) -> bool {
-        self.cell
+    /// A board with nothing on it.
+    pub fn empty(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![None; width * height],
+            walls: vec![false; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Width and height together.
+    pub fn size(&self) -> USizeVec2 {
+        usizevec2(self.width, self.height)
+    }
+
+    /// Whether `coord` is on the board.
+    pub fn contains(&self, coord: USizeVec2) -> bool {
+        coord.x < self.width && coord.y < self.height
+    }
+
+    /// The piece at `coord`, or `None` if the cell is empty or off the board.
+    pub fn get(&self, coord: USizeVec2) -> Option<Piece> {
+        if self.contains(coord) {
+            self[coord]
+        } else {
+            None
+        }
+    }
+
+    /// Whether `coord` is a wall. Cells off the board aren't.
+    pub fn is_wall(&self, coord: USizeVec2) -> bool {
+        self.contains(coord) && self.walls[coord.y * self.width + coord.x]
+    }
+
+    /// Walls off `coord`, or opens it back up. Any piece there is left where it is, which makes
+    /// the position [invalid](Board::validate).
+    pub fn set_wall(&mut self, coord: USizeVec2, wall: bool) {
+        assert!(self.contains(coord), "{coord} is off the board");
+        self.walls[coord.y * self.width + coord.x] = wall;
+    }
+
+    /// Every wall on the board, row by row from the bottom.
+    pub fn walls(&self) -> impl Iterator<Item = USizeVec2> + '_ {
+        self.walls
             .iter()
-            .flat_map(|row| row)
-            .filter(|x| {
-                matches!(
-                    x,
-                    Some(Piece {
-                        kind: PieceKind::King,
-                        ..
-                    })
-                )
-            })
-            .count()
-            < 2
+            .enumerate()
+            .filter(|&(_, &wall)| wall)
+            .map(|(index, _)| usizevec2(index % self.width, index / self.width))
+    }
+
+    /// Every piece on the board with its coordinate, row by row from the bottom.
+    pub fn pieces(&self) -> impl Iterator<Item = (USizeVec2, Piece)> + '_ {
+        self.cells.iter().enumerate().filter_map(|(index, cell)| {
+            cell.map(|piece| (usizevec2(index % self.width, index / self.width), piece))
+        })
+    }
+
+    /// Every piece `player` owns, with its coordinate.
+    pub fn pieces_of(&self, player: Player) -> impl Iterator<Item = (USizeVec2, Piece)> + '_ {
+        self.pieces()
+            .filter(move |(_, piece)| piece.allegiance == player)
+    }
+
+    /// How many of each kind of piece `player` has left.
+    pub fn piece_counts(&self, player: Player) -> PieceCounts {
+        let mut counts = PieceCounts::default();
+        for (_, piece) in self.pieces_of(player) {
+            let count = match piece.kind {
+                PieceKind::King => &mut counts.kings,
+                PieceKind::Block { stacked: true } => &mut counts.stacked_blocks,
+                PieceKind::Block { stacked: false } => &mut counts.half_blocks,
+                PieceKind::OneSide(_) => &mut counts.mirrors,
+                PieceKind::TwoSide(_) => &mut counts.two_sided_mirrors,
+                PieceKind::Defender(_) => &mut counts.defenders,
+                PieceKind::Emitter(_) => &mut counts.emitters,
+                PieceKind::Splitter(_) => &mut counts.splitters,
+            };
+            *count += 1;
+        }
+        counts
+    }
+
+    /// Builds one of the opening positions for a game under `rules`. Only random setups depend on
+    /// the rules, since they're rolled to suit them.
+    pub fn from_setup(setup: SetupKind, rules: &RulesConfig) -> Self {
+        use CompassQuadrant::*;
+        use Orientation::*;
+        use Player::*;
+        let pieces: &[(USizeVec2, Piece)] = match setup {
+            SetupKind::Random { seed } => {
+                return Self::random_setup(seed, rules);
+            }
+            SetupKind::Handicap { pieces_removed } => {
+                let pieces_removed = pieces_removed.into();
+                return Self::handicap_setup(SetupKind::Classic, rules, Player1, pieces_removed);
+            }
+            SetupKind::Classic => &[
+                (usizevec2(7, 0), Piece::emitter(Player1, North)),
+                (usizevec2(2, 0), Piece::two_sided(Player1, NW)),
+                (usizevec2(3, 0), Piece::block(Player1)),
+                (usizevec2(4, 0), Piece::king(Player1)),
+                (usizevec2(5, 0), Piece::block(Player1)),
+                (usizevec2(6, 0), Piece::mirror(Player1, NE)),
+                (usizevec2(3, 3), Piece::two_sided(Player1, NW)),
+                (usizevec2(3, 4), Piece::mirror(Player1, SW)),
+                (usizevec2(7, 3), Piece::mirror(Player1, SW)),
+                (usizevec2(7, 4), Piece::mirror(Player1, NW)),
+                (usizevec2(2, 5), Piece::mirror(Player1, NW)),
+                (usizevec2(2, 2), Piece::mirror(Player1, SW)),
+            ],
+            SetupKind::Imhotep => &[
+                (usizevec2(7, 0), Piece::emitter(Player1, North)),
+                (usizevec2(3, 0), Piece::defender(Player1, North)),
+                (usizevec2(4, 0), Piece::king(Player1)),
+                (usizevec2(5, 0), Piece::defender(Player1, North)),
+                (usizevec2(6, 0), Piece::mirror(Player1, NE)),
+                (usizevec2(2, 1), Piece::two_sided(Player1, NE)),
+                (usizevec2(5, 2), Piece::two_sided(Player1, NE)),
+                (usizevec2(0, 2), Piece::mirror(Player1, NE)),
+                (usizevec2(7, 2), Piece::mirror(Player1, SW)),
+                (usizevec2(1, 3), Piece::mirror(Player1, SE)),
+                (usizevec2(3, 3), Piece::mirror(Player1, NW)),
+                (usizevec2(1, 4), Piece::mirror(Player1, NW)),
+            ],
+            SetupKind::Dynasty => &[
+                (usizevec2(7, 0), Piece::emitter(Player1, North)),
+                (usizevec2(2, 0), Piece::mirror(Player1, NE)),
+                (usizevec2(4, 0), Piece::king(Player1)),
+                (usizevec2(5, 0), Piece::defender(Player1, North)),
+                (usizevec2(3, 1), Piece::defender(Player1, North)),
+                (usizevec2(5, 1), Piece::mirror(Player1, NW)),
+                (usizevec2(3, 2), Piece::two_sided(Player1, NW)),
+                (usizevec2(4, 2), Piece::two_sided(Player1, NE)),
+                (usizevec2(7, 2), Piece::mirror(Player1, SW)),
+                (usizevec2(0, 3), Piece::mirror(Player1, SE)),
+                (usizevec2(6, 3), Piece::mirror(Player1, NW)),
+                (usizevec2(2, 4), Piece::mirror(Player1, SE)),
+            ],
+        };
+        // Player 2 gets the same layout rotated 180 degrees, so every setup is symmetric
+        let mut board = Self::empty(8, 8);
+        let far_corner = board.size() - 1;
+        for &(coord, piece) in pieces {
+            board[coord] = Some(piece);
+            board[far_corner - coord] = Some(piece.opposing());
+        }
+        board
+    }
+
+    /// `base`, built for `rules`, with `player` giving material odds: their `pieces_removed` most
+    /// advanced one-sided mirrors are taken off, or all of them if they don't have that many.
+    pub fn handicap_setup(
+        base: SetupKind,
+        rules: &RulesConfig,
+        player: Player,
+        pieces_removed: usize,
+    ) -> Self {
+        let mut board = Self::from_setup(base, rules);
+        let mut mirrors: Vec<USizeVec2> = board
+            .pieces_of(player)
+            .filter(|(_, piece)| matches!(piece.kind, PieceKind::OneSide(_)))
+            .map(|(coord, _)| coord)
+            .collect();
+        // Furthest from the player's own back rank first, then left to right from their side
+        let far_corner = board.size() - 1;
+        mirrors.sort_by_key(|&coord| {
+            let seen_by_player = match player {
+                Player::Player1 => coord,
+                Player::Player2 => far_corner - coord,
+                // Players 3 and 4 sit on the west and east edges
+                Player::Player3 => usizevec2(far_corner.y - coord.y, coord.x),
+                Player::Player4 => usizevec2(coord.y, far_corner.x - coord.x),
+            };
+            (Reverse(seen_by_player.y), seen_by_player.x)
+        });
+        for coord in mirrors.into_iter().take(pieces_removed) {
+            board[coord] = None;
+        }
+        board
+    }
+
+    /// The standard opening position.
+    pub fn classic_setup() -> Self {
+        Self::from_setup(SetupKind::Classic, &RulesConfig::default())
+    }
+
+    pub fn has_king(&self, player: Player) -> bool {
+        self.pieces()
+            .any(|(_, piece)| piece.kind == PieceKind::King && piece.allegiance == player)
     }
 
+    /// Whether fewer than two kings are left, so nobody is left to play against.
+    pub fn game_over(&self) -> bool {
+        let kings = self
+            .pieces()
+            .filter(|(_, piece)| piece.kind == PieceKind::King)
+            .count();
+        if kings < 2 {
+            trace_event!(debug, kings, "game over");
+        }
+        kings < 2
+    }
+
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "trace", skip(self, rules), err(level = "debug", Display))
+    )]
     pub fn try_move_piece(
-        mut self,
+        &self,
         player_move: &Move,
         player: Player,
+        rules: &RulesConfig,
     ) -> Result<Self, InvalidMove> {
-        let piece =
-            self.cell[player_move.from.y][player_move.from.x].ok_or(InvalidMove::NoPieceAtFrom)?;
+        let mut board = self.clone();
+        board.move_piece(player_move, player, rules)?;
+        Ok(board)
+    }
+
+    /// Moves a piece in place, leaving the board untouched if the move is invalid.
+    fn move_piece(
+        &mut self,
+        player_move: &Move,
+        player: Player,
+        rules: &RulesConfig,
+    ) -> Result<(), InvalidMove> {
+        if player_move.kind == MoveKind::Pass {
+            return if rules.allow_passing {
+                Ok(())
+            } else {
+                Err(InvalidMove::PassingNotAllowed)
+            };
+        }
+        if !self.contains(player_move.from) {
+            return Err(InvalidMove::OutOfBounds);
+        }
+        let piece = self[player_move.from].ok_or(InvalidMove::NoPieceAtFrom(player_move.from))?;
         if piece.allegiance != player {
-            return Err(InvalidMove::NotYourPiece);
+            return Err(InvalidMove::NotYourPiece(player_move.from));
         }
         match player_move.kind {
+            MoveKind::Move(_) if matches!(piece.kind, PieceKind::Emitter(_)) => {
+                return Err(InvalidMove::CannotMove);
+            }
             MoveKind::Move(direction) => {
-                let to = add_compass_octant(player_move.from, direction)
+                let to = add_compass_octant(player_move.from, direction, self.size())
+                    .ok_or(InvalidMove::OutOfBounds)?;
+                if self[to].is_some() {
+                    return Err(InvalidMove::DestinationOccupied(to));
+                }
+                if self.is_wall(to) {
+                    return Err(InvalidMove::Wall(to));
+                }
+                if !rules.may_occupy(to, player) {
+                    return Err(InvalidMove::RestrictedSquare(to));
+                }
+                self[to] = self[player_move.from];
+                self[player_move.from] = None;
+            }
+            MoveKind::Swap(direction) => {
+                let PieceKind::TwoSide(_) = piece.kind else {
+                    return Err(InvalidMove::CannotSwap);
+                };
+                let to = add_compass_octant(player_move.from, direction, self.size())
+                    .ok_or(InvalidMove::OutOfBounds)?;
+                let other = self[to].ok_or(InvalidMove::NothingToSwap(to))?;
+                // Like a Khet scarab, two-sided mirrors can push mirrors and blocks of either side
+                // out of the way, but not kings or each other
+                if matches!(
+                    other.kind,
+                    PieceKind::King
+                        | PieceKind::TwoSide(_)
+                        | PieceKind::Emitter(_)
+                        | PieceKind::Splitter(_)
+                ) {
+                    return Err(InvalidMove::SwapNotAllowed(to));
+                }
+                if !rules.may_occupy(to, player) {
+                    return Err(InvalidMove::RestrictedSquare(to));
+                }
+                if !rules.may_occupy(player_move.from, other.allegiance) {
+                    return Err(InvalidMove::RestrictedSquare(player_move.from));
+                }
+                self[to] = Some(piece);
+                self[player_move.from] = Some(other);
+            }
+            MoveKind::StackOnto(direction) => {
+                let to = add_compass_octant(player_move.from, direction, self.size())
+                    .ok_or(InvalidMove::OutOfBounds)?;
+                let half = Piece {
+                    kind: PieceKind::Block { stacked: false },
+                    allegiance: player,
+                };
+                if piece != half || self[to] != Some(half) {
+                    return Err(InvalidMove::CannotStack);
+                }
+                if !rules.may_occupy(to, player) {
+                    return Err(InvalidMove::RestrictedSquare(to));
+                }
+                self[to] = Some(Piece::block(player));
+                self[player_move.from] = None;
+            }
+            MoveKind::Unstack(direction) => {
+                let PieceKind::Block { stacked: true } = piece.kind else {
+                    return Err(InvalidMove::CannotUnstack);
+                };
+                let to = add_compass_octant(player_move.from, direction, self.size())
                     .ok_or(InvalidMove::OutOfBounds)?;
-                if self.cell[to.y][to.x].is_some() {
-                    return Err(InvalidMove::DestinationOccupied);
+                if self[to].is_some() {
+                    return Err(InvalidMove::DestinationOccupied(to));
+                }
+                if self.is_wall(to) {
+                    return Err(InvalidMove::Wall(to));
                 }
-                self.cell[to.y][to.x] = self.cell[player_move.from.y][player_move.from.x];
-                self.cell[player_move.from.y][player_move.from.x] = None;
+                if !rules.may_occupy(to, player) {
+                    return Err(InvalidMove::RestrictedSquare(to));
+                }
+                let half = Piece {
+                    kind: PieceKind::Block { stacked: false },
+                    allegiance: player,
+                };
+                self[to] = Some(half);
+                self[player_move.from] = Some(half);
             }
+            // Passes never get this far
+            MoveKind::Pass => {}
             MoveKind::Rotate(chirality) => {
                 let new_kind = match piece.kind {
+                    // A king has no facing, so rotating it leaves it as it is
+                    PieceKind::King if rules.kings_can_rotate => PieceKind::King,
                     PieceKind::King | PieceKind::Block { .. } => {
                         return Err(InvalidMove::CannotRotate);
                     }
                     PieceKind::OneSide(x) => PieceKind::OneSide(x.rotate(chirality)),
                     PieceKind::TwoSide(x) => PieceKind::TwoSide(x.rotate(chirality)),
+                    PieceKind::Splitter(x) => PieceKind::Splitter(x.rotate(chirality)),
+                    PieceKind::Defender(x) => PieceKind::Defender(rotate_quadrant(x, chirality)),
+                    PieceKind::Emitter(x) => {
+                        let facing = rotate_quadrant(x, chirality);
+                        // Emitters have to fire onto the board
+                        if add_compass_quadrant(player_move.from, facing, self.size()).is_none() {
+                            return Err(InvalidMove::CannotRotate);
+                        }
+                        PieceKind::Emitter(facing)
+                    }
                 };
-                self.cell[player_move.from.y][player_move.from.x] = Some(Piece {
+                self[player_move.from] = Some(Piece {
                     kind: new_kind,
                     allegiance: piece.allegiance,
                 });
             }
         }
-        Ok(self)
+        Ok(())
+    }
+
+    /// Plays `player_move` in place, like [`Board::try_move`], and returns what's needed to take
+    /// it back with [`Board::unmake_move`]. This is for engines searching through lots of
+    /// positions: it doesn't copy the board or record the laser's path.
+    pub fn make_move(
+        &mut self,
+        player_move: &Move,
+        player: Player,
+        rules: &RulesConfig,
+    ) -> Result<MoveUndo, InvalidMove> {
+        let from = (player_move.from, self.get(player_move.from));
+        let to = player_move
+            .to(self.size())
+            .map(|coord| (coord, self.get(coord)));
+        self.move_piece(player_move, player, rules)?;
+        let captures: Vec<_> = self
+            .laser_hits(player, rules)
+            .into_iter()
+            .filter_map(Capture::from_hit)
+            .collect();
+        for capture in &captures {
+            self[capture.position] = capture.remains;
+        }
+        Ok(MoveUndo { from, to, captures })
+    }
+
+    /// Takes back a move made with [`Board::make_move`]. Moves have to be unmade in the reverse of
+    /// the order they were made in.
+    pub fn unmake_move(&mut self, undo: MoveUndo) {
+        for capture in undo.captures {
+            self[capture.position] = Some(capture.piece);
+        }
+        if let Some((coord, cell)) = undo.to {
+            self[coord] = cell;
+        }
+        let (coord, cell) = undo.from;
+        self[coord] = cell;
+    }
+
+    /// Plays `player_move` for `player` on this board and fires their laser, returning what
+    /// happened.
+    pub fn try_move(
+        &mut self,
+        player_move: &Move,
+        player: Player,
+        rules: &RulesConfig,
+    ) -> Result<MoveOutcome, InvalidMove> {
+        let outcome = self.preview_move(player_move, player, rules)?;
+        self.clone_from(&outcome.board);
+        Ok(outcome)
     }
 
-    pub fn try_move(&mut self, player_move: &Move, player: Player) -> Result<(), InvalidMove> {
-        *self = self.try_move_piece(player_move, player)?;
+    /// What playing `player_move` would do, without touching this board: the position it would
+    /// lead to, where the laser would go and what it would capture.
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(level = "debug", skip(self, rules), err(Display))
+    )]
+    pub fn preview_move(
+        &self,
+        player_move: &Move,
+        player: Player,
+        rules: &RulesConfig,
+    ) -> Result<MoveOutcome, InvalidMove> {
+        let mut board = self.try_move_piece(player_move, player, rules)?;
 
         // Now shoot the laser and blow crap up!!!!
-        let laser = match player {
-            Player::Player1 => Laser {
-                position: usizevec2(7, 0),
-                direction: CompassQuadrant::North,
-            },
-            Player::Player2 => Laser {
-                position: usizevec2(0, 7),
-                direction: CompassQuadrant::South,
-            },
+        let path = board.fire_laser(player, rules);
+        for hit in &path.hits {
+            trace_event!(debug, ?hit, "laser hit piece");
+            board[hit.position] = hit.replacement;
+        }
+        if path.hits.is_empty() {
+            trace_event!(debug, "laser hit wall");
+        }
+        let captures = path
+            .hits
+            .iter()
+            .copied()
+            .filter_map(Capture::from_hit)
+            .collect();
+        Ok(MoveOutcome {
+            board,
+            path,
+            captures,
+        })
+    }
+
+    /// Where `player`'s laser enters the board: the cell in front of their emitter if they have
+    /// one, or the origin `rules` gives otherwise. `None` if the emitter faces a wall or the rules
+    /// give no origin.
+    pub fn laser_origin(&self, player: Player, rules: &RulesConfig) -> Option<Laser> {
+        let emitter = self
+            .pieces()
+            .find_map(|(position, piece)| match piece.kind {
+                PieceKind::Emitter(direction) if piece.allegiance == player => Some(Laser {
+                    position,
+                    direction,
+                }),
+                _ => None,
+            });
+        match emitter {
+            Some(emitter) => emitter.advance(self.size()),
+            None => rules.laser_origin(player),
+        }
+    }
+
+    /// Traces `player`'s laser from its origin without changing the board, recording every cell it
+    /// crosses and what it hits. Splitters send the beam two ways at once, and each branch is
+    /// traced in turn until it runs off the board or stops on a piece.
+    pub fn fire_laser(&self, player: Player, rules: &RulesConfig) -> LaserPath {
+        let mut path = LaserPath::default();
+        // Branches still to trace, from where they are now
+        let mut beams = Vec::from_iter(self.laser_origin(player, rules));
+        // Every beam that loops or runs into another branch leaves the same piece the same way
+        // twice
+        let mut bounces = HashSet::new();
+        let mut stops = Vec::new();
+        while let Some(current) = beams.pop() {
+            let entry = current.direction;
+            // Walls soak up the beam like the edge of the board
+            if self.is_wall(current.position) {
+                continue;
+            }
+            let Some(piece) = self[current.position] else {
+                path.steps.push(LaserStep {
+                    position: current.position,
+                    entry,
+                    exit: Some(entry),
+                });
+                beams.extend(current.advance(self.size()));
+                continue;
+            };
+            match piece.reflect(entry) {
+                Ok(reflected) => {
+                    // Splitters let half the beam straight through too. It goes on the stack
+                    // first, so the reflected half is traced first.
+                    let through = matches!(piece.kind, PieceKind::Splitter(_)).then_some(entry);
+                    for exit in [through, Some(reflected)].into_iter().flatten() {
+                        let beam = Laser {
+                            position: current.position,
+                            direction: exit,
+                        };
+                        if !bounces.insert(beam) {
+                            trace_event!(
+                                debug,
+                                position = ?current.position,
+                                "laser caught in a loop"
+                            );
+                            path.looped = true;
+                            continue;
+                        }
+                        trace_event!(trace, position = ?current.position, ?exit, "laser reflected");
+                        path.steps.push(LaserStep {
+                            position: current.position,
+                            entry,
+                            exit: Some(exit),
+                        });
+                        beams.extend(beam.advance(self.size()));
+                    }
+                }
+                Err(_) => {
+                    path.steps.push(LaserStep {
+                        position: current.position,
+                        entry,
+                        exit: None,
+                    });
+                    stops.push(current);
+                }
+            }
+        }
+        path.hits = self.resolve_hits(stops, player, rules);
+        path
+    }
+
+    /// What `player`'s laser would hit, like [`Board::fire_laser`] but without recording the path.
+    /// Doesn't allocate unless the beam hits something or meets a splitter.
+    pub fn laser_hits(&self, player: Player, rules: &RulesConfig) -> Vec<LaserHit> {
+        let Some(mut laser) = self.laser_origin(player, rules) else {
+            return Vec::new();
         };
-        if let Some((hit_coord, new_piece_state)) = self.bounce_laser(laser) {
-            self.cell[hit_coord.y][hit_coord.x] = new_piece_state;
+        // Without looping, a beam crosses each cell at most once in each direction
+        for _ in 0..self.cells.len() * 4 {
+            if self.is_wall(laser.position) {
+                return Vec::new();
+            }
+            if let Some(piece) = self[laser.position] {
+                match piece.reflect(laser.direction) {
+                    Ok(_) if matches!(piece.kind, PieceKind::Splitter(_)) => {
+                        return self.fire_laser(player, rules).hits;
+                    }
+                    Ok(exit) => laser.direction = exit,
+                    Err(_) => return self.resolve_hits([laser], player, rules),
+                }
+            }
+            let Some(next) = laser.advance(self.size()) else {
+                return Vec::new();
+            };
+            laser = next;
         }
-        Ok(())
+        Vec::new()
     }
 
-    /// Raycast a laser in a straight line until it hits a wall (return None) or a piece (return Some).
-    pub fn cast_laser(&self, laser: Laser) -> Option<(USizeVec2, Piece)> {
-        self.cell[laser.position.y][laser.position.x]
-            .map(|cell| (laser.position, cell))
-            .or_else(|| self.cast_laser(laser.advance()?))
+    /// Works out what `player`'s laser does to the pieces its branches stopped on, given where
+    /// each branch stopped and which way it was going, in the order they were traced. A piece hit
+    /// by more than one branch takes each hit in that order, so a stacked block hit twice is
+    /// destroyed, and is listed once.
+    fn resolve_hits(
+        &self,
+        stops: impl IntoIterator<Item = Laser>,
+        player: Player,
+        rules: &RulesConfig,
+    ) -> Vec<LaserHit> {
+        let mut hits: Vec<LaserHit> = Vec::new();
+        for stop in stops {
+            let index = match hits.iter().position(|hit| hit.position == stop.position) {
+                Some(index) => index,
+                None => {
+                    let piece = self[stop.position].unwrap(); // Branches only stop on pieces
+                    hits.push(LaserHit {
+                        position: stop.position,
+                        piece,
+                        replacement: Some(piece),
+                    });
+                    hits.len() - 1
+                }
+            };
+            let hit = &mut hits[index];
+            if let Some(current) = hit.replacement {
+                let replacement = current
+                    .reflect(stop.direction)
+                    .err()
+                    .unwrap_or(Some(current));
+                hit.replacement = laser_damage(current, replacement, player, rules);
+            }
+        }
+        hits
+    }
+
+    /// The path an opponent's laser would take if fired right now, if it would destroy `player`'s
+    /// king. Like check in chess: unless `player` does something about it, the opponent can fire
+    /// it with any move that doesn't get in its way. With more than one opponent still in the
+    /// game, the first one in turn order whose laser would do it.
+    pub fn king_in_beam(&self, player: Player, rules: &RulesConfig) -> Option<LaserPath> {
+        rules
+            .players
+            .active(self)
+            .filter(|&opponent| opponent != player)
+            .map(|opponent| self.fire_laser(opponent, rules))
+            .find(|path| {
+                path.hits.iter().any(|hit| {
+                    hit.piece.kind == PieceKind::King
+                        && hit.piece.allegiance == player
+                        && hit.replacement.is_none()
+                })
+            })
+    }
+
+    /// Raycast a laser in a straight line until it hits a wall (return None) or a piece (return
+    /// Some). Walled-off cells count as walls too.
+    pub fn cast_laser(&self, mut laser: Laser) -> Option<(USizeVec2, Piece)> {
+        loop {
+            if self.is_wall(laser.position) {
+                return None;
+            }
+            if let Some(piece) = self[laser.position] {
+                return Some((laser.position, piece));
+            }
+            laser = laser.advance(self.size())?;
+        }
     }
 
     /// Bounce a laser off mirrors until it hits a wall (return None) or hits a piece (return Some).
     /// If the piece is hit, the piece's replacement is returned -- `None` if the piece was
     /// destroyed, or `Some(piece)` if the piece was changed (e.g., a stacked block losing its top
-    /// block).
-    pub fn bounce_laser(&self, laser: Laser) -> Option<(USizeVec2, Option<Piece>)> {
-        let (hit_coord, hit_piece) = self.cast_laser(laser)?; // We hit the wall
-        match hit_piece.reflect(laser.direction) {
-            Ok(new_direction) => self.bounce_laser(
-                Laser {
-                    position: hit_coord,
-                    direction: new_direction,
+    /// block). A beam that gets caught going round in circles also returns None. Only the
+    /// reflected half of a split beam is followed; see [`Board::fire_laser`] for the rest.
+    pub fn bounce_laser(&self, mut laser: Laser) -> Option<(USizeVec2, Option<Piece>)> {
+        let mut bounces = HashSet::new();
+        loop {
+            let (hit_coord, hit_piece) = self.cast_laser(laser)?; // We hit the wall
+            match hit_piece.reflect(laser.direction) {
+                Ok(new_direction) => {
+                    let bounce = Laser {
+                        position: hit_coord,
+                        direction: new_direction,
+                    };
+                    if !bounces.insert(bounce) {
+                        trace_event!(debug, ?hit_coord, "laser caught in a loop");
+                        return None;
+                    }
+                    trace_event!(trace, ?hit_coord, ?new_direction, "laser reflected");
+                    laser = bounce.advance(self.size())?;
                 }
-                .advance()?,
-            ),
-            Err(new_piece_state) => Some((hit_coord, new_piece_state)),
+                Err(new_piece_state) => return Some((hit_coord, new_piece_state)),
+            }
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// How many pieces of each kind a player has, from [`Board::piece_counts`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PieceCounts {
+    pub kings: usize,
+    pub stacked_blocks: usize,
+    pub half_blocks: usize,
+    pub mirrors: usize,
+    pub two_sided_mirrors: usize,
+    pub defenders: usize,
+    pub emitters: usize,
+    pub splitters: usize,
+}
+
+/// The opening positions: the official ones, or a random one.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum SetupKind {
+    #[default]
+    Classic,
+    Imhotep,
+    Dynasty,
+    /// A [`Board::random_setup`] under the game's rules. Anyone with the seed and the rules can
+    /// rebuild it.
+    Random {
+        seed: u64,
+    },
+    /// The classic setup with player 1, the stronger player, giving odds: their most advanced
+    /// mirrors are taken off. See [`Board::handicap_setup`].
+    Handicap {
+        pieces_removed: u8,
+    },
+}
+
+impl SetupKind {
+    /// The official setups.
+    pub const ALL: [Self; 3] = [Self::Classic, Self::Imhotep, Self::Dynasty];
+
+    /// The official setup `board` is, if it's one of them.
+    pub fn recognize(board: &Board) -> Option<Self> {
+        let rules = RulesConfig::default();
+        Self::ALL
+            .into_iter()
+            .find(|&setup| Board::from_setup(setup, &rules) == *board)
+    }
+}
+
+impl fmt::Display for SetupKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetupKind::Classic => write!(f, "classic"),
+            SetupKind::Imhotep => write!(f, "imhotep"),
+            SetupKind::Dynasty => write!(f, "dynasty"),
+            SetupKind::Random { seed } => write!(f, "random:{seed}"),
+            SetupKind::Handicap { pieces_removed } => write!(f, "handicap:{pieces_removed}"),
+        }
+    }
+}
+
+/// Parses a setup name. `random:<seed>` picks a random setup, which always needs its seed so the
+/// same name gives the same setup. `handicap:<n>` takes `n` mirrors off player 1, and plain
+/// `handicap` one.
+impl FromStr for SetupKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("random") {
+            return Err("A random setup needs a seed, as in random:42".to_string());
+        }
+        if let Some(seed) = s.to_ascii_lowercase().strip_prefix("random:") {
+            let seed = seed
+                .parse()
+                .map_err(|_| format!("Invalid random setup seed '{seed}'"))?;
+            return Ok(Self::Random { seed });
+        }
+        if s.eq_ignore_ascii_case("handicap") {
+            return Ok(Self::Handicap { pieces_removed: 1 });
+        }
+        if let Some(count) = s.to_ascii_lowercase().strip_prefix("handicap:") {
+            let pieces_removed = count
+                .parse()
+                .map_err(|_| format!("Invalid handicap '{count}'"))?;
+            return Ok(Self::Handicap { pieces_removed });
+        }
+        Self::ALL
+            .into_iter()
+            .find(|setup| setup.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown setup '{s}' (expected classic, imhotep, dynasty, random or handicap)"
+                )
+            })
+    }
+}
+
+/// Why a move was rejected. Variants about a particular cell carry its coordinate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum InvalidMove {
     OutOfBounds,
-    NoPieceAtFrom,
-    NotYourPiece,
-    DestinationOccupied,
+    NoPieceAtFrom(USizeVec2),
+    NotYourPiece(USizeVec2),
+    DestinationOccupied(USizeVec2),
+    CannotMove,
     CannotRotate,
+    /// Only two-sided mirrors can swap.
+    CannotSwap,
+    NothingToSwap(USizeVec2),
+    /// The piece there can't be swapped with: kings, emitters, splitters and other two-sided
+    /// mirrors stay put.
+    SwapNotAllowed(USizeVec2),
+    CannotStack,
+    CannotUnstack,
+    /// A piece would end up on a square reserved for the other player.
+    RestrictedSquare(USizeVec2),
+    /// A piece would end up in a wall.
+    Wall(USizeVec2),
+    NotYourTurn,
+    GameOver,
+    PassingNotAllowed,
 }
 
 impl fmt::Display for InvalidMove {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
+        match *self {
             InvalidMove::OutOfBounds => write!(f, "Move goes out of bounds"),
-            InvalidMove::NoPieceAtFrom => write!(f, "No piece at 'from' position"),
-            InvalidMove::NotYourPiece => write!(f, "The piece at 'from' does not belong to you"),
-            InvalidMove::DestinationOccupied => {
-                write!(f, "The destination cell is already occupied")
+            InvalidMove::NoPieceAtFrom(coord) => write!(f, "No piece at {}", format_coord(coord)),
+            InvalidMove::NotYourPiece(coord) => {
+                write!(
+                    f,
+                    "The piece at {} does not belong to you",
+                    format_coord(coord)
+                )
             }
-            InvalidMove::CannotRotate => write!(f, "This piece cannot be rotated"),
+            InvalidMove::DestinationOccupied(coord) => {
+                write!(f, "{} is already occupied", format_coord(coord))
+            }
+            InvalidMove::CannotMove => write!(f, "This piece cannot be moved"),
+            InvalidMove::CannotRotate => write!(f, "This piece cannot be rotated that way"),
+            InvalidMove::CannotSwap => write!(f, "Only two-sided mirrors can swap"),
+            InvalidMove::NothingToSwap(coord) => {
+                write!(
+                    f,
+                    "There is no piece at {} to swap with",
+                    format_coord(coord)
+                )
+            }
+            InvalidMove::SwapNotAllowed(coord) => write!(
+                f,
+                "The piece at {} can't be swapped with: kings, emitters, splitters and two-sided \
+                 mirrors stay put",
+                format_coord(coord)
+            ),
+            InvalidMove::CannotStack => {
+                write!(
+                    f,
+                    "Only a half block can be stacked, onto another of your half blocks"
+                )
+            }
+            InvalidMove::CannotUnstack => write!(f, "Only a stacked block can be split"),
+            InvalidMove::RestrictedSquare(coord) => {
+                write!(
+                    f,
+                    "{} is reserved for the other player",
+                    format_coord(coord)
+                )
+            }
+            InvalidMove::Wall(coord) => write!(f, "{} is a wall", format_coord(coord)),
+            InvalidMove::NotYourTurn => write!(f, "It's not your turn"),
+            InvalidMove::GameOver => write!(f, "The game is already over"),
+            InvalidMove::PassingNotAllowed => write!(f, "Passing isn't allowed in this game"),
         }
     }
 }
 
+impl std::error::Error for InvalidMove {}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Move {
     pub from: USizeVec2,
     pub kind: MoveKind,
 }
 
+impl Move {
+    /// Passing the turn. Passes don't start from any cell, so `from` is left at the origin.
+    pub fn pass() -> Self {
+        Self {
+            from: USizeVec2::ZERO,
+            kind: MoveKind::Pass,
+        }
+    }
+
+    /// The neighbouring cell the move moves, swaps or stacks a piece onto, on a board of the given
+    /// size. `None` for rotations and passes, and for moves off the edge.
+    pub fn to(&self, board_size: USizeVec2) -> Option<USizeVec2> {
+        match self.kind {
+            MoveKind::Rotate(_) | MoveKind::Pass => None,
+            MoveKind::Move(direction)
+            | MoveKind::Swap(direction)
+            | MoveKind::StackOnto(direction)
+            | MoveKind::Unstack(direction) => add_compass_octant(self.from, direction, board_size),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum MoveKind {
     Move(CompassOctant),
     Rotate(Chirality),
+    /// Trade places with the piece in the neighbouring cell. Only two-sided mirrors can do this.
+    Swap(CompassOctant),
+    /// Move a half block onto a neighbouring half block of the same player to stack them.
+    StackOnto(CompassOctant),
+    /// Move the top half of a stacked block to a neighbouring empty cell, leaving the bottom half.
+    Unstack(CompassOctant),
+    /// Leave every piece where it is and just fire. Only allowed when
+    /// [`RulesConfig::allow_passing`] is set.
+    Pass,
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -150,37 +1016,48 @@ pub enum Chirality {
     CounterClockwise,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// A side in the game. Players 3 and 4 only take part in [four-player](PlayerSet::Four) games.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum Player {
     Player1,
     Player2,
+    Player3,
+    Player4,
 }
 
 impl Player {
+    pub const ALL: [Player; 4] = [
+        Player::Player1,
+        Player::Player2,
+        Player::Player3,
+        Player::Player4,
+    ];
+
     pub fn index(&self) -> usize {
         match self {
             Player::Player1 => 0,
             Player::Player2 => 1,
+            Player::Player3 => 2,
+            Player::Player4 => 3,
         }
     }
 
     pub fn from_index(index: usize) -> Option<Self> {
-        match index {
-            0 => Some(Player::Player1),
-            1 => Some(Player::Player2),
-            _ => None,
-        }
+        Self::ALL.get(index).copied()
     }
 
+    /// The player sitting across the board: in a two-player game, the other player.
     pub fn opponent(&self) -> Self {
         match self {
             Player::Player1 => Player::Player2,
             Player::Player2 => Player::Player1,
+            Player::Player3 => Player::Player4,
+            Player::Player4 => Player::Player3,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Piece {
     pub kind: PieceKind,
     pub allegiance: Player,
@@ -215,15 +1092,37 @@ impl Piece {
         }
     }
 
+    pub fn defender(allegiance: Player, facing: CompassQuadrant) -> Self {
+        Self {
+            kind: PieceKind::Defender(facing),
+            allegiance,
+        }
+    }
+
+    pub fn emitter(allegiance: Player, facing: CompassQuadrant) -> Self {
+        Self {
+            kind: PieceKind::Emitter(facing),
+            allegiance,
+        }
+    }
+
+    pub fn splitter(allegiance: Player, orientation: Orientation) -> Self {
+        Self {
+            kind: PieceKind::Splitter(orientation),
+            allegiance,
+        }
+    }
+
     pub fn opposing(self) -> Self {
         Self {
-            kind: self.kind.mirrored(),
+            kind: self.kind.rotated_180(),
             allegiance: self.allegiance.opponent(),
         }
     }
 
     /// Reflect a laser off this piece. Returns the new direction if reflected, or the new piece
-    /// state if the laser did not hit a reflective surface.
+    /// state if the laser did not hit a reflective surface. For a splitter this is the half of the
+    /// beam it reflects; the other half carries straight on.
     pub fn reflect(&self, direction: CompassQuadrant) -> Result<CompassQuadrant, Option<Self>> {
         match self.kind.reflect(direction) {
             Ok(new_direction) => Ok(new_direction),
@@ -235,20 +1134,66 @@ impl Piece {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum PieceKind {
     King,
-    Block { stacked: bool },
+    Block {
+        stacked: bool,
+    },
     OneSide(Orientation),
     TwoSide(Orientation),
+    /// Shielded on the side it faces, where it absorbs lasers. Hits from any other side destroy
+    /// it.
+    Defender(CompassQuadrant),
+    /// Where its owner's laser comes from, firing the way it faces. It can be rotated but never
+    /// moved or destroyed.
+    Emitter(CompassQuadrant),
+    /// A variant piece, only allowed when [`RulesConfig::allow_splitters`] is set. It reflects
+    /// lasers from both sides like a two-sided mirror, but lets half the beam straight through as
+    /// well, so one shot can hit two pieces.
+    Splitter(Orientation),
 }
 
 impl PieceKind {
-    fn mirrored(self) -> Self {
+    /// What players call this kind of piece, e.g. for "your mirror was destroyed".
+    pub fn name(self) -> &'static str {
+        match self {
+            PieceKind::King => "king",
+            PieceKind::Block { stacked: true } => "stacked block",
+            PieceKind::Block { stacked: false } => "block",
+            PieceKind::OneSide(_) => "mirror",
+            PieceKind::TwoSide(_) => "two-sided mirror",
+            PieceKind::Defender(_) => "defender",
+            PieceKind::Emitter(_) => "emitter",
+            PieceKind::Splitter(_) => "splitter",
+        }
+    }
+
+    /// The piece turned half way round.
+    fn rotated_180(self) -> Self {
+        match self {
+            x @ (PieceKind::King | PieceKind::Block { .. }) => x,
+            PieceKind::OneSide(orientation) => PieceKind::OneSide(orientation.rotated_180()),
+            PieceKind::TwoSide(orientation) => PieceKind::TwoSide(orientation.rotated_180()),
+            PieceKind::Splitter(orientation) => PieceKind::Splitter(orientation.rotated_180()),
+            PieceKind::Defender(facing) => PieceKind::Defender(facing.opposite()),
+            PieceKind::Emitter(facing) => PieceKind::Emitter(facing.opposite()),
+        }
+    }
+
+    /// The piece as it looks in a mirror running north to south.
+    fn reflected(self) -> Self {
+        let reflect_facing = |facing: CompassQuadrant| match facing {
+            CompassQuadrant::East | CompassQuadrant::West => facing.opposite(),
+            CompassQuadrant::North | CompassQuadrant::South => facing,
+        };
         match self {
             x @ (PieceKind::King | PieceKind::Block { .. }) => x,
-            PieceKind::OneSide(orientation) => PieceKind::OneSide(orientation.mirrored()),
-            PieceKind::TwoSide(orientation) => PieceKind::TwoSide(orientation.mirrored()),
+            PieceKind::OneSide(orientation) => PieceKind::OneSide(orientation.reflected()),
+            PieceKind::TwoSide(orientation) => PieceKind::TwoSide(orientation.reflected()),
+            PieceKind::Splitter(orientation) => PieceKind::Splitter(orientation.reflected()),
+            PieceKind::Defender(facing) => PieceKind::Defender(reflect_facing(facing)),
+            PieceKind::Emitter(facing) => PieceKind::Emitter(reflect_facing(facing)),
         }
     }
 
@@ -275,6 +1220,14 @@ impl PieceKind {
             (Self::TwoSide(NW | SE), North) => Ok(East),
             (Self::TwoSide(NW | SE), West) => Ok(South),
 
+            (Self::Splitter(orientation), _) => Self::TwoSide(*orientation).reflect(direction),
+
+            // A laser travelling towards the shield hits it head on
+            (Self::Defender(facing), _) if direction == facing.opposite() => Err(Some(*self)),
+            (Self::Defender(_), _) => Err(None),
+
+            (Self::Emitter(_), _) => Err(Some(*self)),
+
             (Self::Block { stacked: true }, _) => Err(Some(Self::Block { stacked: false })),
             (Self::Block { stacked: false }, _) => Err(None),
             (Self::King, _) => Err(None),
@@ -282,7 +1235,7 @@ impl PieceKind {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Orientation {
     NE,
     NW,
@@ -291,7 +1244,7 @@ pub enum Orientation {
 }
 
 impl Orientation {
-    fn mirrored(self) -> Self {
+    fn rotated_180(self) -> Self {
         use Orientation::*;
         match self {
             NE => SW,
@@ -301,6 +1254,16 @@ impl Orientation {
         }
     }
 
+    fn reflected(self) -> Self {
+        use Orientation::*;
+        match self {
+            NE => NW,
+            NW => NE,
+            SE => SW,
+            SW => SE,
+        }
+    }
+
     fn rotate(self, chirality: Chirality) -> Self {
         use Chirality::*;
         use Orientation::*;
@@ -318,32 +1281,176 @@ impl Orientation {
 }
 
 /// Describes where a laser is. It's a combination of a position and a direction.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Laser {
     pub position: USizeVec2,
     pub direction: CompassQuadrant,
 }
 
 impl Laser {
-    pub fn advance(self) -> Option<Self> {
+    /// Where `player`'s laser starts and which way it fires on a standard 8x8 board without
+    /// emitters, under the default rules. Each player fires from the corner on their right, away
+    /// from their own edge of the board.
+    pub fn origin(player: Player) -> Self {
+        match player {
+            Player::Player1 => Laser {
+                position: usizevec2(7, 0),
+                direction: CompassQuadrant::North,
+            },
+            Player::Player2 => Laser {
+                position: usizevec2(0, 7),
+                direction: CompassQuadrant::South,
+            },
+            Player::Player3 => Laser {
+                position: usizevec2(0, 0),
+                direction: CompassQuadrant::East,
+            },
+            Player::Player4 => Laser {
+                position: usizevec2(7, 7),
+                direction: CompassQuadrant::West,
+            },
+        }
+    }
+
+    /// Moves the laser one cell on, or `None` if it leaves a board of the given size.
+    pub fn advance(self, board_size: USizeVec2) -> Option<Self> {
         Some(Self {
-            position: add_compass_quadrant(self.position, self.direction)?,
+            position: add_compass_quadrant(self.position, self.direction, board_size)?,
             direction: self.direction,
         })
     }
 }
 
-fn add_compass_quadrant(pos: USizeVec2, dir: CompassQuadrant) -> Option<USizeVec2> {
+/// A cell a laser passed through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaserStep {
+    pub position: USizeVec2,
+    /// The direction the beam was travelling when it entered the cell.
+    pub entry: CompassQuadrant,
+    /// The direction the beam left the cell in, or `None` if it stopped here.
+    pub exit: Option<CompassQuadrant>,
+}
+
+/// The piece a laser stopped on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaserHit {
+    pub position: USizeVec2,
+    /// The piece as it was before being hit.
+    pub piece: Piece,
+    /// What's left of the piece: `None` if it was destroyed, e.g. an unstacked block if it was
+    /// damaged, or the piece itself if it was unharmed.
+    pub replacement: Option<Piece>,
+}
+
+/// Everything a laser shot did: each cell it crossed and the pieces it hit. Without splitters
+/// there's a single beam, `steps` are in order and there's at most one hit.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaserPath {
+    /// The cells each branch of the beam crossed, one branch after another.
+    pub steps: Vec<LaserStep>,
+    /// Each piece a branch stopped on, once, in the order they were reached.
+    pub hits: Vec<LaserHit>,
+    /// Whether mirrors sent the beam round in a circle, or a split beam back into one of its own
+    /// branches. A looping branch is absorbed without hitting anything, and its steps end where
+    /// it would start retracing the beam.
+    #[serde(default)]
+    pub looped: bool,
+}
+
+/// The consequences of a move: what [`Board::try_move`] did, or what [`Board::preview_move`] says
+/// it would do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MoveOutcome {
+    /// The position after the move and the laser shot.
+    pub board: Board,
+    pub path: LaserPath,
+    /// The pieces the laser destroyed or damaged. At most one without splitters.
+    pub captures: Vec<Capture>,
+}
+
+/// What [`Board::make_move`] changed, so [`Board::unmake_move`] can put it back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MoveUndo {
+    /// The cell the move started from and what was in it.
+    from: (USizeVec2, Option<Piece>),
+    /// The other cell the move touched, if any, and what was in it.
+    to: Option<(USizeVec2, Option<Piece>)>,
+    captures: Vec<Capture>,
+}
+
+impl MoveUndo {
+    /// The pieces the laser destroyed or damaged.
+    pub fn captures(&self) -> &[Capture] {
+        &self.captures
+    }
+}
+
+/// A piece that was destroyed or damaged by a laser.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capture {
+    pub position: USizeVec2,
+    /// The piece as it was before being hit.
+    pub piece: Piece,
+    /// What's left of the piece, e.g. the bottom half of a stacked block, or `None` if it was
+    /// destroyed outright.
+    pub remains: Option<Piece>,
+}
+
+impl Capture {
+    /// `None` if the hit left the piece unharmed.
+    fn from_hit(hit: LaserHit) -> Option<Self> {
+        (hit.replacement != Some(hit.piece)).then_some(Self {
+            position: hit.position,
+            piece: hit.piece,
+            remains: hit.replacement,
+        })
+    }
+
+    /// Whether the piece was only damaged, like a stacked block losing its top half.
+    pub fn reduced(&self) -> bool {
+        self.remains.is_some()
+    }
+}
+
+/// What's left of `piece` after `player`'s laser hits it, given what the piece itself says
+/// would be left.
+fn laser_damage(
+    piece: Piece,
+    replacement: Option<Piece>,
+    player: Player,
+    rules: &RulesConfig,
+) -> Option<Piece> {
+    // Without friendly fire your own pieces soak up the beam unharmed
+    if piece.allegiance == player && !rules.friendly_fire {
+        Some(piece)
+    } else {
+        replacement
+    }
+}
+
+fn rotate_quadrant(direction: CompassQuadrant, chirality: Chirality) -> CompassQuadrant {
+    let quarter_turns = match chirality {
+        Chirality::Clockwise => 1,
+        Chirality::CounterClockwise => 3,
+    };
+    CompassQuadrant::from_index((direction.to_index() + quarter_turns) % 4).unwrap()
+}
+
+fn add_compass_quadrant(
+    pos: USizeVec2,
+    dir: CompassQuadrant,
+    board_size: USizeVec2,
+) -> Option<USizeVec2> {
     match dir {
         CompassQuadrant::North => pos.y.checked_add(1).and_then(|y| {
-            if y < 8 {
+            if y < board_size.y {
                 Some(USizeVec2::new(pos.x, y))
             } else {
                 None
             }
         }),
         CompassQuadrant::East => pos.x.checked_add(1).and_then(|x| {
-            if x < 8 {
+            if x < board_size.x {
                 Some(USizeVec2::new(x, pos.y))
             } else {
                 None
@@ -354,10 +1461,14 @@ fn add_compass_quadrant(pos: USizeVec2, dir: CompassQuadrant) -> Option<USizeVec
     }
 }
 
-pub fn add_compass_octant(pos: USizeVec2, dir: CompassOctant) -> Option<USizeVec2> {
+pub fn add_compass_octant(
+    pos: USizeVec2,
+    dir: CompassOctant,
+    board_size: USizeVec2,
+) -> Option<USizeVec2> {
     match dir {
         CompassOctant::North => pos.y.checked_add(1).and_then(|y| {
-            if y < 8 {
+            if y < board_size.y {
                 Some(USizeVec2::new(pos.x, y))
             } else {
                 None
@@ -365,7 +1476,7 @@ pub fn add_compass_octant(pos: USizeVec2, dir: CompassOctant) -> Option<USizeVec
         }),
         CompassOctant::NorthEast => pos.x.checked_add(1).and_then(|x| {
             pos.y.checked_add(1).and_then(|y| {
-                if x < 8 && y < 8 {
+                if x < board_size.x && y < board_size.y {
                     Some(USizeVec2::new(x, y))
                 } else {
                     None
@@ -373,7 +1484,7 @@ pub fn add_compass_octant(pos: USizeVec2, dir: CompassOctant) -> Option<USizeVec
             })
         }),
         CompassOctant::East => pos.x.checked_add(1).and_then(|x| {
-            if x < 8 {
+            if x < board_size.x {
                 Some(USizeVec2::new(x, pos.y))
             } else {
                 None
@@ -381,7 +1492,7 @@ pub fn add_compass_octant(pos: USizeVec2, dir: CompassOctant) -> Option<USizeVec
         }),
         CompassOctant::SouthEast => pos.x.checked_add(1).and_then(|x| {
             pos.y.checked_sub(1).and_then(|y| {
-                if x < 8 {
+                if x < board_size.x {
                     Some(USizeVec2::new(x, y))
                 } else {
                     None
@@ -396,7 +1507,7 @@ pub fn add_compass_octant(pos: USizeVec2, dir: CompassOctant) -> Option<USizeVec
         CompassOctant::West => pos.x.checked_sub(1).map(|x| USizeVec2::new(x, pos.y)),
         CompassOctant::NorthWest => pos.x.checked_sub(1).and_then(|x| {
             pos.y.checked_add(1).and_then(|y| {
-                if y < 8 {
+                if y < board_size.y {
                     Some(USizeVec2::new(x, y))
                 } else {
                     None
@@ -405,3 +1516,189 @@ pub fn add_compass_octant(pos: USizeVec2, dir: CompassOctant) -> Option<USizeVec
         }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{CompassOctant, CompassQuadrant, usizevec2};
+
+    use super::*;
+
+    /// Rules for a small test board: player 1's laser starts at `origin`, player 2 has none, and
+    /// no squares are reserved.
+    fn rules_from(origin: Laser) -> RulesConfig {
+        RulesConfig {
+            laser_origins: vec![origin],
+            reserved_squares: Vec::new(),
+            allow_passing: true,
+            ..RulesConfig::default()
+        }
+    }
+
+    /// An empty 4x4 board with the kings tucked away in the far corners.
+    fn board() -> Board {
+        let mut board = Board::empty(4, 4);
+        board[usizevec2(3, 3)] = Some(Piece::king(Player::Player2));
+        board[usizevec2(3, 1)] = Some(Piece::king(Player::Player1));
+        board
+    }
+
+    #[test]
+    fn laser_unstacks_a_stacked_block() {
+        let mut board = board();
+        let target = usizevec2(0, 2);
+        board[target] = Some(Piece::block(Player::Player2));
+        let rules = rules_from(Laser {
+            position: usizevec2(0, 0),
+            direction: CompassQuadrant::North,
+        });
+        let half = Piece {
+            kind: PieceKind::Block { stacked: false },
+            allegiance: Player::Player2,
+        };
+
+        let outcome = board
+            .try_move(&Move::pass(), Player::Player1, &rules)
+            .unwrap();
+        assert_eq!(
+            board[target],
+            Some(half),
+            "the block should lose its top half"
+        );
+        assert_eq!(
+            outcome.captures,
+            [Capture {
+                position: target,
+                piece: Piece::block(Player::Player2),
+                remains: Some(half),
+            }]
+        );
+        assert!(outcome.captures[0].reduced());
+
+        // The bottom half goes with the next shot
+        board
+            .try_move(&Move::pass(), Player::Player1, &rules)
+            .unwrap();
+        assert_eq!(board[target], None);
+    }
+
+    #[test]
+    fn stacking_needs_your_own_half_block_to_land_on() {
+        let mut board = board();
+        let from = usizevec2(1, 1);
+        let half = |allegiance| Piece {
+            kind: PieceKind::Block { stacked: false },
+            allegiance,
+        };
+        board[from] = Some(half(Player::Player1));
+        let rules = rules_from(Laser {
+            position: usizevec2(0, 0),
+            direction: CompassQuadrant::East,
+        });
+        let stack = |direction| Move {
+            from,
+            kind: MoveKind::StackOnto(direction),
+        };
+
+        // Onto an empty cell, a mirror, a stacked block, the opponent's half block and a king
+        board[usizevec2(1, 2)] = Some(Piece::mirror(Player::Player1, Orientation::NE));
+        board[usizevec2(2, 1)] = Some(Piece::block(Player::Player1));
+        board[usizevec2(2, 2)] = Some(half(Player::Player2));
+        board[usizevec2(3, 1)] = None;
+        board[usizevec2(2, 0)] = Some(Piece::king(Player::Player1));
+        for direction in [
+            CompassOctant::South,
+            CompassOctant::North,
+            CompassOctant::East,
+            CompassOctant::NorthEast,
+            CompassOctant::SouthEast,
+        ] {
+            assert_eq!(
+                board.try_move_piece(&stack(direction), Player::Player1, &rules),
+                Err(InvalidMove::CannotStack),
+                "stacking {direction:?}"
+            );
+        }
+
+        board[usizevec2(0, 1)] = Some(half(Player::Player1));
+        let stacked = board
+            .try_move_piece(&stack(CompassOctant::West), Player::Player1, &rules)
+            .unwrap();
+        assert_eq!(
+            stacked[usizevec2(0, 1)],
+            Some(Piece::block(Player::Player1))
+        );
+        assert_eq!(stacked[from], None);
+    }
+
+    #[test]
+    fn laser_in_a_mirror_loop_stops() {
+        let mut board = board();
+        // The laser starts inside a square of two-sided mirrors, which sends it round and round
+        for (coord, orientation) in [
+            (usizevec2(2, 0), Orientation::NW),
+            (usizevec2(2, 2), Orientation::NE),
+            (usizevec2(0, 2), Orientation::SE),
+            (usizevec2(0, 0), Orientation::NE),
+        ] {
+            board[coord] = Some(Piece::two_sided(Player::Player1, orientation));
+        }
+        let rules = rules_from(Laser {
+            position: usizevec2(1, 0),
+            direction: CompassQuadrant::East,
+        });
+
+        let path = board.fire_laser(Player::Player1, &rules);
+        assert!(path.looped);
+        assert!(path.hits.is_empty());
+        // Once round the eight cells on the square's edge, and back into the first
+        assert_eq!(path.steps.len(), 9);
+        assert!(board.laser_hits(Player::Player1, &rules).is_empty());
+    }
+
+    #[test]
+    fn no_capture_limit_is_checked() {
+        let board = Board::classic_setup();
+        for moves in [0, MAX_NO_CAPTURE_DRAW_MOVES + 1, u32::MAX] {
+            let rules = RulesConfig {
+                no_capture_draw_moves: Some(moves),
+                ..RulesConfig::default()
+            };
+            assert_eq!(
+                board.validate(&rules),
+                Err(SetupError::NoCaptureLimit(moves))
+            );
+        }
+    }
+
+    #[test]
+    fn random_setups_follow_the_games_rules() {
+        let mut rules = RulesConfig::default();
+        // Keep player 1 off the left half of their back rank
+        rules
+            .reserved_squares
+            .extend((0..4).map(|x| (usizevec2(x, 0), Player::Player2)));
+        for seed in 0..10 {
+            let board = Board::from_setup(SetupKind::Random { seed }, &rules);
+            assert_eq!(board.validate(&rules), Ok(()));
+            assert!(
+                board
+                    .pieces_of(Player::Player1)
+                    .all(|(coord, _)| rules.may_occupy(coord, Player::Player1))
+            );
+            assert_eq!(board, Board::random_setup(seed, &rules));
+        }
+    }
+
+    #[test]
+    fn random_setups_give_up_on_rules_they_cant_suit() {
+        let rules = RulesConfig::four_player();
+        let board = Board::from_setup(SetupKind::Random { seed: 1 }, &rules);
+        assert!(board.validate(&rules).is_err());
+    }
+
+    #[test]
+    fn random_setups_need_a_seed() {
+        assert!("random".parse::<SetupKind>().is_err());
+        assert_eq!("random:7".parse(), Ok(SetupKind::Random { seed: 7 }));
+    }
+}
@@ -0,0 +1,320 @@
+//! [`proptest`] strategies for random positions and moves, and the invariants any move on any
+//! position should keep. Only built with the `proptest` feature.
+//!
+//! A property test typically draws from [`position_and_move`] and hands the result to
+//! [`check_move`].
+
+use bevy_math::{CompassOctant, CompassQuadrant, USizeVec2, usizevec2};
+use proptest::{
+    collection::vec,
+    option,
+    prelude::*,
+    sample::{Index, select},
+};
+
+use super::{
+    Board, Chirality, Laser, Move, MoveKind, Orientation, Piece, PieceKind, Player, RulesConfig,
+};
+
+/// The largest board side generated. Big enough for Khet-sized boards and odd shapes, small
+/// enough that pieces still run into each other.
+const MAX_SIDE: usize = 12;
+
+pub fn player() -> impl Strategy<Value = Player> {
+    select(vec![Player::Player1, Player::Player2])
+}
+
+pub fn orientation() -> impl Strategy<Value = Orientation> {
+    select(vec![
+        Orientation::NE,
+        Orientation::NW,
+        Orientation::SE,
+        Orientation::SW,
+    ])
+}
+
+pub fn quadrant() -> impl Strategy<Value = CompassQuadrant> {
+    select(vec![
+        CompassQuadrant::North,
+        CompassQuadrant::East,
+        CompassQuadrant::South,
+        CompassQuadrant::West,
+    ])
+}
+
+pub fn octant() -> impl Strategy<Value = CompassOctant> {
+    select(vec![
+        CompassOctant::North,
+        CompassOctant::NorthEast,
+        CompassOctant::East,
+        CompassOctant::SouthEast,
+        CompassOctant::South,
+        CompassOctant::SouthWest,
+        CompassOctant::West,
+        CompassOctant::NorthWest,
+    ])
+}
+
+/// Any piece except kings and emitters, which a valid position can only have one of per player.
+pub fn piece() -> impl Strategy<Value = Piece> {
+    let kind = prop_oneof![
+        any::<bool>().prop_map(|stacked| PieceKind::Block { stacked }),
+        orientation().prop_map(PieceKind::OneSide),
+        orientation().prop_map(PieceKind::TwoSide),
+        quadrant().prop_map(PieceKind::Defender),
+        orientation().prop_map(PieceKind::Splitter),
+    ];
+    (kind, player()).prop_map(|(kind, allegiance)| Piece { kind, allegiance })
+}
+
+/// A laser starting anywhere on a board of the given size.
+pub fn laser(board_size: USizeVec2) -> impl Strategy<Value = Laser> {
+    (0..board_size.x, 0..board_size.y, quadrant()).prop_map(|(x, y, direction)| Laser {
+        position: usizevec2(x, y),
+        direction,
+    })
+}
+
+/// A random position that passes [`Board::validate`] under the rules it comes with. Boards are
+/// anywhere from 2x2 to 12x12, with one king per player, sometimes an emitter each, and mirrors,
+/// blocks, defenders, walls and, if the rules allow them, splitters scattered around. The rules
+/// fire from random cells and shuffle the optional rules, but reserve no squares.
+pub fn position() -> impl Strategy<Value = (Board, RulesConfig)> {
+    (2..=MAX_SIDE, 2..=MAX_SIDE)
+        .prop_flat_map(|(width, height)| {
+            let size = usizevec2(width, height);
+            let cells = vec(option::weighted(0.3, piece()), width * height);
+            let walls = vec(prop::bool::weighted(0.1), width * height);
+            let kings = (any::<Index>(), any::<Index>());
+            let emitters = (
+                option::of((any::<Index>(), quadrant())),
+                option::of((any::<Index>(), quadrant())),
+            );
+            let rules = (
+                vec![laser(size), laser(size)],
+                any::<bool>(),
+                any::<bool>(),
+                any::<bool>(),
+                any::<bool>(),
+            )
+                .prop_map(
+                    |(
+                        laser_origins,
+                        friendly_fire,
+                        kings_can_rotate,
+                        allow_passing,
+                        allow_splitters,
+                    )| RulesConfig {
+                        laser_origins,
+                        friendly_fire,
+                        kings_can_rotate,
+                        allow_passing,
+                        allow_splitters,
+                        reserved_squares: Vec::new(),
+                        ..RulesConfig::default()
+                    },
+                );
+            (Just(size), cells, walls, kings, emitters, rules)
+        })
+        .prop_map(|(size, cells, walls, kings, emitters, rules)| {
+            let mut board = Board::empty(size.x, size.y);
+            board.cells = cells;
+            if !rules.allow_splitters {
+                for piece in board.cells.iter_mut().flatten() {
+                    if let PieceKind::Splitter(orientation) = piece.kind {
+                        piece.kind = PieceKind::TwoSide(orientation);
+                    }
+                }
+            }
+            let coord = |index: Index| {
+                let i = index.index(size.x * size.y);
+                usizevec2(i % size.x, i / size.x)
+            };
+            let (king1, king2) = (coord(kings.0), coord(kings.1));
+            for (player, emitter) in [(Player::Player1, emitters.0), (Player::Player2, emitters.1)]
+            {
+                let Some((index, facing)) = emitter else {
+                    continue;
+                };
+                let position = coord(index);
+                let faces_board = Laser {
+                    position,
+                    direction: facing,
+                }
+                .advance(size)
+                .is_some();
+                if faces_board && position != king1 && position != king2 {
+                    board[position] = Some(Piece::emitter(player, facing));
+                }
+            }
+            board[king1] = Some(Piece::king(Player::Player1));
+            // The second king may land on the first; the filter below throws those away
+            board[king2] = Some(Piece::king(Player::Player2));
+            for (i, wall) in walls.into_iter().enumerate() {
+                let coord = usizevec2(i % size.x, i / size.x);
+                if wall && board[coord].is_none() {
+                    board.set_wall(coord, true);
+                }
+            }
+            (board, rules)
+        })
+        .prop_filter("position must be valid", |(board, rules)| {
+            board.validate(rules).is_ok()
+        })
+}
+
+/// Any move starting on or just off a board of the given size, legal or not.
+pub fn player_move(board_size: USizeVec2) -> impl Strategy<Value = Move> {
+    let kind = prop_oneof![
+        octant().prop_map(MoveKind::Move),
+        select(vec![Chirality::Clockwise, Chirality::CounterClockwise]).prop_map(MoveKind::Rotate),
+        octant().prop_map(MoveKind::Swap),
+        octant().prop_map(MoveKind::StackOnto),
+        octant().prop_map(MoveKind::Unstack),
+    ];
+    let piece_move = (0..=board_size.x, 0..=board_size.y, kind).prop_map(|(x, y, kind)| Move {
+        from: usizevec2(x, y),
+        kind,
+    });
+    prop_oneof![5 => piece_move, 1 => Just(Move::pass())]
+}
+
+/// A random [`position`] with a random move to try on it, which is more often than not illegal.
+pub fn position_and_move() -> impl Strategy<Value = (Board, RulesConfig, Move)> {
+    position().prop_flat_map(|(board, rules)| {
+        let player_move = player_move(board.size());
+        (Just(board), Just(rules), player_move)
+    })
+}
+
+/// Tries `player_move` on `board` every way the crate offers and checks the results agree and
+/// make sense:
+///
+/// - nothing panics, and the laser always stops;
+/// - each piece is hit at most once;
+/// - [`Board::try_move`], [`Board::preview_move`] and [`Board::make_move`] agree on what happens,
+///   and [`Board::unmake_move`] puts everything back;
+/// - an invalid move leaves the board alone;
+/// - nobody ends up with more than one king, or gains a king;
+/// - no piece ends up inside a wall;
+/// - [`Board::legal_moves`] lists the move if and only if it's valid.
+pub fn check_move(
+    board: &Board,
+    player_move: &Move,
+    player: Player,
+    rules: &RulesConfig,
+) -> Result<(), TestCaseError> {
+    let legal = board.legal_moves(player, rules).contains(player_move);
+    let preview = board.preview_move(player_move, player, rules);
+    let mut moved = board.clone();
+    let played = moved.try_move(player_move, player, rules);
+    let mut made = board.clone();
+    let undo = made.make_move(player_move, player, rules);
+    prop_assert_eq!(&preview, &played);
+    prop_assert_eq!(
+        legal,
+        preview.is_ok(),
+        "legal_moves disagrees with try_move"
+    );
+
+    let Ok(outcome) = preview else {
+        prop_assert_eq!(&moved, board, "invalid move changed the board");
+        prop_assert_eq!(&made, board, "invalid move changed the board");
+        return Ok(());
+    };
+    prop_assert_eq!(&moved, &outcome.board);
+    prop_assert_eq!(&made, &outcome.board);
+    let undo = undo.unwrap(); // try_move accepted it, so make_move did too
+    prop_assert_eq!(undo.captures(), &outcome.captures[..]);
+    made.unmake_move(undo);
+    prop_assert_eq!(&made, board, "unmake_move didn't restore the board");
+
+    // A beam can cross each cell at most once each way before it starts looping
+    prop_assert!(outcome.path.steps.len() <= board.cells.len() * 4);
+    for (i, hit) in outcome.path.hits.iter().enumerate() {
+        prop_assert!(
+            outcome.path.hits[..i]
+                .iter()
+                .all(|other| other.position != hit.position),
+            "piece hit twice"
+        );
+    }
+    for side in [Player::Player1, Player::Player2] {
+        let kings = |board: &Board| {
+            board
+                .pieces()
+                .filter(|(_, piece)| piece.kind == PieceKind::King && piece.allegiance == side)
+                .count()
+        };
+        prop_assert!(kings(&outcome.board) <= kings(board).min(1));
+    }
+    prop_assert!(
+        outcome
+            .board
+            .pieces()
+            .all(|(coord, _)| !outcome.board.is_wall(coord)),
+        "piece moved into a wall"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{CompassOctant, usizevec2};
+    use proptest::{prelude::*, sample::Index};
+
+    use super::{
+        super::{Board, Move, MoveKind, Player, RulesConfig, SetupKind},
+        check_move, player, position_and_move,
+    };
+
+    proptest! {
+        #[test]
+        fn any_move_keeps_the_invariants(
+            (board, rules, player_move) in position_and_move(),
+            player in player(),
+        ) {
+            check_move(&board, &player_move, player, &rules)?;
+        }
+
+        // Random moves are mostly illegal, so legal ones get a test of their own
+        #[test]
+        fn legal_moves_keep_the_invariants(
+            (board, rules, _) in position_and_move(),
+            player in player(),
+            index in any::<Index>(),
+        ) {
+            let moves = board.legal_moves(player, &rules);
+            prop_assume!(!moves.is_empty());
+            check_move(&board, index.get(&moves), player, &rules)?;
+        }
+    }
+
+    #[test]
+    fn every_move_from_the_official_setups_keeps_the_invariants() {
+        let rules = RulesConfig::default();
+        for setup in SetupKind::ALL {
+            let board = Board::from_setup(setup, &rules);
+            for player in [Player::Player1, Player::Player2] {
+                for player_move in board.legal_moves(player, &rules) {
+                    check_move(&board, &player_move, player, &rules).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_moves_keep_the_invariants() {
+        let rules = RulesConfig::default();
+        let board = Board::classic_setup();
+        // An empty cell, and off the board
+        for from in [usizevec2(1, 1), usizevec2(40, 0)] {
+            let player_move = Move {
+                from,
+                kind: MoveKind::Move(CompassOctant::North),
+            };
+            check_move(&board, &player_move, Player::Player1, &rules).unwrap();
+        }
+        check_move(&board, &Move::pass(), Player::Player1, &rules).unwrap();
+    }
+}
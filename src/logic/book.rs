@@ -0,0 +1,474 @@
+//! Opening books: moves known to work in positions that come up often, so engines can play
+//! them straight away instead of searching.
+//!
+//! A book is keyed by [`GameState::position_hash`], which includes the player to move but not the
+//! rules, so a book should only be used with the rules the games it was built from were played
+//! under. Books are usually built from collections of [`GameRecord`]s with a [`BookBuilder`] and
+//! saved in a compact binary format, all integers little-endian:
+//!
+//! - the magic bytes `LCBK` and a format version byte, currently 1;
+//! - the number of positions as a `u32`;
+//! - for each position, its hash as a `u64` and the number of moves as a `u16`, then for each move
+//!   the file and rank it starts from as a `u8` each, what it does as two `u8`s and its weight as
+//!   a `u32`.
+
+use std::{cmp::Reverse, collections::HashMap, fmt};
+
+use bevy_math::{CompassOctant, usizevec2};
+
+use super::{Chirality, GameRecord, GameResult, GameState, Move, MoveKind, zobrist::splitmix64};
+
+const MAGIC: &[u8; 4] = b"LCBK";
+const VERSION: u8 = 1;
+
+/// Positions and the moves worth playing in them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpeningBook {
+    /// Moves by position hash, heaviest first.
+    entries: HashMap<u64, Vec<BookMove>>,
+}
+
+/// A move in an [`OpeningBook`], with how strongly it's recommended relative to the other moves
+/// in the same position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BookMove {
+    pub player_move: Move,
+    pub weight: u32,
+}
+
+/// Why an opening book couldn't be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BookError {
+    /// The data doesn't start with the book magic bytes.
+    NotABook,
+    UnsupportedVersion(u8),
+    /// The data ends in the middle of an entry.
+    Truncated,
+    /// A move's encoding doesn't stand for any move.
+    InvalidMove,
+}
+
+impl fmt::Display for BookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BookError::NotABook => write!(f, "Not an opening book"),
+            BookError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported opening book version {version}")
+            }
+            BookError::Truncated => write!(f, "Opening book is truncated"),
+            BookError::InvalidMove => write!(f, "Opening book contains an invalid move"),
+        }
+    }
+}
+
+impl std::error::Error for BookError {}
+
+impl OpeningBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Recommends `player_move` in the position with the given hash, adding `weight` to it if
+    /// it's already there.
+    pub fn add(&mut self, hash: u64, player_move: Move, weight: u32) {
+        let moves = self.entries.entry(hash).or_default();
+        match moves.iter_mut().find(|m| m.player_move == player_move) {
+            Some(book_move) => book_move.weight = book_move.weight.saturating_add(weight),
+            None => moves.push(BookMove {
+                player_move,
+                weight,
+            }),
+        }
+        moves.sort_by_key(|m| Reverse(m.weight));
+    }
+
+    /// The moves recommended in the position with the given hash, heaviest first.
+    pub fn moves(&self, hash: u64) -> &[BookMove] {
+        self.entries.get(&hash).map_or(&[], Vec::as_slice)
+    }
+
+    /// How many positions the book covers.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Picks a book move for the current position of `state` at random, in proportion to the
+    /// moves' weights. The same `seed` always picks the same move. Moves that aren't legal in
+    /// the position, which can only happen if two positions share a hash, are never picked.
+    /// Returns `None` when the position isn't in the book or the game is over.
+    pub fn probe(&self, state: &GameState, seed: u64) -> Option<Move> {
+        let moves = self.playable(state);
+        let total: u64 = moves.iter().map(|m| u64::from(m.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = splitmix64(seed) % total;
+        for book_move in moves {
+            match pick.checked_sub(u64::from(book_move.weight)) {
+                Some(rest) => pick = rest,
+                None => return Some(book_move.player_move),
+            }
+        }
+        None
+    }
+
+    /// The heaviest legal book move for the current position of `state`, if there is one.
+    pub fn best(&self, state: &GameState) -> Option<Move> {
+        self.playable(state)
+            .first()
+            .map(|book_move| book_move.player_move)
+    }
+
+    fn playable(&self, state: &GameState) -> Vec<BookMove> {
+        let legal = state.legal_moves();
+        self.moves(state.position_hash())
+            .iter()
+            .filter(|book_move| legal.contains(&book_move.player_move))
+            .copied()
+            .collect()
+    }
+
+    /// The book in the binary format described in the [module docs](self). Positions are
+    /// written in hash order, so the same book always gives the same bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend((self.entries.len() as u32).to_le_bytes());
+        let mut hashes: Vec<u64> = self.entries.keys().copied().collect();
+        hashes.sort_unstable();
+        for hash in hashes {
+            let moves = &self.entries[&hash];
+            bytes.extend(hash.to_le_bytes());
+            bytes.extend((moves.len() as u16).to_le_bytes());
+            for book_move in moves {
+                let from = book_move.player_move.from;
+                bytes.extend([from.x as u8, from.y as u8]);
+                bytes.extend(encode_kind(book_move.player_move.kind));
+                bytes.extend(book_move.weight.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Reads a book written by [`OpeningBook::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BookError> {
+        let mut reader = Reader(bytes);
+        if reader.take::<4>().ok() != Some(*MAGIC) {
+            return Err(BookError::NotABook);
+        }
+        let [version] = reader.take()?;
+        if version != VERSION {
+            return Err(BookError::UnsupportedVersion(version));
+        }
+        let mut book = Self::new();
+        let positions = u32::from_le_bytes(reader.take()?);
+        for _ in 0..positions {
+            let hash = u64::from_le_bytes(reader.take()?);
+            let count = u16::from_le_bytes(reader.take()?);
+            for _ in 0..count {
+                let [x, y, kind, arg] = reader.take()?;
+                let player_move = Move {
+                    from: usizevec2(x.into(), y.into()),
+                    kind: decode_kind(kind, arg).ok_or(BookError::InvalidMove)?,
+                };
+                let weight = u32::from_le_bytes(reader.take()?);
+                book.add(hash, player_move, weight);
+            }
+        }
+        Ok(book)
+    }
+}
+
+/// Builds an [`OpeningBook`] from played games. Each game's early moves are added with a weight
+/// depending on how the game went for the player who made them: 2 for a win, 1 for a draw or an
+/// unfinished game, and moves by the loser aren't added at all.
+#[derive(Clone, Debug)]
+pub struct BookBuilder {
+    book: OpeningBook,
+    max_plies: usize,
+    min_weight: u32,
+}
+
+impl Default for BookBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BookBuilder {
+    /// A builder taking the first 16 plies of each game and keeping every move.
+    pub fn new() -> Self {
+        Self {
+            book: OpeningBook::new(),
+            max_plies: 16,
+            min_weight: 1,
+        }
+    }
+
+    /// Only take the first `plies` moves of each game.
+    pub fn max_plies(mut self, plies: usize) -> Self {
+        self.max_plies = plies;
+        self
+    }
+
+    /// Leave out moves whose total weight ends up below `weight`, e.g. lines only one game
+    /// tried.
+    pub fn min_weight(mut self, weight: u32) -> Self {
+        self.min_weight = weight;
+        self
+    }
+
+    /// Adds the opening of one game. Returns `false` and adds nothing if the record doesn't
+    /// replay.
+    pub fn add_record(&mut self, record: &GameRecord) -> bool {
+        let Ok(game) = record.replay() else {
+            return false;
+        };
+        let mut state = GameState::new(record.board.clone()).with_rules(record.rules.clone());
+        for player_move in game.moves().iter().take(self.max_plies) {
+            let weight = match record.result {
+                Some(GameResult::Win { winner, .. }) if winner == state.to_move() => 2,
+                Some(GameResult::Win { .. }) => 0,
+                Some(GameResult::Draw { .. }) | None => 1,
+            };
+            if weight > 0 {
+                self.book.add(state.position_hash(), *player_move, weight);
+            }
+            // The move already replayed once
+            state.apply(player_move).unwrap();
+        }
+        true
+    }
+
+    /// Adds the openings of every game in `records`, returning how many replayed.
+    pub fn add_records<'a>(&mut self, records: impl IntoIterator<Item = &'a GameRecord>) -> usize {
+        records
+            .into_iter()
+            .filter(|record| self.add_record(record))
+            .count()
+    }
+
+    pub fn build(mut self) -> OpeningBook {
+        for moves in self.book.entries.values_mut() {
+            moves.retain(|book_move| book_move.weight >= self.min_weight);
+        }
+        self.book.entries.retain(|_, moves| !moves.is_empty());
+        self.book
+    }
+}
+
+/// Reads fixed-size chunks off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], BookError> {
+        let (chunk, rest) = self.0.split_first_chunk().ok_or(BookError::Truncated)?;
+        self.0 = rest;
+        Ok(*chunk)
+    }
+}
+
+fn encode_kind(kind: MoveKind) -> [u8; 2] {
+    match kind {
+        MoveKind::Move(direction) => [0, direction.to_index() as u8],
+        MoveKind::Rotate(Chirality::Clockwise) => [1, 0],
+        MoveKind::Rotate(Chirality::CounterClockwise) => [1, 1],
+        MoveKind::Swap(direction) => [2, direction.to_index() as u8],
+        MoveKind::StackOnto(direction) => [3, direction.to_index() as u8],
+        MoveKind::Unstack(direction) => [4, direction.to_index() as u8],
+        MoveKind::Pass => [5, 0],
+    }
+}
+
+fn decode_kind(kind: u8, arg: u8) -> Option<MoveKind> {
+    let direction = || CompassOctant::from_index(arg.into());
+    Some(match (kind, arg) {
+        (0, _) => MoveKind::Move(direction()?),
+        (1, 0) => MoveKind::Rotate(Chirality::Clockwise),
+        (1, 1) => MoveKind::Rotate(Chirality::CounterClockwise),
+        (2, _) => MoveKind::Swap(direction()?),
+        (3, _) => MoveKind::StackOnto(direction()?),
+        (4, _) => MoveKind::Unstack(direction()?),
+        (5, 0) => MoveKind::Pass,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy_math::{CompassOctant, usizevec2};
+
+    use super::{
+        super::{
+            Board, Chirality, DrawReason, GameRecord, GameResult, GameState, Move, MoveKind,
+            Player, RulesConfig, TimedMove, WinReason,
+        },
+        BookBuilder, BookError, BookMove, OpeningBook,
+    };
+
+    /// A record of a game from the classic setup where each player made the first move they
+    /// could, `plies` times, and it ended in `result`.
+    fn record(plies: usize, result: Option<GameResult>) -> GameRecord {
+        let mut record = GameRecord::new(
+            ["alice".into(), "bob".into()],
+            Board::classic_setup(),
+            RulesConfig::default(),
+        );
+        let mut state = GameState::new(record.board.clone());
+        for _ in 0..plies {
+            let player_move = state.legal_moves()[0];
+            state.apply(&player_move).unwrap();
+            record.moves.push(TimedMove {
+                player_move,
+                elapsed: Duration::ZERO,
+                annotation: Default::default(),
+            });
+        }
+        record.result = result;
+        record
+    }
+
+    fn weights(book: &OpeningBook, hash: u64) -> Vec<u32> {
+        book.moves(hash).iter().map(|m| m.weight).collect()
+    }
+
+    #[test]
+    fn adding_a_move_again_adds_its_weight() {
+        let mut book = OpeningBook::new();
+        let a = Move::pass();
+        let b = Move {
+            from: usizevec2(1, 2),
+            kind: MoveKind::Rotate(Chirality::Clockwise),
+        };
+        book.add(7, a, 3);
+        book.add(7, b, 2);
+        book.add(7, b, 2);
+        assert_eq!(
+            book.moves(7),
+            [
+                BookMove {
+                    player_move: b,
+                    weight: 4
+                },
+                BookMove {
+                    player_move: a,
+                    weight: 3
+                },
+            ]
+        );
+        assert_eq!(book.len(), 1);
+        assert!(book.moves(8).is_empty());
+    }
+
+    #[test]
+    fn books_round_trip_through_bytes() {
+        let mut book = OpeningBook::new();
+        let kinds = [
+            MoveKind::Move(CompassOctant::NorthWest),
+            MoveKind::Rotate(Chirality::Clockwise),
+            MoveKind::Rotate(Chirality::CounterClockwise),
+            MoveKind::Swap(CompassOctant::South),
+            MoveKind::StackOnto(CompassOctant::East),
+            MoveKind::Unstack(CompassOctant::West),
+            MoveKind::Pass,
+        ];
+        for (index, kind) in kinds.into_iter().enumerate() {
+            let player_move = Move {
+                from: usizevec2(index, 7 - index),
+                kind,
+            };
+            book.add(index as u64 % 3, player_move, index as u32 + 1);
+        }
+        let bytes = book.to_bytes();
+        assert_eq!(OpeningBook::from_bytes(&bytes), Ok(book.clone()));
+        assert_eq!(book.clone().to_bytes(), bytes);
+
+        assert_eq!(
+            OpeningBook::from_bytes(b"LCBX\x01"),
+            Err(BookError::NotABook)
+        );
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(
+            OpeningBook::from_bytes(&newer),
+            Err(BookError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            OpeningBook::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(BookError::Truncated)
+        );
+        // The first move's kind, after the header, the hash, the move count and its square
+        let mut invalid = bytes;
+        invalid[5 + 4 + 8 + 2 + 2] = 9;
+        assert_eq!(
+            OpeningBook::from_bytes(&invalid),
+            Err(BookError::InvalidMove)
+        );
+    }
+
+    #[test]
+    fn builder_weighs_moves_by_how_the_game_went() {
+        let start = GameState::new(Board::classic_setup());
+        let won = Some(GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        });
+        let drawn = Some(GameResult::Draw {
+            reason: DrawReason::Agreement,
+        });
+        let mut builder = BookBuilder::new().max_plies(1);
+        let mut broken = record(1, None);
+        broken.moves[0].player_move.from = usizevec2(1, 1);
+        assert_eq!(
+            builder.add_records(&[record(4, won), record(2, drawn), broken]),
+            2
+        );
+        let book = builder.build();
+        // Only the first ply of each game, the same move both times
+        assert_eq!(book.len(), 1);
+        assert_eq!(weights(&book, start.position_hash()), [3]);
+
+        // The loser's moves are left out
+        let lost = Some(GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::Resignation,
+        });
+        let mut builder = BookBuilder::new();
+        builder.add_record(&record(2, lost));
+        let book = builder.build();
+        assert_eq!(book.len(), 1);
+        assert!(book.moves(start.position_hash()).is_empty());
+
+        let mut builder = BookBuilder::new().min_weight(2);
+        builder.add_record(&record(2, drawn));
+        assert!(builder.build().is_empty());
+    }
+
+    #[test]
+    fn probing_only_picks_legal_moves() {
+        let state = GameState::new(Board::classic_setup());
+        let legal = state.legal_moves();
+        let mut book = OpeningBook::new();
+        let hash = state.position_hash();
+        book.add(hash, legal[0], 1);
+        book.add(hash, legal[1], 5);
+        // A move that can't be played here, as if another position had the same hash
+        book.add(hash, Move::pass(), 100);
+
+        assert_eq!(book.best(&state), Some(legal[1]));
+        for seed in 0..20 {
+            let pick = book.probe(&state, seed).unwrap();
+            assert!(pick == legal[0] || pick == legal[1]);
+            assert_eq!(book.probe(&state, seed), Some(pick));
+        }
+        let other = GameState::with_player_to_move(Board::classic_setup(), Player::Player2);
+        assert_eq!(book.probe(&other, 0), None);
+        assert_eq!(book.best(&other), None);
+    }
+}
@@ -0,0 +1,282 @@
+//! Building arbitrary positions piece by piece, for puzzles, tests and custom setups, and checking
+//! they're fit to play.
+
+use std::fmt;
+
+use bevy_math::{CompassQuadrant, USizeVec2};
+
+use super::{
+    Board, Laser, MAX_NO_CAPTURE_DRAW_MOVES, Orientation, Piece, PieceKind, Player, RulesConfig,
+    format_coord,
+};
+
+/// Something wrong with a position that makes it unfit to start a game from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SetupError {
+    MissingKing(Player),
+    TooManyKings(Player),
+    /// A piece is on a square reserved for the other player.
+    RestrictedSquare {
+        coord: USizeVec2,
+        player: Player,
+    },
+    /// A piece was placed outside the board, or the rules put a reserved square or laser origin
+    /// there.
+    OffBoard(USizeVec2),
+    TooManyEmitters(Player),
+    /// A player has no emitter and the rules don't say where their laser comes from.
+    NoLaser(Player),
+    /// There are pieces of a player who isn't taking part in the game.
+    NotInGame(Player),
+    /// An emitter faces straight into the edge of the board, so its laser can never fire.
+    EmitterFacingWall(USizeVec2),
+    /// There's a splitter on the board, but the rules don't allow them.
+    SplitterNotAllowed(USizeVec2),
+    /// A piece is stuck inside a wall.
+    PieceInWall(USizeVec2),
+    /// The rules draw the game after no moves without a capture, or too many to count.
+    NoCaptureLimit(u32),
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetupError::MissingKing(player) => write!(f, "{player:?} has no king"),
+            SetupError::TooManyKings(player) => write!(f, "{player:?} has more than one king"),
+            SetupError::RestrictedSquare { coord, player } => write!(
+                f,
+                "{player:?} has a piece on {}, which is reserved for their opponent",
+                format_coord(*coord)
+            ),
+            SetupError::OffBoard(coord) => {
+                write!(f, "{} is off the board", format_coord(*coord))
+            }
+            SetupError::TooManyEmitters(player) => {
+                write!(f, "{player:?} has more than one laser emitter")
+            }
+            SetupError::NoLaser(player) => {
+                write!(f, "{player:?} has no emitter and no laser origin")
+            }
+            SetupError::NotInGame(player) => {
+                write!(f, "{player:?} has pieces but isn't in this game")
+            }
+            SetupError::EmitterFacingWall(coord) => {
+                write!(
+                    f,
+                    "The emitter on {} faces the edge of the board",
+                    format_coord(*coord)
+                )
+            }
+            SetupError::SplitterNotAllowed(coord) => {
+                write!(
+                    f,
+                    "The splitter on {} isn't allowed under these rules",
+                    format_coord(*coord)
+                )
+            }
+            SetupError::PieceInWall(coord) => {
+                write!(
+                    f,
+                    "There's a piece inside the wall on {}",
+                    format_coord(*coord)
+                )
+            }
+            SetupError::NoCaptureLimit(moves) => write!(
+                f,
+                "A draw after {moves} moves without a capture isn't allowed, only after 1 to \
+                 {MAX_NO_CAPTURE_DRAW_MOVES}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SetupError {}
+
+impl Board {
+    /// Checks the position is fit to start a game from under `rules`: each player in the game has
+    /// exactly one king and at most one emitter, which fires onto the board, there are no pieces
+    /// of players outside the game, no piece is on a square reserved
+    /// for its opponent or inside a wall, splitters only appear if the rules allow them,
+    /// everything the rules place on the board, zones included, fits on it, and the no-capture
+    /// draw comes after a sensible number of moves.
+    pub fn validate(&self, rules: &RulesConfig) -> Result<(), SetupError> {
+        if let Some(moves) = rules.no_capture_draw_moves
+            && !(1..=MAX_NO_CAPTURE_DRAW_MOVES).contains(&moves)
+        {
+            return Err(SetupError::NoCaptureLimit(moves));
+        }
+        for &player in rules.players.players() {
+            let count = |matches: fn(PieceKind) -> bool| {
+                self.pieces()
+                    .filter(|(_, piece)| piece.allegiance == player && matches(piece.kind))
+                    .count()
+            };
+            match count(|kind| kind == PieceKind::King) {
+                0 => return Err(SetupError::MissingKing(player)),
+                1 => {}
+                _ => return Err(SetupError::TooManyKings(player)),
+            }
+            match count(|kind| matches!(kind, PieceKind::Emitter(_))) {
+                0 => {
+                    let origin = rules
+                        .laser_origin(player)
+                        .ok_or(SetupError::NoLaser(player))?
+                        .position;
+                    if !self.contains(origin) {
+                        return Err(SetupError::OffBoard(origin));
+                    }
+                }
+                1 => {}
+                _ => return Err(SetupError::TooManyEmitters(player)),
+            }
+        }
+        for (coord, piece) in self.pieces() {
+            if !rules.players.contains(piece.allegiance) {
+                return Err(SetupError::NotInGame(piece.allegiance));
+            }
+            if self.is_wall(coord) {
+                return Err(SetupError::PieceInWall(coord));
+            }
+            if !rules.may_occupy(coord, piece.allegiance) {
+                return Err(SetupError::RestrictedSquare {
+                    coord,
+                    player: piece.allegiance,
+                });
+            }
+            if let PieceKind::Emitter(facing) = piece.kind
+                && (Laser {
+                    position: coord,
+                    direction: facing,
+                })
+                .advance(self.size())
+                .is_none()
+            {
+                return Err(SetupError::EmitterFacingWall(coord));
+            }
+            if matches!(piece.kind, PieceKind::Splitter(_)) && !rules.allow_splitters {
+                return Err(SetupError::SplitterNotAllowed(coord));
+            }
+        }
+        if let Some(&(coord, _)) = rules
+            .reserved_squares
+            .iter()
+            .chain(&rules.king_zones)
+            .find(|(coord, _)| !self.contains(*coord))
+        {
+            return Err(SetupError::OffBoard(coord));
+        }
+        Ok(())
+    }
+}
+
+/// Places pieces one at a time to build up a position, e.g.
+/// `BoardBuilder::new(8, 8).king(usizevec2(4, 0), Player::Player1).build()`.
+#[derive(Clone, Debug)]
+pub struct BoardBuilder {
+    board: Board,
+    /// The first place a piece was put outside the board, reported by [`BoardBuilder::validate`].
+    off_board: Option<USizeVec2>,
+}
+
+impl BoardBuilder {
+    /// Starts from an empty board.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::from_board(Board::empty(width, height))
+    }
+
+    /// Starts from an existing position, e.g. to edit one of the standard setups.
+    pub fn from_board(board: Board) -> Self {
+        Self {
+            board,
+            off_board: None,
+        }
+    }
+
+    /// Puts `piece` on `coord`, replacing whatever was there.
+    pub fn piece(mut self, coord: USizeVec2, piece: Piece) -> Self {
+        if self.board.contains(coord) {
+            self.board[coord] = Some(piece);
+        } else {
+            self.off_board.get_or_insert(coord);
+        }
+        self
+    }
+
+    /// Empties `coord`, taking away any piece or wall there.
+    pub fn clear(mut self, coord: USizeVec2) -> Self {
+        if self.board.contains(coord) {
+            self.board[coord] = None;
+            self.board.set_wall(coord, false);
+        }
+        self
+    }
+
+    /// Walls off `coord`, replacing any piece there.
+    pub fn wall(mut self, coord: USizeVec2) -> Self {
+        if self.board.contains(coord) {
+            self.board[coord] = None;
+            self.board.set_wall(coord, true);
+        } else {
+            self.off_board.get_or_insert(coord);
+        }
+        self
+    }
+
+    pub fn king(self, coord: USizeVec2, player: Player) -> Self {
+        self.piece(coord, Piece::king(player))
+    }
+
+    /// A stacked block.
+    pub fn block(self, coord: USizeVec2, player: Player) -> Self {
+        self.piece(coord, Piece::block(player))
+    }
+
+    /// A block that has already lost its top half.
+    pub fn half_block(self, coord: USizeVec2, player: Player) -> Self {
+        let piece = Piece {
+            kind: PieceKind::Block { stacked: false },
+            allegiance: player,
+        };
+        self.piece(coord, piece)
+    }
+
+    pub fn mirror(self, coord: USizeVec2, player: Player, orientation: Orientation) -> Self {
+        self.piece(coord, Piece::mirror(player, orientation))
+    }
+
+    pub fn two_sided(self, coord: USizeVec2, player: Player, orientation: Orientation) -> Self {
+        self.piece(coord, Piece::two_sided(player, orientation))
+    }
+
+    /// Only valid under rules that [allow splitters](RulesConfig::allow_splitters).
+    pub fn splitter(self, coord: USizeVec2, player: Player, orientation: Orientation) -> Self {
+        self.piece(coord, Piece::splitter(player, orientation))
+    }
+
+    pub fn defender(self, coord: USizeVec2, player: Player, facing: CompassQuadrant) -> Self {
+        self.piece(coord, Piece::defender(player, facing))
+    }
+
+    pub fn emitter(self, coord: USizeVec2, player: Player, facing: CompassQuadrant) -> Self {
+        self.piece(coord, Piece::emitter(player, facing))
+    }
+
+    /// The position built so far.
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    /// Checks the position like [`Board::validate`], and that no piece was placed off the board.
+    pub fn validate(&self, rules: &RulesConfig) -> Result<(), SetupError> {
+        if let Some(coord) = self.off_board {
+            return Err(SetupError::OffBoard(coord));
+        }
+        self.board.validate(rules)
+    }
+
+    /// Finishes the position. Pieces placed off the board are dropped; use
+    /// [`BoardBuilder::validate`] to catch them.
+    pub fn build(self) -> Board {
+        self.board
+    }
+}
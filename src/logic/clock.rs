@@ -0,0 +1,188 @@
+//! Chess clocks, shared by the server, which enforces them, and clients, which show them.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use super::{GameResult, Player, WinReason};
+
+/// The most time a player can start with, a day.
+pub const MAX_INITIAL_TIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most time a player can be given per move, an hour. The per-move limit can be up to
+/// [`MAX_INITIAL_TIME`].
+pub const MAX_INCREMENT: Duration = Duration::from_secs(60 * 60);
+
+/// How much time players get.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TimeControl {
+    /// Each player's time at the start of the game.
+    pub initial: Duration,
+    /// Time added to a player's clock after each of their moves.
+    pub increment: Duration,
+    /// The longest a single move may take, however much time is left on the clock.
+    pub per_move_limit: Option<Duration>,
+}
+
+impl TimeControl {
+    /// `minutes` each with `increment_secs` added per move, the usual "5+3" style.
+    pub fn new(minutes: u64, increment_secs: u64) -> Self {
+        Self {
+            initial: Duration::from_secs(minutes.saturating_mul(60)),
+            increment: Duration::from_secs(increment_secs),
+            per_move_limit: None,
+        }
+    }
+
+    /// Caps every move at `limit`, on top of the main clock.
+    pub fn with_per_move_limit(mut self, limit: Duration) -> Self {
+        self.per_move_limit = Some(limit);
+        self
+    }
+
+    /// Checks the time control is one a game can be played with: some time to start with, and
+    /// no more than [`MAX_INITIAL_TIME`] and [`MAX_INCREMENT`], so clocks never overflow. Time
+    /// controls come from clients, so the server checks them before playing one.
+    pub fn check(&self) -> Result<(), String> {
+        if self.initial.is_zero() {
+            return Err("A time control needs some time to start with".to_string());
+        }
+        if self.initial > MAX_INITIAL_TIME {
+            return Err(format!(
+                "A time control can start with at most {} hours",
+                MAX_INITIAL_TIME.as_secs() / 3600
+            ));
+        }
+        if self.increment > MAX_INCREMENT {
+            return Err(format!(
+                "A time control can add at most {} minutes per move",
+                MAX_INCREMENT.as_secs() / 60
+            ));
+        }
+        if let Some(limit) = self.per_move_limit
+            && (limit.is_zero() || limit > MAX_INITIAL_TIME)
+        {
+            return Err(format!(
+                "A per-move limit must be between a second and {} hours",
+                MAX_INITIAL_TIME.as_secs() / 3600
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Written the usual way, minutes then the increment in seconds, as in `5+3`.
+impl fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let initial = self.initial.as_secs();
+        if initial.is_multiple_of(60) {
+            write!(f, "{}+{}", initial / 60, self.increment.as_secs())?;
+        } else {
+            write!(f, "{}s+{}", initial, self.increment.as_secs())?;
+        }
+        if let Some(limit) = self.per_move_limit {
+            write!(f, " ({}s per move)", limit.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `minutes+increment`, like `5+3`, or just `minutes` for no increment.
+impl FromStr for TimeControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (minutes, increment) = s.split_once('+').unwrap_or((s, "0"));
+        let minutes = minutes
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid minutes '{minutes}' in time control '{s}'"))?;
+        let increment = increment
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid increment '{increment}' in time control '{s}'"))?;
+        let control = Self::new(minutes, increment);
+        control.check()?;
+        Ok(control)
+    }
+}
+
+/// Both players' clocks, with the player to move's running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Clock {
+    control: TimeControl,
+    remaining: [Duration; 2],
+    to_move: Player,
+    /// Set once a player runs out of time, after which the clock stops.
+    flagged: Option<Player>,
+}
+
+impl Clock {
+    /// A fresh clock with player 1 to move.
+    pub fn new(control: TimeControl) -> Self {
+        Self {
+            control,
+            remaining: [control.initial; 2],
+            to_move: Player::Player1,
+            flagged: None,
+        }
+    }
+
+    pub fn control(&self) -> &TimeControl {
+        &self.control
+    }
+
+    /// Whose clock is running.
+    pub fn to_move(&self) -> Player {
+        self.to_move
+    }
+
+    /// Time `player` had left when their last move ended, or when the game started.
+    pub fn remaining(&self, player: Player) -> Duration {
+        self.remaining[player.index()]
+    }
+
+    /// How much longer the player to move can think, given they've already spent `thinking` on
+    /// this move. Zero once they've run out.
+    pub fn time_left(&self, thinking: Duration) -> Duration {
+        let clock = self.remaining(self.to_move);
+        let allowed = match self.control.per_move_limit {
+            Some(limit) => clock.min(limit),
+            None => clock,
+        };
+        allowed.saturating_sub(thinking)
+    }
+
+    /// Charges `elapsed` to the player to move for the move they just made, adds their increment
+    /// and starts their opponent's clock. If that used up all their time, the clock stops instead
+    /// and the result of the game is returned: a loss on time.
+    pub fn apply_move(&mut self, elapsed: Duration) -> Option<GameResult> {
+        if let Some(loser) = self.flagged {
+            return Some(timeout(loser));
+        }
+        // Out of time is when time_left reaches zero, so a move taking all of it is too late
+        let over_time = elapsed >= self.time_left(Duration::ZERO);
+        let remaining = &mut self.remaining[self.to_move.index()];
+        if over_time {
+            // Overrunning the per-move limit loses too, with time still on the clock
+            *remaining = remaining.saturating_sub(elapsed);
+            self.flagged = Some(self.to_move);
+            return Some(timeout(self.to_move));
+        }
+        *remaining = (*remaining - elapsed).saturating_add(self.control.increment);
+        self.to_move = self.to_move.opponent();
+        None
+    }
+
+    /// The player whose time ran out, if anyone's has.
+    pub fn flagged(&self) -> Option<Player> {
+        self.flagged
+    }
+}
+
+fn timeout(loser: Player) -> GameResult {
+    GameResult::Win {
+        winner: loser.opponent(),
+        reason: WinReason::Timeout,
+    }
+}
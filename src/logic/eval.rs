@@ -0,0 +1,401 @@
+//! Static evaluation: a rough score for how good a position is, without searching any moves.
+//!
+//! Scores are in hundredths of a half block, from the point of view of the player to move, so
+//! positive is good for them. Three things go into them:
+//!
+//! - material, from [`piece_value`];
+//! - king safety: a king the other side could fire on next turn is in serious trouble, and pieces
+//!   standing next to a king shelter it from beams;
+//! - mirror mobility: mirrors that can step around redirect the laser more flexibly.
+//!
+//! In a four-player game the player to move is compared with the strongest of their opponents.
+//! [`evaluate_explained`] gives the score term by term.
+
+use std::ops::Sub;
+
+use bevy_math::CompassQuadrant;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Board, GameRecord, GameResult, GameState, Move, Piece, PieceKind, Player, RulesConfig,
+    add_compass_octant, add_compass_quadrant, movegen::DIRECTIONS,
+};
+
+/// The score of a won game, far above anything material and position can add up to.
+pub const WIN_SCORE: i32 = 1_000_000;
+
+/// What a king the player to move can fire on next is worth to them: not quite a win, since
+/// their own move might get in the way.
+const KING_EXPOSED: i32 = 2_000;
+/// The penalty for having your own king in an opponent's beam on your move.
+const KING_IN_BEAM: i32 = 300;
+/// The bonus for each piece standing right next to your king, north, east, south or west.
+const KING_SHELTER: i32 = 20;
+/// The bonus for each step a mirror could take.
+const MIRROR_MOBILITY: i32 = 4;
+/// How far short of the best move a move can fall and still count towards [`accuracy`]: a
+/// quarter of a half block.
+const ACCURACY_MARGIN: i32 = 25;
+
+/// How good `state` is for the player to move. Finished games score [`WIN_SCORE`] for a win, the
+/// negative of it for a loss and 0 for a draw.
+pub fn evaluate(state: &GameState) -> i32 {
+    evaluate_explained(state).total()
+}
+
+/// How good `board` is for `to_move` with them to move, ignoring whether the game is over. Cheaper
+/// than [`evaluate`] for engines that play moves on a bare board.
+pub fn evaluate_position(board: &Board, to_move: Player, rules: &RulesConfig) -> i32 {
+    position_breakdown(board, to_move, rules).total()
+}
+
+/// A score split into the terms it's made of, each from the point of view of the player to move,
+/// so analysis tools can show why a position is judged the way it is. In a four-player game each
+/// term compares the player to move with the strongest opponent overall, not the strongest at
+/// that one term.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvalBreakdown {
+    /// [`WIN_SCORE`], its negative or 0 once the game is over, when every other term is 0.
+    pub result: i32,
+    /// Piece values, from [`piece_value`].
+    pub material: i32,
+    /// Pieces standing next to the kings.
+    pub king_shelter: i32,
+    /// Kings either side could fire on next turn.
+    pub king_exposure: i32,
+    /// How many steps the mirrors could take.
+    pub mirror_mobility: i32,
+}
+
+impl EvalBreakdown {
+    /// The score, as [`evaluate`] gives it.
+    pub fn total(&self) -> i32 {
+        self.result + self.material + self.king_shelter + self.king_exposure + self.mirror_mobility
+    }
+}
+
+impl Sub for EvalBreakdown {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            result: self.result - other.result,
+            material: self.material - other.material,
+            king_shelter: self.king_shelter - other.king_shelter,
+            king_exposure: self.king_exposure - other.king_exposure,
+            mirror_mobility: self.mirror_mobility - other.mirror_mobility,
+        }
+    }
+}
+
+/// [`evaluate`], term by term.
+pub fn evaluate_explained(state: &GameState) -> EvalBreakdown {
+    let to_move = state.to_move();
+    let result = match state.result() {
+        Some(GameResult::Win { winner, .. }) if winner == to_move => WIN_SCORE,
+        Some(GameResult::Win { .. }) => -WIN_SCORE,
+        Some(GameResult::Draw { .. }) => 0,
+        None => return position_breakdown(state.board(), to_move, state.rules()),
+    };
+    EvalBreakdown {
+        result,
+        ..EvalBreakdown::default()
+    }
+}
+
+fn position_breakdown(board: &Board, to_move: Player, rules: &RulesConfig) -> EvalBreakdown {
+    let opponents: Vec<Player> = rules
+        .players
+        .active(board)
+        .filter(|&player| player != to_move)
+        .collect();
+    let strongest = opponents
+        .iter()
+        .map(|&opponent| side_score(board, opponent, rules))
+        .max_by_key(EvalBreakdown::total)
+        .unwrap_or_default();
+    let mut breakdown = side_score(board, to_move, rules) - strongest;
+    if board.king_in_beam(to_move, rules).is_some() {
+        breakdown.king_exposure -= KING_IN_BEAM;
+    }
+    for &opponent in &opponents {
+        if board.king_in_beam(opponent, rules).is_some() {
+            breakdown.king_exposure += KING_EXPOSED;
+        }
+    }
+    breakdown
+}
+
+/// What a piece of the given kind is worth. Kings and emitters can't be traded, so they're worth
+/// nothing here.
+pub fn piece_value(kind: PieceKind) -> i32 {
+    match kind {
+        PieceKind::King | PieceKind::Emitter(_) => 0,
+        PieceKind::Block { stacked: true } => 200,
+        PieceKind::Block { stacked: false } => 100,
+        PieceKind::OneSide(_) => 300,
+        PieceKind::Defender(_) => 250,
+        PieceKind::TwoSide(_) | PieceKind::Splitter(_) => 500,
+    }
+}
+
+/// Static exchange evaluation along the lasers: roughly how much material `player` comes out
+/// ahead by if they make `player_move`. That's what their own shot destroys or damages, less
+/// what the next player's beam would destroy or damage of theirs if it fired with the board left
+/// as it is. Kings count as [`WIN_SCORE`]. Much cheaper than searching the replies, and good at
+/// spotting moves that put a piece in a beam or step out of the way of one, but blind to
+/// replies that redirect the next beam. `None` if the move can't be made. The board is left as
+/// it was.
+pub fn static_exchange(
+    board: &mut Board,
+    player_move: &Move,
+    player: Player,
+    rules: &RulesConfig,
+) -> Option<i32> {
+    let undo = board.make_move(player_move, player, rules).ok()?;
+    let mut score: i32 = undo
+        .captures()
+        .iter()
+        .map(|capture| {
+            let lost = damage_value(capture.piece, capture.remains);
+            if capture.piece.allegiance == player {
+                -lost
+            } else {
+                lost
+            }
+        })
+        .sum();
+    if board.result(rules).is_none() {
+        let next = rules.players.next_active(player, board);
+        // The next player won't shoot their own pieces on purpose, so only count ours
+        score -= board
+            .laser_hits(next, rules)
+            .iter()
+            .filter(|hit| hit.piece.allegiance == player)
+            .map(|hit| damage_value(hit.piece, hit.replacement))
+            .sum::<i32>();
+    }
+    board.unmake_move(undo);
+    Some(score)
+}
+
+/// How accurately `player` played in `record`, from 0 to 1: the share of their moves that scored
+/// within [`ACCURACY_MARGIN`] of the best they had, looking one move ahead. It's rough, better for
+/// spotting trends over many games than for judging a single move. `None` if they made no moves
+/// or the record doesn't replay.
+pub fn accuracy(record: &GameRecord, player: Player) -> Option<f64> {
+    let game = record.replay().ok()?;
+    let rules = &record.rules;
+    let mut state = GameState::new(record.board.clone()).with_rules(rules.clone());
+    let (mut accurate, mut moves) = (0u32, 0u32);
+    for player_move in game.moves() {
+        if state.to_move() == player {
+            let mut board = state.board().clone();
+            let mut score = |m: &Move| one_move_score(&mut board, m, player, rules);
+            let best = state.legal_moves().iter().filter_map(&mut score).max();
+            let played = score(player_move);
+            moves += 1;
+            if played
+                .zip(best)
+                .is_some_and(|(played, best)| played + ACCURACY_MARGIN >= best)
+            {
+                accurate += 1;
+            }
+        }
+        state.apply(player_move).ok()?;
+    }
+    (moves > 0).then(|| f64::from(accurate) / f64::from(moves))
+}
+
+/// How good `player_move` is for `player` by the position it leaves, without looking at any
+/// replies. `None` if the move can't be made. The board is left as it was.
+fn one_move_score(
+    board: &mut Board,
+    player_move: &Move,
+    player: Player,
+    rules: &RulesConfig,
+) -> Option<i32> {
+    let undo = board.make_move(player_move, player, rules).ok()?;
+    let score = match board.result(rules) {
+        Some(GameResult::Win { winner, .. }) if winner == player => WIN_SCORE,
+        Some(GameResult::Win { .. }) => -WIN_SCORE,
+        Some(GameResult::Draw { .. }) => 0,
+        None => {
+            let next = rules.players.next_active(player, board);
+            -evaluate_position(board, next, rules)
+        }
+    };
+    board.unmake_move(undo);
+    Some(score)
+}
+
+/// What a piece loses in value by being hit and leaving `remains`.
+fn damage_value(piece: Piece, remains: Option<Piece>) -> i32 {
+    if piece.kind == PieceKind::King {
+        return if remains.is_none() { WIN_SCORE } else { 0 };
+    }
+    piece_value(piece.kind) - remains.map_or(0, |remains| piece_value(remains.kind))
+}
+
+/// Everything about `player`'s own position: material, shelter and mobility.
+fn side_score(board: &Board, player: Player, rules: &RulesConfig) -> EvalBreakdown {
+    let mut score = EvalBreakdown::default();
+    for (coord, piece) in board.pieces_of(player) {
+        score.material += piece_value(piece.kind);
+        match piece.kind {
+            PieceKind::King => {
+                let shelter = [
+                    CompassQuadrant::North,
+                    CompassQuadrant::East,
+                    CompassQuadrant::South,
+                    CompassQuadrant::West,
+                ]
+                .into_iter()
+                .filter_map(|direction| add_compass_quadrant(coord, direction, board.size()))
+                .filter(|&neighbour| board[neighbour].is_some())
+                .count();
+                score.king_shelter += KING_SHELTER * shelter as i32;
+            }
+            PieceKind::OneSide(_) | PieceKind::TwoSide(_) | PieceKind::Splitter(_) => {
+                let steps = DIRECTIONS
+                    .into_iter()
+                    .filter_map(|direction| add_compass_octant(coord, direction, board.size()))
+                    .filter(|&to| {
+                        board[to].is_none() && !board.is_wall(to) && rules.may_occupy(to, player)
+                    })
+                    .count();
+                score.mirror_mobility += MIRROR_MOBILITY * steps as i32;
+            }
+            _ => {}
+        }
+    }
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy_math::{CompassOctant, CompassQuadrant, usizevec2};
+
+    use super::{
+        super::{
+            Annotation, Board, Chirality, GameRecord, GameState, Move, MoveKind, Piece, Player,
+            RulesConfig, SetupKind, TimedMove,
+        },
+        EvalBreakdown, KING_EXPOSED, KING_IN_BEAM, WIN_SCORE, accuracy, evaluate,
+        evaluate_explained, piece_value, static_exchange,
+    };
+
+    /// Rules for [`duel`], with nothing reserved.
+    fn rules() -> RulesConfig {
+        RulesConfig {
+            reserved_squares: Vec::new(),
+            ..RulesConfig::default()
+        }
+    }
+
+    /// A 4x4 board where player 1's laser runs up the left edge into player 2's king, and player
+    /// 2's runs down the third file, missing everything.
+    fn duel() -> Board {
+        let mut board = Board::empty(4, 4);
+        board[usizevec2(0, 0)] = Some(Piece::emitter(Player::Player1, CompassQuadrant::North));
+        board[usizevec2(3, 0)] = Some(Piece::king(Player::Player1));
+        board[usizevec2(2, 3)] = Some(Piece::emitter(Player::Player2, CompassQuadrant::South));
+        board[usizevec2(0, 3)] = Some(Piece::king(Player::Player2));
+        board
+    }
+
+    fn step(from: (usize, usize), direction: CompassOctant) -> Move {
+        Move {
+            from: usizevec2(from.0, from.1),
+            kind: MoveKind::Move(direction),
+        }
+    }
+
+    #[test]
+    fn official_setups_are_even() {
+        for setup in SetupKind::ALL {
+            let state = GameState::new(Board::from_setup(setup, &RulesConfig::default()));
+            assert_eq!(evaluate(&state), 0, "{setup}");
+        }
+    }
+
+    #[test]
+    fn material_counts_for_whoever_has_it() {
+        let mut board = Board::classic_setup();
+        let (coord, piece) = board.pieces_of(Player::Player2).nth(1).unwrap();
+        board[coord] = None;
+        let value = piece_value(piece.kind);
+        assert!(value > 0);
+        for (to_move, material) in [(Player::Player1, value), (Player::Player2, -value)] {
+            let state = GameState::with_player_to_move(board.clone(), to_move);
+            assert_eq!(evaluate_explained(&state).material, material);
+        }
+    }
+
+    #[test]
+    fn kings_in_a_beam_are_in_trouble() {
+        let attacker = GameState::new(duel()).with_rules(rules());
+        assert_eq!(evaluate_explained(&attacker).king_exposure, KING_EXPOSED);
+        let defender = GameState::with_player_to_move(duel(), Player::Player2).with_rules(rules());
+        assert_eq!(evaluate_explained(&defender).king_exposure, -KING_IN_BEAM);
+    }
+
+    #[test]
+    fn finished_games_score_the_result() {
+        let mut state = GameState::new(duel()).with_rules(rules());
+        state.apply(&step((3, 0), CompassOctant::North)).unwrap();
+        assert!(state.result().is_some());
+        assert_eq!(
+            evaluate_explained(&state),
+            EvalBreakdown {
+                result: -WIN_SCORE,
+                ..EvalBreakdown::default()
+            }
+        );
+    }
+
+    #[test]
+    fn accuracy_is_the_share_of_moves_as_good_as_the_best() {
+        let record = |player_move| {
+            let mut record = GameRecord::new(["alice".into(), "bob".into()], duel(), rules());
+            record.moves.push(TimedMove {
+                player_move,
+                elapsed: Duration::ZERO,
+                annotation: Annotation::default(),
+            });
+            record
+        };
+        let winning = record(step((3, 0), CompassOctant::North));
+        assert_eq!(accuracy(&winning, Player::Player1), Some(1.0));
+        assert_eq!(accuracy(&winning, Player::Player2), None);
+        // Turning the laser onto their own king
+        let losing = record(Move {
+            from: usizevec2(0, 0),
+            kind: MoveKind::Rotate(Chirality::Clockwise),
+        });
+        assert!(losing.replay().unwrap().result().is_some());
+        assert_eq!(accuracy(&losing, Player::Player1), Some(0.0));
+    }
+
+    #[test]
+    fn static_exchange_counts_pieces_left_in_the_beam() {
+        let mut board = duel();
+        let before = board.clone();
+        let player = Player::Player2;
+        let out = step((0, 3), CompassOctant::East);
+        assert_eq!(static_exchange(&mut board, &out, player, &rules()), Some(0));
+        let still_in = step((0, 3), CompassOctant::South);
+        assert_eq!(
+            static_exchange(&mut board, &still_in, player, &rules()),
+            Some(-WIN_SCORE)
+        );
+        let off_board = step((0, 3), CompassOctant::North);
+        assert_eq!(
+            static_exchange(&mut board, &off_board, player, &rules()),
+            None
+        );
+        assert_eq!(board, before);
+    }
+}
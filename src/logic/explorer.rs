@@ -0,0 +1,253 @@
+//! Opening explorers: for any position, the moves played from it in a collection of games and
+//! how those games went, so players can see what's been tried and what worked.
+//!
+//! Like an [`OpeningBook`](super::OpeningBook), an explorer is keyed by
+//! [`GameState::position_hash`], so games that reach the same position by different move orders
+//! are counted together, and it should only be given games played under the same rules.
+
+use std::{cmp::Reverse, collections::HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use super::{GameRecord, GameResult, GameState, Move};
+
+/// The moves played from every position in a collection of finished games.
+#[derive(Clone, Debug, Default)]
+pub struct Explorer {
+    positions: HashMap<u64, ExplorerPosition>,
+}
+
+/// A position in an [`Explorer`]: how many games reached it and what was played next.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExplorerPosition {
+    /// Games that reached the position, including any that ended there.
+    pub games: u32,
+    /// The moves played from it, the most played first.
+    pub moves: Vec<ExplorerMove>,
+}
+
+/// A move played from a position, with how the games it was played in went for the player who
+/// made it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExplorerMove {
+    pub player_move: Move,
+    pub games: u32,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Explorer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds every position of one game. Returns `false` and adds nothing if the game isn't over
+    /// or the record doesn't replay. A game that comes back to a position is only counted there
+    /// once, with the first move it played from it.
+    pub fn add_record(&mut self, record: &GameRecord) -> bool {
+        let Some(result) = record.result else {
+            return false;
+        };
+        let Ok(game) = record.replay() else {
+            return false;
+        };
+        let mut state = GameState::new(record.board.clone()).with_rules(record.rules.clone());
+        let mut seen = Vec::new();
+        for player_move in game.moves().iter().map(Some).chain([None]) {
+            let hash = state.position_hash();
+            if !seen.contains(&hash) {
+                seen.push(hash);
+                let position = self.positions.entry(hash).or_default();
+                position.games += 1;
+                if let Some(player_move) = player_move {
+                    add_move(&mut position.moves, *player_move, result, &state);
+                }
+            }
+            if let Some(player_move) = player_move {
+                // The move already replayed once
+                state.apply(player_move).unwrap();
+            }
+        }
+        true
+    }
+
+    /// Adds every game in `records`, returning how many were added.
+    pub fn add_records<'a>(&mut self, records: impl IntoIterator<Item = &'a GameRecord>) -> usize {
+        records
+            .into_iter()
+            .filter(|record| self.add_record(record))
+            .count()
+    }
+
+    /// What was played from the current position of `state`. Moves that aren't legal there,
+    /// which can only happen if two positions share a hash, are left out.
+    pub fn position(&self, state: &GameState) -> ExplorerPosition {
+        let Some(position) = self.positions.get(&state.position_hash()) else {
+            return ExplorerPosition::default();
+        };
+        let legal = state.legal_moves();
+        ExplorerPosition {
+            games: position.games,
+            moves: position
+                .moves
+                .iter()
+                .filter(|explored| legal.contains(&explored.player_move))
+                .copied()
+                .collect(),
+        }
+    }
+
+    /// How many positions the explorer covers.
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+}
+
+/// Counts `player_move`, played in `state` in a game that ended in `result`, among `moves`.
+fn add_move(
+    moves: &mut Vec<ExplorerMove>,
+    player_move: Move,
+    result: GameResult,
+    state: &GameState,
+) {
+    let index = match moves.iter().position(|m| m.player_move == player_move) {
+        Some(index) => index,
+        None => {
+            moves.push(ExplorerMove {
+                player_move,
+                games: 0,
+                wins: 0,
+                draws: 0,
+                losses: 0,
+            });
+            moves.len() - 1
+        }
+    };
+    let explored = &mut moves[index];
+    explored.games += 1;
+    match result {
+        GameResult::Win { winner, .. } if winner == state.to_move() => explored.wins += 1,
+        GameResult::Win { .. } => explored.losses += 1,
+        GameResult::Draw { .. } => explored.draws += 1,
+    }
+    moves.sort_by_key(|m| Reverse(m.games));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        super::{
+            Board, DrawReason, GameRecord, GameResult, GameState, Move, Player, RulesConfig,
+            TimedMove, WinReason,
+        },
+        Explorer, ExplorerMove,
+    };
+
+    /// A record of a game from the classic setup with `moves` picked from the legal moves by
+    /// index, ending in `result`.
+    fn record(moves: &[usize], result: Option<GameResult>) -> GameRecord {
+        let mut record = GameRecord::new(
+            ["alice".into(), "bob".into()],
+            Board::classic_setup(),
+            RulesConfig::default(),
+        );
+        let mut state = GameState::new(record.board.clone());
+        for &index in moves {
+            let player_move = state.legal_moves()[index];
+            state.apply(&player_move).unwrap();
+            record.moves.push(TimedMove {
+                player_move,
+                elapsed: Duration::ZERO,
+                annotation: Default::default(),
+            });
+        }
+        record.result = result;
+        record
+    }
+
+    fn win(winner: Player) -> Option<GameResult> {
+        Some(GameResult::Win {
+            winner,
+            reason: WinReason::Resignation,
+        })
+    }
+
+    fn explored(player_move: Move, [wins, draws, losses]: [u32; 3]) -> ExplorerMove {
+        ExplorerMove {
+            player_move,
+            games: wins + draws + losses,
+            wins,
+            draws,
+            losses,
+        }
+    }
+
+    #[test]
+    fn counts_moves_and_results_for_the_mover() {
+        let start = GameState::new(Board::classic_setup());
+        let legal = start.legal_moves();
+        let draw = Some(GameResult::Draw {
+            reason: DrawReason::Agreement,
+        });
+        let mut explorer = Explorer::new();
+        let records = [
+            record(&[0, 0], win(Player::Player1)),
+            record(&[0, 1], draw),
+            record(&[1], win(Player::Player1)),
+            record(&[0], win(Player::Player2)),
+            // Unfinished games aren't counted
+            record(&[1], None),
+        ];
+        assert_eq!(explorer.add_records(&records), 4);
+
+        let position = explorer.position(&start);
+        assert_eq!(position.games, 4);
+        assert_eq!(
+            position.moves,
+            [explored(legal[0], [1, 1, 1]), explored(legal[1], [1, 0, 0])]
+        );
+        // Player 2's results after the first move, from their side
+        let mut after = start.clone();
+        after.apply(&legal[0]).unwrap();
+        let replies = after.legal_moves();
+        let position = explorer.position(&after);
+        assert_eq!(position.games, 3);
+        assert_eq!(
+            position.moves,
+            [
+                explored(replies[0], [0, 0, 1]),
+                explored(replies[1], [0, 1, 0])
+            ]
+        );
+        // Where a game ended, it's counted without a move
+        let mut end = after.clone();
+        end.apply(&replies[0]).unwrap();
+        assert_eq!(explorer.position(&end).games, 1);
+        assert!(explorer.position(&end).moves.is_empty());
+    }
+
+    #[test]
+    fn unknown_positions_are_empty() {
+        let explorer = Explorer::new();
+        assert!(explorer.is_empty());
+        let position = explorer.position(&GameState::new(Board::classic_setup()));
+        assert_eq!(position.games, 0);
+        assert!(position.moves.is_empty());
+    }
+
+    #[test]
+    fn games_that_dont_replay_are_left_out() {
+        let mut broken = record(&[0], win(Player::Player1));
+        broken.moves.push(broken.moves[0].clone());
+        let mut explorer = Explorer::new();
+        assert!(!explorer.add_record(&broken));
+        assert!(explorer.is_empty());
+    }
+}
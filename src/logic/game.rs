@@ -0,0 +1,524 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Board, InvalidMove, Move, MoveOutcome, Piece, Player, RulesConfig,
+    history::{BoardDelta, BoardHistory},
+};
+
+/// How a finished game ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameResult {
+    Win { winner: Player, reason: WinReason },
+    Draw { reason: DrawReason },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WinReason {
+    /// The loser's king was hit by a laser. In a four-player game, the winner is the last player
+    /// with a king.
+    KingDestroyed,
+    /// The loser gave up.
+    Resignation,
+    /// The loser ran out of time.
+    Timeout,
+    /// The loser lost their connection to the game and didn't come back.
+    Disconnect,
+    /// The game was drawn, which is as good as a win for the player given draw odds, as in an
+    /// Armageddon game settling a level series.
+    DrawOdds,
+    /// The winner's king reached one of their [zones](RulesConfig::king_zones).
+    KingReachedZone,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DrawReason {
+    /// The same position, with the same player to move, came up three times.
+    Repetition,
+    /// Nothing was hit by a laser for [`RulesConfig::no_capture_draw_moves`] full moves.
+    NoCaptures,
+    /// One shot destroyed every king left, e.g. through a splitter.
+    MutualDestruction,
+    /// The players agreed to a draw.
+    Agreement,
+}
+
+/// A move in a replayed move list that couldn't be played.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayError {
+    /// Where the move is in the list, counting from 0.
+    pub index: usize,
+    pub player_move: Move,
+    pub error: InvalidMove,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Move {} ({}) is invalid: {}",
+            self.index + 1,
+            self.player_move,
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// How many times a position has to occur for the game to be drawn.
+const REPETITION_LIMIT: usize = 3;
+
+/// A game in progress: the board plus whose turn it is and how we got here. Moves are always
+/// made by the player whose turn it is, so turn order can't be broken by going through
+/// [`GameState::apply`].
+///
+/// Serialized as the initial board, the rules and the moves played, which are replayed when
+/// deserializing. Moves that were taken back aren't kept.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "RawGameState", try_from = "RawGameState")]
+pub struct GameState {
+    rules: RulesConfig,
+    history: BoardHistory,
+    /// Bookkeeping for every position in `history`, for the draw rules.
+    positions: Vec<PositionInfo>,
+    moves: Vec<Move>,
+    result: Option<GameResult>,
+    /// Whether `result` came from [`GameState::end`] rather than a move, so it can't be taken back.
+    ended: bool,
+    /// Moves taken back with [`GameState::undo`], most recent last, with the change each one made
+    /// and whether its laser hit anything, so [`GameState::redo`] doesn't have to replay them.
+    undone: Vec<(Move, BoardDelta, bool)>,
+}
+
+/// A [`GameState`] as it's serialized.
+#[derive(Serialize, Deserialize)]
+struct RawGameState {
+    initial: Board,
+    first_to_move: Player,
+    rules: RulesConfig,
+    moves: Vec<Move>,
+    /// How the game ended, which the moves alone don't show if it was [ended](GameState::end)
+    /// early.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<GameResult>,
+}
+
+impl From<GameState> for RawGameState {
+    fn from(game: GameState) -> Self {
+        Self {
+            first_to_move: game.positions[0].to_move,
+            initial: game.history.initial().clone(),
+            rules: game.rules,
+            moves: game.moves,
+            result: game.result,
+        }
+    }
+}
+
+impl TryFrom<RawGameState> for GameState {
+    type Error = String;
+
+    fn try_from(raw: RawGameState) -> Result<Self, Self::Error> {
+        let mut game =
+            Self::with_player_to_move(raw.initial, raw.first_to_move).with_rules(raw.rules);
+        for (index, player_move) in raw.moves.iter().enumerate() {
+            game.apply(player_move)
+                .map_err(|e| format!("move {} ({player_move}) can't be played: {e}", index + 1))?;
+        }
+        if let Some(result) = raw.result {
+            game.end(result);
+        }
+        Ok(game)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PositionInfo {
+    to_move: Player,
+    /// [`Board::position_hash`] of the position, for spotting repetitions.
+    hash: u64,
+    /// Plies played since a laser last hit a piece.
+    quiet_plies: u32,
+}
+
+impl GameState {
+    /// Starts a game from `board` with player 1 to move.
+    pub fn new(board: Board) -> Self {
+        Self::with_player_to_move(board, Player::Player1)
+    }
+
+    /// Starts a game from an arbitrary position, e.g. a puzzle where player 2 moves first.
+    pub fn with_player_to_move(board: Board, to_move: Player) -> Self {
+        Self {
+            rules: RulesConfig::default(),
+            positions: vec![PositionInfo {
+                to_move,
+                hash: board.position_hash(to_move),
+                quiet_plies: 0,
+            }],
+            history: BoardHistory::new(board),
+            moves: Vec::new(),
+            result: None,
+            ended: false,
+            undone: Vec::new(),
+        }
+    }
+
+    /// Reconstructs a game by playing `moves` in order from `initial`, with player 1 moving first.
+    /// Fails on the first move that can't be played, including any made after the game ended.
+    pub fn replay(initial: Board, rules: RulesConfig, moves: &[Move]) -> Result<Self, ReplayError> {
+        let mut game = Self::new(initial).with_rules(rules);
+        for (index, player_move) in moves.iter().enumerate() {
+            game.apply(player_move).map_err(|error| ReplayError {
+                index,
+                player_move: *player_move,
+                error,
+            })?;
+        }
+        Ok(game)
+    }
+
+    /// Plays the game under `rules` instead of the defaults.
+    pub fn with_rules(mut self, rules: RulesConfig) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    pub fn rules(&self) -> &RulesConfig {
+        &self.rules
+    }
+
+    pub fn board(&self) -> &Board {
+        self.history.current()
+    }
+
+    /// The player whose turn it is. Players who have been knocked out of a four-player game are
+    /// skipped.
+    pub fn to_move(&self) -> Player {
+        self.current_position().to_move
+    }
+
+    /// Every move the player to move could make, or none once the game is over.
+    pub fn legal_moves(&self) -> Vec<Move> {
+        if self.result.is_some() {
+            return Vec::new();
+        }
+        self.board().legal_moves(self.to_move(), &self.rules)
+    }
+
+    /// Every move played so far, in order.
+    pub fn moves(&self) -> &[Move] {
+        &self.moves
+    }
+
+    /// Every position reached so far, starting with the initial board.
+    pub fn history(&self) -> &BoardHistory {
+        &self.history
+    }
+
+    /// `None` while the game is still being played.
+    pub fn result(&self) -> Option<GameResult> {
+        self.result
+    }
+
+    /// Hash of the current position, including the player to move.
+    pub fn position_hash(&self) -> u64 {
+        self.current_position().hash
+    }
+
+    /// How many times the current position has occurred so far, counting this time.
+    pub fn repetitions(&self) -> usize {
+        let current = self.position_hash();
+        self.positions
+            .iter()
+            .filter(|position| position.hash == current)
+            .count()
+    }
+
+    /// How many plies have been played since a laser last hit a piece.
+    pub fn quiet_plies(&self) -> u32 {
+        self.current_position().quiet_plies
+    }
+
+    fn current_position(&self) -> &PositionInfo {
+        self.positions.last().unwrap() // There's always at least the initial position
+    }
+
+    /// Plays `player_move` for the player whose turn it is, fires their laser and passes the turn.
+    /// Returns what the move did.
+    pub fn apply(&mut self, player_move: &Move) -> Result<MoveOutcome, InvalidMove> {
+        if self.result.is_some() {
+            return Err(InvalidMove::GameOver);
+        }
+        let outcome = self
+            .board()
+            .preview_move(player_move, self.to_move(), &self.rules)?;
+        self.undone.clear();
+        self.push_position(*player_move, &outcome.board, !outcome.captures.is_empty());
+        Ok(outcome)
+    }
+
+    /// Plays `player_move` on behalf of `player`, like [`GameState::apply`], but rejects it if it
+    /// isn't `player`'s turn.
+    pub fn apply_as(
+        &mut self,
+        player: Player,
+        player_move: &Move,
+    ) -> Result<MoveOutcome, InvalidMove> {
+        if self.result.is_none() && player != self.to_move() {
+            return Err(InvalidMove::NotYourTurn);
+        }
+        self.apply(player_move)
+    }
+
+    /// Ends the game with `result` without another move being played, e.g. when a player resigns.
+    /// Does nothing if the game is already over.
+    pub fn end(&mut self, result: GameResult) {
+        if self.result.is_none() {
+            self.result = Some(result);
+            self.ended = true;
+        }
+    }
+
+    /// Takes back the last move, returning it, or `None` at the start of the game or once it's
+    /// been [ended](GameState::end), say by a resignation, which can't be taken back. Undone moves
+    /// can be replayed with [`GameState::redo`] until a different move is applied.
+    pub fn undo(&mut self) -> Option<Move> {
+        if self.ended {
+            return None;
+        }
+        let delta = self.history.pop()?;
+        let player_move = self.moves.pop()?;
+        // The laser hit something if the count of quiet plies started again
+        let captured = self.positions.pop()?.quiet_plies == 0;
+        self.undone.push((player_move, delta, captured));
+        // Moves can't be applied after the game ends, so it was still going before this one
+        self.result = None;
+        Some(player_move)
+    }
+
+    /// Replays the most recently undone move, returning it, or `None` if there's nothing to redo or
+    /// the game has been [ended](GameState::end).
+    pub fn redo(&mut self) -> Option<Move> {
+        if self.ended {
+            return None;
+        }
+        let (player_move, delta, captured) = self.undone.pop()?;
+        let mut board = self.board().clone();
+        delta.apply(&mut board);
+        self.push_position(player_move, &board, captured);
+        Some(player_move)
+    }
+
+    /// Records the position `player_move` led to, passes the turn and checks whether the game is
+    /// over.
+    fn push_position(&mut self, player_move: Move, board: &Board, captured: bool) {
+        let to_move = self.rules.players.next_active(self.to_move(), board);
+        self.positions.push(PositionInfo {
+            to_move,
+            hash: board.position_hash(to_move),
+            quiet_plies: if captured {
+                0
+            } else {
+                self.quiet_plies().saturating_add(1)
+            },
+        });
+        self.history.push(board);
+        self.moves.push(player_move);
+        self.result = board.result(&self.rules);
+        if self.result.is_none() && self.repetitions() >= REPETITION_LIMIT {
+            trace_event!(debug, hash = self.position_hash(), "draw by repetition");
+            self.result = Some(GameResult::Draw {
+                reason: DrawReason::Repetition,
+            });
+        }
+        if let Some(limit) = self.rules.no_capture_draw_moves
+            && self.result.is_none()
+            && self.quiet_plies() >= limit.saturating_mul(2)
+        {
+            trace_event!(
+                debug,
+                quiet_plies = self.quiet_plies(),
+                "draw by no captures"
+            );
+            self.result = Some(GameResult::Draw {
+                reason: DrawReason::NoCaptures,
+            });
+        }
+    }
+}
+
+impl Board {
+    /// Plays `moves` in order on this board, with the players in the game taking turns starting
+    /// with `first_player`. Unlike [`GameState::replay`] this keeps no history and doesn't stop
+    /// when a king falls. On error the board is left as it was after the last good move.
+    pub fn apply_moves(
+        &mut self,
+        moves: &[Move],
+        first_player: Player,
+        rules: &RulesConfig,
+    ) -> Result<(), ReplayError> {
+        let mut player = first_player;
+        for (index, player_move) in moves.iter().enumerate() {
+            self.try_move(player_move, player, rules)
+                .map_err(|error| ReplayError {
+                    index,
+                    player_move: *player_move,
+                    error,
+                })?;
+            player = rules.players.next_active(player, self);
+        }
+        Ok(())
+    }
+
+    /// The result of a game under `rules` that has reached this position, if the position alone
+    /// decides it: the last player with a king wins, as does a player whose king is on one of
+    /// their zones. Draws and wins that depend on how the game went are tracked by
+    /// [`GameState::result`].
+    pub fn result(&self, rules: &RulesConfig) -> Option<GameResult> {
+        let mut active = rules.players.active(self);
+        match (active.next(), active.next()) {
+            (Some(winner), None) => Some(GameResult::Win {
+                winner,
+                reason: WinReason::KingDestroyed,
+            }),
+            (None, _) => Some(GameResult::Draw {
+                reason: DrawReason::MutualDestruction,
+            }),
+            _ => self.king_in_zone(rules).map(|winner| GameResult::Win {
+                winner,
+                reason: WinReason::KingReachedZone,
+            }),
+        }
+    }
+
+    /// The player whose king stands on one of their zones under `rules`, if any.
+    fn king_in_zone(&self, rules: &RulesConfig) -> Option<Player> {
+        rules.king_zones.iter().find_map(|&(coord, player)| {
+            (self.contains(coord) && self[coord] == Some(Piece::king(player))).then_some(player)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{CompassOctant, CompassQuadrant, usizevec2};
+
+    use super::super::{Board, Laser, Move, MoveKind, Piece, Player, RulesConfig};
+    use super::{GameResult, GameState, WinReason};
+
+    /// A 4x4 game where player 1 passes to fire up the first column at a stacked block of player
+    /// 2's, with player 2's king on the far side of it.
+    fn shooting_game() -> GameState {
+        let mut board = Board::empty(4, 4);
+        board[usizevec2(0, 3)] = Some(Piece::king(Player::Player2));
+        board[usizevec2(0, 2)] = Some(Piece::block(Player::Player2));
+        board[usizevec2(3, 1)] = Some(Piece::king(Player::Player1));
+        let rules = RulesConfig {
+            laser_origins: vec![Laser {
+                position: usizevec2(0, 0),
+                direction: CompassQuadrant::North,
+            }],
+            reserved_squares: Vec::new(),
+            allow_passing: true,
+            ..RulesConfig::default()
+        };
+        GameState::new(board).with_rules(rules)
+    }
+
+    #[test]
+    fn huge_no_capture_limit_does_not_overflow() {
+        // Games can still be set up without validating their rules
+        let rules = RulesConfig {
+            no_capture_draw_moves: Some(u32::MAX),
+            ..RulesConfig::default()
+        };
+        let mut game = GameState::new(Board::classic_setup()).with_rules(rules);
+        let first_move = game.legal_moves()[0];
+        game.apply(&first_move).unwrap();
+        assert_eq!(game.result(), None);
+    }
+
+    #[test]
+    fn undo_and_redo_keep_captures() {
+        let mut game = shooting_game();
+        game.apply(&Move::pass()).unwrap();
+        game.apply(&Move::pass()).unwrap();
+        assert_eq!(game.quiet_plies(), 1);
+
+        game.undo().unwrap();
+        game.undo().unwrap();
+        game.redo().unwrap();
+        assert_eq!(game.quiet_plies(), 0, "the first pass hit the block");
+        game.redo().unwrap();
+        assert_eq!(game.quiet_plies(), 1);
+    }
+
+    #[test]
+    fn undo_takes_back_a_winning_move() {
+        let mut game = shooting_game();
+        // Player 1's first two shots take the block apart and the third hits the king
+        for _ in 0..5 {
+            game.apply(&Move::pass()).unwrap();
+        }
+        assert_eq!(
+            game.result(),
+            Some(GameResult::Win {
+                winner: Player::Player1,
+                reason: WinReason::KingDestroyed,
+            })
+        );
+
+        game.undo().unwrap();
+        assert_eq!(game.result(), None);
+        game.redo().unwrap();
+        assert!(game.result().is_some());
+    }
+
+    #[test]
+    fn undo_does_not_revive_an_ended_game() {
+        let mut game = shooting_game();
+        game.apply(&Move::pass()).unwrap();
+        let resigned = GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        };
+        game.end(resigned);
+
+        assert_eq!(game.undo(), None);
+        assert_eq!(game.result(), Some(resigned));
+        assert_eq!(game.moves().len(), 1);
+    }
+
+    #[test]
+    fn kings_win_by_reaching_their_zone() {
+        let mut game = shooting_game();
+        let mut rules = game.rules().clone();
+        // Player 2's king already stands on player 1's other zone, which does it no good
+        rules.king_zones = vec![
+            (usizevec2(3, 2), Player::Player1),
+            (usizevec2(0, 3), Player::Player1),
+        ];
+        game = game.with_rules(rules);
+        assert_eq!(game.board().result(game.rules()), None);
+
+        let king_move = Move {
+            from: usizevec2(3, 1),
+            kind: MoveKind::Move(CompassOctant::North),
+        };
+        game.apply(&king_move).unwrap();
+        assert_eq!(
+            game.result(),
+            Some(GameResult::Win {
+                winner: Player::Player1,
+                reason: WinReason::KingReachedZone,
+            })
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Compact storage for sequences of board positions.
+//!
+//! Keeping a full [`Board`] per ply adds up quickly over long games and deep search lines, but
+//! a single move only ever touches a handful of cells. [`BoardHistory`] stores the cells that
+//! changed each ply, plus a shared full snapshot every [`KEYFRAME_INTERVAL`] plies so any
+//! position can be rebuilt without replaying the whole game.
+
+use std::sync::Arc;
+
+use bevy_math::{USizeVec2, usizevec2};
+use serde::{Deserialize, Serialize};
+
+use super::{Board, Piece};
+
+/// How many plies apart full board snapshots are kept.
+pub const KEYFRAME_INTERVAL: usize = 16;
+
+/// The contents of a single cell before and after a ply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CellChange {
+    pub coord: USizeVec2,
+    pub before: Option<Piece>,
+    pub after: Option<Piece>,
+}
+
+/// The set of cells that changed between two positions.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardDelta {
+    pub changes: Vec<CellChange>,
+}
+
+impl BoardDelta {
+    /// The cells that differ between two boards of the same size.
+    pub fn between(before: &Board, after: &Board) -> Self {
+        debug_assert_eq!(before.size(), after.size());
+        let mut changes = Vec::new();
+        for y in 0..before.height() {
+            for x in 0..before.width() {
+                let coord = usizevec2(x, y);
+                if before[coord] != after[coord] {
+                    changes.push(CellChange {
+                        coord,
+                        before: before[coord],
+                        after: after[coord],
+                    });
+                }
+            }
+        }
+        Self { changes }
+    }
+
+    /// Turns the "before" position into the "after" position.
+    pub fn apply(&self, board: &mut Board) {
+        for change in &self.changes {
+            board[change.coord] = change.after;
+        }
+    }
+
+    /// Turns the "after" position back into the "before" position.
+    pub fn revert(&self, board: &mut Board) {
+        for change in &self.changes {
+            board[change.coord] = change.before;
+        }
+    }
+}
+
+/// A sequence of positions starting from an initial board. Cloning is cheap: keyframes are shared
+/// between clones, so search lines can branch off a game's history without copying it.
+#[derive(Clone, Debug)]
+pub struct BoardHistory {
+    /// Full snapshots of every `KEYFRAME_INTERVAL`th position, starting with the initial board.
+    keyframes: Vec<Arc<Board>>,
+    /// `deltas[i]` turns position `i` into position `i + 1`.
+    deltas: Vec<BoardDelta>,
+    current: Board,
+}
+
+impl BoardHistory {
+    pub fn new(initial: Board) -> Self {
+        Self {
+            keyframes: vec![Arc::new(initial.clone())],
+            deltas: Vec::new(),
+            current: initial,
+        }
+    }
+
+    /// Number of positions stored, including the initial one.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + 1
+    }
+
+    /// A history always contains at least its initial position.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn initial(&self) -> &Board {
+        &self.keyframes[0]
+    }
+
+    pub fn current(&self) -> &Board {
+        &self.current
+    }
+
+    /// Records the next position.
+    pub fn push(&mut self, board: &Board) {
+        self.deltas.push(BoardDelta::between(&self.current, board));
+        self.current = board.clone();
+        if self.deltas.len().is_multiple_of(KEYFRAME_INTERVAL) {
+            self.keyframes.push(Arc::new(self.current.clone()));
+        }
+    }
+
+    /// Drops the most recent position, returning the delta that led to it. The initial position
+    /// can't be popped.
+    pub fn pop(&mut self) -> Option<BoardDelta> {
+        let delta = self.deltas.pop()?;
+        delta.revert(&mut self.current);
+        self.keyframes
+            .truncate(self.deltas.len() / KEYFRAME_INTERVAL + 1);
+        Some(delta)
+    }
+
+    /// Rebuilds the position after `ply` plies, or `None` if the history isn't that long.
+    pub fn board_at(&self, ply: usize) -> Option<Board> {
+        if ply > self.deltas.len() {
+            return None;
+        }
+        let keyframe = ply / KEYFRAME_INTERVAL;
+        let mut board = Board::clone(&self.keyframes[keyframe]);
+        for delta in &self.deltas[keyframe * KEYFRAME_INTERVAL..ply] {
+            delta.apply(&mut board);
+        }
+        Some(board)
+    }
+
+    pub fn deltas(&self) -> &[BoardDelta] {
+        &self.deltas
+    }
+}
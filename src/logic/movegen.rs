@@ -0,0 +1,210 @@
+//! Listing every legal move in a position, what those moves threaten, and perft, the usual way of
+//! checking move generation: count the positions reachable in exactly so many plies and compare
+//! against known totals.
+
+use bevy_math::{CompassOctant, USizeVec2};
+
+use super::{Board, Chirality, GameState, Move, MoveKind, Piece, PieceKind, Player, RulesConfig};
+
+pub(super) const DIRECTIONS: [CompassOctant; 8] = [
+    CompassOctant::North,
+    CompassOctant::NorthEast,
+    CompassOctant::East,
+    CompassOctant::SouthEast,
+    CompassOctant::South,
+    CompassOctant::SouthWest,
+    CompassOctant::West,
+    CompassOctant::NorthWest,
+];
+
+impl Board {
+    /// Every move `player` could make here, piece by piece. Doesn't check whether the game is
+    /// already over.
+    pub fn legal_moves(&self, player: Player, rules: &RulesConfig) -> Vec<Move> {
+        let mut moves = Vec::new();
+        if rules.allow_passing {
+            moves.push(Move::pass());
+        }
+        // Moves are checked by trying them on a scratch board and putting it back afterwards
+        let mut scratch = self.clone();
+        for (from, piece) in self.pieces_of(player) {
+            let candidates = candidate_kinds(piece.kind)
+                .into_iter()
+                .map(|kind| Move { from, kind });
+            for candidate in candidates {
+                let to = candidate.to(self.size());
+                if scratch.move_piece(&candidate, player, rules).is_ok() {
+                    moves.push(candidate);
+                    scratch[from] = self[from];
+                    if let Some(to) = to {
+                        scratch[to] = self[to];
+                    }
+                }
+            }
+        }
+        moves
+    }
+
+    /// `player`'s pieces that an opponent still in the game could destroy or damage with their next
+    /// move, with where they stand now. Each piece is listed once, however many moves threaten it.
+    pub fn threatened_pieces(
+        &self,
+        player: Player,
+        rules: &RulesConfig,
+    ) -> Vec<(USizeVec2, Piece)> {
+        let mut board = self.clone();
+        let mut threatened = Vec::new();
+        let opponents = rules.players.active(self).filter(|&other| other != player);
+        let opponent_moves = opponents.flat_map(|opponent| {
+            self.legal_moves(opponent, rules)
+                .into_iter()
+                .map(move |opponent_move| (opponent, opponent_move))
+        });
+        for (opponent, opponent_move) in opponent_moves {
+            // legal_moves only lists moves that can be made
+            let undo = board.make_move(&opponent_move, opponent, rules).unwrap();
+            for capture in undo.captures() {
+                if capture.piece.allegiance != player {
+                    continue;
+                }
+                // A swap moves the other piece onto the swapping piece's cell before the shot
+                let position = match opponent_move.kind {
+                    MoveKind::Swap(_) if capture.position == opponent_move.from => {
+                        opponent_move.to(self.size()).unwrap() // The swap went through
+                    }
+                    _ => capture.position,
+                };
+                if !threatened.iter().any(|&(coord, _)| coord == position) {
+                    threatened.push((position, capture.piece));
+                }
+            }
+            board.unmake_move(undo);
+        }
+        threatened
+    }
+}
+
+/// The kinds of move that might be legal for a piece of the given kind, before looking at what's
+/// around it.
+fn candidate_kinds(kind: PieceKind) -> Vec<MoveKind> {
+    let mut kinds = Vec::new();
+    // Emitters can only turn on the spot
+    if !matches!(kind, PieceKind::Emitter(_)) {
+        kinds.extend(DIRECTIONS.map(MoveKind::Move));
+    }
+    match kind {
+        PieceKind::Block { stacked: false } => kinds.extend(DIRECTIONS.map(MoveKind::StackOnto)),
+        PieceKind::Block { stacked: true } => kinds.extend(DIRECTIONS.map(MoveKind::Unstack)),
+        PieceKind::TwoSide(_) => kinds.extend(DIRECTIONS.map(MoveKind::Swap)),
+        _ => {}
+    }
+    kinds.extend([Chirality::Clockwise, Chirality::CounterClockwise].map(MoveKind::Rotate));
+    kinds
+}
+
+/// Counts the positions reachable from `state` in exactly `depth` plies. Lines where the game is
+/// decided early stop there and don't count. The draw rules aren't applied, since they depend
+/// on how the position was reached rather than on the position itself.
+///
+/// Under the default rules the counts for depths 1 to 4 are as follows, and the tests check them
+/// up to depth 3:
+///
+/// | Setup   | 1  | 2    | 3      | 4        |
+/// |---------|----|------|--------|----------|
+/// | Classic | 73 | 5229 | 363723 | 25312524 |
+/// | Imhotep | 75 | 5608 | 400158 | 28474624 |
+/// | Dynasty | 72 | 4949 | 327834 | 21353776 |
+pub fn perft(state: &GameState, depth: u32) -> u64 {
+    if state.result().is_some() && depth > 0 {
+        return 0;
+    }
+    perft_board(
+        &mut state.board().clone(),
+        state.to_move(),
+        state.rules(),
+        depth,
+    )
+}
+
+fn perft_board(board: &mut Board, to_move: Player, rules: &RulesConfig, depth: u32) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    if board.result(rules).is_some() {
+        return 0;
+    }
+    let moves = board.legal_moves(to_move, rules);
+    if depth == 1 {
+        return moves.len() as u64;
+    }
+    moves
+        .iter()
+        .map(|player_move| {
+            // legal_moves only lists moves that can be made
+            let undo = board.make_move(player_move, to_move, rules).unwrap();
+            let next = rules.players.next_active(to_move, board);
+            let count = perft_board(board, next, rules, depth - 1);
+            board.unmake_move(undo);
+            count
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Board, GameState, PieceKind, Player, RulesConfig, SetupKind};
+    use super::perft;
+
+    /// Checks `setup`'s perft counts for depths 1 to 3 against the known ones.
+    fn check_perft(setup: SetupKind, expected: [u64; 3]) {
+        let state = GameState::new(Board::from_setup(setup, &RulesConfig::default()));
+        for (depth, expected) in (1..).zip(expected) {
+            assert_eq!(perft(&state, depth), expected, "{setup} perft({depth})");
+        }
+    }
+
+    #[test]
+    fn classic_perft() {
+        check_perft(SetupKind::Classic, [73, 5229, 363723]);
+    }
+
+    #[test]
+    fn imhotep_perft() {
+        check_perft(SetupKind::Imhotep, [75, 5608, 400158]);
+    }
+
+    #[test]
+    fn dynasty_perft() {
+        check_perft(SetupKind::Dynasty, [72, 4949, 327834]);
+    }
+
+    #[test]
+    fn perft_agrees_with_playing_the_moves() {
+        let state = GameState::new(Board::classic_setup());
+        let moves = state.legal_moves();
+        assert_eq!(perft(&state, 1), moves.len() as u64);
+        let played: u64 = moves
+            .iter()
+            .map(|player_move| {
+                let mut next = state.clone();
+                next.apply(player_move).unwrap();
+                perft(&next, 1)
+            })
+            .sum();
+        assert_eq!(perft(&state, 2), played);
+    }
+
+    #[test]
+    fn perft_stops_once_the_game_is_decided() {
+        let mut board = Board::classic_setup();
+        let (king, _) = board
+            .pieces_of(Player::Player2)
+            .find(|(_, piece)| piece.kind == PieceKind::King)
+            .unwrap();
+        board[king] = None;
+        let state = GameState::new(board);
+        assert_eq!(perft(&state, 0), 1);
+        assert_eq!(perft(&state, 1), 0);
+        assert_eq!(perft(&state, 3), 0);
+    }
+}
@@ -0,0 +1,498 @@
+//! Compact text notation for positions, in the spirit of chess FEN.
+//!
+//! A board is written rank by rank from the top down to rank 1, ranks separated by `/`. Within a
+//! rank, files run from A onwards; a number stands for that many empty cells. Every rank has to be
+//! the same width. Files past Z are lettered like spreadsheet columns, `AA`, `AB` and so on up to
+//! `ZZ`, so boards can be at most 702 files wide. Pieces are a letter, uppercase for player 1 and
+//! lowercase for player 2:
+//!
+//! - `K` king
+//! - `B` stacked block, `H` half (unstacked) block
+//! - `M` one-sided mirror, `T` two-sided mirror, both followed by their orientation in lowercase
+//!   (`ne`, `nw`, `se` or `sw`)
+//! - `S` splitter, followed by its orientation like a mirror
+//! - `D` defender and `E` laser emitter, followed by the direction they face in lowercase (`n`,
+//!   `e`, `s` or `w`)
+//!
+//! Players 3 and 4, who only play in four-player games, are written like players 1 and 2 with a
+//! `*` in front: `*K` is player 3's king and `*k` player 4's. A `#` is a wall, which belongs to
+//! nobody.
+//!
+//! A full game position appends the player to move, `1` to `4`, e.g. the classic setup is
+//! `esmswbkbtse2/8/2Mnw2mne2/mne2Mswtse2Mnw/mse2Tnwmne2Msw/2Msw2mse2/8/2TnwBKBMneEn 1`.
+//!
+//! Moves name the piece's cell followed by what it does: `E3>NE` steps the piece on E3 one cell
+//! north-east, `E3<>NE` swaps it with the piece north-east of it, `E3+NE` stacks it onto the block
+//! north-east of it, `E3-NE` moves half of its stack north-east, `E3L` rotates it
+//! counter-clockwise and `E3R` clockwise. Passing, where the rules allow it, is `PASS`.
+
+use std::{fmt, str::FromStr};
+
+use bevy_math::{CompassOctant, CompassQuadrant, USizeVec2, usizevec2};
+
+use super::{Board, Chirality, GameState, Move, MoveKind, Orientation, Piece, PieceKind, Player};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NotationError {
+    TooWide(usize),
+    WrongRankLength { rank: usize },
+    UnknownPiece(char),
+    MissingOrientation,
+    UnknownOrientation(String),
+    MissingSideToMove,
+    UnknownSideToMove(String),
+    TrailingInput(String),
+    InvalidCoordinate(String),
+    UnknownMove(String),
+}
+
+impl fmt::Display for NotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotationError::TooWide(width) => {
+                write!(
+                    f,
+                    "Boards can be at most {MAX_WIDTH} files wide, found {width}"
+                )
+            }
+            NotationError::WrongRankLength { rank } => {
+                write!(f, "Rank {rank} isn't as wide as the top rank")
+            }
+            NotationError::UnknownPiece(c) => write!(f, "Unknown piece '{c}'"),
+            NotationError::MissingOrientation => write!(f, "Mirror is missing its orientation"),
+            NotationError::UnknownOrientation(s) => write!(f, "Unknown orientation '{s}'"),
+            NotationError::MissingSideToMove => write!(f, "Missing the player to move"),
+            NotationError::UnknownSideToMove(s) => {
+                write!(f, "Expected player to move to be 1 to 4, found '{s}'")
+            }
+            NotationError::TrailingInput(s) => write!(f, "Unexpected trailing input '{s}'"),
+            NotationError::InvalidCoordinate(s) => write!(f, "Invalid coordinate '{s}'"),
+            NotationError::UnknownMove(s) => {
+                write!(f, "Unknown move '{s}' (expected e.g. E3>NE, E3L or E3R)")
+            }
+        }
+    }
+}
+
+impl std::error::Error for NotationError {}
+
+/// Files are lettered up to ZZ. That's far wider than anyone plays, and stops a long run of digits
+/// allocating a huge board.
+const MAX_WIDTH: usize = 26 * 27;
+
+impl Board {
+    /// Parses the piece placement part of the notation (see the [module docs](self)).
+    pub fn from_notation(notation: &str) -> Result<Self, NotationError> {
+        let ranks = notation
+            .trim()
+            .split('/')
+            .map(parse_rank)
+            .collect::<Result<Vec<_>, _>>()?;
+        let height = ranks.len();
+        let width = ranks[0].len(); // Splitting always gives at least one rank
+        let mut board = Board::empty(width, height);
+        for (rank_index, rank) in ranks.into_iter().enumerate() {
+            let y = height - 1 - rank_index;
+            if rank.is_empty() || rank.len() != width {
+                return Err(NotationError::WrongRankLength { rank: y + 1 });
+            }
+            for (x, cell) in rank.into_iter().enumerate() {
+                match cell {
+                    Cell::Piece(piece) => board[usizevec2(x, y)] = Some(piece),
+                    Cell::Wall => board.set_wall(usizevec2(x, y), true),
+                    Cell::Empty => {}
+                }
+            }
+        }
+        Ok(board)
+    }
+}
+
+/// What a single cell in the notation holds.
+#[derive(Clone, Copy)]
+enum Cell {
+    Empty,
+    Wall,
+    Piece(Piece),
+}
+
+fn parse_rank(rank: &str) -> Result<Vec<Cell>, NotationError> {
+    let mut cells = Vec::new();
+    let mut chars = rank.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '#' {
+            cells.push(Cell::Wall);
+            continue;
+        }
+        if c == '*' {
+            let c = chars.next().ok_or(NotationError::UnknownPiece(c))?;
+            let piece = parse_piece(c, &mut chars)?;
+            let allegiance = match piece.allegiance {
+                Player::Player1 => Player::Player3,
+                _ => Player::Player4,
+            };
+            cells.push(Cell::Piece(Piece {
+                allegiance,
+                ..piece
+            }));
+            continue;
+        }
+        let Some(digit) = c.to_digit(10) else {
+            cells.push(Cell::Piece(parse_piece(c, &mut chars)?));
+            continue;
+        };
+        let mut empty = digit as usize;
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            chars.next();
+            empty = empty * 10 + digit as usize;
+            // Bail out early so a long run of digits can't overflow or allocate a huge board
+            if empty > MAX_WIDTH {
+                return Err(NotationError::TooWide(empty));
+            }
+        }
+        cells.resize(cells.len() + empty, Cell::Empty);
+    }
+    if cells.len() > MAX_WIDTH {
+        return Err(NotationError::TooWide(cells.len()));
+    }
+    Ok(cells)
+}
+
+fn parse_piece(c: char, rest: &mut impl Iterator<Item = char>) -> Result<Piece, NotationError> {
+    let allegiance = if c.is_ascii_uppercase() {
+        Player::Player1
+    } else {
+        Player::Player2
+    };
+    let kind = match c.to_ascii_uppercase() {
+        'K' => PieceKind::King,
+        'B' => PieceKind::Block { stacked: true },
+        'H' => PieceKind::Block { stacked: false },
+        'M' => PieceKind::OneSide(parse_orientation(rest)?),
+        'T' => PieceKind::TwoSide(parse_orientation(rest)?),
+        'S' => PieceKind::Splitter(parse_orientation(rest)?),
+        'D' => PieceKind::Defender(parse_facing(rest)?),
+        'E' => PieceKind::Emitter(parse_facing(rest)?),
+        _ => return Err(NotationError::UnknownPiece(c)),
+    };
+    Ok(Piece { kind, allegiance })
+}
+
+fn parse_orientation(rest: &mut impl Iterator<Item = char>) -> Result<Orientation, NotationError> {
+    let orientation = rest.take(2).collect::<String>();
+    match orientation.as_str() {
+        "ne" => Ok(Orientation::NE),
+        "nw" => Ok(Orientation::NW),
+        "se" => Ok(Orientation::SE),
+        "sw" => Ok(Orientation::SW),
+        "" => Err(NotationError::MissingOrientation),
+        _ => Err(NotationError::UnknownOrientation(orientation)),
+    }
+}
+
+fn parse_facing(rest: &mut impl Iterator<Item = char>) -> Result<CompassQuadrant, NotationError> {
+    match rest.next() {
+        Some('n') => Ok(CompassQuadrant::North),
+        Some('e') => Ok(CompassQuadrant::East),
+        Some('s') => Ok(CompassQuadrant::South),
+        Some('w') => Ok(CompassQuadrant::West),
+        Some(c) => Err(NotationError::UnknownOrientation(c.into())),
+        None => Err(NotationError::MissingOrientation),
+    }
+}
+
+impl fmt::Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in (0..self.height()).rev() {
+            let mut empty = 0;
+            for x in 0..self.width() {
+                let coord = usizevec2(x, y);
+                let cell = match self[coord] {
+                    // A piece inside a wall can't be written down, so the wall wins
+                    _ if self.is_wall(coord) => "#".to_string(),
+                    Some(piece) => piece.to_string(),
+                    None => {
+                        empty += 1;
+                        continue;
+                    }
+                };
+                if empty > 0 {
+                    write!(f, "{empty}")?;
+                    empty = 0;
+                }
+                write!(f, "{cell}")?;
+            }
+            if empty > 0 {
+                write!(f, "{empty}")?;
+            }
+            if y > 0 {
+                write!(f, "/")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Piece {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let letter = match self.kind {
+            PieceKind::King => 'K',
+            PieceKind::Block { stacked: true } => 'B',
+            PieceKind::Block { stacked: false } => 'H',
+            PieceKind::OneSide(_) => 'M',
+            PieceKind::TwoSide(_) => 'T',
+            PieceKind::Splitter(_) => 'S',
+            PieceKind::Defender(_) => 'D',
+            PieceKind::Emitter(_) => 'E',
+        };
+        match self.allegiance {
+            Player::Player1 => write!(f, "{letter}")?,
+            Player::Player2 => write!(f, "{}", letter.to_ascii_lowercase())?,
+            Player::Player3 => write!(f, "*{letter}")?,
+            Player::Player4 => write!(f, "*{}", letter.to_ascii_lowercase())?,
+        }
+        match self.kind {
+            PieceKind::OneSide(orientation)
+            | PieceKind::TwoSide(orientation)
+            | PieceKind::Splitter(orientation) => {
+                write!(f, "{orientation}")
+            }
+            PieceKind::Defender(facing) | PieceKind::Emitter(facing) => match facing {
+                CompassQuadrant::North => write!(f, "n"),
+                CompassQuadrant::East => write!(f, "e"),
+                CompassQuadrant::South => write!(f, "s"),
+                CompassQuadrant::West => write!(f, "w"),
+            },
+            PieceKind::King | PieceKind::Block { .. } => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Orientation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Orientation::NE => write!(f, "ne"),
+            Orientation::NW => write!(f, "nw"),
+            Orientation::SE => write!(f, "se"),
+            Orientation::SW => write!(f, "sw"),
+        }
+    }
+}
+
+impl FromStr for Board {
+    type Err = NotationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_notation(s)
+    }
+}
+
+impl GameState {
+    /// Parses a full position: piece placement followed by the player to move. The game starts
+    /// fresh from that position, with no move history, under the default two-player rules; use
+    /// [`GameState::with_rules`] for four players.
+    pub fn from_notation(notation: &str) -> Result<Self, NotationError> {
+        let mut parts = notation.split_whitespace();
+        let board = Board::from_notation(parts.next().unwrap_or_default())?;
+        let to_move = match parts.next() {
+            Some(number) => number
+                .parse::<usize>()
+                .ok()
+                .and_then(|number| Player::from_index(number.checked_sub(1)?))
+                .ok_or_else(|| NotationError::UnknownSideToMove(number.to_string()))?,
+            None => return Err(NotationError::MissingSideToMove),
+        };
+        if let Some(rest) = parts.next() {
+            return Err(NotationError::TrailingInput(rest.to_string()));
+        }
+        Ok(Self::with_player_to_move(board, to_move))
+    }
+}
+
+impl fmt::Display for GameState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.board(), self.to_move().index() + 1)
+    }
+}
+
+impl FromStr for GameState {
+    type Err = NotationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_notation(s)
+    }
+}
+
+/// Names file `x` with letters like a spreadsheet column: `A` to `Z`, then `AA`, `AB` and so on.
+pub fn format_file(x: usize) -> String {
+    let letter = |n: usize| char::from(b'A' + (n % 26) as u8);
+    let mut file = vec![letter(x)];
+    let mut rest = x / 26;
+    while rest > 0 {
+        rest -= 1;
+        file.push(letter(rest));
+        rest /= 26;
+    }
+    file.into_iter().rev().collect()
+}
+
+/// Formats a coordinate as a file and rank number, e.g. `usizevec2(4, 0)` is `E1` and
+/// `usizevec2(26, 9)` is `AA10`.
+pub fn format_coord(coord: USizeVec2) -> String {
+    format!("{}{}", format_file(coord.x), coord.y + 1)
+}
+
+/// Parses a coordinate like `E1`, `J10` or `AB3` (case-insensitive). The coordinate isn't checked
+/// against any particular board's size.
+pub fn parse_coord(coord: &str) -> Option<USizeVec2> {
+    let file_end = coord
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(coord.len());
+    let (file, rank) = coord.split_at(file_end);
+    if file.is_empty() || rank.is_empty() || !rank.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // Files count from 1 for A, with each extra letter worth 26 times as much
+    let x = file.bytes().try_fold(0usize, |x, b| {
+        let letter = usize::from(b.to_ascii_uppercase() - b'A') + 1;
+        x.checked_mul(26)?.checked_add(letter)
+    })?;
+    let rank = rank.parse::<usize>().ok()?.checked_sub(1)?;
+    Some(usizevec2(x - 1, rank))
+}
+
+const OCTANT_NAMES: [(CompassOctant, &str); 8] = [
+    (CompassOctant::North, "N"),
+    (CompassOctant::NorthEast, "NE"),
+    (CompassOctant::East, "E"),
+    (CompassOctant::SouthEast, "SE"),
+    (CompassOctant::South, "S"),
+    (CompassOctant::SouthWest, "SW"),
+    (CompassOctant::West, "W"),
+    (CompassOctant::NorthWest, "NW"),
+];
+
+impl Move {
+    /// Parses a move in the notation described in the [module docs](self), case-insensitively.
+    pub fn parse(notation: &str) -> Result<Self, NotationError> {
+        let notation = notation.trim().to_ascii_uppercase();
+        if notation == "PASS" {
+            return Ok(Self::pass());
+        }
+        let unknown = || NotationError::UnknownMove(notation.clone());
+        // The coordinate is the file's letters followed by the rank's digits
+        let file_end = notation
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(notation.len());
+        let coord_end = notation[file_end..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(notation.len(), |i| file_end + i);
+        let (coord, action) = notation.split_at(coord_end);
+        let from =
+            parse_coord(coord).ok_or_else(|| NotationError::InvalidCoordinate(coord.into()))?;
+        let kind = match action {
+            "L" => MoveKind::Rotate(Chirality::CounterClockwise),
+            "R" => MoveKind::Rotate(Chirality::Clockwise),
+            action => {
+                let direction_start = action
+                    .find(|c: char| c.is_ascii_alphabetic())
+                    .ok_or_else(unknown)?;
+                let (symbol, direction) = action.split_at(direction_start);
+                let kind: fn(_) -> _ = match symbol {
+                    ">" => MoveKind::Move,
+                    "<>" => MoveKind::Swap,
+                    "+" => MoveKind::StackOnto,
+                    "-" => MoveKind::Unstack,
+                    _ => return Err(unknown()),
+                };
+                let (octant, _) = OCTANT_NAMES
+                    .into_iter()
+                    .find(|(_, name)| *name == direction)
+                    .ok_or_else(unknown)?;
+                kind(octant)
+            }
+        };
+        Ok(Self { from, kind })
+    }
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let from = format_coord(self.from);
+        let octant_name = |direction| {
+            let (_, name) = OCTANT_NAMES
+                .into_iter()
+                .find(|(octant, _)| *octant == direction)
+                .unwrap(); // Every octant is in the table
+            name
+        };
+        match self.kind {
+            MoveKind::Move(direction) => write!(f, "{from}>{}", octant_name(direction)),
+            MoveKind::Swap(direction) => write!(f, "{from}<>{}", octant_name(direction)),
+            MoveKind::StackOnto(direction) => write!(f, "{from}+{}", octant_name(direction)),
+            MoveKind::Unstack(direction) => write!(f, "{from}-{}", octant_name(direction)),
+            MoveKind::Rotate(Chirality::CounterClockwise) => write!(f, "{from}L"),
+            MoveKind::Rotate(Chirality::Clockwise) => write!(f, "{from}R"),
+            MoveKind::Pass => write!(f, "PASS"),
+        }
+    }
+}
+
+impl FromStr for Move {
+    type Err = NotationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::usizevec2;
+
+    use super::{
+        super::{Board, Piece, Player},
+        MAX_WIDTH, Move, NotationError, format_coord, parse_coord,
+    };
+
+    #[test]
+    fn files_past_z_take_more_letters() {
+        let cases = [
+            (0, "A1"),
+            (25, "Z1"),
+            (26, "AA1"),
+            (27, "AB1"),
+            (701, "ZZ1"),
+            (702, "AAA1"),
+        ];
+        for (x, name) in cases {
+            assert_eq!(format_coord(usizevec2(x, 0)), name);
+            assert_eq!(parse_coord(name), Some(usizevec2(x, 0)));
+        }
+        assert_eq!(parse_coord("ab12"), Some(usizevec2(27, 11)));
+        assert_eq!(parse_coord("AB"), None);
+        assert_eq!(parse_coord("12"), None);
+        assert_eq!(parse_coord(&format!("{}1", "Z".repeat(100))), None);
+    }
+
+    #[test]
+    fn wide_boards_round_trip() {
+        let mut board = Board::empty(30, 2);
+        board[usizevec2(27, 1)] = Some(Piece::king(Player::Player2));
+        board[usizevec2(0, 0)] = Some(Piece::king(Player::Player1));
+        assert_eq!(Board::from_notation(&board.to_string()), Ok(board));
+
+        let too_wide = format!("{}/{}", MAX_WIDTH + 1, MAX_WIDTH + 1);
+        assert_eq!(
+            Board::from_notation(&too_wide),
+            Err(NotationError::TooWide(MAX_WIDTH + 1))
+        );
+    }
+
+    #[test]
+    fn moves_on_wide_boards_round_trip() {
+        for notation in ["AA3>NE", "AB10L", "ZZ1R", "E3<>SW"] {
+            assert_eq!(Move::parse(notation).unwrap().to_string(), notation);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Who takes part in a game and the order they move in.
+//!
+//! The standard game has two players facing each other. The four-player variant seats players 1
+//! and 2 across the board from each other as usual, with player 3 on the west edge and player 4
+//! on the east, and play goes round the board clockwise: 1, 3, 2, 4. A player whose king is
+//! destroyed is out. Their pieces stay on the board, but their turns are skipped and their laser
+//! never fires again. The last player with a king wins.
+
+use serde::{Deserialize, Serialize};
+
+use super::{Board, Player};
+
+/// The players in a game, from [`RulesConfig::players`](super::RulesConfig::players).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PlayerSet {
+    /// Players 1 and 2.
+    #[default]
+    Two,
+    /// All four players.
+    Four,
+}
+
+impl PlayerSet {
+    /// The players taking part, in the order they move.
+    pub fn players(self) -> &'static [Player] {
+        match self {
+            PlayerSet::Two => &[Player::Player1, Player::Player2],
+            PlayerSet::Four => &[
+                Player::Player1,
+                Player::Player3,
+                Player::Player2,
+                Player::Player4,
+            ],
+        }
+    }
+
+    pub fn contains(self, player: Player) -> bool {
+        self.players().contains(&player)
+    }
+
+    /// Who moves after `player`, whether or not they're still in the game. Players outside the
+    /// set are followed by player 1.
+    pub fn next(self, player: Player) -> Player {
+        let players = self.players();
+        match players.iter().position(|&p| p == player) {
+            Some(i) => players[(i + 1) % players.len()],
+            None => players[0],
+        }
+    }
+
+    /// Who moves after `player` on `board`, skipping anyone whose king is gone. If nobody else has
+    /// a king, that's simply the next player round.
+    pub fn next_active(self, player: Player, board: &Board) -> Player {
+        let mut next = self.next(player);
+        for _ in 1..self.players().len() {
+            if board.has_king(next) {
+                return next;
+            }
+            next = self.next(next);
+        }
+        self.next(player)
+    }
+
+    /// The players still in the game on `board`, in the order they move.
+    pub fn active(self, board: &Board) -> impl Iterator<Item = Player> + '_ {
+        self.players()
+            .iter()
+            .copied()
+            .filter(|&player| board.has_king(player))
+    }
+}
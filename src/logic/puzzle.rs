@@ -0,0 +1,256 @@
+//! Puzzles: positions where the player to move can destroy every other king within a few moves
+//! whatever their opponents do, the laser chess version of "mate in N".
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Board, GameResult, GameState, Move, Player, ReplayError, RulesConfig};
+
+/// A position to solve, with the winning line.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Puzzle {
+    pub board: Board,
+    #[serde(default)]
+    pub rules: RulesConfig,
+    /// The player solving the puzzle, who moves first.
+    pub to_move: Player,
+    /// Every move of the main line, the solver's and their opponents', ending with the one that
+    /// destroys the last other king.
+    pub solution: Vec<Move>,
+}
+
+/// Why a [`Puzzle`] isn't sound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PuzzleError {
+    /// A move in the solution can't be played.
+    Replay(ReplayError),
+    /// The solution doesn't end with the solver winning.
+    DoesNotWin,
+    /// The solver move at this index in the solution lets the opponents escape.
+    NotForced(usize),
+}
+
+impl fmt::Display for PuzzleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PuzzleError::Replay(e) => write!(f, "Invalid solution: {e}"),
+            PuzzleError::DoesNotWin => write!(f, "The solution doesn't win"),
+            PuzzleError::NotForced(index) => {
+                write!(f, "Move {} of the solution doesn't force a win", index + 1)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PuzzleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PuzzleError::Replay(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Puzzle {
+    /// The game to play the puzzle in, at its starting position.
+    pub fn state(&self) -> GameState {
+        GameState::with_player_to_move(self.board.clone(), self.to_move)
+            .with_rules(self.rules.clone())
+    }
+
+    /// How many moves the solver makes in the solution, the N in "win in N".
+    pub fn moves_to_win(&self) -> u32 {
+        let mut player = self.to_move;
+        let mut board = self.board.clone();
+        let mut count = 0;
+        for player_move in &self.solution {
+            if player == self.to_move {
+                count += 1;
+            }
+            if board.try_move(player_move, player, &self.rules).is_err() {
+                break;
+            }
+            player = self.rules.players.next_active(player, &board);
+        }
+        count
+    }
+
+    /// Checks that the solution can be played, wins, and that every one of the solver's moves in
+    /// it still forces the win against any defence, not just the one in the solution.
+    pub fn verify(&self) -> Result<(), PuzzleError> {
+        let mut state = self.state();
+        // Where the opponents have to reply to each of the solver's moves, with its index
+        let mut replies = Vec::new();
+        for (index, player_move) in self.solution.iter().enumerate() {
+            let solver_move = state.to_move() == self.to_move;
+            state.apply(player_move).map_err(|error| {
+                PuzzleError::Replay(ReplayError {
+                    index,
+                    player_move: *player_move,
+                    error,
+                })
+            })?;
+            if solver_move && state.result().is_none() {
+                replies.push((index, state.board().clone(), state.to_move()));
+            }
+        }
+        match state.result() {
+            Some(GameResult::Win { winner, .. }) if winner == self.to_move => {}
+            _ => return Err(PuzzleError::DoesNotWin),
+        }
+        let mut moves_left = self.moves_to_win();
+        for (index, mut board, to_move) in replies {
+            moves_left -= 1;
+            if search(&mut board, self.to_move, to_move, &self.rules, moves_left).is_none() {
+                return Err(PuzzleError::NotForced(index));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Looks for a way for the player to move in `state` to destroy every other king within `depth`
+/// of their own moves, however their opponents reply. Returns the shortest such win as a line of
+/// moves, the solver's and their opponents', following the defence that holds out longest.
+///
+/// Like [`perft`](super::perft) this ignores the draw rules, and the search grows quickly with
+/// `depth`: more than 2 or 3 is only practical on sparse boards.
+pub fn find_forced_win(state: &GameState, depth: u32) -> Option<Vec<Move>> {
+    if state.result().is_some() {
+        return None;
+    }
+    let mut board = state.board().clone();
+    (1..=depth).find_map(|depth| {
+        search(
+            &mut board,
+            state.to_move(),
+            state.to_move(),
+            state.rules(),
+            depth,
+        )
+    })
+}
+
+/// The winning line for `solver` with `to_move` to play on `board`, if `solver` can win within
+/// `depth` more moves of their own whatever the others do. Every opponent move is tried in turn,
+/// so a position where an opponent has no moves at all isn't a win.
+fn search(
+    board: &mut Board,
+    solver: Player,
+    to_move: Player,
+    rules: &RulesConfig,
+    depth: u32,
+) -> Option<Vec<Move>> {
+    let solver_to_move = to_move == solver;
+    // With no moves left the solver can still win if every reply destroys the replying king
+    if depth == 0 && solver_to_move {
+        return None;
+    }
+    // The longest defence found so far, when it's the opponents' turn
+    let mut longest: Option<Vec<Move>> = None;
+    for player_move in board.legal_moves(to_move, rules) {
+        // legal_moves only lists moves that can be made
+        let undo = board.make_move(&player_move, to_move, rules).unwrap();
+        let line = match board.result(rules) {
+            Some(GameResult::Win { winner, .. }) if winner == solver => Some(Vec::new()),
+            Some(_) => None,
+            None => {
+                let next = rules.players.next_active(to_move, board);
+                let depth = if solver_to_move { depth - 1 } else { depth };
+                search(board, solver, next, rules, depth)
+            }
+        };
+        board.unmake_move(undo);
+        let line = line.map(|mut line| {
+            line.insert(0, player_move);
+            line
+        });
+        match line {
+            Some(line) if solver_to_move => return Some(line),
+            Some(line) => {
+                if longest
+                    .as_ref()
+                    .is_none_or(|longest| line.len() > longest.len())
+                {
+                    longest = Some(line);
+                }
+            }
+            None if solver_to_move => {}
+            // The opponents have a way out
+            None => return None,
+        }
+    }
+    longest
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{CompassOctant, CompassQuadrant, usizevec2};
+
+    use super::{
+        super::{Board, GameState, Move, MoveKind, Piece, Player, RulesConfig},
+        Puzzle, PuzzleError, find_forced_win,
+    };
+
+    /// A 4x4 board where player 1's laser runs up the left edge towards player 2's king, but
+    /// stops on player 1's own block on the way.
+    fn puzzle(solution: Vec<Move>) -> Puzzle {
+        let mut board = Board::empty(4, 4);
+        board[usizevec2(0, 0)] = Some(Piece::emitter(Player::Player1, CompassQuadrant::North));
+        board[usizevec2(0, 1)] = Some(Piece::block(Player::Player1));
+        board[usizevec2(3, 0)] = Some(Piece::king(Player::Player1));
+        board[usizevec2(2, 3)] = Some(Piece::emitter(Player::Player2, CompassQuadrant::South));
+        board[usizevec2(0, 3)] = Some(Piece::king(Player::Player2));
+        Puzzle {
+            board,
+            rules: RulesConfig {
+                reserved_squares: Vec::new(),
+                ..RulesConfig::default()
+            },
+            to_move: Player::Player1,
+            solution,
+        }
+    }
+
+    fn step(from: (usize, usize), direction: CompassOctant) -> Move {
+        Move {
+            from: usizevec2(from.0, from.1),
+            kind: MoveKind::Move(direction),
+        }
+    }
+
+    #[test]
+    fn stepping_out_of_the_way_wins() {
+        let solution = find_forced_win(&puzzle(Vec::new()).state(), 2).unwrap();
+        assert_eq!(solution.len(), 1);
+        assert_eq!(solution[0].from, usizevec2(0, 1));
+        let puzzle = puzzle(solution);
+        assert_eq!(puzzle.moves_to_win(), 1);
+        assert_eq!(puzzle.verify(), Ok(()));
+    }
+
+    #[test]
+    fn there_is_no_win_for_the_other_side() {
+        let Puzzle { board, rules, .. } = puzzle(Vec::new());
+        let state = GameState::with_player_to_move(board, Player::Player2).with_rules(rules);
+        assert_eq!(find_forced_win(&state, 2), None);
+    }
+
+    #[test]
+    fn unsound_puzzles_are_caught() {
+        assert_eq!(puzzle(Vec::new()).verify(), Err(PuzzleError::DoesNotWin));
+        let blocked = puzzle(vec![step((0, 1), CompassOctant::North)]);
+        assert_eq!(blocked.verify(), Err(PuzzleError::DoesNotWin));
+        let off_board = puzzle(vec![step((3, 0), CompassOctant::East)]);
+        assert!(matches!(off_board.verify(), Err(PuzzleError::Replay(_))));
+        // Player 2 only stays in the beam to be shot because the solution says so
+        let not_forced = puzzle(vec![
+            step((3, 0), CompassOctant::North),
+            step((0, 3), CompassOctant::South),
+            step((0, 1), CompassOctant::East),
+        ]);
+        assert_eq!(not_forced.moves_to_win(), 2);
+        assert_eq!(not_forced.verify(), Err(PuzzleError::NotForced(0)));
+    }
+}
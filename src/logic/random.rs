@@ -0,0 +1,96 @@
+//! Random but fair starting positions, in the spirit of Fischer random chess.
+
+use bevy_math::{CompassQuadrant, USizeVec2, usizevec2};
+
+use super::{Board, Orientation, Piece, Player, RulesConfig, zobrist::splitmix64};
+
+/// How many positions [`Board::random_setup`] rolls before settling for one that doesn't suit the
+/// rules. Under rules any position might suit, one turns up within a few rolls.
+const MAX_ROLLS: usize = 1000;
+
+/// A small, fast pseudo-random generator. Positions only need to come out the same for the same
+/// seed on every machine, not to be unpredictable.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        let value = splitmix64(self.0);
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        value
+    }
+
+    /// A number from 0 up to but not including `n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn orientation(&mut self) -> Orientation {
+        [
+            Orientation::NE,
+            Orientation::NW,
+            Orientation::SE,
+            Orientation::SW,
+        ][self.below(4)]
+    }
+}
+
+impl Board {
+    /// A random 8x8 starting position, the same every time for the same `seed`. Each player gets
+    /// the classic set of pieces placed at random on their half, with the emitter in its usual
+    /// corner, and player 2's half mirrors player 1's so neither side is favoured. Positions that
+    /// break `rules` or leave a king in the opposing laser's path are thrown away and rerolled.
+    /// Rules no such position can suit, like four-player ones, get the last roll, which
+    /// [`Board::validate`] then turns down.
+    pub fn random_setup(seed: u64, rules: &RulesConfig) -> Self {
+        let mut rng = SplitMix(seed);
+        let mut board = random_layout(&mut rng);
+        for _ in 1..MAX_ROLLS {
+            let king_exposed = [Player::Player1, Player::Player2]
+                .into_iter()
+                .any(|player| board.king_in_beam(player, rules).is_some());
+            if !king_exposed && board.validate(rules).is_ok() {
+                break;
+            }
+            board = random_layout(&mut rng);
+        }
+        board
+    }
+}
+
+fn random_layout(rng: &mut SplitMix) -> Board {
+    let mut board = Board::empty(8, 8);
+    let emitter = usizevec2(7, 0);
+    place(
+        &mut board,
+        emitter,
+        Piece::emitter(Player::Player1, CompassQuadrant::North),
+    );
+    // Keep the king on the back two ranks, where there's something to hide behind
+    let king = usizevec2(rng.below(7), rng.below(2));
+    place(&mut board, king, Piece::king(Player::Player1));
+
+    let mut pieces = vec![Piece::block(Player::Player1); 2];
+    for _ in 0..2 {
+        pieces.push(Piece::two_sided(Player::Player1, rng.orientation()));
+    }
+    for _ in 0..6 {
+        pieces.push(Piece::mirror(Player::Player1, rng.orientation()));
+    }
+    // Player 1 sets up in the bottom four ranks, which leaves the top four for player 2's copy
+    let mut free: Vec<USizeVec2> = (0..4)
+        .flat_map(|y| (0..8).map(move |x| usizevec2(x, y)))
+        .filter(|&coord| board[coord].is_none())
+        .collect();
+    for piece in pieces {
+        let coord = free.swap_remove(rng.below(free.len()));
+        place(&mut board, coord, piece);
+    }
+    board
+}
+
+/// Puts `piece` on `coord` and player 2's copy of it on the opposite cell.
+fn place(board: &mut Board, coord: USizeVec2, piece: Piece) {
+    let opposite = board.size() - 1 - coord;
+    board[coord] = Some(piece);
+    board[opposite] = Some(piece.opposing());
+}
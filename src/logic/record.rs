@@ -0,0 +1,606 @@
+//! Complete records of played games, for saving, sharing and replaying them.
+//!
+//! Besides serde, records have a text format loosely based on chess PGN. A few tags come first,
+//! one per line, then the moves, numbered in pairs with the time each was made in braces:
+//!
+//! ```text
+//! [Player1 "alice"]
+//! [Player2 "bob"]
+//! [Board "esmswbkbtse2/8/2Mnw2mne2/mne2Mswtse2Mnw/mse2Tnwmne2Msw/2Msw2mse2/8/2TnwBKBMneEn"]
+//! [Rules "{\"no_capture_draw_moves\":50, ...}"]
+//! [Result "1-0 king-destroyed"]
+//!
+//! 1. D5>N {2.104} D8>SE?! {5.870} {[%eval -40] [%clk 294.130] Leaves the king open}
+//! ```
+//!
+//! The board uses the same notation as [`Board::from_notation`], the rules are JSON and times are
+//! seconds since the game started, to the millisecond. Games still in progress have no `Result`
+//! tag.
+//!
+//! Moves can carry an [`Annotation`], as in the second move above: a [`MoveMark`] straight after
+//! the move, then a second pair of braces after the time holding an evaluation, the time left on
+//! the mover's clock and a free-form comment, in that order and each optional. Inside the comment,
+//! a backslash escapes the next character, so `\}` is a literal brace.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Board, DrawReason, GameResult, GameState, Move, NotationError, Player, ReplayError,
+    RulesConfig, WinReason,
+};
+
+/// Everything needed to replay a game and know who played it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameRecord {
+    /// Player 1's name, then player 2's.
+    pub players: [String; 2],
+    /// The position the game started from.
+    pub board: Board,
+    pub rules: RulesConfig,
+    pub moves: Vec<TimedMove>,
+    /// `None` while the game is still going.
+    pub result: Option<GameResult>,
+}
+
+/// A move along with when it was made.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimedMove {
+    pub player_move: Move,
+    /// Time since the game started.
+    pub elapsed: Duration,
+    #[serde(default, skip_serializing_if = "Annotation::is_empty")]
+    pub annotation: Annotation,
+}
+
+/// Commentary on a move, for analysis tools and replays. Every part is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub mark: Option<MoveMark>,
+    /// How good the position was after the move for the player who made it, in the units of
+    /// [`eval`](super::eval).
+    pub eval: Option<i32>,
+    /// The time left on the mover's clock after the move.
+    pub clock: Option<Duration>,
+    pub comment: Option<String>,
+}
+
+impl Annotation {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A quick verdict on a move, written after it as in chess.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MoveMark {
+    /// `!!`
+    Brilliant,
+    /// `!`
+    Good,
+    /// `!?`
+    Interesting,
+    /// `?!`
+    Dubious,
+    /// `?`
+    Mistake,
+    /// `??`
+    Blunder,
+}
+
+impl MoveMark {
+    const SYMBOLS: [(MoveMark, &str); 6] = [
+        (MoveMark::Brilliant, "!!"),
+        (MoveMark::Good, "!"),
+        (MoveMark::Interesting, "!?"),
+        (MoveMark::Dubious, "?!"),
+        (MoveMark::Mistake, "?"),
+        (MoveMark::Blunder, "??"),
+    ];
+
+    pub fn symbol(self) -> &'static str {
+        Self::SYMBOLS
+            .into_iter()
+            .find(|&(mark, _)| mark == self)
+            .map(|(_, symbol)| symbol)
+            .unwrap() // Every mark has a symbol
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        Self::SYMBOLS
+            .into_iter()
+            .find(|&(_, s)| s == symbol)
+            .map(|(mark, _)| mark)
+    }
+}
+
+impl GameRecord {
+    /// Starts a record of a game between `players`, with no moves yet.
+    pub fn new(players: [String; 2], board: Board, rules: RulesConfig) -> Self {
+        Self {
+            players,
+            board,
+            rules,
+            moves: Vec::new(),
+            result: None,
+        }
+    }
+
+    /// Records are of two-player games, so players 3 and 4 have no name.
+    pub fn player_name(&self, player: Player) -> &str {
+        self.players.get(player.index()).map_or("", String::as_str)
+    }
+
+    /// Replays the recorded moves, checking each one along the way.
+    pub fn replay(&self) -> Result<GameState, ReplayError> {
+        let moves: Vec<Move> = self.moves.iter().map(|timed| timed.player_move).collect();
+        GameState::replay(self.board.clone(), self.rules.clone(), &moves)
+    }
+}
+
+/// Why a game record couldn't be read from text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordError {
+    MalformedTag(String),
+    MissingTag(&'static str),
+    Board(NotationError),
+    Move(NotationError),
+    Rules(String),
+    UnknownResult(String),
+    MissingTime(String),
+    InvalidTime(String),
+    UnclosedBrace,
+    InvalidAnnotation(String),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::MalformedTag(line) => write!(f, "Malformed tag '{line}'"),
+            RecordError::MissingTag(tag) => write!(f, "Missing the {tag} tag"),
+            RecordError::Board(e) => write!(f, "Invalid board: {e}"),
+            RecordError::Move(e) => write!(f, "Invalid move: {e}"),
+            RecordError::Rules(e) => write!(f, "Invalid rules: {e}"),
+            RecordError::UnknownResult(s) => write!(f, "Unknown result '{s}'"),
+            RecordError::MissingTime(s) => write!(f, "Move {s} has no time"),
+            RecordError::InvalidTime(s) => write!(f, "Invalid time '{s}'"),
+            RecordError::UnclosedBrace => write!(f, "A brace is never closed"),
+            RecordError::InvalidAnnotation(s) => write!(f, "Invalid annotation '{s}'"),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl fmt::Display for GameRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules = serde_json::to_string(&self.rules).map_err(|_| fmt::Error)?;
+        write_tag(f, "Player1", &self.players[0])?;
+        write_tag(f, "Player2", &self.players[1])?;
+        write_tag(f, "Board", &self.board.to_string())?;
+        write_tag(f, "Rules", &rules)?;
+        if let Some(result) = self.result {
+            write_tag(f, "Result", &format_result(result))?;
+        }
+        writeln!(f)?;
+        for (ply, timed) in self.moves.iter().enumerate() {
+            if ply % 2 == 0 {
+                if ply > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}. ", ply / 2 + 1)?;
+            } else {
+                write!(f, " ")?;
+            }
+            let mark = timed.annotation.mark.map_or("", MoveMark::symbol);
+            write!(
+                f,
+                "{}{mark} {{{}}}",
+                timed.player_move,
+                format_time(timed.elapsed)
+            )?;
+            if has_commentary(&timed.annotation) {
+                write!(f, " {{{}}}", format_annotation(&timed.annotation))?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+impl FromStr for GameRecord {
+    type Err = RecordError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().map(str::trim).peekable();
+        let mut tags = Vec::new();
+        while let Some(line) = lines.next_if(|line| line.starts_with('[')) {
+            tags.push(parse_tag(line)?);
+        }
+        let tag = |name: &'static str| {
+            tags.iter()
+                .find(|(tag, _)| tag == name)
+                .map(|(_, value)| value.as_str())
+                .ok_or(RecordError::MissingTag(name))
+        };
+        let players = [tag("Player1")?.to_string(), tag("Player2")?.to_string()];
+        let board = tag("Board")?.parse().map_err(RecordError::Board)?;
+        let rules =
+            serde_json::from_str(tag("Rules")?).map_err(|e| RecordError::Rules(e.to_string()))?;
+        let result = match tag("Result") {
+            Ok(result) => Some(parse_result(result)?),
+            Err(_) => None,
+        };
+
+        let mut moves = Vec::new();
+        let movetext = lines.collect::<Vec<_>>().join("\n");
+        let mut tokens = movetext_tokens(&movetext)?.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            let word = match token {
+                Token::Word(word) => word,
+                // Annotations only go after a move's time
+                Token::Braced(braced) => {
+                    return Err(RecordError::InvalidAnnotation(format!("{{{braced}}}")));
+                }
+            };
+            // Move numbers are only there for people reading the record
+            if word.ends_with('.') {
+                continue;
+            }
+            let mark_start = word.trim_end_matches(['!', '?']).len();
+            let (notation, mark) = word.split_at(mark_start);
+            let player_move = notation.parse().map_err(RecordError::Move)?;
+            let mut annotation = Annotation {
+                mark: match mark {
+                    "" => None,
+                    mark => Some(
+                        MoveMark::from_symbol(mark)
+                            .ok_or_else(|| RecordError::InvalidAnnotation(mark.into()))?,
+                    ),
+                },
+                ..Annotation::default()
+            };
+            let Some(Token::Braced(time)) = tokens.next() else {
+                return Err(RecordError::MissingTime(word.into()));
+            };
+            let elapsed = parse_time(time)?;
+            if let Some(&Token::Braced(commentary)) = tokens.peek() {
+                tokens.next();
+                parse_annotation(commentary, &mut annotation)?;
+            }
+            moves.push(TimedMove {
+                player_move,
+                elapsed,
+                annotation,
+            });
+        }
+
+        Ok(Self {
+            players,
+            board,
+            rules,
+            moves,
+            result,
+        })
+    }
+}
+
+fn write_tag(f: &mut fmt::Formatter<'_>, name: &str, value: &str) -> fmt::Result {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+    writeln!(f, "[{name} \"{value}\"]")
+}
+
+fn parse_tag(line: &str) -> Result<(String, String), RecordError> {
+    let malformed = || RecordError::MalformedTag(line.into());
+    let (name, value) = line
+        .strip_prefix('[')
+        .and_then(|line| line.strip_suffix(']'))
+        .and_then(|line| line.split_once(' '))
+        .ok_or_else(malformed)?;
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(malformed)?;
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => chars.next().ok_or_else(malformed)?,
+            c => c,
+        });
+    }
+    Ok((name.into(), unescaped))
+}
+
+fn format_result(result: GameResult) -> String {
+    match result {
+        GameResult::Win { winner, reason } => {
+            let score = match winner {
+                Player::Player1 => "1-0",
+                Player::Player2 => "0-1",
+                Player::Player3 => "0-0-1-0",
+                Player::Player4 => "0-0-0-1",
+            };
+            let reason = match reason {
+                WinReason::KingDestroyed => "king-destroyed",
+                WinReason::Resignation => "resignation",
+                WinReason::Timeout => "timeout",
+                WinReason::Disconnect => "disconnect",
+                WinReason::DrawOdds => "draw-odds",
+                WinReason::KingReachedZone => "king-reached-zone",
+            };
+            format!("{score} {reason}")
+        }
+        GameResult::Draw { reason } => {
+            let reason = match reason {
+                DrawReason::Repetition => "repetition",
+                DrawReason::NoCaptures => "no-captures",
+                DrawReason::MutualDestruction => "mutual-destruction",
+                DrawReason::Agreement => "agreement",
+            };
+            format!("1/2-1/2 {reason}")
+        }
+    }
+}
+
+fn parse_result(s: &str) -> Result<GameResult, RecordError> {
+    let unknown = || RecordError::UnknownResult(s.into());
+    let (score, reason) = s.split_once(' ').ok_or_else(unknown)?;
+    let win = |winner| {
+        let reason = match reason {
+            "king-destroyed" => WinReason::KingDestroyed,
+            "resignation" => WinReason::Resignation,
+            "timeout" => WinReason::Timeout,
+            "disconnect" => WinReason::Disconnect,
+            "draw-odds" => WinReason::DrawOdds,
+            "king-reached-zone" => WinReason::KingReachedZone,
+            _ => return Err(unknown()),
+        };
+        Ok(GameResult::Win { winner, reason })
+    };
+    match score {
+        "1-0" => win(Player::Player1),
+        "0-1" => win(Player::Player2),
+        "0-0-1-0" => win(Player::Player3),
+        "0-0-0-1" => win(Player::Player4),
+        "1/2-1/2" => {
+            let reason = match reason {
+                "repetition" => DrawReason::Repetition,
+                "no-captures" => DrawReason::NoCaptures,
+                "mutual-destruction" => DrawReason::MutualDestruction,
+                "agreement" => DrawReason::Agreement,
+                _ => return Err(unknown()),
+            };
+            Ok(GameResult::Draw { reason })
+        }
+        _ => Err(unknown()),
+    }
+}
+
+/// A piece of move text: a move or move number, or the inside of a pair of braces.
+enum Token<'a> {
+    Word(&'a str),
+    /// Still escaped.
+    Braced(&'a str),
+}
+
+/// Splits move text at whitespace, except inside braces, which may hold spaces and escaped
+/// braces.
+fn movetext_tokens(text: &str) -> Result<Vec<Token<'_>>, RecordError> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        if let Some(braced) = rest.strip_prefix('{') {
+            let mut escaped = false;
+            let end = braced
+                .char_indices()
+                .find(|&(_, c)| {
+                    let close = c == '}' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    close
+                })
+                .map(|(i, _)| i)
+                .ok_or(RecordError::UnclosedBrace)?;
+            tokens.push(Token::Braced(&braced[..end]));
+            rest = &braced[end + 1..];
+        } else {
+            let end = rest
+                .find(|c: char| c.is_whitespace() || c == '{')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(&rest[..end]));
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Whether an annotation has anything to write in braces after the time.
+fn has_commentary(annotation: &Annotation) -> bool {
+    annotation.eval.is_some() || annotation.clock.is_some() || annotation.comment.is_some()
+}
+
+fn format_annotation(annotation: &Annotation) -> String {
+    let mut parts = Vec::new();
+    if let Some(eval) = annotation.eval {
+        parts.push(format!("[%eval {eval}]"));
+    }
+    if let Some(clock) = annotation.clock {
+        parts.push(format!("[%clk {}]", format_time(clock)));
+    }
+    if let Some(comment) = &annotation.comment {
+        let mut escaped = String::with_capacity(comment.len());
+        for c in comment.chars() {
+            if matches!(c, '\\' | '{' | '}' | '[') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        parts.push(escaped);
+    }
+    parts.join(" ")
+}
+
+/// Reads the evaluation, clock and comment from the inside of an annotation's braces into
+/// `annotation`.
+fn parse_annotation(raw: &str, annotation: &mut Annotation) -> Result<(), RecordError> {
+    let invalid = || RecordError::InvalidAnnotation(raw.into());
+    let mut rest = raw.trim_start();
+    while let Some(command) = rest.strip_prefix("[%") {
+        let (command, after) = command.split_once(']').ok_or_else(invalid)?;
+        match command.split_once(' ').ok_or_else(invalid)? {
+            ("eval", eval) => annotation.eval = Some(eval.parse().map_err(|_| invalid())?),
+            ("clk", clock) => annotation.clock = Some(parse_time(clock)?),
+            _ => return Err(invalid()),
+        }
+        rest = after.trim_start();
+    }
+    if !rest.is_empty() {
+        let mut comment = String::with_capacity(rest.len());
+        let mut chars = rest.trim_end().chars();
+        while let Some(c) = chars.next() {
+            comment.push(match c {
+                '\\' => chars.next().ok_or_else(invalid)?,
+                c => c,
+            });
+        }
+        annotation.comment = Some(comment);
+    }
+    Ok(())
+}
+
+fn format_time(time: Duration) -> String {
+    let millis = time.as_millis();
+    format!("{}.{:03}", millis / 1000, millis % 1000)
+}
+
+/// Parses seconds with up to three decimal places, e.g. `12.5`.
+fn parse_time(time: &str) -> Result<Duration, RecordError> {
+    let invalid = || RecordError::InvalidTime(time.into());
+    let (secs, fraction) = time.split_once('.').unwrap_or((time, ""));
+    if fraction.len() > 3 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let secs: u64 = secs.parse().map_err(|_| invalid())?;
+    let millis: u64 = format!("{fraction:0<3}").parse().map_err(|_| invalid())?;
+    Ok(Duration::from_secs(secs) + Duration::from_millis(millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        super::{Board, GameResult, GameState, Player, RulesConfig, WinReason},
+        Annotation, GameRecord, MoveMark, RecordError, TimedMove,
+    };
+
+    /// A game from the classic setup where each player made the first move they could, a few
+    /// of them annotated, ended by player 2 resigning.
+    fn record() -> GameRecord {
+        let mut record = GameRecord::new(
+            ["alice \"the laser\"".into(), "bob\\".into()],
+            Board::classic_setup(),
+            RulesConfig::default(),
+        );
+        let mut state = GameState::new(record.board.clone());
+        let annotations = [
+            Annotation::default(),
+            Annotation {
+                mark: Some(MoveMark::Dubious),
+                eval: Some(-40),
+                clock: Some(Duration::from_millis(294_130)),
+                comment: Some("Leaves {the} king open \\ [sort of]".into()),
+            },
+            Annotation {
+                mark: Some(MoveMark::Brilliant),
+                ..Annotation::default()
+            },
+            Annotation {
+                comment: Some("Only a comment".into()),
+                ..Annotation::default()
+            },
+        ];
+        for (ply, annotation) in annotations.into_iter().enumerate() {
+            let player_move = state.legal_moves()[ply];
+            state.apply(&player_move).unwrap();
+            record.moves.push(TimedMove {
+                player_move,
+                elapsed: Duration::from_millis(2_104 * (ply as u64 + 1)),
+                annotation,
+            });
+        }
+        record.result = Some(GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        });
+        record
+    }
+
+    #[test]
+    fn records_round_trip_through_text() {
+        let record = record();
+        let text = record.to_string();
+        assert!(text.contains("[Result \"1-0 resignation\"]"), "{text}");
+        assert!(text.contains(" 2. "), "{text}");
+        assert_eq!(text.parse(), Ok(record.clone()));
+
+        let mut unfinished = record;
+        unfinished.result = None;
+        unfinished.moves.clear();
+        let text = unfinished.to_string();
+        assert!(!text.contains("Result"), "{text}");
+        assert_eq!(text.parse(), Ok(unfinished));
+    }
+
+    #[test]
+    fn bad_records_say_what_is_wrong() {
+        let text = record().to_string();
+        let parse = |text: &str| text.parse::<GameRecord>().unwrap_err();
+        assert_eq!(
+            parse(&text.replace("[Player2", "[Opponent")),
+            RecordError::MissingTag("Player2")
+        );
+        assert_eq!(
+            parse(&text.replace("1-0 resignation", "1-0 boredom")),
+            RecordError::UnknownResult("1-0 boredom".into())
+        );
+        assert!(matches!(
+            parse(&text.replace("[Player1 \"", "[Player1 ")),
+            RecordError::MalformedTag(_)
+        ));
+        assert!(matches!(
+            parse(&format!("{text} D5>N")),
+            RecordError::MissingTime(_)
+        ));
+        assert!(matches!(
+            parse(&format!("{text} D5>N {{1.2345}}")),
+            RecordError::InvalidTime(_)
+        ));
+        assert_eq!(
+            parse(&format!("{text} D5>N {{1.2")),
+            RecordError::UnclosedBrace
+        );
+        assert!(matches!(
+            parse(&format!("{text} D5>N!!! {{1.2}}")),
+            RecordError::InvalidAnnotation(_)
+        ));
+        assert!(matches!(
+            parse(&format!("{text} D5>N {{1.2}} {{[%depth 3]}}")),
+            RecordError::InvalidAnnotation(_)
+        ));
+    }
+
+    #[test]
+    fn records_replay_their_moves() {
+        let mut record = record();
+        let state = record.replay().unwrap();
+        assert_eq!(state.moves().len(), record.moves.len());
+        assert_eq!(record.player_name(Player::Player2), "bob\\");
+        assert_eq!(record.player_name(Player::Player3), "");
+
+        // Player 2 moving player 1's piece
+        record.moves[1].player_move = record.moves[0].player_move;
+        assert_eq!(record.replay().unwrap_err().index, 1);
+    }
+}
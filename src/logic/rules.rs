@@ -0,0 +1,121 @@
+use bevy_math::{USizeVec2, usizevec2};
+use serde::{Deserialize, Serialize};
+
+use super::{Laser, Player, PlayerSet};
+
+/// The longest [`RulesConfig::no_capture_draw_moves`] allowed. Nobody plays that many moves
+/// without a capture, and it keeps the count of plies well clear of overflowing.
+pub const MAX_NO_CAPTURE_DRAW_MOVES: u32 = 10_000;
+
+/// Tunable rules for a game. Both players need to agree on these, so they're part of the game
+/// setup rather than hardcoded.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RulesConfig {
+    /// The game is drawn after this many full moves (one by each player) in a row where the laser
+    /// didn't destroy or damage a piece, from 1 up to [`MAX_NO_CAPTURE_DRAW_MOVES`]. `None` turns
+    /// the rule off.
+    pub no_capture_draw_moves: Option<u32>,
+    /// Who takes part and the order they move in.
+    #[serde(default)]
+    pub players: PlayerSet,
+    /// Where each player's laser starts and which way it fires, indexed by [`Player::index`]. Only
+    /// used for players without an emitter on the board, who need an entry here.
+    pub laser_origins: Vec<Laser>,
+    /// Whether your own laser destroys your own pieces. If not, it stops on them harmlessly.
+    pub friendly_fire: bool,
+    /// Whether a king may be "rotated". Kings have no facing, so this amounts to passing the turn
+    /// while still firing the laser.
+    pub kings_can_rotate: bool,
+    /// Cells only one player's pieces may occupy. By default those are the cells next to each
+    /// player's laser, so the opponent can't smother it.
+    pub reserved_squares: Vec<(USizeVec2, Player)>,
+    /// Whether players may pass with [`MoveKind::Pass`](super::MoveKind::Pass) instead of moving a
+    /// piece. Their laser still fires.
+    #[serde(default)]
+    pub allow_passing: bool,
+    /// Whether boards may have [splitters](super::PieceKind::Splitter), which split the laser in
+    /// two.
+    #[serde(default)]
+    pub allow_splitters: bool,
+    /// Cells that win the game for the player they're listed for as soon as that player's king
+    /// stands on one, as in [`RulesConfig::king_to_zone`]. None in the standard game.
+    #[serde(default)]
+    pub king_zones: Vec<(USizeVec2, Player)>,
+}
+
+impl RulesConfig {
+    /// The default rules for a four-player game on an 8x8 board, with every player's laser firing
+    /// from the corner on their right and no reserved squares.
+    pub fn four_player() -> Self {
+        Self {
+            players: PlayerSet::Four,
+            laser_origins: Player::ALL.map(Laser::origin).to_vec(),
+            reserved_squares: Vec::new(),
+            ..Self::default()
+        }
+    }
+
+    /// The default rules, plus a win for marching your king onto the middle two squares of your
+    /// opponent's back rank.
+    pub fn king_to_zone() -> Self {
+        Self {
+            king_zones: vec![
+                (usizevec2(3, 7), Player::Player1),
+                (usizevec2(4, 7), Player::Player1),
+                (usizevec2(3, 0), Player::Player2),
+                (usizevec2(4, 0), Player::Player2),
+            ],
+            ..Self::default()
+        }
+    }
+
+    /// The player whose king wins the game by reaching `coord`, if any.
+    pub fn zone_for(&self, coord: USizeVec2) -> Option<Player> {
+        self.king_zones
+            .iter()
+            .find(|(square, _)| *square == coord)
+            .map(|&(_, player)| player)
+    }
+
+    /// Where `player`'s laser starts and which way it fires, if the rules say.
+    pub fn laser_origin(&self, player: Player) -> Option<Laser> {
+        self.laser_origins.get(player.index()).copied()
+    }
+
+    /// The player `coord` is reserved for, if any.
+    pub fn reserved_for(&self, coord: USizeVec2) -> Option<Player> {
+        self.reserved_squares
+            .iter()
+            .find(|(square, _)| *square == coord)
+            .map(|&(_, player)| player)
+    }
+
+    /// Whether `player`'s pieces may stand on `coord`.
+    pub fn may_occupy(&self, coord: USizeVec2, player: Player) -> bool {
+        self.reserved_for(coord).is_none_or(|owner| owner == player)
+    }
+}
+
+impl Default for RulesConfig {
+    fn default() -> Self {
+        Self {
+            no_capture_draw_moves: Some(50),
+            players: PlayerSet::Two,
+            laser_origins: vec![
+                Laser::origin(Player::Player1),
+                Laser::origin(Player::Player2),
+            ],
+            friendly_fire: true,
+            kings_can_rotate: false,
+            reserved_squares: vec![
+                (usizevec2(6, 0), Player::Player1),
+                (usizevec2(7, 1), Player::Player1),
+                (usizevec2(1, 7), Player::Player2),
+                (usizevec2(0, 6), Player::Player2),
+            ],
+            allow_passing: false,
+            allow_splitters: false,
+            king_zones: Vec::new(),
+        }
+    }
+}
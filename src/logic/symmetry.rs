@@ -0,0 +1,182 @@
+//! Turning and reflecting whole positions, and picking one representative for positions that are
+//! the same game seen from different sides.
+
+use bevy_math::usizevec2;
+
+use super::{Board, Piece, Player};
+
+impl Board {
+    /// The board turned half way round, pieces and all. Pieces keep their owners.
+    pub fn rotated_180(&self) -> Self {
+        let far_corner = self.size() - 1;
+        let mut board = Self::empty(self.width, self.height);
+        for (coord, piece) in self.pieces() {
+            board[far_corner - coord] = Some(Piece {
+                kind: piece.kind.rotated_180(),
+                allegiance: piece.allegiance,
+            });
+        }
+        for coord in self.walls() {
+            board.set_wall(far_corner - coord, true);
+        }
+        board
+    }
+
+    /// The board reflected left to right, pieces and all. Pieces keep their owners.
+    ///
+    /// Unlike [`Board::flipped`] this isn't a symmetry of the game under the standard rules, which
+    /// reserve squares and fire lasers from one particular side of the board.
+    pub fn mirrored(&self) -> Self {
+        let mut board = Self::empty(self.width, self.height);
+        for (coord, piece) in self.pieces() {
+            board[usizevec2(self.width - 1 - coord.x, coord.y)] = Some(Piece {
+                kind: piece.kind.reflected(),
+                allegiance: piece.allegiance,
+            });
+        }
+        for coord in self.walls() {
+            board.set_wall(usizevec2(self.width - 1 - coord.x, coord.y), true);
+        }
+        board
+    }
+
+    /// The same position from the other player's side: turned half way round with every piece
+    /// changing sides. Under rules that treat both players alike, like the defaults, player 1 to
+    /// move here plays exactly like player 2 to move on the flipped board.
+    pub fn flipped(&self) -> Self {
+        let far_corner = self.size() - 1;
+        let mut board = Self::empty(self.width, self.height);
+        for (coord, piece) in self.pieces() {
+            board[far_corner - coord] = Some(piece.opposing());
+        }
+        for coord in self.walls() {
+            board.set_wall(far_corner - coord, true);
+        }
+        board
+    }
+
+    /// One representative for this position with `to_move` to move and its
+    /// [flipped](Board::flipped) twin: the version with player 1 to move, or player 3 in a
+    /// four-player game. Opening books and training sets can key on it to treat both as one
+    /// position.
+    pub fn canonical_form(&self, to_move: Player) -> Self {
+        match to_move {
+            Player::Player1 | Player::Player3 => self.clone(),
+            Player::Player2 | Player::Player4 => self.flipped(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{CompassOctant, CompassQuadrant, usizevec2};
+
+    use super::super::{
+        Board, Chirality, Move, MoveKind, Orientation, Piece, Player, RulesConfig, SetupKind,
+    };
+
+    /// `direction` as it looks in a mirror running north to south.
+    fn reflect_octant(direction: CompassOctant) -> CompassOctant {
+        use CompassOctant::*;
+        match direction {
+            East => West,
+            West => East,
+            NorthEast => NorthWest,
+            NorthWest => NorthEast,
+            SouthEast => SouthWest,
+            SouthWest => SouthEast,
+            North | South => direction,
+        }
+    }
+
+    /// `player_move` as it's played on the [mirrored](Board::mirrored) `board`.
+    fn mirror_move(board: &Board, player_move: Move) -> Move {
+        let from = usizevec2(board.width() - 1 - player_move.from.x, player_move.from.y);
+        let kind = match player_move.kind {
+            MoveKind::Move(direction) => MoveKind::Move(reflect_octant(direction)),
+            MoveKind::Swap(direction) => MoveKind::Swap(reflect_octant(direction)),
+            MoveKind::StackOnto(direction) => MoveKind::StackOnto(reflect_octant(direction)),
+            MoveKind::Unstack(direction) => MoveKind::Unstack(reflect_octant(direction)),
+            MoveKind::Rotate(Chirality::Clockwise) => MoveKind::Rotate(Chirality::CounterClockwise),
+            MoveKind::Rotate(Chirality::CounterClockwise) => MoveKind::Rotate(Chirality::Clockwise),
+            MoveKind::Pass => MoveKind::Pass,
+        };
+        Move { from, kind }
+    }
+
+    #[test]
+    fn mirroring_or_rotating_twice_gives_back_the_setup() {
+        for setup in SetupKind::ALL {
+            let board = Board::from_setup(setup, &RulesConfig::default());
+            assert_eq!(board.mirrored().mirrored(), board, "{setup} mirrored twice");
+            assert_eq!(
+                board.rotated_180().rotated_180(),
+                board,
+                "{setup} rotated twice"
+            );
+            assert_eq!(board.flipped().flipped(), board, "{setup} flipped twice");
+        }
+    }
+
+    #[test]
+    fn mirrored_moves_are_legal_on_the_mirrored_board() {
+        // Reserved squares sit on one side of the board only, so mirroring is only a symmetry
+        // without them
+        let rules = RulesConfig {
+            reserved_squares: Vec::new(),
+            ..RulesConfig::default()
+        };
+        for setup in SetupKind::ALL {
+            let board = Board::from_setup(setup, &RulesConfig::default());
+            let mirrored = board.mirrored();
+            for player in [Player::Player1, Player::Player2] {
+                for player_move in board.legal_moves(player, &rules) {
+                    let after = board.try_move_piece(&player_move, player, &rules).unwrap();
+                    let mirrored_move = mirror_move(&board, player_move);
+                    let mirrored_after = mirrored
+                        .try_move_piece(&mirrored_move, player, &rules)
+                        .unwrap_or_else(|e| {
+                            panic!("{mirrored_move:?} isn't legal in the mirrored {setup}: {e}")
+                        });
+                    assert_eq!(mirrored_after, after.mirrored());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn two_sided_mirrors_reflect_from_both_faces() {
+        use CompassQuadrant::*;
+        let mirror = Piece::two_sided(Player::Player1, Orientation::NE);
+        // Lasers bounce off the front and the back alike
+        assert_eq!(mirror.reflect(South), Ok(East));
+        assert_eq!(mirror.reflect(North), Ok(West));
+        assert_eq!(mirror.reflect(West), Ok(North));
+        assert_eq!(mirror.reflect(East), Ok(South));
+    }
+
+    #[test]
+    fn two_sided_mirrors_rotate_and_reflect_as_turned() {
+        let mut board = Board::empty(8, 8);
+        let at = usizevec2(3, 3);
+        board[at] = Some(Piece::two_sided(Player::Player1, Orientation::NE));
+        let rotate = Move {
+            from: at,
+            kind: MoveKind::Rotate(Chirality::Clockwise),
+        };
+        let rules = RulesConfig::default();
+        let turned = board
+            .try_move_piece(&rotate, Player::Player1, &rules)
+            .unwrap();
+        let mirror = turned.get(at).unwrap();
+        assert_eq!(mirror, Piece::two_sided(Player::Player1, Orientation::SE));
+        assert_eq!(
+            mirror.reflect(CompassQuadrant::South),
+            Ok(CompassQuadrant::West)
+        );
+        assert_eq!(
+            mirror.reflect(CompassQuadrant::North),
+            Ok(CompassQuadrant::East)
+        );
+    }
+}
@@ -0,0 +1,527 @@
+//! Endgame tablebases: every position with a handful of pieces, solved, so engines can play
+//! them perfectly instead of searching.
+//!
+//! A tablebase is built by retrograde analysis. It lists every way to place a set of pieces on a
+//! fixed frame of walls and emitters, and every position those lead to. Positions where the
+//! player to move wins on the spot, or loses whatever they do, are solved first. From there it
+//! works backwards until nothing else can be solved, and whatever is left is a draw. Like
+//! [`perft`](super::perft) it ignores the draw rules, so a "draw" is a position neither player
+//! can force a win from, and it only handles two-player games.
+//!
+//! Entries are keyed by [`GameState::position_hash`] like an [`OpeningBook`](super::OpeningBook),
+//! and saved in a similar binary format, all integers little-endian:
+//!
+//! - the magic bytes `LCTB` and a format version byte, currently 1;
+//! - the number of positions as a `u32`;
+//! - for each position, its hash as a `u64`, the outcome as a `u8` (0 for a loss, 1 for a draw, 2
+//!   for a win) and the plies until the end as a `u16`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use bevy_math::{CompassQuadrant, usizevec2};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Board, GameResult, GameState, Move, Orientation, Piece, PieceKind, Player, PlayerSet,
+    RulesConfig,
+};
+
+const MAGIC: &[u8; 4] = b"LCTB";
+const VERSION: u8 = 1;
+
+/// Solved positions, by hash.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tablebase {
+    entries: HashMap<u64, TablebaseEntry>,
+}
+
+/// How a position ends with perfect play, for the player to move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TablebaseEntry {
+    pub outcome: Outcome,
+    /// How many plies it takes: the fewest the winner needs, or the most the loser can hold out
+    /// for. 0 for draws.
+    pub plies: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Outcome {
+    Win,
+    Draw,
+    Loss,
+}
+
+/// Why a tablebase couldn't be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TablebaseError {
+    /// The data doesn't start with the tablebase magic bytes.
+    NotATablebase,
+    UnsupportedVersion(u8),
+    /// The data ends in the middle of an entry.
+    Truncated,
+    /// An entry's outcome isn't 0, 1 or 2.
+    InvalidOutcome(u8),
+}
+
+impl fmt::Display for TablebaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TablebaseError::NotATablebase => write!(f, "Not a tablebase"),
+            TablebaseError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported tablebase version {version}")
+            }
+            TablebaseError::Truncated => write!(f, "Tablebase is truncated"),
+            TablebaseError::InvalidOutcome(outcome) => {
+                write!(f, "Tablebase contains an invalid outcome {outcome}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TablebaseError {}
+
+/// Where a move leads, while solving.
+#[derive(Clone, Copy)]
+enum Edge {
+    /// The game goes on, in the position with this index.
+    Position(u32),
+    /// The game ends, and this is how for the player who moved.
+    End(Outcome),
+}
+
+impl Tablebase {
+    /// Solves every position with some or all of `pieces` placed on the empty cells of `frame`,
+    /// in every orientation, with either player to move, and every position reachable from them.
+    /// The pieces on `frame`, typically the emitters, are in every position, with the emitters
+    /// turned every way they can fire. Placements that [`Board::validate`] rejects under `rules`,
+    /// such as ones missing a king, are left out.
+    ///
+    /// The number of positions multiplies by the number of cells for each piece, so beyond the
+    /// kings and one more piece this is only practical on small boards.
+    ///
+    /// # Panics
+    ///
+    /// If `rules` are for a four-player game.
+    pub fn generate(frame: &Board, pieces: &[Piece], rules: &RulesConfig) -> Self {
+        assert!(
+            rules.players == PlayerSet::Two,
+            "Tablebases are only for two-player games"
+        );
+        let mut positions: Vec<(Board, Player)> = Vec::new();
+        let mut indices: HashMap<u64, u32> = HashMap::new();
+        let mut add = |board: Board, to_move: Player, positions: &mut Vec<_>| {
+            *indices
+                .entry(board.position_hash(to_move))
+                .or_insert_with(|| {
+                    positions.push((board, to_move));
+                    positions.len() as u32 - 1
+                })
+        };
+
+        for frame in emitter_facings(frame) {
+            for subset in 0..1_u32 << pieces.len() {
+                let subset: Vec<Piece> = (0..pieces.len())
+                    .filter(|i| subset & 1 << i != 0)
+                    .map(|i| pieces[i])
+                    .collect();
+                for board in placements(&frame, &subset) {
+                    if board.validate(rules).is_err() || board.result(rules).is_some() {
+                        continue;
+                    }
+                    for to_move in [Player::Player1, Player::Player2] {
+                        add(board.clone(), to_move, &mut positions);
+                    }
+                }
+            }
+        }
+
+        // Every move from every position, adding the positions they lead to as they turn up
+        let mut edges: Vec<Vec<Edge>> = Vec::new();
+        while edges.len() < positions.len() {
+            let (board, to_move) = positions[edges.len()].clone();
+            let mut moves = Vec::new();
+            for player_move in board.legal_moves(to_move, rules) {
+                let mut next = board.clone();
+                // legal_moves only lists moves that can be made
+                next.make_move(&player_move, to_move, rules).unwrap();
+                moves.push(match next.result(rules) {
+                    Some(GameResult::Win { winner, .. }) if winner == to_move => {
+                        Edge::End(Outcome::Win)
+                    }
+                    Some(GameResult::Win { .. }) => Edge::End(Outcome::Loss),
+                    Some(GameResult::Draw { .. }) => Edge::End(Outcome::Draw),
+                    None => Edge::Position(add(next, to_move.opponent(), &mut positions)),
+                });
+            }
+            edges.push(moves);
+        }
+
+        let mut solved: Vec<Option<TablebaseEntry>> = vec![None; positions.len()];
+        // The moves from each position not yet known to lose
+        let mut unrefuted: Vec<u32> = vec![0; positions.len()];
+        let mut predecessors: Vec<Vec<u32>> = vec![Vec::new(); positions.len()];
+        let mut queue = VecDeque::new();
+        for (index, moves) in edges.iter().enumerate() {
+            for edge in moves {
+                match *edge {
+                    Edge::End(Outcome::Win) => {
+                        solved[index] = Some(TablebaseEntry {
+                            outcome: Outcome::Win,
+                            plies: 1,
+                        });
+                    }
+                    Edge::End(Outcome::Loss) => {}
+                    Edge::End(Outcome::Draw) => unrefuted[index] += 1,
+                    Edge::Position(next) => {
+                        unrefuted[index] += 1;
+                        predecessors[next as usize].push(index as u32);
+                    }
+                }
+            }
+            if solved[index].is_none() && !moves.is_empty() && unrefuted[index] == 0 {
+                // Every move destroys the mover's own king
+                solved[index] = Some(TablebaseEntry {
+                    outcome: Outcome::Loss,
+                    plies: 1,
+                });
+            }
+            if solved[index].is_some() {
+                queue.push_back(index);
+            }
+        }
+        // Solved positions come off the queue in order of plies, so the first win found for a
+        // position is the quickest and the last refutation of a loss the slowest
+        while let Some(index) = queue.pop_front() {
+            let entry = solved[index].unwrap(); // Only solved positions are queued
+            for &previous in &predecessors[index] {
+                let previous = previous as usize;
+                if solved[previous].is_some() {
+                    continue;
+                }
+                let outcome = match entry.outcome {
+                    Outcome::Loss => Outcome::Win,
+                    Outcome::Win => {
+                        unrefuted[previous] -= 1;
+                        if unrefuted[previous] > 0 {
+                            continue;
+                        }
+                        Outcome::Loss
+                    }
+                    Outcome::Draw => unreachable!("Draws are never queued"),
+                };
+                solved[previous] = Some(TablebaseEntry {
+                    outcome,
+                    plies: entry.plies.saturating_add(1),
+                });
+                queue.push_back(previous);
+            }
+        }
+
+        let draw = TablebaseEntry {
+            outcome: Outcome::Draw,
+            plies: 0,
+        };
+        let entries = positions
+            .iter()
+            .zip(solved)
+            .map(|((board, to_move), entry)| (board.position_hash(*to_move), entry.unwrap_or(draw)))
+            .collect();
+        Self { entries }
+    }
+
+    /// How the current position of `state` ends with perfect play, if it's in the tablebase.
+    pub fn probe(&self, state: &GameState) -> Option<TablebaseEntry> {
+        self.entries.get(&state.position_hash()).copied()
+    }
+
+    /// The move that does best in the current position of `state`: the quickest win, a draw, or
+    /// the loss that holds out longest. `None` if the position isn't in the tablebase or the
+    /// game is over.
+    pub fn best_move(&self, state: &GameState) -> Option<Move> {
+        self.probe(state)?;
+        state
+            .legal_moves()
+            .into_iter()
+            .filter_map(|player_move| {
+                let mut next = state.clone();
+                next.apply(&player_move).ok()?;
+                let outcome = match next.result() {
+                    Some(GameResult::Win { winner, .. }) if winner == state.to_move() => {
+                        (Outcome::Win, 1)
+                    }
+                    Some(GameResult::Win { .. }) => (Outcome::Loss, 1),
+                    Some(GameResult::Draw { .. }) => (Outcome::Draw, 0),
+                    None => {
+                        let reply = self.probe(&next)?;
+                        let outcome = match reply.outcome {
+                            Outcome::Win => Outcome::Loss,
+                            Outcome::Draw => Outcome::Draw,
+                            Outcome::Loss => Outcome::Win,
+                        };
+                        (outcome, i32::from(reply.plies) + 1)
+                    }
+                };
+                Some((player_move, outcome))
+            })
+            .max_by_key(|&(_, (outcome, plies))| match outcome {
+                Outcome::Win => (2, -plies),
+                Outcome::Draw => (1, 0),
+                Outcome::Loss => (0, plies),
+            })
+            .map(|(player_move, _)| player_move)
+    }
+
+    /// How many positions the tablebase covers.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The tablebase in the binary format described in the [module docs](self). Positions are
+    /// written in hash order, so the same tablebase always gives the same bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend((self.entries.len() as u32).to_le_bytes());
+        let mut entries: Vec<_> = self.entries.iter().collect();
+        entries.sort_unstable_by_key(|&(hash, _)| *hash);
+        for (hash, entry) in entries {
+            bytes.extend(hash.to_le_bytes());
+            bytes.push(match entry.outcome {
+                Outcome::Loss => 0,
+                Outcome::Draw => 1,
+                Outcome::Win => 2,
+            });
+            bytes.extend(entry.plies.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads a tablebase written by [`Tablebase::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TablebaseError> {
+        let mut reader = Reader(bytes);
+        if reader.take::<4>().ok() != Some(*MAGIC) {
+            return Err(TablebaseError::NotATablebase);
+        }
+        let [version] = reader.take()?;
+        if version != VERSION {
+            return Err(TablebaseError::UnsupportedVersion(version));
+        }
+        let count = u32::from_le_bytes(reader.take()?);
+        let mut entries = HashMap::new();
+        for _ in 0..count {
+            let hash = u64::from_le_bytes(reader.take()?);
+            let outcome = match reader.take()? {
+                [0] => Outcome::Loss,
+                [1] => Outcome::Draw,
+                [2] => Outcome::Win,
+                [other] => return Err(TablebaseError::InvalidOutcome(other)),
+            };
+            let plies = u16::from_le_bytes(reader.take()?);
+            entries.insert(hash, TablebaseEntry { outcome, plies });
+        }
+        Ok(Self { entries })
+    }
+}
+
+/// Reads fixed-size chunks off the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], TablebaseError> {
+        let (chunk, rest) = self
+            .0
+            .split_first_chunk()
+            .ok_or(TablebaseError::Truncated)?;
+        self.0 = rest;
+        Ok(*chunk)
+    }
+}
+
+/// `frame` with its emitters turned every possible way. Ones facing the edge are left for
+/// [`Board::validate`] to weed out.
+fn emitter_facings(frame: &Board) -> Vec<Board> {
+    let mut boards = vec![frame.clone()];
+    for (coord, piece) in frame.pieces() {
+        if let PieceKind::Emitter(_) = piece.kind {
+            boards = boards
+                .into_iter()
+                .flat_map(|board| {
+                    kinds(piece.kind).into_iter().map(move |kind| {
+                        let mut board = board.clone();
+                        board[coord] = Some(Piece { kind, ..piece });
+                        board
+                    })
+                })
+                .collect();
+        }
+    }
+    boards
+}
+
+/// Every way to place `pieces` on the empty cells of `frame`, in every orientation.
+fn placements(frame: &Board, pieces: &[Piece]) -> Vec<Board> {
+    let Some((piece, rest)) = pieces.split_first() else {
+        return vec![frame.clone()];
+    };
+    let mut boards = Vec::new();
+    for board in placements(frame, rest) {
+        for y in 0..board.height() {
+            for x in 0..board.width() {
+                let coord = usizevec2(x, y);
+                if board.is_wall(coord) || board.get(coord).is_some() {
+                    continue;
+                }
+                for kind in kinds(piece.kind) {
+                    let mut board = board.clone();
+                    board[coord] = Some(Piece { kind, ..*piece });
+                    boards.push(board);
+                }
+            }
+        }
+    }
+    boards
+}
+
+/// `kind` facing every way it can.
+fn kinds(kind: PieceKind) -> Vec<PieceKind> {
+    use Orientation::*;
+    const ORIENTATIONS: [Orientation; 4] = [NE, NW, SE, SW];
+    const QUADRANTS: [CompassQuadrant; 4] = [
+        CompassQuadrant::North,
+        CompassQuadrant::East,
+        CompassQuadrant::South,
+        CompassQuadrant::West,
+    ];
+    match kind {
+        PieceKind::King | PieceKind::Block { .. } => vec![kind],
+        PieceKind::OneSide(_) => ORIENTATIONS.map(PieceKind::OneSide).to_vec(),
+        PieceKind::TwoSide(_) => ORIENTATIONS.map(PieceKind::TwoSide).to_vec(),
+        PieceKind::Splitter(_) => ORIENTATIONS.map(PieceKind::Splitter).to_vec(),
+        PieceKind::Defender(_) => QUADRANTS.map(PieceKind::Defender).to_vec(),
+        PieceKind::Emitter(_) => QUADRANTS.map(PieceKind::Emitter).to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_math::{CompassQuadrant, usizevec2};
+
+    use super::{
+        super::{Board, GameResult, GameState, Orientation, Piece, Player, RulesConfig},
+        Outcome, Tablebase, TablebaseError, emitter_facings, placements,
+    };
+
+    fn rules() -> RulesConfig {
+        RulesConfig {
+            reserved_squares: Vec::new(),
+            ..RulesConfig::default()
+        }
+    }
+
+    /// A 3x3 board with an emitter in two opposite corners.
+    fn frame() -> Board {
+        let mut frame = Board::empty(3, 3);
+        frame[usizevec2(0, 0)] = Some(Piece::emitter(Player::Player1, CompassQuadrant::North));
+        frame[usizevec2(2, 2)] = Some(Piece::emitter(Player::Player2, CompassQuadrant::South));
+        frame
+    }
+
+    fn kings() -> [Piece; 2] {
+        [Piece::king(Player::Player1), Piece::king(Player::Player2)]
+    }
+
+    /// Both kings and a player 1 mirror, solved on [`frame`].
+    fn tablebase() -> Tablebase {
+        let [king, other_king] = kings();
+        let mirror = Piece::mirror(Player::Player1, Orientation::NE);
+        Tablebase::generate(&frame(), &[king, other_king, mirror], &rules())
+    }
+
+    #[test]
+    fn best_play_ends_the_way_the_tablebase_says() {
+        let tablebase = tablebase();
+        let mut decided = 0;
+        for frame in emitter_facings(&frame()) {
+            for board in placements(&frame, &kings()) {
+                if board.validate(&rules()).is_err() {
+                    continue;
+                }
+                for to_move in [Player::Player1, Player::Player2] {
+                    let mut state =
+                        GameState::with_player_to_move(board.clone(), to_move).with_rules(rules());
+                    let entry = tablebase.probe(&state).unwrap();
+                    let plies = match entry.outcome {
+                        Outcome::Draw => 10,
+                        _ => entry.plies,
+                    };
+                    for _ in 0..plies {
+                        assert_eq!(state.result(), None);
+                        let best_move = tablebase.best_move(&state).unwrap();
+                        state.apply(&best_move).unwrap();
+                    }
+                    let winner = match entry.outcome {
+                        Outcome::Win => Some(to_move),
+                        Outcome::Loss => Some(to_move.opponent()),
+                        Outcome::Draw => None,
+                    };
+                    match state.result() {
+                        Some(GameResult::Win { winner: actual, .. }) => {
+                            assert_eq!(Some(actual), winner)
+                        }
+                        _ => assert_eq!(winner, None),
+                    }
+                    decided += usize::from(winner.is_some());
+                }
+            }
+        }
+        // Make sure there was something to decide
+        assert!(decided > 0);
+    }
+
+    #[test]
+    fn positions_without_both_kings_are_left_out() {
+        let tablebase = tablebase();
+        let mut board = frame();
+        board[usizevec2(1, 1)] = Some(Piece::king(Player::Player1));
+        let state = GameState::new(board).with_rules(rules());
+        assert_eq!(tablebase.probe(&state), None);
+        assert_eq!(tablebase.best_move(&state), None);
+    }
+
+    #[test]
+    fn tablebases_round_trip_through_bytes() {
+        let tablebase = tablebase();
+        assert!(!tablebase.is_empty());
+        let bytes = tablebase.to_bytes();
+        assert_eq!(Tablebase::from_bytes(&bytes), Ok(tablebase));
+
+        assert_eq!(
+            Tablebase::from_bytes(b"LCBK\x01"),
+            Err(TablebaseError::NotATablebase)
+        );
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(
+            Tablebase::from_bytes(&newer),
+            Err(TablebaseError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            Tablebase::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(TablebaseError::Truncated)
+        );
+        // The first entry's outcome, after the header, the count and the hash
+        let mut invalid = bytes;
+        invalid[5 + 4 + 8] = 3;
+        assert_eq!(
+            Tablebase::from_bytes(&invalid),
+            Err(TablebaseError::InvalidOutcome(3))
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Zobrist hashing for positions: each (cell, piece) pair gets a pseudo-random key and a position
+//! hashes to the XOR of the keys of its occupied cells, so hashes can be compared cheaply and
+//! updated incrementally.
+
+use super::{Board, Orientation, Piece, PieceKind, Player};
+
+/// Keys XORed in when players 2 to 4 are to move, so the same layout with different players to
+/// move hashes differently.
+const TO_MOVE: [u64; 3] = [
+    splitmix64(u64::MAX),
+    splitmix64(u64::MAX - 1),
+    splitmix64(u64::MAX - 2),
+];
+
+/// The SplitMix64 mixing function, which turns consecutive inputs into well-distributed keys.
+pub(crate) const fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A distinct small number for every piece state a cell can hold.
+fn piece_index(piece: &Piece) -> u64 {
+    let orientation = |orientation: Orientation| match orientation {
+        Orientation::NE => 0,
+        Orientation::NW => 1,
+        Orientation::SE => 2,
+        Orientation::SW => 3,
+    };
+    let kind = match piece.kind {
+        PieceKind::King => 0,
+        PieceKind::Block { stacked: true } => 1,
+        PieceKind::Block { stacked: false } => 2,
+        PieceKind::OneSide(x) => 3 + orientation(x),
+        PieceKind::TwoSide(x) => 7 + orientation(x),
+        PieceKind::Defender(facing) => 11 + facing.to_index() as u64,
+        PieceKind::Emitter(facing) => 15 + facing.to_index() as u64,
+        PieceKind::Splitter(x) => 19 + orientation(x),
+    };
+    kind * 4 + piece.allegiance.index() as u64
+}
+
+/// The key for `piece` sitting on the cell with the given index.
+pub fn piece_key(cell_index: usize, piece: &Piece) -> u64 {
+    splitmix64((cell_index as u64) << 16 | piece_index(piece))
+}
+
+/// The key for a wall on the cell with the given index, distinct from every piece's.
+fn wall_key(cell_index: usize) -> u64 {
+    splitmix64((cell_index as u64) << 16 | 0xffff)
+}
+
+impl Board {
+    /// A 64-bit hash of this position with `to_move` to play. Equal positions always hash the
+    /// same; different positions collide with negligible probability.
+    pub fn position_hash(&self, to_move: Player) -> u64 {
+        let mut hash = match to_move.index() {
+            0 => 0,
+            index => TO_MOVE[index - 1],
+        };
+        for (coord, piece) in self.pieces() {
+            hash ^= piece_key(coord.y * self.width() + coord.x, &piece);
+        }
+        for coord in self.walls() {
+            hash ^= wall_key(coord.y * self.width() + coord.x);
+        }
+        hash
+    }
+}
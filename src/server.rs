@@ -0,0 +1,343 @@
+//! The game server. [`Server`] takes WebSocket connections at `/game`, pairs up players as they
+//! finish setting up, and plays each game as a [`GameSession`]. It can serve a listener of its
+//! own or hand out its [`Router`] to be merged into a larger axum app. With a [`GameStore`], it
+//! keeps finished games and serves them over HTTP too.
+//!
+//! A [`GameSession`] plays one game between two [`PlayerConnection`]s, relaying moves and
+//! enforcing the rules, whatever the connections are: WebSockets, a bot the server hosts, or
+//! channels in a test.
+
+use std::{
+    fmt::Debug,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    Extension, Router,
+    extract::{
+        ConnectInfo, State,
+        connect_info::Connected,
+        ws::{WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::Response,
+    routing::get,
+    serve::{IncomingStream, Listener},
+};
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    watch,
+};
+use tracing::{error, info};
+
+use crate::{ai::Difficulty, logic::TimeControl};
+
+mod bot;
+mod correspondence;
+mod guests;
+mod history;
+mod lobby;
+mod matchmaking;
+mod metrics;
+mod oauth;
+mod proxy;
+mod queue;
+mod ratings;
+mod series;
+mod session;
+mod shutdown;
+mod signing;
+mod storage;
+mod vacation;
+
+pub use oauth::{OAuthClient, OAuthProvider};
+pub use proxy::{IpNetwork, PeerAddr};
+pub use series::MAX_SERIES_GAMES;
+pub use session::{GameSession, MAX_CHAT_LENGTH, PlayerConnection, PlayerHandle};
+pub use signing::verify_record;
+#[cfg(feature = "sqlite")]
+pub use storage::SqliteStore;
+pub use storage::{FinishedGame, GameStore, OngoingGame, Series, Vacation};
+pub use vacation::MAX_DAYS_PER_MOVE;
+
+/// How a [`Server`] behaves.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Proxies whose forwarding headers are believed when working out a client's address.
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// How long a player waits for an opponent before being given a bot instead. `None` waits
+    /// as long as it takes.
+    pub bot_timeout: Option<Duration>,
+    /// How strong the bots given to players left waiting are.
+    pub bot_difficulty: Difficulty,
+    /// How long a game is held for a player who lost their connection to come back to it before
+    /// their opponent is given the win. `None` gives it straight away.
+    pub reconnect_grace: Option<Duration>,
+    /// How often players are pinged while they wait for an opponent or play. Pings keep idle
+    /// connections open through proxies that drop them, and a player who stops answering is
+    /// treated as having lost their connection. `None` doesn't ping.
+    pub heartbeat_interval: Option<Duration>,
+    /// How long a new connection has to set up, from connecting to answering the latency check,
+    /// before it's dropped. `None` waits as long as it takes.
+    pub setup_timeout: Option<Duration>,
+    /// The file players' ratings are kept in, created after the first rated game if it doesn't
+    /// exist. `None` keeps them in memory, starting everyone afresh when the server does.
+    pub ratings_file: Option<PathBuf>,
+    /// The time control games are played with when neither player asks for one. `None` leaves
+    /// them untimed.
+    pub default_time_control: Option<TimeControl>,
+    /// The most live games to play at once. Players who finish setting up while there are this
+    /// many are turned away, though those already waiting for an opponent still get a game.
+    /// `None` plays as many as there are players for.
+    pub max_games: Option<usize>,
+    /// The apps players can log in through with an account elsewhere. Logging in this way needs
+    /// a [store](Server::with_store) for the accounts and a [`public_url`](Self::public_url)
+    /// providers can send players back to.
+    pub oauth_clients: Vec<OAuthClient>,
+    /// Where players reach the server, such as `https://lasers.example.com`.
+    pub public_url: Option<String>,
+    /// The seed of the ed25519 key finished games are signed with. `None` makes up a new key
+    /// each time the server starts, so signatures can't be checked once it restarts.
+    pub signing_key: Option<[u8; 32]>,
+}
+
+impl Default for ServerConfig {
+    /// No trusted proxies, no bots for players left waiting, a minute to reconnect, a ping every 15
+    /// seconds, 30 seconds to set up, ratings kept in memory, untimed games unless players ask
+    /// otherwise, no limit on how many, no logging in with other accounts, and a new signing key
+    /// each time.
+    fn default() -> Self {
+        Self {
+            trusted_proxies: Vec::new(),
+            bot_timeout: None,
+            bot_difficulty: Difficulty::default(),
+            reconnect_grace: Some(Duration::from_secs(60)),
+            heartbeat_interval: Some(Duration::from_secs(15)),
+            setup_timeout: Some(Duration::from_secs(30)),
+            ratings_file: None,
+            default_time_control: None,
+            max_games: None,
+            oauth_clients: Vec::new(),
+            public_url: None,
+            signing_key: None,
+        }
+    }
+}
+
+/// A running game server. Clones share the same matchmaking, so players connecting through
+/// different listeners can be paired with each other.
+#[derive(Clone)]
+pub struct Server {
+    state: AppState,
+    store: Option<Arc<dyn GameStore>>,
+    oauth: Option<oauth::OAuth>,
+    signer: signing::Signer,
+    ratings: matchmaking::SharedRatings,
+    in_use: guests::AccountsInUse,
+    metrics: Arc<metrics::Metrics>,
+    /// Set to start shutting down. It's closed once everything watching it has wrapped up.
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+#[derive(Clone)]
+struct AppState {
+    matchmaking_tx: UnboundedSender<(WebSocket, Option<IpAddr>)>,
+    trusted_proxies: Arc<Vec<IpNetwork>>,
+}
+
+impl Server {
+    /// Starts matchmaking on the current Tokio runtime. It runs until every clone of the server
+    /// and its router has been dropped. Fails if the [ratings
+    /// file](ServerConfig::ratings_file) can't be read, or if there are [OAuth
+    /// clients](ServerConfig::oauth_clients), which need a store.
+    ///
+    /// # Panics
+    ///
+    /// Outside a Tokio runtime.
+    pub fn new(config: ServerConfig) -> io::Result<Self> {
+        Self::start(config, None)
+    }
+
+    /// The same, keeping every finished game in `store`. The [router](Server::router) serves
+    /// them too, for clients that don't speak the WebSocket protocol:
+    ///
+    /// - `GET /games`: the games that ended last
+    /// - `GET /games/{id}`: one game
+    /// - `GET /players/{name}/games`: the games a player played last
+    ///
+    /// Games come as JSON [`FinishedGame`]s, with an `id` in lists. With `?format=notation` they
+    /// come in [`GameRecord`](crate::logic::GameRecord)'s text notation instead, lists separated
+    /// by blank lines. Lists take a `?limit`, 50 by default and at most 500.
+    ///
+    /// Every game comes signed with the server's [signing key](ServerConfig::signing_key), so
+    /// whoever it's shared with can check it wasn't altered: an ed25519 signature over the
+    /// record's text notation, in base64. JSON games carry it as `signature`, and notation
+    /// starts with a `[Signature "..."]` line, which leaves exactly what was signed once it's
+    /// taken out. `GET /signing-key` serves the key to check them with as JSON:
+    /// `{"algorithm": "ed25519", "public_key": ...}`, in base64. [`verify_record`] checks one.
+    ///
+    /// `GET /players/{name}` serves a player's profile as JSON, from their last 500 games: their
+    /// rating after each rated game, how many they won, drew and lost, the setups they played
+    /// most, how accurately they played their last 10 games and the games themselves.
+    ///
+    /// `GET /explorer?position=<notation>` serves an opening explorer over the 2,000 games that
+    /// ended last: the moves played from the position, in the notation of
+    /// [`GameState::from_notation`](crate::logic::GameState::from_notation), and how the games
+    /// went for whoever played them, as a JSON
+    /// [`ExplorerPosition`](crate::logic::ExplorerPosition). Without a position, it's the classic
+    /// setup. Only games under the standard rules count.
+    ///
+    /// With [OAuth clients](ServerConfig::oauth_clients), `GET /auth/{provider}` sends players to
+    /// log in with `github` or `google`, which sends them back to `/auth/{provider}/callback`.
+    /// That answers with the name of an account tied to theirs at the provider, made the first
+    /// time they log in, and a token to log in to it with, as JSON: `{"player_name": ...,
+    /// "token": ...}`. Fails if there are OAuth clients but no [public
+    /// URL](ServerConfig::public_url).
+    ///
+    /// `POST /guests` makes a guest account to play straight away, answering with its name, which
+    /// starts with `guest-`, and token in the same JSON. Guests' ratings aren't kept when the
+    /// server restarts. `POST /guests/{name}/upgrade` with `{"token": ..., "player_name": ...}`
+    /// keeps the account under that name, with its games, correspondence games and rating, and
+    /// the same token. It's turned down while the guest is waiting for a live game or playing one.
+    pub fn with_store(config: ServerConfig, store: Arc<dyn GameStore>) -> io::Result<Self> {
+        Self::start(config, Some(store))
+    }
+
+    fn start(config: ServerConfig, store: Option<Arc<dyn GameStore>>) -> io::Result<Self> {
+        let ratings = ratings::Ratings::load(config.ratings_file.clone())?;
+        let oauth = match (&store, &config.public_url, config.oauth_clients.is_empty()) {
+            (_, _, true) => None,
+            (Some(store), Some(public_url), false) => Some(
+                oauth::OAuth::new(config.oauth_clients.clone(), public_url, store.clone())
+                    .map_err(io::Error::other)?,
+            ),
+            (None, _, false) => {
+                let reason = "Logging in with OAuth needs a store for the accounts";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+            }
+            (_, None, false) => {
+                let reason = "Logging in with OAuth needs the server's public URL";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+            }
+        };
+        let (matchmaking_tx, matchmaking_rx) = mpsc::unbounded_channel();
+        let trusted_proxies = Arc::new(config.trusted_proxies.clone());
+        let metrics = Arc::new(metrics::Metrics::default());
+        let (shutdown, watch_shutdown) = shutdown::Shutdown::new();
+        let ratings = Arc::new(Mutex::new(ratings));
+        let signer = signing::Signer::new(config.signing_key);
+        let in_use = guests::AccountsInUse::default();
+        tokio::spawn(matchmaking::matchmaking_loop(
+            matchmaking_rx,
+            config,
+            ratings.clone(),
+            in_use.clone(),
+            store.clone(),
+            metrics.clone(),
+            watch_shutdown,
+        ));
+        Ok(Self {
+            state: AppState {
+                matchmaking_tx,
+                trusted_proxies,
+            },
+            store,
+            oauth,
+            signer,
+            ratings,
+            in_use,
+            metrics,
+            shutdown: Arc::new(shutdown),
+        })
+    }
+
+    /// The server's routes. Clients' addresses come from [`PeerAddr`] or [`SocketAddr`] connect
+    /// info, so serve the app with [`Router::into_make_service_with_connect_info`] to have them
+    /// logged; without either, clients' addresses are unknown and forwarding headers ignored.
+    ///
+    /// `GET /metrics` serves counts of what the server is doing in Prometheus's text format:
+    /// games being played, connections open, moves played and rejected, and how long players
+    /// waited for games and games lasted.
+    pub fn router(&self) -> Router {
+        let router = Router::new()
+            .route("/game", get(websocket_handler))
+            .with_state(self.state.clone())
+            .route("/metrics", get(metrics::serve))
+            .with_state(self.metrics.clone());
+        let router = match &self.oauth {
+            Some(oauth) => router.merge(oauth.clone().routes()),
+            None => router,
+        };
+        match &self.store {
+            Some(store) => router
+                .merge(history::routes(store.clone(), self.signer.clone()))
+                .merge(guests::routes(
+                    store.clone(),
+                    self.ratings.clone(),
+                    self.in_use.clone(),
+                )),
+            None => router,
+        }
+    }
+
+    /// Serves the game on `listener` until it fails, or until the server [shuts
+    /// down](Server::shutdown) and the requests in flight have been answered.
+    pub async fn serve<L>(self, listener: L) -> io::Result<()>
+    where
+        L: Listener,
+        L::Addr: Debug,
+        PeerAddr: for<'a> Connected<IncomingStream<'a, L>>,
+    {
+        let mut shutdown = shutdown::Shutdown::watching(&self.shutdown);
+        axum::serve(
+            listener,
+            self.router()
+                .into_make_service_with_connect_info::<PeerAddr>(),
+        )
+        .with_graceful_shutdown(async move { shutdown.requested().await })
+        .await
+    }
+
+    /// Shuts the server down without losing the games being played, finishing once it's done.
+    /// Listeners [served](Server::serve) stop taking connections, players waiting for a game or
+    /// logged in for correspondence play are told the server is going away, and live games are
+    /// stopped where they are. With a store, a game between two players is kept as a
+    /// correspondence game, to finish by logging in under the same names once the server's back,
+    /// and the players are told which. Clocks, ratings and spectators don't carry over.
+    ///
+    /// With [`router`](Server::router) merged into a larger app, stop that taking connections
+    /// too, say with axum's graceful shutdown, or newcomers will be left hanging.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        self.shutdown.closed().await;
+    }
+}
+
+// WebSocket handler that accepts connections and sends them to matchmaking.
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    peer: Option<Extension<ConnectInfo<PeerAddr>>>,
+    socket_addr: Option<Extension<ConnectInfo<SocketAddr>>>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    let peer = match (peer, socket_addr) {
+        (Some(Extension(ConnectInfo(peer))), _) => Some(peer),
+        (None, Some(Extension(ConnectInfo(addr)))) => Some(PeerAddr(Some(addr.ip()))),
+        (None, None) => None,
+    };
+    let addr = peer.and_then(|peer| proxy::client_addr(peer, &headers, &state.trusted_proxies));
+    ws.on_upgrade(move |socket| async move {
+        info!(
+            "New WebSocket connection established from {}",
+            matchmaking::fmt_addr(addr)
+        );
+        if let Err(e) = state.matchmaking_tx.send((socket, addr)) {
+            error!("Failed to send connection to matchmaking: {}", e);
+        }
+    })
+}
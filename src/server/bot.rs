@@ -0,0 +1,144 @@
+//! Bots the server hosts itself, for players nobody else is there to play.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::{
+    ClientRequest, ServerMessage,
+    ai::{AlphaBeta, Difficulty, Engine, SearchLimits, SearchResult},
+    logic::{Clock, GameState, Move, Player},
+};
+
+/// The built-in engine standing in for a player. It follows the game from the messages the
+/// server sends it, like a client would, and answers with a move whenever it's its turn.
+pub(super) struct Bot {
+    /// `None` while it's thinking on another thread.
+    engine: Option<AlphaBeta>,
+    limits: SearchLimits,
+    game: Option<GameState>,
+    player: Player,
+    /// The chess clock as of the last move, in a timed game.
+    clock: Option<Clock>,
+    /// The move the bot sent, until the server says whether it was played.
+    sent: Option<Move>,
+    /// Kept here rather than in the future awaiting it, so a search survives the game loop
+    /// listening to the other player in the meantime.
+    thinking: Option<JoinHandle<(AlphaBeta, SearchResult)>>,
+}
+
+impl Bot {
+    pub(super) fn new(difficulty: Difficulty) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self {
+            engine: Some(difficulty.engine(seed)),
+            limits: difficulty.limits(),
+            game: None,
+            player: Player::Player1,
+            clock: None,
+            sent: None,
+            thinking: None,
+        }
+    }
+
+    pub(super) fn receive(&mut self, message: &ServerMessage) {
+        match message {
+            ServerMessage::InitialSetup {
+                board,
+                rules,
+                time_control,
+                player_order,
+                ..
+            } => {
+                self.game = Some(GameState::new(board.clone()).with_rules(rules.clone()));
+                self.player = Player::from_index(*player_order).unwrap_or(Player::Player1);
+                self.clock = time_control.map(Clock::new);
+            }
+            ServerMessage::MoveAccepted { clock } => {
+                self.clock = *clock;
+                if let (Some(game), Some(player_move)) = (&mut self.game, self.sent.take())
+                    && let Err(e) = game.apply(&player_move)
+                {
+                    error!("Bot couldn't play its own move {}: {}", player_move, e);
+                }
+            }
+            ServerMessage::MoveRejected { reason } => {
+                // Searching the same position again would pick the same move, so stop playing
+                error!("Bot's move was rejected: {}", reason);
+                self.sent = None;
+                self.game = None;
+            }
+            ServerMessage::OpponentMoved { player_move, clock } => {
+                self.clock = *clock;
+                if let Some(game) = &mut self.game
+                    && let Err(e) = game.apply(player_move)
+                {
+                    error!("Bot couldn't follow {}: {}", player_move, e);
+                }
+            }
+            ServerMessage::StateSync {
+                state,
+                player_order,
+                clock,
+                ..
+            } => {
+                self.game = Some(state.clone());
+                self.clock = *clock;
+                self.player = Player::from_index(*player_order).unwrap_or(Player::Player1);
+                self.sent = None;
+            }
+            // Bots are paired as soon as they're made, so never wait for an opponent
+            ServerMessage::QueueStatus { .. } | ServerMessage::LobbyState { .. } => {}
+            // The bot plays on, which turns the offer down
+            ServerMessage::DrawOffered | ServerMessage::DrawDeclined => {}
+            // The bot has nothing to say
+            ServerMessage::Chat { .. } => {}
+            // The bot waits for the game to go on either way
+            ServerMessage::OpponentDisconnected { .. } | ServerMessage::OpponentReconnected => {}
+            // Bots only play live games
+            ServerMessage::LoggedIn { .. }
+            | ServerMessage::MyGames { .. }
+            | ServerMessage::ConditionalMoves { .. }
+            | ServerMessage::Vacation { .. }
+            | ServerMessage::RequestFailed { .. } => {}
+            ServerMessage::GameOver(_) | ServerMessage::ShuttingDown { .. } => self.game = None,
+        }
+    }
+
+    /// Waits until it's the bot's turn and it has chosen a move.
+    pub(super) async fn next_request(&mut self) -> anyhow::Result<ClientRequest> {
+        if self.thinking.is_none() {
+            let (Some(game), Some(mut engine)) = (&self.game, self.engine.take()) else {
+                return std::future::pending().await;
+            };
+            if game.result().is_some() || game.to_move() != self.player || self.sent.is_some() {
+                self.engine = Some(engine);
+                return std::future::pending().await;
+            }
+            let game = game.clone();
+            let mut limits = self.limits;
+            if let Some(clock) = &self.clock {
+                // Save most of the clock for the rest of the game
+                let budget = clock.time_left(Duration::ZERO) / 20;
+                limits.time = Some(limits.time.map_or(budget, |time| time.min(budget)));
+            }
+            self.thinking = Some(tokio::task::spawn_blocking(move || {
+                let result = engine.best_move(&game, limits);
+                (engine, result)
+            }));
+        }
+        // Awaiting the handle by reference leaves it in place if this future is dropped
+        let (engine, result) = self.thinking.as_mut().unwrap().await?;
+        self.thinking = None;
+        self.engine = Some(engine);
+        let player_move = result
+            .best_move
+            .ok_or_else(|| anyhow::anyhow!("The bot has no move to make"))?;
+        self.sent = Some(player_move);
+        Ok(ClientRequest::Move(player_move))
+    }
+}
@@ -0,0 +1,1241 @@
+//! Correspondence games, played by logged-in players who move whenever they next connect. Games
+//! live in the [`GameStore`] between moves, so the players never need to be there at once, and
+//! each is told which games are waiting on them when they log in. A player can have any number
+//! of their games open at once, each request saying which it's about.
+//!
+//! Games can give each move a number of days, which a player who takes longer loses on. Nothing
+//! watches the clocks: a game that's run out is ended the next time either player looks at it.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+use crate::{
+    ClientRequest, CorrespondenceGame, Envelope, SeriesConfig, ServerMessage,
+    logic::{
+        Annotation, Board, GameRecord, GameResult, GameState, Move, Player, RulesConfig, SetupKind,
+        TimedMove, WinReason,
+    },
+};
+
+use super::{
+    FinishedGame, GameStore, OngoingGame,
+    guests::{GUEST_PREFIX, is_guest},
+    matchmaking::{Socket, session_token, tokens_match},
+    metrics::Metrics,
+    series,
+    shutdown::Shutdown,
+    storage::{self, Vacation},
+    vacation::{self, MAX_DAYS_PER_MOVE},
+};
+
+/// The most lines of conditional moves a player can leave in a game.
+const MAX_CONDITIONAL_LINES: usize = 32;
+
+/// The most moves, both players', a line of conditional moves can go on for.
+const MAX_CONDITIONAL_MOVES: usize = 20;
+
+/// Correspondence play, shared by every logged-in connection.
+#[derive(Clone)]
+pub(super) struct Correspondence {
+    store: Arc<dyn GameStore>,
+    /// Held while a game is loaded, played in and saved again, so two moves sent at once can't
+    /// both be played, nor a move and the game running out of time.
+    moves: Arc<Mutex<()>>,
+    metrics: Arc<Metrics>,
+    shutdown: Shutdown,
+}
+
+impl Correspondence {
+    pub(super) fn new(
+        store: Arc<dyn GameStore>,
+        metrics: Arc<Metrics>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            store,
+            moves: Arc::default(),
+            metrics,
+            shutdown,
+        }
+    }
+
+    /// Logs `player` in with `token`, or claims their name for them if there's no token, then
+    /// serves their requests until they leave or the server shuts down.
+    pub(super) async fn serve(
+        mut self,
+        mut connection: Socket,
+        player: String,
+        token: Option<String>,
+    ) {
+        let replies = match self.login(&player, token).await {
+            Ok(Ok(token)) => {
+                info!("{} logged in", player);
+                self.welcome(&player, token)
+                    .await
+                    .unwrap_or_else(|e| about(None, vec![trouble(e)]))
+            }
+            Ok(Err(reason)) => {
+                info!("{} couldn't log in: {}", player, reason);
+                about(None, vec![failed(reason)])
+            }
+            Err(e) => about(None, vec![trouble(e)]),
+        };
+        let logged_in = matches!(replies[0].message, ServerMessage::LoggedIn { .. });
+        if !send_all(&mut connection, &player, replies).await || !logged_in {
+            connection.close().await;
+            return;
+        }
+
+        // The games `player` has open, with which side they're playing in each
+        let mut open = HashMap::new();
+        loop {
+            let request = tokio::select! {
+                request = connection.recv() => request,
+                () = self.shutdown.requested() => {
+                    // Games are saved after every move, so there's nothing to lose
+                    let replies = about(None, vec![failed("The server is shutting down")]);
+                    send_all(&mut connection, &player, replies).await;
+                    connection.close().await;
+                    break;
+                }
+            };
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    info!("{} logged out: {}", player, e);
+                    break;
+                }
+            };
+            let game_id = request.game_id;
+            let replies = self
+                .handle(&player, &mut open, request)
+                .await
+                .unwrap_or_else(|e| vec![Envelope::new(game_id, trouble(e))]);
+            if !send_all(&mut connection, &player, replies).await {
+                break;
+            }
+        }
+    }
+
+    /// Checks `token` is the one `player` logs in with. Without a token, claims `player`'s name
+    /// for them and returns the token they're to log in with from now on. Refusals come with the
+    /// reason to give the player.
+    async fn login(
+        &self,
+        player: &str,
+        token: Option<String>,
+    ) -> anyhow::Result<Result<Option<String>, String>> {
+        let name = player.to_string();
+        match token {
+            Some(token) => {
+                if check_token(&self.store, player, &token).await? {
+                    Ok(Ok(None))
+                } else {
+                    Ok(Err("Wrong name or token".to_string()))
+                }
+            }
+            None if is_guest(player) => Ok(Err(format!(
+                "Names starting with {GUEST_PREFIX} are kept for guests"
+            ))),
+            None => {
+                let token = session_token();
+                let hash = hash_token(&token);
+                let registered =
+                    storage::blocking(&self.store, move |store| store.register(&name, &hash));
+                if registered.await? {
+                    Ok(Ok(Some(token)))
+                } else {
+                    Ok(Err(format!(
+                        "{player} is already taken. Log in with its token"
+                    )))
+                }
+            }
+        }
+    }
+
+    /// What to tell `player` once they're logged in: that they are, whether they're on vacation,
+    /// and their games.
+    async fn welcome(
+        &self,
+        player: &str,
+        token: Option<String>,
+    ) -> anyhow::Result<Vec<Envelope<ServerMessage>>> {
+        let mut replies = vec![Envelope::new(None, ServerMessage::LoggedIn { token })];
+        let vacations = self.vacations(player).await?;
+        let now = SystemTime::now();
+        if vacation::on_vacation(&vacations, now) {
+            replies.push(Envelope::new(None, vacation_status(&vacations, now)));
+        }
+        replies.extend(self.my_games(player).await?);
+        Ok(replies)
+    }
+
+    /// Carries out `request` from `player`, who has the games `open` open, returning what to tell
+    /// them about which game.
+    async fn handle(
+        &self,
+        player: &str,
+        open: &mut HashMap<u64, Player>,
+        request: Envelope<ClientRequest>,
+    ) -> anyhow::Result<Vec<Envelope<ServerMessage>>> {
+        let game_id = request.game_id;
+        // The open game the request is about, if it's about one
+        let game = game_id.and_then(|id| Some((id, *open.get(&id)?)));
+        let replies = match request.message {
+            ClientRequest::ListMyGames => return self.my_games(player).await,
+            ClientRequest::ChallengePlayer {
+                opponent,
+                setup,
+                days_per_move,
+                series,
+            } => {
+                let challenge = self.challenge(player, opponent, setup, days_per_move, series);
+                return challenge.await;
+            }
+            ClientRequest::ResumeGame { id } => {
+                let replies = self.open(player, id, open).await?;
+                return Ok(about(Some(id), replies));
+            }
+            ClientRequest::Move(player_move) => match game {
+                Some((id, side)) => self.play(id, side, Some(player_move)).await?,
+                None => vec![failed("Open the game to move in first")],
+            },
+            ClientRequest::Resign => match game {
+                Some((id, side)) => self.play(id, side, None).await?,
+                None => vec![failed("Open the game to resign first")],
+            },
+            ClientRequest::SetConditionalMoves { lines } => match game {
+                Some((id, side)) => self.set_conditional_moves(id, side, lines).await?,
+                None => vec![failed("Open the game to leave moves in first")],
+            },
+            ClientRequest::SetVacation { on } => self.set_vacation(player, on).await?,
+            _ => vec![failed(
+                "Only correspondence requests are taken once logged in",
+            )],
+        };
+        if let Some(id) = game_id
+            && replies
+                .iter()
+                .any(|reply| matches!(reply, ServerMessage::GameOver(_)))
+        {
+            open.remove(&id);
+        }
+        Ok(about(game_id, replies))
+    }
+
+    /// The correspondence games `player` is playing. Any that have run out of time are ended
+    /// first, and `player` is told how each went before the list.
+    async fn my_games(&self, player: &str) -> anyhow::Result<Vec<Envelope<ServerMessage>>> {
+        let _playing = self.moves.lock().await;
+        let name = player.to_string();
+        let ongoing = storage::blocking(&self.store, move |store| store.ongoing_games_of(&name));
+        let mut replies = Vec::new();
+        let mut games = Vec::new();
+        for (id, game) in ongoing.await? {
+            if let Some(result) = self.timed_out(id, &game).await? {
+                replies.push(Envelope::new(Some(id), ServerMessage::GameOver(result)));
+                continue;
+            }
+            let [player1, player2] = &game.record.players;
+            let (side, opponent_name) = if player1 == player {
+                (Player::Player1, player2.clone())
+            } else {
+                (Player::Player2, player1.clone())
+            };
+            let opponent_vacations = self.vacations(&opponent_name).await?;
+            games.push(CorrespondenceGame {
+                id,
+                opponent_name,
+                your_turn: to_move(&game) == side,
+                moves_played: game.record.moves.len(),
+                hours_left: self.time_left(&game).await?.map(hours),
+                series: game.series.map(|series| series::standing(&series, side)),
+                opponent_on_vacation: vacation::on_vacation(&opponent_vacations, SystemTime::now()),
+            });
+        }
+        replies.push(Envelope::new(None, ServerMessage::MyGames { games }));
+        Ok(replies)
+    }
+
+    /// The vacations `player` has taken and is taking.
+    async fn vacations(&self, player: &str) -> anyhow::Result<Vec<Vacation>> {
+        let name = player.to_string();
+        storage::blocking(&self.store, move |store| store.vacations(&name)).await
+    }
+
+    /// How long the player to move in `game` has left to move, or `None` if it isn't timed.
+    async fn time_left(&self, game: &OngoingGame) -> anyhow::Result<Option<Duration>> {
+        let to_move = to_move(game);
+        let Some(days_per_move) = series::days_per_move(game, to_move) else {
+            return Ok(None);
+        };
+        let vacations = self
+            .vacations(&game.record.players[to_move.index()])
+            .await?;
+        // Each move is timed from the last one, and the first from the start of the game
+        let last_move = game.record.moves.last().map(|last| last.elapsed);
+        let since = game.started_at + last_move.unwrap_or_default();
+        let left = vacation::time_left(days_per_move, since, &vacations, SystemTime::now());
+        Ok(Some(left))
+    }
+
+    /// Ends `game`, kept under `id`, if the player to move in it has run out of time, returning
+    /// how it ended. Called with `moves` held.
+    async fn timed_out(&self, id: u64, game: &OngoingGame) -> anyhow::Result<Option<GameResult>> {
+        if self.time_left(game).await? != Some(Duration::ZERO) {
+            return Ok(None);
+        }
+        let result = GameResult::Win {
+            winner: to_move(game).opponent(),
+            reason: WinReason::Timeout,
+        };
+        self.finish(id, game.clone(), result).await.map(Some)
+    }
+
+    /// Moves `ongoing`, kept under `id`, to the finished games, ended in `result`, and starts the
+    /// next game of its series if there is one. Returns the result as the series counts it.
+    async fn finish(
+        &self,
+        id: u64,
+        mut ongoing: OngoingGame,
+        result: GameResult,
+    ) -> anyhow::Result<GameResult> {
+        let result = series::adjudicate(ongoing.series.as_ref(), result);
+        ongoing.record.result = Some(result);
+        let next = ongoing.series.and_then(|series| {
+            let [player1, player2] = ongoing.record.players.clone();
+            Some(OngoingGame {
+                record: GameRecord::new(
+                    [player2, player1],
+                    ongoing.record.board.clone(),
+                    ongoing.record.rules.clone(),
+                ),
+                started_at: SystemTime::now(),
+                conditional_moves: Vec::new(),
+                days_per_move: ongoing.days_per_move,
+                series: Some(series::next(&series, result)?),
+            })
+        });
+        let finished = FinishedGame {
+            record: ongoing.record,
+            rated: false,
+            ratings: None,
+            started_at: ongoing.started_at,
+            ended_at: SystemTime::now(),
+        };
+        storage::blocking(&self.store, move |store| {
+            store.finish_ongoing(id, &finished)
+        })
+        .await?;
+        info!("Correspondence game {} is over: {:?}", id, result);
+        if let Some(next) = next {
+            let next_id =
+                storage::blocking(&self.store, move |store| store.start_ongoing(&next)).await?;
+            info!("Started correspondence game {} of the series", next_id);
+        }
+        Ok(result)
+    }
+
+    /// Sends `player` on vacation, or brings them back from one.
+    async fn set_vacation(&self, player: &str, on: bool) -> anyhow::Result<Vec<ServerMessage>> {
+        let vacations = self.vacations(player).await?;
+        let now = SystemTime::now();
+        if on
+            && !vacation::on_vacation(&vacations, now)
+            && vacation::budget_left(&vacations, now).is_zero()
+        {
+            return Ok(vec![failed("You've had all your vacation for this year")]);
+        }
+        let vacations = vacation::set_vacation(&vacations, on, now);
+        let (name, kept) = (player.to_string(), vacations.clone());
+        storage::blocking(&self.store, move |store| store.set_vacations(&name, &kept)).await?;
+        match on {
+            true => info!("{} is on vacation", player),
+            false => info!("{} is back from vacation", player),
+        }
+        Ok(vec![vacation_status(&vacations, now)])
+    }
+
+    /// Starts a game between `player` and `opponent` from `setup`, with `player` moving first and
+    /// each move given `days_per_move` if there's a limit. With a `series`, it's the first game of
+    /// it.
+    async fn challenge(
+        &self,
+        player: &str,
+        opponent: String,
+        setup: Option<SetupKind>,
+        days_per_move: Option<u32>,
+        series: Option<SeriesConfig>,
+    ) -> anyhow::Result<Vec<Envelope<ServerMessage>>> {
+        let refuse = |reason| Ok(about(None, vec![failed(reason)]));
+        if opponent == player {
+            return refuse("You can't challenge yourself".into());
+        }
+        if days_per_move.is_some_and(|days| !(1..=MAX_DAYS_PER_MOVE).contains(&days)) {
+            return refuse(format!(
+                "Give each move between 1 and {MAX_DAYS_PER_MOVE} days"
+            ));
+        }
+        if let Some(Err(reason)) = series.as_ref().map(series::check) {
+            return refuse(reason);
+        }
+        let name = opponent.clone();
+        let known = storage::blocking(&self.store, move |store| store.token_hash(&name));
+        if known.await?.is_none() {
+            return refuse(format!("{opponent} has never logged in"));
+        }
+        let setup = setup.unwrap_or_default();
+        let rules = RulesConfig::default();
+        let board = Board::from_setup(setup, &rules);
+        if let Err(e) = board.validate(&rules) {
+            return refuse(format!("Can't play the {setup} setup: {e}"));
+        }
+        let game = OngoingGame {
+            record: GameRecord::new([player.to_string(), opponent.clone()], board, rules),
+            started_at: SystemTime::now(),
+            conditional_moves: Vec::new(),
+            days_per_move,
+            series: series.map(series::start),
+        };
+        let id = storage::blocking(&self.store, move |store| store.start_ongoing(&game)).await?;
+        info!(
+            "{} challenged {} to correspondence game {}",
+            player, opponent, id
+        );
+        self.my_games(player).await
+    }
+
+    /// Opens game `id` for `player` to play in, sending them where it's at.
+    async fn open(
+        &self,
+        player: &str,
+        id: u64,
+        open: &mut HashMap<u64, Player>,
+    ) -> anyhow::Result<Vec<ServerMessage>> {
+        let _playing = self.moves.lock().await;
+        let game = storage::blocking(&self.store, move |store| store.ongoing(id)).await?;
+        let side = game.as_ref().and_then(|game| {
+            let order = game.record.players.iter().position(|name| name == player)?;
+            Some((game, order))
+        });
+        let Some((game, order)) = side else {
+            return Ok(vec![failed(format!("You're not playing a game {id}"))]);
+        };
+        if let Some(result) = self.timed_out(id, game).await? {
+            return Ok(vec![ServerMessage::GameOver(result)]);
+        }
+        let side = Player::from_index(order).unwrap(); // Records have two players
+        open.insert(id, side);
+        let state = game.record.replay()?;
+        // Only the player waiting on their opponent can have moves left
+        let waiting = state.to_move() != side;
+        let mut replies = vec![ServerMessage::StateSync {
+            state,
+            player_order: order,
+            opponent_name: game.record.players[1 - order].clone(),
+            clock: None,
+        }];
+        if waiting && !game.conditional_moves.is_empty() {
+            let lines = game.conditional_moves.clone();
+            replies.push(ServerMessage::ConditionalMoves { lines });
+        }
+        Ok(replies)
+    }
+
+    /// Leaves `lines` to be played for `side` in game `id`, if it's their opponent's move and
+    /// every line can be played from there.
+    async fn set_conditional_moves(
+        &self,
+        id: u64,
+        side: Player,
+        lines: Vec<Vec<Move>>,
+    ) -> anyhow::Result<Vec<ServerMessage>> {
+        let too_long = lines.iter().any(|line| line.len() > MAX_CONDITIONAL_MOVES);
+        if lines.len() > MAX_CONDITIONAL_LINES || too_long {
+            return Ok(vec![failed(format!(
+                "Leave at most {MAX_CONDITIONAL_LINES} lines of {MAX_CONDITIONAL_MOVES} moves"
+            ))]);
+        }
+        let _playing = self.moves.lock().await;
+        let Some(mut ongoing) =
+            storage::blocking(&self.store, move |store| store.ongoing(id)).await?
+        else {
+            return Ok(vec![failed(format!("Game {id} is over"))]);
+        };
+        if let Some(result) = self.timed_out(id, &ongoing).await? {
+            return Ok(vec![ServerMessage::GameOver(result)]);
+        }
+        let game = ongoing.record.replay()?;
+        if game.to_move() == side {
+            return Ok(vec![failed(
+                "It's your move. Leave moves for when it's your opponent's",
+            )]);
+        }
+        if let Some(reason) = lines.iter().find_map(|line| check_line(&game, line).err()) {
+            return Ok(vec![failed(reason)]);
+        }
+        ongoing.conditional_moves = lines.clone();
+        storage::blocking(&self.store, move |store| store.update_ongoing(id, &ongoing)).await?;
+        Ok(vec![ServerMessage::ConditionalMoves { lines }])
+    }
+
+    /// Plays `player_move` for `side` in game `id`, or resigns for them without a move, and saves
+    /// the game.
+    async fn play(
+        &self,
+        id: u64,
+        side: Player,
+        player_move: Option<Move>,
+    ) -> anyhow::Result<Vec<ServerMessage>> {
+        let _playing = self.moves.lock().await;
+        let Some(mut ongoing) =
+            storage::blocking(&self.store, move |store| store.ongoing(id)).await?
+        else {
+            return Ok(vec![failed(format!("Game {id} is over"))]);
+        };
+        if let Some(result) = self.timed_out(id, &ongoing).await? {
+            return Ok(vec![ServerMessage::GameOver(result)]);
+        }
+        let mut game = ongoing.record.replay()?;
+        let mut replies = Vec::new();
+        match player_move {
+            Some(player_move) => {
+                if let Err(reason) = game.apply_as(side, &player_move) {
+                    self.metrics.move_rejected();
+                    return Ok(vec![ServerMessage::MoveRejected { reason }]);
+                }
+                self.metrics.move_played();
+                ongoing.record.moves.push(TimedMove {
+                    player_move,
+                    elapsed: ongoing.started_at.elapsed().unwrap_or_default(),
+                    annotation: Annotation::default(),
+                });
+                replies.push(ServerMessage::MoveAccepted { clock: None });
+                // The opponent's moves left for this one are played straight away
+                let reply = match game.result() {
+                    Some(_) => None,
+                    None => follow_lines(&mut ongoing.conditional_moves, player_move),
+                };
+                if let Some(reply) = reply {
+                    match game.apply_as(side.opponent(), &reply) {
+                        Ok(_) => {
+                            info!("Played a conditional move in correspondence game {}", id);
+                            self.metrics.move_played();
+                            ongoing.record.moves.push(TimedMove {
+                                player_move: reply,
+                                elapsed: ongoing.started_at.elapsed().unwrap_or_default(),
+                                annotation: Annotation::default(),
+                            });
+                            replies.push(ServerMessage::OpponentMoved {
+                                player_move: reply,
+                                clock: None,
+                            });
+                        }
+                        // Lines were checked when they were left, so this is a game that's
+                        // changed under them
+                        Err(e) => {
+                            error!("Conditional move {} no longer plays: {}", reply, e);
+                            ongoing.conditional_moves.clear();
+                        }
+                    }
+                }
+            }
+            None => game.end(GameResult::Win {
+                winner: side.opponent(),
+                reason: WinReason::Resignation,
+            }),
+        }
+        match game.result() {
+            Some(result) => {
+                let result = self.finish(id, ongoing, result).await?;
+                replies.push(ServerMessage::GameOver(result));
+            }
+            None => {
+                storage::blocking(&self.store, move |store| store.update_ongoing(id, &ongoing))
+                    .await?
+            }
+        }
+        Ok(replies)
+    }
+}
+
+/// Whose move it is in `game`. Players take turns, so it goes by how many moves have been played.
+fn to_move(game: &OngoingGame) -> Player {
+    Player::from_index(game.record.moves.len() % 2).unwrap()
+}
+
+/// `duration` in hours, rounded up so there's no saying none are left while some time is.
+fn hours(duration: Duration) -> u64 {
+    duration.as_secs().div_ceil(60 * 60)
+}
+
+/// Where a player with `vacations` stands at `now`.
+fn vacation_status(vacations: &[Vacation], now: SystemTime) -> ServerMessage {
+    ServerMessage::Vacation {
+        on_vacation: vacation::on_vacation(vacations, now),
+        hours_left: hours(vacation::budget_left(vacations, now)),
+    }
+}
+
+/// Checks `line` of conditional moves can be played from `game`: a move for whoever's to move,
+/// a reply to it, and so on, as many times over as it likes. Refusals come with the reason to
+/// give the player.
+fn check_line(game: &GameState, line: &[Move]) -> Result<(), String> {
+    if line.is_empty() || line.len() % 2 == 1 {
+        return Err("Each line needs your opponent's moves and your replies to them".into());
+    }
+    let mut game = game.clone();
+    for player_move in line {
+        game.apply(player_move)
+            .map_err(|e| format!("{player_move} can't be played in that line: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Follows conditional `lines` along the opponent's `player_move`, returning the reply the first
+/// line it starts has for it. Only the lines that start with both go on, from after them.
+fn follow_lines(lines: &mut Vec<Vec<Move>>, player_move: Move) -> Option<Move> {
+    let reply = lines
+        .iter()
+        .find(|line| line.first() == Some(&player_move))
+        .and_then(|line| line.get(1))
+        .copied();
+    let Some(reply) = reply else {
+        lines.clear();
+        return None;
+    };
+    lines.retain(|line| line.len() > 2 && line.starts_with(&[player_move, reply]));
+    for line in lines.iter_mut() {
+        line.drain(..2);
+    }
+    Some(reply)
+}
+
+/// Sends `player` all of `replies`, returning whether they could be reached.
+async fn send_all(
+    connection: &mut Socket,
+    player: &str,
+    replies: Vec<Envelope<ServerMessage>>,
+) -> bool {
+    for reply in &replies {
+        if let Err(e) = connection.send(reply.game_id, &reply.message).await {
+            info!("{} logged out: {}", player, e);
+            return false;
+        }
+    }
+    true
+}
+
+/// Whether `token` is the one `player` logs in with.
+pub(super) async fn check_token(
+    store: &Arc<dyn GameStore>,
+    player: &str,
+    token: &str,
+) -> anyhow::Result<bool> {
+    let name = player.to_string();
+    let hash = storage::blocking(store, move |store| store.token_hash(&name)).await?;
+    Ok(hash.is_some_and(|hash| tokens_match(&hash, &hash_token(token))))
+}
+
+/// Tokens are only kept hashed, so a leaked store doesn't let anyone log in. They're random, so a
+/// plain hash is as good as a slow one.
+pub(super) fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// `replies`, all about game `game_id` if they're about one.
+fn about(game_id: Option<u64>, replies: Vec<ServerMessage>) -> Vec<Envelope<ServerMessage>> {
+    replies
+        .into_iter()
+        .map(|reply| Envelope::new(game_id, reply))
+        .collect()
+}
+
+fn failed(reason: impl Into<String>) -> ServerMessage {
+    ServerMessage::RequestFailed {
+        reason: reason.into(),
+    }
+}
+
+/// What to tell a player when the store lets the server down, logging what happened.
+fn trouble(e: anyhow::Error) -> ServerMessage {
+    error!("Correspondence request failed: {}", e);
+    failed("Something went wrong on the server. Please try again later")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        ArmageddonOdds, ClientRequest, CorrespondenceGame, Envelope, SeriesConfig, SeriesStanding,
+        ServerMessage,
+        logic::{
+            Board, DrawReason, GameRecord, GameResult, GameState, Player, RulesConfig, WinReason,
+        },
+    };
+
+    use super::{
+        super::{
+            GameStore, OngoingGame,
+            shutdown::Shutdown,
+            storage::{Vacation, tests::MemoryStore},
+        },
+        Correspondence, check_token,
+    };
+
+    /// Correspondence over an empty store, and the store.
+    fn correspondence() -> (Correspondence, Arc<dyn GameStore>) {
+        let store: Arc<dyn GameStore> = Arc::new(MemoryStore::default());
+        let (_, shutdown) = Shutdown::new();
+        let correspondence = Correspondence::new(store.clone(), Arc::default(), shutdown);
+        (correspondence, store)
+    }
+
+    /// Claims `player`'s name, returning their token.
+    async fn register(correspondence: &Correspondence, player: &str) -> String {
+        correspondence
+            .login(player, None)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    /// The one reply to `request` from `player` about game `game_id`.
+    async fn reply(
+        correspondence: &Correspondence,
+        player: &str,
+        open: &mut HashMap<u64, Player>,
+        game_id: Option<u64>,
+        request: ClientRequest,
+    ) -> ServerMessage {
+        let mut replies = correspondence
+            .handle(player, open, Envelope::new(game_id, request))
+            .await
+            .unwrap();
+        assert_eq!(replies.len(), 1);
+        let reply = replies.pop().unwrap();
+        assert_eq!(reply.game_id, game_id);
+        reply.message
+    }
+
+    fn refusal(message: ServerMessage) -> String {
+        match message {
+            ServerMessage::RequestFailed { reason } => reason,
+            other => panic!("Expected a refusal, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn names_are_claimed_with_tokens() {
+        let (correspondence, store) = correspondence();
+        let token = register(&correspondence, "alice").await;
+        assert!(check_token(&store, "alice", &token).await.unwrap());
+        assert!(!check_token(&store, "alice", "guess").await.unwrap());
+        assert!(!check_token(&store, "bob", &token).await.unwrap());
+
+        let login = |token| correspondence.login("alice", token);
+        assert_eq!(login(Some(token.clone())).await.unwrap(), Ok(None));
+        assert_eq!(
+            login(Some("guess".into())).await.unwrap(),
+            Err("Wrong name or token".into())
+        );
+        assert_eq!(
+            login(None).await.unwrap(),
+            Err("alice is already taken. Log in with its token".into())
+        );
+        // The token isn't kept as it is
+        assert_ne!(store.token_hash("alice").unwrap(), Some(token));
+
+        assert_eq!(
+            correspondence.login("guest-1234", None).await.unwrap(),
+            Err("Names starting with guest- are kept for guests".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn games_are_played_a_move_at_a_time() {
+        let (correspondence, store) = correspondence();
+        register(&correspondence, "alice").await;
+        register(&correspondence, "bob").await;
+        let (mut alice, mut bob) = (HashMap::new(), HashMap::new());
+        let challenge = ClientRequest::ChallengePlayer {
+            opponent: "bob".into(),
+            setup: None,
+            days_per_move: None,
+            series: None,
+        };
+        let ServerMessage::MyGames { games } =
+            reply(&correspondence, "alice", &mut alice, None, challenge).await
+        else {
+            panic!("Expected alice's games");
+        };
+        let id = games[0].id;
+        assert_eq!(
+            games,
+            [CorrespondenceGame {
+                id,
+                opponent_name: "bob".into(),
+                your_turn: true,
+                moves_played: 0,
+                hours_left: None,
+                opponent_on_vacation: false,
+                series: None,
+            }]
+        );
+
+        let first_move = GameState::new(Board::classic_setup()).legal_moves()[0];
+        let play = |player_move| ClientRequest::Move(player_move);
+        assert_eq!(
+            refusal(
+                reply(
+                    &correspondence,
+                    "alice",
+                    &mut alice,
+                    Some(id),
+                    play(first_move)
+                )
+                .await
+            ),
+            "Open the game to move in first"
+        );
+        let resume = ClientRequest::ResumeGame { id };
+        let opened = reply(&correspondence, "alice", &mut alice, Some(id), resume).await;
+        assert!(matches!(
+            opened,
+            ServerMessage::StateSync { player_order: 0, ref opponent_name, .. }
+                if opponent_name == "bob"
+        ));
+        let accepted = reply(
+            &correspondence,
+            "alice",
+            &mut alice,
+            Some(id),
+            play(first_move),
+        );
+        assert!(matches!(
+            accepted.await,
+            ServerMessage::MoveAccepted { clock: None }
+        ));
+        let early = reply(
+            &correspondence,
+            "alice",
+            &mut alice,
+            Some(id),
+            play(first_move),
+        );
+        assert!(matches!(early.await, ServerMessage::MoveRejected { .. }));
+        assert_eq!(store.ongoing(id).unwrap().unwrap().record.moves.len(), 1);
+
+        let ServerMessage::MyGames { games } = reply(
+            &correspondence,
+            "bob",
+            &mut bob,
+            None,
+            ClientRequest::ListMyGames,
+        )
+        .await
+        else {
+            panic!("Expected bob's games");
+        };
+        assert!(games[0].your_turn);
+        assert_eq!(games[0].moves_played, 1);
+        let resume = ClientRequest::ResumeGame { id };
+        let opened = reply(&correspondence, "bob", &mut bob, Some(id), resume).await;
+        assert!(matches!(
+            opened,
+            ServerMessage::StateSync {
+                player_order: 1,
+                ..
+            }
+        ));
+        let resigned = reply(
+            &correspondence,
+            "bob",
+            &mut bob,
+            Some(id),
+            ClientRequest::Resign,
+        );
+        let expected = GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        };
+        assert!(matches!(resigned.await, ServerMessage::GameOver(result) if result == expected));
+        assert!(bob.is_empty());
+        assert_eq!(store.ongoing(id).unwrap(), None);
+        let finished = &store.recent_games(1).unwrap()[0].1;
+        assert_eq!(finished.record.result, Some(expected));
+        assert!(!finished.rated);
+
+        // Alice still has the game open, but it's gone
+        let late = reply(
+            &correspondence,
+            "alice",
+            &mut alice,
+            Some(id),
+            ClientRequest::Resign,
+        );
+        assert_eq!(refusal(late.await), format!("Game {id} is over"));
+    }
+
+    /// Everything `player` is told in answer to `request` about game `id`.
+    async fn replies(
+        correspondence: &Correspondence,
+        player: &str,
+        open: &mut HashMap<u64, Player>,
+        id: u64,
+        request: ClientRequest,
+    ) -> Vec<ServerMessage> {
+        let replies = correspondence
+            .handle(player, open, Envelope::new(Some(id), request))
+            .await
+            .unwrap();
+        replies.into_iter().map(|reply| reply.message).collect()
+    }
+
+    #[tokio::test]
+    async fn conditional_moves_are_played_for_the_player_waiting() {
+        let (correspondence, store) = correspondence();
+        register(&correspondence, "alice").await;
+        register(&correspondence, "bob").await;
+        let (mut alice, mut bob) = (HashMap::new(), HashMap::new());
+        let challenge = ClientRequest::ChallengePlayer {
+            opponent: "bob".into(),
+            setup: None,
+            days_per_move: None,
+            series: None,
+        };
+        reply(&correspondence, "alice", &mut alice, None, challenge).await;
+        let id = store.ongoing_games_of("alice").unwrap()[0].0;
+        for (player, open) in [("alice", &mut alice), ("bob", &mut bob)] {
+            let resume = ClientRequest::ResumeGame { id };
+            reply(&correspondence, player, open, Some(id), resume).await;
+        }
+
+        // Alice's first move, Bob's answer to it, then Alice's next move or another one
+        let mut game = GameState::new(Board::classic_setup());
+        let [first, other_first] = [0, 1].map(|index| game.legal_moves()[index]);
+        game.apply(&first).unwrap();
+        let answer = game.legal_moves()[0];
+        game.apply(&answer).unwrap();
+        let [second, other_second] = [0, 1].map(|index| game.legal_moves()[index]);
+        game.apply(&second).unwrap();
+        let second_answer = game.legal_moves()[0];
+
+        let set = |lines: Vec<Vec<_>>| ClientRequest::SetConditionalMoves { lines };
+        let refused = reply(&correspondence, "alice", &mut alice, Some(id), set(vec![]));
+        assert_eq!(
+            refusal(refused.await),
+            "It's your move. Leave moves for when it's your opponent's"
+        );
+        let unanswered = set(vec![vec![first]]);
+        let refused = reply(&correspondence, "bob", &mut bob, Some(id), unanswered);
+        assert_eq!(
+            refusal(refused.await),
+            "Each line needs your opponent's moves and your replies to them"
+        );
+        // Bob can't answer with Alice's next move
+        let illegal = set(vec![vec![first, second]]);
+        let refused = reply(&correspondence, "bob", &mut bob, Some(id), illegal);
+        assert!(refusal(refused.await).contains("can't be played in that line"));
+
+        let lines = vec![
+            vec![first, answer, second, second_answer],
+            vec![other_first, answer],
+        ];
+        let set_lines = reply(
+            &correspondence,
+            "bob",
+            &mut bob,
+            Some(id),
+            set(lines.clone()),
+        );
+        assert!(
+            matches!(set_lines.await, ServerMessage::ConditionalMoves { lines: ref kept } if *kept == lines)
+        );
+
+        let played = replies(
+            &correspondence,
+            "alice",
+            &mut alice,
+            id,
+            ClientRequest::Move(first),
+        );
+        let played = played.await;
+        assert_eq!(played.len(), 2);
+        assert!(matches!(played[0], ServerMessage::MoveAccepted { .. }));
+        assert!(matches!(
+            played[1],
+            ServerMessage::OpponentMoved { player_move, clock: None } if player_move == answer
+        ));
+        let ongoing = store.ongoing(id).unwrap().unwrap();
+        assert_eq!(ongoing.record.moves.len(), 2);
+        assert_eq!(ongoing.conditional_moves, [vec![second, second_answer]]);
+
+        // Bob is shown what's left when he opens the game
+        let resume = ClientRequest::ResumeGame { id };
+        let opened = replies(&correspondence, "bob", &mut bob, id, resume).await;
+        assert!(matches!(
+            opened[..],
+            [ServerMessage::StateSync { .. }, ServerMessage::ConditionalMoves { ref lines }]
+                if *lines == [vec![second, second_answer]]
+        ));
+
+        // A move off the lines leaves Bob to answer it himself
+        let played = replies(
+            &correspondence,
+            "alice",
+            &mut alice,
+            id,
+            ClientRequest::Move(other_second),
+        );
+        assert!(matches!(
+            played.await[..],
+            [ServerMessage::MoveAccepted { .. }]
+        ));
+        let ongoing = store.ongoing(id).unwrap().unwrap();
+        assert_eq!(ongoing.record.moves.len(), 3);
+        assert!(ongoing.conditional_moves.is_empty());
+    }
+
+    #[tokio::test]
+    async fn challenges_need_a_known_opponent() {
+        let (correspondence, _) = correspondence();
+        register(&correspondence, "alice").await;
+        let mut open = HashMap::new();
+        let challenge = |opponent: &str| ClientRequest::ChallengePlayer {
+            opponent: opponent.into(),
+            setup: None,
+            days_per_move: None,
+            series: None,
+        };
+        let herself = reply(
+            &correspondence,
+            "alice",
+            &mut open,
+            None,
+            challenge("alice"),
+        );
+        assert_eq!(refusal(herself.await), "You can't challenge yourself");
+        let unknown = reply(
+            &correspondence,
+            "alice",
+            &mut open,
+            None,
+            challenge("carol"),
+        );
+        assert_eq!(refusal(unknown.await), "carol has never logged in");
+
+        let resume = ClientRequest::ResumeGame { id: 7 };
+        let opened = reply(&correspondence, "alice", &mut open, Some(7), resume);
+        assert_eq!(refusal(opened.await), "You're not playing a game 7");
+        let queued = reply(
+            &correspondence,
+            "alice",
+            &mut open,
+            None,
+            ClientRequest::OfferDraw,
+        );
+        assert_eq!(
+            refusal(queued.await),
+            "Only correspondence requests are taken once logged in"
+        );
+    }
+
+    #[tokio::test]
+    async fn timed_games_are_lost_on_time_unless_on_vacation() {
+        let (correspondence, store) = correspondence();
+        register(&correspondence, "alice").await;
+        register(&correspondence, "bob").await;
+        let (mut alice, mut bob) = (HashMap::new(), HashMap::new());
+        let too_long = ClientRequest::ChallengePlayer {
+            opponent: "bob".into(),
+            setup: None,
+            days_per_move: Some(100),
+            series: None,
+        };
+        let refused = reply(&correspondence, "alice", &mut alice, None, too_long);
+        assert_eq!(
+            refusal(refused.await),
+            "Give each move between 1 and 14 days"
+        );
+
+        let days_ago = |days: u64| SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+        let started = |players: [&str; 2], days, days_per_move| OngoingGame {
+            record: GameRecord::new(
+                players.map(String::from),
+                Board::classic_setup(),
+                RulesConfig::default(),
+            ),
+            started_at: days_ago(days),
+            conditional_moves: Vec::new(),
+            days_per_move: Some(days_per_move),
+            series: None,
+        };
+        // Alice has been on her move for three days of two, but away for two of them
+        let paused = store
+            .start_ongoing(&started(["alice", "bob"], 3, 2))
+            .unwrap();
+        let away = [Vacation {
+            started_at: days_ago(2),
+            ended_at: None,
+        }];
+        store.set_vacations("alice", &away).unwrap();
+        // Bob has been on his for two days of one
+        let lost = store
+            .start_ongoing(&started(["bob", "alice"], 2, 1))
+            .unwrap();
+
+        let listed = correspondence
+            .handle(
+                "alice",
+                &mut alice,
+                Envelope::new(None, ClientRequest::ListMyGames),
+            )
+            .await
+            .unwrap();
+        let expected = GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::Timeout,
+        };
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].game_id, Some(lost));
+        assert!(matches!(listed[0].message, ServerMessage::GameOver(result) if result == expected));
+        let ServerMessage::MyGames { ref games } = listed[1].message else {
+            panic!("Expected alice's games");
+        };
+        assert_eq!(games.len(), 1);
+        assert_eq!((games[0].id, games[0].hours_left), (paused, Some(24)));
+        assert_eq!(store.ongoing(lost).unwrap(), None);
+        assert_eq!(
+            store.recent_games(1).unwrap()[0].1.record.result,
+            Some(expected)
+        );
+
+        // Bob is shown Alice is away
+        let bobs = reply(
+            &correspondence,
+            "bob",
+            &mut bob,
+            None,
+            ClientRequest::ListMyGames,
+        );
+        assert!(
+            matches!(bobs.await, ServerMessage::MyGames { ref games } if games[0].opponent_on_vacation)
+        );
+
+        // Coming back starts her clock again, with what's left of her vacation to come
+        let back = ClientRequest::SetVacation { on: false };
+        assert!(matches!(
+            reply(&correspondence, "alice", &mut alice, None, back).await,
+            ServerMessage::Vacation {
+                on_vacation: false,
+                hours_left: 672
+            }
+        ));
+        let kept = store.vacations("alice").unwrap();
+        assert_eq!(kept[0].started_at, away[0].started_at);
+        assert!(kept[0].ended_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn series_go_on_until_someone_wins_them() {
+        let (correspondence, store) = correspondence();
+        register(&correspondence, "alice").await;
+        register(&correspondence, "bob").await;
+        let (mut alice, mut bob) = (HashMap::new(), HashMap::new());
+        let config = SeriesConfig {
+            games: 1,
+            armageddon: Some(ArmageddonOdds {
+                must_win_days: 2,
+                draw_odds_days: 3,
+            }),
+        };
+        let backwards = ClientRequest::ChallengePlayer {
+            opponent: "bob".into(),
+            setup: None,
+            days_per_move: None,
+            series: Some(config),
+        };
+        let refused = reply(&correspondence, "alice", &mut alice, None, backwards);
+        assert_eq!(
+            refusal(refused.await),
+            "Give the player who has to win more days per move than the one with draw odds"
+        );
+
+        let config = SeriesConfig {
+            armageddon: Some(ArmageddonOdds {
+                must_win_days: 3,
+                draw_odds_days: 2,
+            }),
+            ..config
+        };
+        let challenge = ClientRequest::ChallengePlayer {
+            opponent: "bob".into(),
+            setup: None,
+            days_per_move: None,
+            series: Some(config),
+        };
+        reply(&correspondence, "alice", &mut alice, None, challenge).await;
+        let (first, game) = store.ongoing_games_of("alice").unwrap().remove(0);
+        let draw = GameResult::Draw {
+            reason: DrawReason::Agreement,
+        };
+        correspondence.finish(first, game, draw).await.unwrap();
+
+        // A level series goes to Armageddon, with Bob moving first and needing to win
+        let ServerMessage::MyGames { games } = reply(
+            &correspondence,
+            "bob",
+            &mut bob,
+            None,
+            ClientRequest::ListMyGames,
+        )
+        .await
+        else {
+            panic!("Expected bob's games");
+        };
+        assert_eq!(games.len(), 1);
+        assert!(games[0].your_turn);
+        assert_eq!(games[0].hours_left, Some(72));
+        assert_eq!(
+            games[0].series,
+            Some(SeriesStanding {
+                game: 2,
+                config,
+                your_half_points: 1,
+                opponent_half_points: 1,
+            })
+        );
+
+        // Where a draw wins the series for Alice
+        let (armageddon, game) = store.ongoing_games_of("alice").unwrap().remove(0);
+        assert_eq!(game.record.players, ["bob", "alice"]);
+        let result = correspondence.finish(armageddon, game, draw).await.unwrap();
+        let expected = GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::DrawOdds,
+        };
+        assert_eq!(result, expected);
+        assert!(store.ongoing_games_of("alice").unwrap().is_empty());
+        assert_eq!(
+            store.recent_games(1).unwrap()[0].1.record.result,
+            Some(expected)
+        );
+    }
+}
@@ -0,0 +1,333 @@
+//! Guest accounts, for playing straight away without picking a name. A guest is given a name and
+//! a token like any other account, and a rating that's only kept in memory. Keeping the account
+//! renames it to a name of the guest's choosing, taking its games and rating along.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::post,
+};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use super::{
+    GameStore,
+    correspondence::{check_token, hash_token},
+    matchmaking::{SharedRatings, session_token},
+    oauth::LoggedIn,
+    storage,
+};
+
+/// What every guest's name starts with, and nobody else's.
+pub(super) const GUEST_PREFIX: &str = "guest-";
+
+/// How many names are tried for a new guest before giving up, should they all be taken.
+const GUEST_NAME_ATTEMPTS: usize = 5;
+
+/// Whether `player` is a guest's name.
+pub(super) fn is_guest(player: &str) -> bool {
+    player.starts_with(GUEST_PREFIX)
+}
+
+/// How many connections are using each account, waiting for a live game or playing one. An
+/// account in use can't be renamed, or its games would finish under a name that's gone.
+#[derive(Clone, Default)]
+pub(super) struct AccountsInUse(Arc<Mutex<HashMap<String, usize>>>);
+
+/// Holds an account in use until it's dropped.
+pub(super) struct AccountInUse {
+    accounts: AccountsInUse,
+    account: String,
+}
+
+impl AccountsInUse {
+    pub(super) fn hold(&self, account: &str) -> AccountInUse {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry(account.to_string())
+            .or_default() += 1;
+        AccountInUse {
+            accounts: self.clone(),
+            account: account.to_string(),
+        }
+    }
+
+    fn in_use(&self, account: &str) -> bool {
+        self.0.lock().unwrap().contains_key(account)
+    }
+}
+
+impl Drop for AccountInUse {
+    fn drop(&mut self) {
+        let mut accounts = self.accounts.0.lock().unwrap();
+        if let Some(count) = accounts.get_mut(&self.account) {
+            *count -= 1;
+            if *count == 0 {
+                accounts.remove(&self.account);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Guests {
+    store: Arc<dyn GameStore>,
+    ratings: SharedRatings,
+    in_use: AccountsInUse,
+}
+
+/// `POST /guests` makes a guest account, and `POST /guests/{name}/upgrade` keeps one.
+pub(super) fn routes(
+    store: Arc<dyn GameStore>,
+    ratings: SharedRatings,
+    in_use: AccountsInUse,
+) -> Router {
+    Router::new()
+        .route("/guests", post(new_guest))
+        .route("/guests/{name}/upgrade", post(upgrade))
+        .with_state(Guests {
+            store,
+            ratings,
+            in_use,
+        })
+}
+
+/// Makes a guest account with a random name.
+async fn new_guest(State(guests): State<Guests>) -> Response {
+    let token = session_token();
+    let hash = hash_token(&token);
+    let registered = storage::blocking(&guests.store, move |store| {
+        for _ in 0..GUEST_NAME_ATTEMPTS {
+            let name = format!("{GUEST_PREFIX}{}", &session_token()[..8]);
+            if store.register(&name, &hash)? {
+                return Ok(name);
+            }
+        }
+        anyhow::bail!("No free guest name in {} tries", GUEST_NAME_ATTEMPTS)
+    });
+    match registered.await {
+        Ok(player_name) => {
+            info!("{} is playing as a guest", player_name);
+            Json(LoggedIn { player_name, token }).into_response()
+        }
+        Err(e) => trouble(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct UpgradeRequest {
+    /// The guest's token.
+    token: String,
+    /// The name to keep the account under.
+    player_name: String,
+}
+
+/// Keeps the guest account `name` under the name it asks for, along with its games and rating.
+/// It logs in with the same token as before.
+async fn upgrade(
+    State(guests): State<Guests>,
+    Path(name): Path<String>,
+    Json(request): Json<UpgradeRequest>,
+) -> Response {
+    let player_name = request.player_name.trim().to_string();
+    if !is_guest(&name) {
+        return (StatusCode::BAD_REQUEST, format!("{name} isn't a guest")).into_response();
+    }
+    if player_name.is_empty() || is_guest(&player_name) {
+        let reason = format!("Pick a name that doesn't start with {GUEST_PREFIX}");
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+    match check_token(&guests.store, &name, &request.token).await {
+        Ok(true) => {}
+        Ok(false) => return (StatusCode::FORBIDDEN, "Wrong name or token").into_response(),
+        Err(e) => return trouble(e),
+    }
+    if guests.in_use.in_use(&name) {
+        let reason = "Finish your game before keeping your account";
+        return (StatusCode::CONFLICT, reason).into_response();
+    }
+    let (from, to) = (name.clone(), player_name.clone());
+    let renamed = storage::blocking(&guests.store, move |store| store.rename_account(&from, &to));
+    match renamed.await {
+        Ok(true) => {
+            info!("{} kept their account as {}", name, player_name);
+            guests.ratings.lock().unwrap().rename(&name, &player_name);
+            let token = request.token;
+            Json(LoggedIn { player_name, token }).into_response()
+        }
+        Ok(false) => {
+            let reason = format!("{player_name} is already taken");
+            (StatusCode::CONFLICT, reason).into_response()
+        }
+        Err(e) => trouble(e),
+    }
+}
+
+/// What to tell a player when the store lets the server down, logging what happened.
+fn trouble(e: anyhow::Error) -> Response {
+    error!("Guest request failed: {}", e);
+    let reason = "Something went wrong on the server. Please try again later";
+    (StatusCode::INTERNAL_SERVER_ERROR, reason).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::{self, Body},
+        http::{Request, StatusCode, header},
+    };
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use crate::logic::{GameResult, Player, WinReason};
+
+    use super::{
+        super::{
+            GameStore,
+            correspondence::check_token,
+            matchmaking::SharedRatings,
+            oauth::LoggedIn,
+            ratings::{INITIAL_RATING, Ratings},
+            storage::tests::{MemoryStore, finished},
+        },
+        AccountsInUse, routes,
+    };
+
+    struct Server {
+        store: Arc<dyn GameStore>,
+        ratings: SharedRatings,
+        in_use: AccountsInUse,
+    }
+
+    impl Server {
+        fn new() -> Self {
+            Self {
+                store: Arc::new(MemoryStore::default()),
+                ratings: Arc::new(Mutex::new(Ratings::load(None).unwrap())),
+                in_use: AccountsInUse::default(),
+            }
+        }
+
+        /// Posts `body` to `uri`, returning the status and body.
+        async fn post(&self, uri: &str, body: serde_json::Value) -> (StatusCode, String) {
+            let request = Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let routes = routes(
+                self.store.clone(),
+                self.ratings.clone(),
+                self.in_use.clone(),
+            );
+            let response = routes.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        async fn new_guest(&self) -> LoggedIn {
+            let (status, body) = self.post("/guests", json!(null)).await;
+            assert_eq!(status, StatusCode::OK);
+            serde_json::from_str(&body).unwrap()
+        }
+
+        /// Asks to keep `guest`'s account as `player_name`, returning the status.
+        async fn upgrade(&self, guest: &LoggedIn, token: &str, player_name: &str) -> StatusCode {
+            let uri = format!("/guests/{}/upgrade", guest.player_name);
+            let body = json!({ "token": token, "player_name": player_name });
+            self.post(&uri, body).await.0
+        }
+    }
+
+    #[tokio::test]
+    async fn guests_get_an_account_straight_away() {
+        let server = Server::new();
+        let guest = server.new_guest().await;
+        let other = server.new_guest().await;
+        assert!(guest.player_name.starts_with("guest-"));
+        assert_ne!(guest.player_name, other.player_name);
+        let checked = check_token(&server.store, &guest.player_name, &guest.token).await;
+        assert!(checked.unwrap());
+    }
+
+    #[tokio::test]
+    async fn guests_keep_their_games_and_rating_when_they_keep_their_account() {
+        let server = Server::new();
+        let guest = server.new_guest().await;
+        let name = guest.player_name.as_str();
+        server.store.save(&finished([name, "bob"], 20)).unwrap();
+        server.store.register("bob", "hash").unwrap();
+        let win = GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        };
+        server.ratings.lock().unwrap().record([name, "bob"], &win);
+
+        assert_eq!(
+            server.upgrade(&guest, "guess", "alice").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            server.upgrade(&guest, &guest.token, "bob").await,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            server.upgrade(&guest, &guest.token, "guest-alice").await,
+            StatusCode::BAD_REQUEST
+        );
+        {
+            let _playing = server.in_use.hold(name);
+            assert_eq!(
+                server.upgrade(&guest, &guest.token, "alice").await,
+                StatusCode::CONFLICT
+            );
+        }
+        assert_eq!(
+            server.upgrade(&guest, &guest.token, " alice ").await,
+            StatusCode::OK
+        );
+
+        assert!(
+            check_token(&server.store, "alice", &guest.token)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !check_token(&server.store, name, &guest.token)
+                .await
+                .unwrap()
+        );
+        let games = server.store.games_of("alice", 10).unwrap();
+        assert_eq!(games[0].1.record.players, ["alice", "bob"]);
+        let ratings = server.ratings.lock().unwrap();
+        assert_eq!(ratings.get("alice"), INITIAL_RATING + 16);
+        assert_eq!(ratings.get(name), INITIAL_RATING);
+    }
+
+    #[tokio::test]
+    async fn only_guests_can_be_kept() {
+        let server = Server::new();
+        server.store.register("bob", "hash").unwrap();
+        let bob = LoggedIn {
+            player_name: "bob".into(),
+            token: "token".into(),
+        };
+        assert_eq!(
+            server.upgrade(&bob, "token", "robert").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,595 @@
+//! Serving finished games over HTTP, for [`Server::with_store`](super::Server::with_store), along
+//! with players' profiles and an opening explorer built from them. Games are served with the
+//! server's [signature](super::signing) on them.
+
+use std::{cmp::Reverse, collections::HashMap, sync::Arc, time::SystemTime};
+
+use axum::{
+    Json, Router,
+    extract::{FromRef, Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::logic::{Board, Explorer, GameResult, GameState, Player, RulesConfig, SetupKind, eval};
+
+use super::{FinishedGame, GameStore, signing::Signer, storage};
+
+/// How many games a list has unless it asks for a different number.
+const DEFAULT_LIMIT: usize = 50;
+
+/// The most games a list can have.
+const MAX_LIMIT: usize = 500;
+
+/// How many of a player's games their profile is worked out from, the most recent first.
+const PROFILE_GAMES: usize = 500;
+
+/// How many games a profile lists, and works out how accurately they were played.
+const PROFILE_RECENT_GAMES: usize = 10;
+
+/// How many of the games that ended last the explorer looks through.
+const EXPLORER_GAMES: usize = 2_000;
+
+#[derive(Clone)]
+struct History {
+    store: Arc<dyn GameStore>,
+    signer: Signer,
+}
+
+impl FromRef<History> for Arc<dyn GameStore> {
+    fn from_ref(history: &History) -> Self {
+        history.store.clone()
+    }
+}
+
+impl FromRef<History> for Signer {
+    fn from_ref(history: &History) -> Self {
+        history.signer.clone()
+    }
+}
+
+pub(super) fn routes(store: Arc<dyn GameStore>, signer: Signer) -> Router {
+    Router::new()
+        .route("/games", get(recent_games))
+        .route("/games/{id}", get(game))
+        .route("/players/{name}", get(profile))
+        .route("/players/{name}/games", get(games_of))
+        .route("/explorer", get(explorer))
+        .route("/signing-key", get(signing_key))
+        .with_state(History { store, signer })
+}
+
+#[derive(Deserialize)]
+struct Params {
+    limit: Option<usize>,
+    #[serde(default)]
+    format: Format,
+}
+
+impl Params {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Json,
+    Notation,
+}
+
+/// Everything about a player, worked out from their last [`PROFILE_GAMES`] games.
+#[derive(Serialize)]
+struct PlayerProfile {
+    name: String,
+    /// The rating their last rated game left them on, if they've played one.
+    rating: Option<i32>,
+    /// Their rating after each rated game, oldest first.
+    rating_history: Vec<RatingChange>,
+    wins: u32,
+    draws: u32,
+    losses: u32,
+    /// The setups they've played, the most played first, with `custom` for positions that aren't
+    /// one of the official setups.
+    favorite_setups: Vec<SetupCount>,
+    /// How accurately they played their last games, oldest first, by [`eval::accuracy`].
+    accuracy: Vec<GameAccuracy>,
+    /// Their last games, the most recent first.
+    recent_games: Vec<ListedGame>,
+}
+
+#[derive(Serialize)]
+struct RatingChange {
+    id: u64,
+    #[serde(with = "storage::unix_millis")]
+    ended_at: SystemTime,
+    rating: i32,
+}
+
+#[derive(Serialize)]
+struct SetupCount {
+    setup: String,
+    games: u32,
+}
+
+#[derive(Serialize)]
+struct GameAccuracy {
+    id: u64,
+    /// From 0 to 1.
+    accuracy: f64,
+}
+
+#[derive(Deserialize)]
+struct ExplorerParams {
+    /// In the notation of [`GameState::from_notation`], the classic setup if it's left out.
+    position: Option<String>,
+}
+
+/// A game with the server's signature on its record.
+#[derive(Serialize)]
+struct SignedGame {
+    #[serde(flatten)]
+    game: FinishedGame,
+    /// In base64.
+    signature: String,
+}
+
+impl SignedGame {
+    fn new(game: FinishedGame, signer: &Signer) -> Self {
+        Self {
+            signature: signer.sign(&game.record),
+            game,
+        }
+    }
+}
+
+/// A game in a list, with the id to fetch it by.
+#[derive(Serialize)]
+struct ListedGame {
+    id: u64,
+    #[serde(flatten)]
+    game: SignedGame,
+}
+
+async fn recent_games(
+    State(store): State<Arc<dyn GameStore>>,
+    State(signer): State<Signer>,
+    Query(params): Query<Params>,
+) -> Response {
+    let limit = params.limit();
+    let games = query(store, move |store| store.recent_games(limit)).await;
+    list(games, params.format, &signer)
+}
+
+async fn games_of(
+    State(store): State<Arc<dyn GameStore>>,
+    State(signer): State<Signer>,
+    Path(name): Path<String>,
+    Query(params): Query<Params>,
+) -> Response {
+    let limit = params.limit();
+    let games = query(store, move |store| store.games_of(&name, limit)).await;
+    list(games, params.format, &signer)
+}
+
+async fn profile(
+    State(store): State<Arc<dyn GameStore>>,
+    State(signer): State<Signer>,
+    Path(name): Path<String>,
+) -> Response {
+    let player = name.clone();
+    let profile = query(store, move |store| {
+        let games = store.games_of(&player, PROFILE_GAMES)?;
+        if games.is_empty() && store.token_hash(&player)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(player_profile(player, games, &signer)))
+    });
+    match profile.await {
+        Ok(Some(profile)) => Json(profile).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, format!("No player {name}")).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// `player`'s profile from their `games`, the most recent first, signing them with `signer`.
+fn player_profile(
+    player: String,
+    games: Vec<(u64, FinishedGame)>,
+    signer: &Signer,
+) -> PlayerProfile {
+    let side = |game: &FinishedGame| game.record.players.iter().position(|name| *name == player);
+    let mut profile = PlayerProfile {
+        name: player.clone(),
+        rating: None,
+        rating_history: Vec::new(),
+        wins: 0,
+        draws: 0,
+        losses: 0,
+        favorite_setups: Vec::new(),
+        accuracy: Vec::new(),
+        recent_games: Vec::new(),
+    };
+    let mut setups = HashMap::<String, u32>::new();
+    for (id, game) in games.iter().rev() {
+        let Some(side) = side(game) else {
+            continue;
+        };
+        match game.record.result {
+            Some(GameResult::Win { winner, .. }) if winner.index() == side => profile.wins += 1,
+            Some(GameResult::Win { .. }) => profile.losses += 1,
+            Some(GameResult::Draw { .. }) => profile.draws += 1,
+            None => {}
+        }
+        if let Some(ratings) = game.ratings {
+            profile.rating_history.push(RatingChange {
+                id: *id,
+                ended_at: game.ended_at,
+                rating: ratings[side],
+            });
+        }
+        let setup = SetupKind::recognize(&game.record.board)
+            .map_or_else(|| "custom".to_string(), |setup| setup.to_string());
+        *setups.entry(setup).or_default() += 1;
+    }
+    profile.rating = profile.rating_history.last().map(|change| change.rating);
+    profile.favorite_setups = setups
+        .into_iter()
+        .map(|(setup, games)| SetupCount { setup, games })
+        .collect();
+    profile
+        .favorite_setups
+        .sort_by(|a, b| (Reverse(a.games), &a.setup).cmp(&(Reverse(b.games), &b.setup)));
+    let recent = games.into_iter().take(PROFILE_RECENT_GAMES);
+    for (id, game) in recent {
+        let accuracy =
+            side(&game).and_then(|side| eval::accuracy(&game.record, Player::from_index(side)?));
+        if let Some(accuracy) = accuracy {
+            profile.accuracy.push(GameAccuracy { id, accuracy });
+        }
+        let game = SignedGame::new(game, signer);
+        profile.recent_games.push(ListedGame { id, game });
+    }
+    profile.accuracy.reverse();
+    profile
+}
+
+async fn game(
+    State(store): State<Arc<dyn GameStore>>,
+    State(signer): State<Signer>,
+    Path(id): Path<u64>,
+    Query(params): Query<Params>,
+) -> Response {
+    match query(store, move |store| store.game(id)).await {
+        Ok(Some(game)) => match params.format {
+            Format::Json => Json(SignedGame::new(game, &signer)).into_response(),
+            Format::Notation => notation(signer.signed_notation(&game.record)),
+        },
+        Ok(None) => (StatusCode::NOT_FOUND, format!("No game {id}")).into_response(),
+        Err(response) => response,
+    }
+}
+
+/// What was played from a position in the games that ended last, among those played under the
+/// standard rules, since positions are only comparable under the same rules.
+async fn explorer(
+    State(store): State<Arc<dyn GameStore>>,
+    Query(params): Query<ExplorerParams>,
+) -> Response {
+    let state = match params.position.as_deref().map(str::parse::<GameState>) {
+        Some(Ok(state)) => state,
+        Some(Err(e)) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid position: {e}")).into_response();
+        }
+        None => GameState::new(Board::classic_setup()),
+    };
+    let position = query(store, move |store| {
+        let games = store.recent_games(EXPLORER_GAMES)?;
+        let rules = RulesConfig::default();
+        let mut explorer = Explorer::new();
+        explorer.add_records(
+            games
+                .iter()
+                .map(|(_, game)| &game.record)
+                .filter(|record| record.rules == rules),
+        );
+        Ok(explorer.position(&state))
+    });
+    match position.await {
+        Ok(position) => Json(position).into_response(),
+        Err(response) => response,
+    }
+}
+
+fn list(
+    games: Result<Vec<(u64, FinishedGame)>, Response>,
+    format: Format,
+    signer: &Signer,
+) -> Response {
+    let games = match games {
+        Ok(games) => games,
+        Err(response) => return response,
+    };
+    match format {
+        Format::Json => {
+            let games: Vec<_> = games
+                .into_iter()
+                .map(|(id, game)| ListedGame {
+                    id,
+                    game: SignedGame::new(game, signer),
+                })
+                .collect();
+            Json(games).into_response()
+        }
+        Format::Notation => {
+            let records: Vec<_> = games
+                .iter()
+                .map(|(_, game)| signer.signed_notation(&game.record))
+                .collect();
+            notation(records.join("\n"))
+        }
+    }
+}
+
+async fn signing_key(State(signer): State<Signer>) -> Response {
+    Json(signer.public_key()).into_response()
+}
+
+fn notation(text: String) -> Response {
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], text).into_response()
+}
+
+/// Runs `f` against `store`. Failures are logged and turned into a server error for the client.
+async fn query<T: Send + 'static>(
+    store: Arc<dyn GameStore>,
+    f: impl FnOnce(&dyn GameStore) -> anyhow::Result<T> + Send + 'static,
+) -> Result<T, Response> {
+    storage::blocking(&store, f).await.map_err(|e| {
+        error!("Failed to look up games: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to look up games").into_response()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{
+        body::{self, Body},
+        http::{Request, StatusCode},
+    };
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use crate::logic::{
+        Annotation, Board, ExplorerPosition, GameState, RulesConfig, SetupKind, TimedMove,
+    };
+
+    use super::{
+        super::{
+            GameStore,
+            signing::{Signer, verify_record},
+            storage::tests::{MemoryStore, finished},
+        },
+        DEFAULT_LIMIT, Format, MAX_LIMIT, Params, routes,
+    };
+
+    /// The same key every time, so tests can check the signatures.
+    fn signer() -> Signer {
+        Signer::new(Some([1; 32]))
+    }
+
+    /// Fetches `uri` from the routes over `store`, returning the status and body.
+    async fn get(store: &Arc<dyn GameStore>, uri: &str) -> (StatusCode, String) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = routes(store.clone(), signer())
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn store() -> Arc<dyn GameStore> {
+        let store = MemoryStore::default();
+        store.save(&finished(["alice", "bob"], 20)).unwrap();
+        store.save(&finished(["bob", "carol"], 30)).unwrap();
+        store.save(&finished(["carol", "alice"], 10)).unwrap();
+        Arc::new(store)
+    }
+
+    fn ids(body: &str) -> Vec<u64> {
+        let games: Vec<serde_json::Value> = serde_json::from_str(body).unwrap();
+        games
+            .iter()
+            .map(|game| game["id"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lists_games_latest_first() {
+        let store = store();
+        let (status, body) = get(&store, "/games").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&body), [2, 1, 3]);
+        assert_eq!(ids(&get(&store, "/games?limit=1").await.1), [2]);
+        assert_eq!(ids(&get(&store, "/players/alice/games").await.1), [1, 3]);
+        assert!(ids(&get(&store, "/players/dave/games").await.1).is_empty());
+
+        let games: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(games[0]["record"]["players"][0], "bob");
+        assert_eq!(games[0]["ended_at"], 30_000);
+    }
+
+    #[tokio::test]
+    async fn serves_single_games() {
+        let store = store();
+        let (status, body) = get(&store, "/games/1").await;
+        assert_eq!(status, StatusCode::OK);
+        let game: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(game["record"]["players"][1], "bob");
+        assert!(game.get("id").is_none());
+        let record = finished(["alice", "bob"], 20).record;
+        assert_eq!(game["signature"], signer().sign(&record));
+
+        let (status, body) = get(&store, "/games/1?format=notation").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, signer().signed_notation(&record));
+
+        assert_eq!(
+            get(&store, "/games/9").await,
+            (StatusCode::NOT_FOUND, "No game 9".into())
+        );
+        assert_eq!(get(&store, "/games/nine").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            get(&store, "/games/1?format=pdf").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_games_in_notation() {
+        let store = store();
+        let (status, body) = get(&store, "/players/alice/games?format=notation").await;
+        assert_eq!(status, StatusCode::OK);
+        let expected = [
+            signer().signed_notation(&finished(["alice", "bob"], 20).record),
+            signer().signed_notation(&finished(["carol", "alice"], 10).record),
+        ];
+        assert_eq!(body, expected.join("\n"));
+    }
+
+    #[tokio::test]
+    async fn profiles_sum_up_a_players_games() {
+        let store = MemoryStore::default();
+        let mut opening = finished(["alice", "bob"], 20);
+        let first_move = GameState::new(Board::classic_setup()).legal_moves()[0];
+        opening.record.moves.push(TimedMove {
+            player_move: first_move,
+            elapsed: Duration::ZERO,
+            annotation: Annotation::default(),
+        });
+        store.save(&opening).unwrap();
+        store.save(&finished(["bob", "alice"], 30)).unwrap();
+        let mut imhotep = finished(["carol", "alice"], 11);
+        imhotep.record.board = Board::from_setup(SetupKind::Imhotep, &RulesConfig::default());
+        store.save(&imhotep).unwrap();
+        store.register("dave", "hash").unwrap();
+        let store: Arc<dyn GameStore> = Arc::new(store);
+
+        let (status, body) = get(&store, "/players/alice").await;
+        assert_eq!(status, StatusCode::OK);
+        let profile: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(profile["name"], "alice");
+        assert_eq!(profile["rating"], 1484);
+        assert_eq!(
+            profile["rating_history"],
+            json!([
+                { "id": 1, "ended_at": 20_000, "rating": 1516 },
+                { "id": 2, "ended_at": 30_000, "rating": 1484 },
+            ])
+        );
+        assert_eq!(
+            [&profile["wins"], &profile["draws"], &profile["losses"]],
+            [1, 0, 2]
+        );
+        assert_eq!(
+            profile["favorite_setups"],
+            json!([{ "setup": "classic", "games": 2 }, { "setup": "imhotep", "games": 1 }])
+        );
+        // Only the game with a move of hers has an accuracy
+        let accuracy = profile["accuracy"].as_array().unwrap();
+        assert_eq!(accuracy.len(), 1);
+        assert_eq!(accuracy[0]["id"], 1);
+        assert!((0.0..=1.0).contains(&accuracy[0]["accuracy"].as_f64().unwrap()));
+        let recent: Vec<_> = profile["recent_games"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|game| game["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(recent, [2, 1, 3]);
+
+        let (status, body) = get(&store, "/players/dave").await;
+        assert_eq!(status, StatusCode::OK);
+        let profile: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(profile["rating"], serde_json::Value::Null);
+        assert_eq!(profile["recent_games"], json!([]));
+        assert_eq!(
+            get(&store, "/players/erin").await,
+            (StatusCode::NOT_FOUND, "No player erin".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn explores_the_stored_games() {
+        let start = GameState::new(Board::classic_setup());
+        let first_move = start.legal_moves()[0];
+        let mut game = finished(["alice", "bob"], 20);
+        game.record.moves.push(TimedMove {
+            player_move: first_move,
+            elapsed: Duration::ZERO,
+            annotation: Annotation::default(),
+        });
+        let mut other_rules = game.clone();
+        other_rules.record.rules.no_capture_draw_moves = None;
+        let store = MemoryStore::default();
+        store.save(&game).unwrap();
+        store.save(&other_rules).unwrap();
+        let store: Arc<dyn GameStore> = Arc::new(store);
+
+        let (status, body) = get(&store, "/explorer").await;
+        assert_eq!(status, StatusCode::OK);
+        let position: ExplorerPosition = serde_json::from_str(&body).unwrap();
+        assert_eq!(position.games, 1);
+        assert_eq!(position.moves[0].player_move, first_move);
+        assert_eq!(position.moves[0].wins, 1);
+
+        let mut after = start.clone();
+        after.apply(&first_move).unwrap();
+        let uri = format!("/explorer?position={}", after.to_string().replace(' ', "+"));
+        let position: ExplorerPosition = serde_json::from_str(&get(&store, &uri).await.1).unwrap();
+        assert_eq!((position.games, position.moves.len()), (1, 0));
+
+        let (status, body) = get(&store, "/explorer?position=nonsense").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.starts_with("Invalid position"), "{body}");
+    }
+
+    #[test]
+    fn limits_are_capped() {
+        let params = |limit| Params {
+            limit,
+            format: Format::Json,
+        };
+        assert_eq!(params(None).limit(), DEFAULT_LIMIT);
+        assert_eq!(params(Some(3)).limit(), 3);
+        assert_eq!(params(Some(MAX_LIMIT + 1)).limit(), MAX_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn games_verify_against_the_signing_key() {
+        let store = store();
+        let (status, body) = get(&store, "/signing-key").await;
+        assert_eq!(status, StatusCode::OK);
+        let key: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(key["algorithm"], "ed25519");
+        let key = key["public_key"].as_str().unwrap();
+
+        let games: Vec<serde_json::Value> =
+            serde_json::from_str(&get(&store, "/games").await.1).unwrap();
+        for game in games {
+            let record = serde_json::from_value(game["record"].clone()).unwrap();
+            let signature = game["signature"].as_str().unwrap();
+            assert!(verify_record(&record, signature, key));
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! Lobbies, where players pick their opponent from a list of open challenges instead of taking
+//! whoever's next in the queue.
+
+use futures_util::future::select_all;
+use tracing::{info, warn};
+
+use crate::{
+    Challenge, ClientRequest, ServerMessage,
+    logic::{Board, SetupKind},
+};
+
+use super::{
+    PlayerConnection,
+    matchmaking::ConnectedPlayer,
+    ratings::{HANDICAP_NOT_LOGGED_IN, NOT_LOGGED_IN},
+};
+
+/// Everyone in a lobby, across all of them.
+#[derive(Default)]
+pub(super) struct Lobbies {
+    members: Vec<Member>,
+    next_id: u64,
+}
+
+struct Member {
+    player: ConnectedPlayer,
+    lobby: String,
+    /// The challenge this player has posted, if they have.
+    challenge: Option<Challenge>,
+}
+
+impl Lobbies {
+    /// Puts `player` in `lobby` and shows them its challenges. A player logged in to an account
+    /// that's already in a lobby, say after connecting twice, has the old connection closed,
+    /// taking any challenge it had posted with it. Anyone else is only known by their connection.
+    pub(super) async fn join(&mut self, player: ConnectedPlayer, lobby: String) {
+        let duplicate = self
+            .members
+            .iter()
+            .position(|member| player.account.is_some() && member.player.account == player.account);
+        if let Some(index) = duplicate {
+            info!("{} connected again from a lobby", player.name);
+            let mut old = self.members.remove(index);
+            let reason = "You connected again from somewhere else".to_string();
+            let _ = old
+                .player
+                .connection
+                .send(&ServerMessage::RequestFailed { reason })
+                .await;
+            old.player.connection.close().await;
+            if old.lobby != lobby {
+                self.broadcast(&old.lobby).await;
+            }
+        }
+        info!("{} joined the {} lobby", player.name, lobby);
+        self.members.push(Member {
+            player,
+            lobby: lobby.clone(),
+            challenge: None,
+        });
+        self.broadcast(&lobby).await;
+    }
+
+    /// Waits for a request from any member, returning which one it came from. Never finishes if
+    /// the lobbies are empty.
+    pub(super) async fn next_request(&mut self) -> (usize, anyhow::Result<ClientRequest>) {
+        if self.members.is_empty() {
+            return std::future::pending().await;
+        }
+        let requests = self
+            .members
+            .iter_mut()
+            .map(|member| Box::pin(member.player.connection.recv()));
+        let (request, index, _) = select_all(requests).await;
+        (index, request)
+    }
+
+    /// Takes everyone out of the lobbies.
+    pub(super) fn drain(&mut self) -> impl Iterator<Item = ConnectedPlayer> + '_ {
+        self.members.drain(..).map(|member| member.player)
+    }
+
+    /// Acts on a request from the member at `index`. Returns the players of the game to start if
+    /// it accepted a challenge, the challenger first, with their preferences set to the
+    /// challenge's.
+    pub(super) async fn handle(
+        &mut self,
+        index: usize,
+        request: anyhow::Result<ClientRequest>,
+    ) -> Option<[ConnectedPlayer; 2]> {
+        let member = &self.members[index];
+        let (name, lobby) = (member.player.name.clone(), member.lobby.clone());
+        match request {
+            Ok(ClientRequest::PostChallenge {
+                setup,
+                time_control,
+                rated,
+            }) => {
+                let player = &self.members[index].player;
+                // Whoever accepts plays the challenger's setup by the challenger's rules
+                let board = Board::from_setup(setup.unwrap_or_default(), &player.rules);
+                let problem = match time_control.map(|control| control.check()) {
+                    Some(Err(reason)) => Some(reason),
+                    _ if rated && player.account.is_none() => Some(NOT_LOGGED_IN.to_string()),
+                    _ if is_handicap(setup) && player.account.is_none() => {
+                        Some(HANDICAP_NOT_LOGGED_IN.to_string())
+                    }
+                    _ => board.validate(&player.rules).err().map(|e| e.to_string()),
+                };
+                if let Some(reason) = problem {
+                    info!(
+                        "{} posted a challenge that can't be played: {}",
+                        name, reason
+                    );
+                    let refusal = ServerMessage::RequestFailed { reason };
+                    // Anyone dropped takes their challenge with them
+                    if !self.send(index, &refusal).await {
+                        self.broadcast(&lobby).await;
+                    }
+                    return None;
+                }
+                info!("{} posted a challenge in the {} lobby", name, lobby);
+                self.members[index].challenge = Some(Challenge {
+                    id: self.next_id,
+                    player_name: name,
+                    setup,
+                    time_control,
+                    rated,
+                    rules: self.members[index].player.rules.clone(),
+                });
+                self.next_id += 1;
+            }
+            Ok(ClientRequest::AcceptChallenge { id }) => {
+                let challenger = self.members.iter().position(|member| {
+                    member.lobby == lobby && member.challenge.as_ref().is_some_and(|c| c.id == id)
+                });
+                // Rated and handicap games need whoever accepts to have a rating too
+                let login_needed = challenger
+                    .and_then(|challenger| self.members[challenger].challenge.as_ref())
+                    .filter(|_| self.members[index].player.account.is_none())
+                    .and_then(|challenge| {
+                        if challenge.rated {
+                            Some(NOT_LOGGED_IN)
+                        } else if is_handicap(challenge.setup) {
+                            Some(HANDICAP_NOT_LOGGED_IN)
+                        } else {
+                            None
+                        }
+                    });
+                match (challenger, login_needed) {
+                    (Some(_), Some(reason)) => {
+                        info!("{} accepted a challenge without logging in", name);
+                        let reason = reason.to_string();
+                        if !self
+                            .send(index, &ServerMessage::RequestFailed { reason })
+                            .await
+                        {
+                            self.broadcast(&lobby).await;
+                        }
+                    }
+                    (Some(challenger), None) if challenger != index => {
+                        let players = self.take_pair(challenger, index);
+                        self.broadcast(&lobby).await;
+                        return Some(players);
+                    }
+                    (Some(_), None) => warn!("{} accepted their own challenge", name),
+                    // Taken, or its challenger left, so show them what's still open
+                    (None, _) => {
+                        info!("{} accepted challenge {}, which is gone", name, id);
+                        let state = self.state(&lobby);
+                        self.send(index, &state).await;
+                    }
+                }
+                return None;
+            }
+            Ok(ClientRequest::CancelMatchmaking) => {
+                info!("{} left the {} lobby", name, lobby);
+                let mut member = self.members.remove(index);
+                member.player.connection.close().await;
+            }
+            Ok(_) => {
+                warn!("Unexpected request from {} in a lobby", name);
+                return None;
+            }
+            Err(e) => {
+                info!("{} left the {} lobby: {}", name, lobby, e);
+                self.members.remove(index);
+            }
+        }
+        self.broadcast(&lobby).await;
+        None
+    }
+
+    /// Takes the challenger at `challenger` and whoever accepted at `accepter` out of the lobby,
+    /// both set to play the challenge.
+    fn take_pair(&mut self, challenger: usize, accepter: usize) -> [ConnectedPlayer; 2] {
+        // Remove the later one first so the other's index still holds
+        let (first, second) = (challenger.max(accepter), challenger.min(accepter));
+        let first = self.members.remove(first);
+        let second = self.members.remove(second);
+        let (challenger, accepter) = if challenger > accepter {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        let challenge = challenger.challenge.unwrap(); // It was found by its challenge
+        info!(
+            "{} accepted {}'s challenge in the {} lobby",
+            accepter.player.name, challenger.player.name, challenger.lobby
+        );
+        [challenger.player, accepter.player].map(|mut player| {
+            player.preferred_setup = challenge.setup;
+            player.time_control = challenge.time_control;
+            player.rated = challenge.rated;
+            player.rules = challenge.rules.clone();
+            player
+        })
+    }
+
+    /// The open challenges in `lobby`.
+    fn state(&self, lobby: &str) -> ServerMessage {
+        ServerMessage::LobbyState {
+            lobby: lobby.to_string(),
+            challenges: self
+                .members
+                .iter()
+                .filter(|member| member.lobby == lobby)
+                .filter_map(|member| member.challenge.clone())
+                .collect(),
+        }
+    }
+
+    /// Sends `message` to the member at `index`, taking them out of the lobby if they can't be
+    /// reached. Returns whether they were.
+    async fn send(&mut self, index: usize, message: &ServerMessage) -> bool {
+        let member = &mut self.members[index];
+        match member.player.connection.send(message).await {
+            Ok(()) => true,
+            Err(e) => {
+                info!(
+                    "{} left the {} lobby: {}",
+                    member.player.name, member.lobby, e
+                );
+                self.members.remove(index);
+                false
+            }
+        }
+    }
+
+    /// Shows everyone in `lobby` its challenges, dropping any who can't be reached. A dropped
+    /// challenger's challenge goes with them, so everyone's told again.
+    async fn broadcast(&mut self, lobby: &str) {
+        loop {
+            let state = self.state(lobby);
+            let mut index = 0;
+            let mut dropped_challenge = false;
+            while index < self.members.len() {
+                if self.members[index].lobby != lobby {
+                    index += 1;
+                    continue;
+                }
+                let challenger = self.members[index].challenge.is_some();
+                if self.send(index, &state).await {
+                    index += 1;
+                } else {
+                    dropped_challenge |= challenger;
+                }
+            }
+            if !dropped_challenge {
+                break;
+            }
+        }
+    }
+}
+
+/// Whether a challenge for `setup` is for a handicap game.
+fn is_handicap(setup: Option<SetupKind>) -> bool {
+    matches!(setup, Some(SetupKind::Handicap { .. }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        ClientRequest, ServerMessage,
+        logic::{SetupKind, TimeControl},
+    };
+
+    use super::{
+        super::{
+            matchmaking::tests::{Client, message, player, request},
+            ratings::{HANDICAP_NOT_LOGGED_IN, NOT_LOGGED_IN},
+        },
+        Lobbies,
+    };
+
+    /// Has a player called `name`, logged in to an account of that name if `logged_in`, join
+    /// `lobby`, and checks they're shown its challenges.
+    async fn join(lobbies: &mut Lobbies, name: &str, logged_in: bool, lobby: &str) -> Client {
+        let (mut player, mut client) = player(name, Duration::ZERO).await;
+        player.account = logged_in.then(|| name.to_string());
+        lobbies.join(player, lobby.to_string()).await;
+        assert!(matches!(
+            message(&mut client).await,
+            ServerMessage::LobbyState { lobby: shown, .. } if shown == lobby
+        ));
+        client
+    }
+
+    /// The challenges in the last state `client` was shown, by who posted them.
+    async fn challengers(client: &mut Client) -> Vec<String> {
+        match message(client).await {
+            ServerMessage::LobbyState { challenges, .. } => challenges
+                .into_iter()
+                .map(|challenge| challenge.player_name)
+                .collect(),
+            other => panic!("Expected the lobby's state, got {other:?}"),
+        }
+    }
+
+    async fn refusal(client: &mut Client) -> String {
+        match message(client).await {
+            ServerMessage::RequestFailed { reason } => reason,
+            other => panic!("Expected a refusal, got {other:?}"),
+        }
+    }
+
+    fn challenge(setup: Option<SetupKind>, rated: bool) -> ClientRequest {
+        ClientRequest::PostChallenge {
+            setup,
+            time_control: Some(TimeControl::new(5, 0)),
+            rated,
+        }
+    }
+
+    #[tokio::test]
+    async fn challenges_are_shown_and_accepted_within_their_lobby() {
+        let mut lobbies = Lobbies::default();
+        let mut alice = join(&mut lobbies, "alice", false, "blitz").await;
+        let mut bob = join(&mut lobbies, "bob", false, "blitz").await;
+        assert!(challengers(&mut alice).await.is_empty());
+        let _carol = join(&mut lobbies, "carol", false, "classical").await;
+
+        let posted = challenge(Some(SetupKind::Imhotep), false);
+        assert!(lobbies.handle(0, Ok(posted)).await.is_none());
+        assert_eq!(challengers(&mut alice).await, ["alice"]);
+        assert_eq!(challengers(&mut bob).await, ["alice"]);
+        assert!(matches!(
+            lobbies.state("classical"),
+            ServerMessage::LobbyState { challenges, .. } if challenges.is_empty()
+        ));
+
+        request(&mut bob, ClientRequest::AcceptChallenge { id: 0 }).await;
+        let (index, accepted) = lobbies.next_request().await;
+        assert_eq!(index, 1);
+        let [challenger, accepter] = lobbies.handle(index, accepted).await.unwrap();
+        assert_eq!(
+            (challenger.name.as_str(), accepter.name.as_str()),
+            ("alice", "bob")
+        );
+        for player in [&challenger, &accepter] {
+            assert_eq!(player.preferred_setup, Some(SetupKind::Imhotep));
+            assert_eq!(player.time_control, Some(TimeControl::new(5, 0)));
+        }
+        assert_eq!(lobbies.members.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rated_and_handicap_games_need_everyone_logged_in() {
+        let mut lobbies = Lobbies::default();
+        let mut alice = join(&mut lobbies, "alice", true, "rated").await;
+        let mut bob = join(&mut lobbies, "bob", false, "rated").await;
+        assert!(challengers(&mut alice).await.is_empty());
+
+        let handicap = Some(SetupKind::Handicap { pieces_removed: 1 });
+        assert!(
+            lobbies
+                .handle(1, Ok(challenge(handicap, false)))
+                .await
+                .is_none()
+        );
+        assert_eq!(refusal(&mut bob).await, HANDICAP_NOT_LOGGED_IN);
+
+        for (id, posted, reason) in [
+            (0, challenge(None, true), NOT_LOGGED_IN),
+            (1, challenge(handicap, false), HANDICAP_NOT_LOGGED_IN),
+        ] {
+            assert!(lobbies.handle(0, Ok(posted)).await.is_none());
+            assert_eq!(challengers(&mut alice).await, ["alice"]);
+            assert_eq!(challengers(&mut bob).await, ["alice"]);
+            let accepted = ClientRequest::AcceptChallenge { id };
+            assert!(lobbies.handle(1, Ok(accepted)).await.is_none());
+            assert_eq!(refusal(&mut bob).await, reason);
+        }
+        assert_eq!(lobbies.members.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unplayable_challenges_are_refused() {
+        let mut lobbies = Lobbies::default();
+        let mut alice = join(&mut lobbies, "alice", false, "blitz").await;
+        let endless = ClientRequest::PostChallenge {
+            setup: None,
+            time_control: Some(TimeControl::new(0, 0)),
+            rated: false,
+        };
+        assert!(lobbies.handle(0, Ok(endless)).await.is_none());
+        refusal(&mut alice).await;
+        assert!(lobbies.members[0].challenge.is_none());
+    }
+
+    #[tokio::test]
+    async fn connecting_again_replaces_the_old_connection() {
+        let mut lobbies = Lobbies::default();
+        let mut first = join(&mut lobbies, "alice", true, "blitz").await;
+        assert!(
+            lobbies
+                .handle(0, Ok(challenge(None, false)))
+                .await
+                .is_none()
+        );
+        assert_eq!(challengers(&mut first).await, ["alice"]);
+
+        let _second = join(&mut lobbies, "alice", true, "blitz").await;
+        refusal(&mut first).await;
+        assert_eq!(lobbies.members.len(), 1);
+        assert!(lobbies.members[0].challenge.is_none());
+    }
+}
@@ -0,0 +1,1084 @@
+//! Pairing up players as they connect and starting their games.
+
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::extract::ws::{Message, WebSocket};
+use tokio::{
+    sync::mpsc::{self, UnboundedSender, error::SendError},
+    time::{interval_at, sleep_until, timeout_at},
+};
+use tracing::{Instrument, error, info, info_span, warn};
+
+use crate::{
+    ClientRequest, Envelope, ServerMessage,
+    ai::Difficulty,
+    logic::{Board, Player, RulesConfig, SetupError, SetupKind, TimeControl},
+};
+
+use super::{
+    FinishedGame, GameSession, GameStore, OngoingGame, PlayerConnection, PlayerHandle,
+    ServerConfig,
+    bot::Bot,
+    correspondence::{Correspondence, check_token},
+    guests::{AccountInUse, AccountsInUse},
+    lobby::Lobbies,
+    metrics::{Gauged, Metrics},
+    queue::{Place, Queue},
+    ratings::{HANDICAP_NOT_LOGGED_IN, NOT_LOGGED_IN, Ratings},
+    shutdown::Shutdown,
+    storage,
+};
+
+/// Games in progress that players can resume, by session token, with the game and player each
+/// token is for and where their game takes new connections.
+type Resumable = Arc<Mutex<HashMap<String, (u64, Player, UnboundedSender<(Player, Connection)>)>>>;
+
+/// Everyone's ratings, shared by all the games updating them.
+pub(super) type SharedRatings = Arc<Mutex<Ratings>>;
+
+/// What all games share: the ids they're given, how they're resumed, the ratings they update, where
+/// they're kept once they're over and when to stop them. Correspondence games need somewhere to be
+/// kept, so they're only played with a store.
+#[derive(Clone)]
+struct Games {
+    /// The id the next live game is played under. Correspondence games have the id they're kept
+    /// under instead, and are never played on the same connection as a live one.
+    next_id: Arc<AtomicU64>,
+    /// What's counted for `/metrics`, including how many live games are being played.
+    metrics: Arc<Metrics>,
+    /// The time control for games nobody asked for one in.
+    default_time_control: Option<TimeControl>,
+    reconnect_grace: Option<Duration>,
+    resumable: Resumable,
+    ratings: SharedRatings,
+    /// The accounts players are waiting for live games or playing them with.
+    in_use: AccountsInUse,
+    store: Option<Arc<dyn GameStore>>,
+    correspondence: Option<Correspondence>,
+    /// Held until everything's wrapped up, so the server knows when it's done shutting down.
+    shutdown: Shutdown,
+}
+
+pub(super) fn fmt_addr(addr: Option<IpAddr>) -> String {
+    addr.map_or_else(|| "unknown address".to_string(), |ip| ip.to_string())
+}
+
+pub(super) struct ConnectedPlayer {
+    pub(super) connection: Connection,
+    pub(super) name: String,
+    /// The account this player logged in to with its token, if they did. It's the only way to
+    /// tell it's the same player on another connection, since anyone can give any name.
+    pub(super) account: Option<String>,
+    /// Holds `account` in use until this player's game is over, so it isn't renamed from under
+    /// them.
+    in_use: Option<AccountInUse>,
+    /// The client's real address, if it could be determined.
+    addr: Option<IpAddr>,
+    pub(super) preferred_setup: Option<SetupKind>,
+    /// The time control this player wants to play. They're only paired with players who want
+    /// the same one or don't mind.
+    pub(super) time_control: Option<TimeControl>,
+    /// The rules this player wants to play by. They're only paired with players who want the
+    /// same.
+    pub(super) rules: RulesConfig,
+    /// Whether this player wants a rated game. They're only paired with players who want the
+    /// same.
+    pub(super) rated: bool,
+    /// This player's rating, looked up once they're done setting up. `None` for bots and
+    /// players who didn't log in, who have no account to keep one for.
+    pub(super) rating: Option<i32>,
+    /// When this player finished setting up and started looking for a game.
+    ready_since: Instant,
+    /// The bot this player asked to play instead of another player.
+    wants_bot: Option<Difficulty>,
+    /// The lobby this player asked to join instead of the queue.
+    lobby: Option<String>,
+}
+
+impl ConnectedPlayer {
+    /// The setup this player and `opponent` would play: the one they asked for unless they asked
+    /// for different ones, in which case the classic setup. Two players who both want a random
+    /// setup get this player's. A handicap needs both players' ratings to say who gives the odds,
+    /// so with anyone unrated, say a bot, it's the classic setup too.
+    pub(super) fn setup_with(&self, opponent: &ConnectedPlayer) -> SetupKind {
+        let setup = match (self.preferred_setup, opponent.preferred_setup) {
+            (Some(a @ SetupKind::Random { .. }), Some(SetupKind::Random { .. })) => a,
+            (Some(a), Some(b)) if a != b => SetupKind::default(),
+            (a, b) => a.or(b).unwrap_or_default(),
+        };
+        let unrated = self.rating.is_none() || opponent.rating.is_none();
+        if unrated && matches!(setup, SetupKind::Handicap { .. }) {
+            return SetupKind::default();
+        }
+        setup
+    }
+
+    /// Checks this player, as player 1, and `opponent` could start a game: the setup they'd play
+    /// has to work under this player's rules, which they only agree on if they're the same.
+    pub(super) fn can_play(&self, opponent: &ConnectedPlayer) -> Result<(), SetupError> {
+        Board::from_setup(self.setup_with(opponent), &self.rules).validate(&self.rules)
+    }
+
+    /// A bot hosted by the server, to play someone nobody else is there to play by `rules`.
+    fn bot(difficulty: Difficulty, rules: RulesConfig) -> Self {
+        Self {
+            connection: Connection::Bot(Box::new(Bot::new(difficulty))),
+            name: format!("{difficulty} bot"),
+            account: None,
+            in_use: None,
+            addr: None,
+            preferred_setup: None,
+            time_control: None,
+            rules,
+            rated: false,
+            rating: None,
+            ready_since: Instant::now(),
+            wants_bot: None,
+            lobby: None,
+        }
+    }
+}
+
+/// How often players waiting for an opponent are told how many others are waiting.
+const QUEUE_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many pings in a row a player can leave unanswered before their connection is given up on.
+const MISSED_PONGS: u32 = 2;
+
+/// What heartbeat pings carry, so their pongs can be told apart from any others.
+const HEARTBEAT_PAYLOAD: &[u8] = b"laser-chess-heartbeat";
+
+/// How the server talks to a player: over their WebSocket, or directly to a bot it's hosting.
+pub(super) enum Connection {
+    Socket(Box<Socket>),
+    Bot(Box<Bot>),
+}
+
+/// A player's WebSocket, pinged while the game waits on them if there's a heartbeat.
+pub(super) struct Socket {
+    socket: WebSocket,
+    /// The live game played on this connection, once it's started. Everything sent is about it,
+    /// and requests about any other game are ignored.
+    game_id: Option<u64>,
+    heartbeat_interval: Option<Duration>,
+    /// Round-trip time to the player, measured at setup and kept up to date by the heartbeat.
+    latency: Duration,
+    /// When the oldest heartbeat ping still waiting for its pong was sent.
+    ping_sent: Option<Instant>,
+    /// When anything last arrived from the player.
+    last_seen: Instant,
+    /// When the player is next pinged. Kept here since the game drops the future listening to a
+    /// player whenever their opponent gets in first.
+    next_ping: Instant,
+    /// Counts the connection as open until it's dropped.
+    _open: Gauged,
+}
+
+impl Socket {
+    /// A socket to a player `latency` away, or `Duration::ZERO` if that's not known yet.
+    fn new(
+        socket: WebSocket,
+        heartbeat_interval: Option<Duration>,
+        latency: Duration,
+        open: Gauged,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            socket,
+            game_id: None,
+            heartbeat_interval,
+            latency,
+            ping_sent: None,
+            last_seen: now,
+            next_ping: now + heartbeat_interval.unwrap_or_default(),
+            _open: open,
+        }
+    }
+
+    /// Waits for the player's next text message, sending pings on the way. Fails once the player
+    /// has gone quiet for too many heartbeats.
+    async fn recv_text(&mut self) -> anyhow::Result<String> {
+        loop {
+            let message = match self.heartbeat_interval {
+                Some(interval) => tokio::select! {
+                    message = self.socket.recv() => message,
+                    _ = sleep_until(self.next_ping.into()) => {
+                        if self.last_seen.elapsed() > interval * MISSED_PONGS {
+                            anyhow::bail!("No answer to the last {} pings", MISSED_PONGS);
+                        }
+                        let now = Instant::now();
+                        self.next_ping = now + interval;
+                        self.ping_sent.get_or_insert(now);
+                        self.socket.send(Message::Ping(HEARTBEAT_PAYLOAD.into())).await?;
+                        continue;
+                    }
+                },
+                None => self.socket.recv().await,
+            };
+            self.last_seen = Instant::now();
+            match message {
+                Some(Ok(Message::Text(text))) => break Ok(text.to_string()),
+                Some(Ok(Message::Pong(data))) if data == HEARTBEAT_PAYLOAD => {
+                    if let Some(sent) = self.ping_sent.take() {
+                        // Smooth it out, so one slow pong doesn't change much
+                        self.latency = (self.latency * 3 + sent.elapsed()) / 4;
+                    }
+                }
+                // Axum answers pings itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(_)) => {
+                    anyhow::bail!("Expected text message, got different message")
+                }
+                Some(Err(e)) => anyhow::bail!("WebSocket error: {}", e),
+                None => anyhow::bail!("Connection closed"),
+            }
+        }
+    }
+
+    /// Waits for the player's next request, with the game it's about.
+    pub(super) async fn recv(&mut self) -> anyhow::Result<Envelope<ClientRequest>> {
+        Ok(serde_json::from_str(&self.recv_text().await?)?)
+    }
+
+    /// Sends the player `message` about game `game_id`, if it's about one.
+    pub(super) async fn send(
+        &mut self,
+        game_id: Option<u64>,
+        message: &ServerMessage,
+    ) -> anyhow::Result<()> {
+        let envelope = Envelope::new(game_id, message);
+        let text = serde_json::to_string(&envelope)?;
+        Ok(self.socket.send(Message::text(text)).await?)
+    }
+
+    pub(super) async fn close(&mut self) {
+        let _ = self.socket.send(Message::Close(None)).await;
+    }
+}
+
+impl Connection {
+    /// Round-trip time to the player, as of the last heartbeat. Bots are right here.
+    pub(super) fn latency(&self) -> Duration {
+        match self {
+            Connection::Socket(socket) => socket.latency,
+            Connection::Bot(_) => Duration::ZERO,
+        }
+    }
+
+    /// Makes this the connection to game `game_id`, so everything sent on it is about that game.
+    fn join(&mut self, game_id: u64) {
+        if let Connection::Socket(socket) = self {
+            socket.game_id = Some(game_id);
+        }
+    }
+}
+
+impl PlayerConnection for Connection {
+    async fn send(&mut self, message: &ServerMessage) -> anyhow::Result<()> {
+        match self {
+            Connection::Socket(socket) => socket.send(socket.game_id, message).await?,
+            Connection::Bot(bot) => bot.receive(message),
+        }
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> anyhow::Result<ClientRequest> {
+        match self {
+            Connection::Socket(socket) => loop {
+                let request = socket.recv().await?;
+                match request.game_id {
+                    Some(id) if socket.game_id != Some(id) => {
+                        warn!("Ignoring a request about game {}, not played here", id)
+                    }
+                    _ => break Ok(request.message),
+                }
+            },
+            Connection::Bot(bot) => bot.next_request().await,
+        }
+    }
+
+    async fn close(&mut self) {
+        if let Connection::Socket(socket) = self {
+            socket.close().await;
+        }
+    }
+}
+
+/// Awaits a player connection, awaits a setup packet, then returns either the [`ConnectedPlayer`]
+/// or the setup error. A connection asking to resume a game is handed to that game instead, and
+/// one logging in for correspondence play is served until it leaves, both returning `None`. Either
+/// way, the connection is pinged every `heartbeat_interval` once the game is listening to it, and
+/// counted as open by `open` until it's dropped. A connection that hasn't set up within
+/// `setup_timeout` is dropped, so it can't hang around forever.
+async fn connect_player(
+    mut connection: WebSocket,
+    open: Gauged,
+    addr: Option<IpAddr>,
+    games: &Games,
+    heartbeat_interval: Option<Duration>,
+    setup_timeout: Option<Duration>,
+) -> anyhow::Result<Option<ConnectedPlayer>> {
+    let deadline = setup_timeout.map(|timeout| Instant::now() + timeout);
+    let mut shutdown = games.shutdown.clone();
+    let setup = tokio::select! {
+        setup = connection.recv() => setup,
+        () = shutdown.requested() => {
+            let _ = connection.send(Message::Close(None)).await;
+            anyhow::bail!("The server is shutting down");
+        }
+        _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+            let _ = connection.send(Message::Close(None)).await;
+            anyhow::bail!("Nothing sent to set up within {:?}", setup_timeout.unwrap());
+        }
+    };
+    match setup {
+        Some(Ok(Message::Text(text))) => {
+            let setup: Envelope<ClientRequest> = serde_json::from_str(&text)?;
+            match setup.message {
+                ClientRequest::InitialSetup {
+                    player_name,
+                    setup,
+                    bot,
+                    time_control,
+                    rules,
+                    lobby,
+                    rated,
+                    token,
+                } => {
+                    // Clocks are only good for so long, so don't take time controls past that
+                    if let Some(Err(reason)) = time_control.map(|control| control.check()) {
+                        refuse(&mut connection, reason.clone()).await;
+                        anyhow::bail!("{} asked for a bad time control: {}", player_name, reason);
+                    }
+                    let account = match token {
+                        Some(token) => {
+                            let Some(store) = &games.store else {
+                                let reason = "This server doesn't keep accounts".to_string();
+                                refuse(&mut connection, reason).await;
+                                anyhow::bail!("{} tried to log in to play", player_name);
+                            };
+                            if !check_token(store, &player_name, &token).await? {
+                                refuse(&mut connection, "Wrong name or token".to_string()).await;
+                                anyhow::bail!("{} gave the wrong token", player_name);
+                            }
+                            Some(player_name.clone())
+                        }
+                        None => None,
+                    };
+                    let in_use = account.as_ref().map(|account| games.in_use.hold(account));
+                    if rated && account.is_none() {
+                        refuse(&mut connection, NOT_LOGGED_IN.to_string()).await;
+                        anyhow::bail!("{} asked for a rated game without logging in", player_name);
+                    }
+                    if matches!(setup, Some(SetupKind::Handicap { .. })) && account.is_none() {
+                        refuse(&mut connection, HANDICAP_NOT_LOGGED_IN.to_string()).await;
+                        anyhow::bail!("{} asked for a handicap without logging in", player_name);
+                    }
+                    // Rules nobody can start a game under would leave the player waiting forever
+                    let rules = rules.map_or_else(RulesConfig::default, |rules| *rules);
+                    let board = Board::from_setup(setup.unwrap_or_default(), &rules);
+                    if let Err(e) = board.validate(&rules) {
+                        refuse(&mut connection, format!("Those rules don't work: {e}")).await;
+                        anyhow::bail!("{} asked for rules that don't work: {}", player_name, e);
+                    }
+                    let latency = match deadline {
+                        Some(deadline) => {
+                            timeout_at(deadline.into(), measure_latency(&mut connection))
+                                .await
+                                .map_err(|_| {
+                                    anyhow::anyhow!("No answer to the latency check in time")
+                                })??
+                        }
+                        None => measure_latency(&mut connection).await?,
+                    };
+                    info!(
+                        "{} ({}) connected with {:?} latency",
+                        player_name,
+                        fmt_addr(addr),
+                        latency
+                    );
+                    Ok(Some(ConnectedPlayer {
+                        connection: Connection::Socket(Box::new(Socket::new(
+                            connection,
+                            heartbeat_interval,
+                            latency,
+                            open,
+                        ))),
+                        name: player_name,
+                        account,
+                        in_use,
+                        addr,
+                        preferred_setup: setup,
+                        time_control,
+                        rules,
+                        rated,
+                        rating: None,
+                        ready_since: Instant::now(),
+                        wants_bot: bot,
+                        lobby,
+                    }))
+                }
+                ClientRequest::Resume { token } => {
+                    let game = games
+                        .resumable
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .find(|(resumes, _)| tokens_match(resumes, &token))
+                        .map(|(_, game)| game.clone());
+                    let Some((game_id, player, game)) = game else {
+                        anyhow::bail!("No game to resume for that token");
+                    };
+                    info!("{} is resuming game {}", fmt_addr(addr), game_id);
+                    let mut connection = Connection::Socket(Box::new(Socket::new(
+                        connection,
+                        heartbeat_interval,
+                        Duration::ZERO,
+                        open,
+                    )));
+                    connection.join(game_id);
+                    game.send((player, connection))
+                        .map_err(|_| anyhow::anyhow!("The game to resume has ended"))?;
+                    Ok(None)
+                }
+                ClientRequest::Login { player_name, token } => {
+                    let mut connection =
+                        Socket::new(connection, heartbeat_interval, Duration::ZERO, open);
+                    let Some(correspondence) = games.correspondence.clone() else {
+                        let reason = "This server doesn't keep correspondence games".to_string();
+                        connection
+                            .send(None, &ServerMessage::RequestFailed { reason })
+                            .await?;
+                        connection.close().await;
+                        anyhow::bail!("{} asked for correspondence play", player_name);
+                    };
+                    info!(
+                        "{} ({}) is logging in for correspondence play",
+                        player_name,
+                        fmt_addr(addr)
+                    );
+                    correspondence.serve(connection, player_name, token).await;
+                    Ok(None)
+                }
+                _ => Err(anyhow::anyhow!(
+                    "Expected InitialSetup message, got different message"
+                )),
+            }
+        }
+        Some(Ok(_)) => Err(anyhow::anyhow!(
+            "Expected text message for setup, got different message"
+        )),
+        Some(Err(e)) => Err(anyhow::anyhow!("WebSocket error during setup: {}", e)),
+        None => Err(anyhow::anyhow!("Connection closed during setup")),
+    }
+}
+
+/// Tells a connection that's still setting up why it's being turned away, and closes it.
+async fn refuse(connection: &mut WebSocket, reason: String) {
+    let refusal = Envelope::new(None, ServerMessage::RequestFailed { reason });
+    if let Ok(text) = serde_json::to_string(&refusal) {
+        let _ = connection.send(Message::text(text)).await;
+    }
+    let _ = connection.send(Message::Close(None)).await;
+}
+
+/// Sends a ping and waits for the matching pong, returning the round-trip time. Any other message
+/// received in the meantime is an error, since the client shouldn't be sending anything until the
+/// game starts.
+async fn measure_latency(connection: &mut WebSocket) -> anyhow::Result<Duration> {
+    let payload = b"laser-chess-latency";
+    let sent = Instant::now();
+    connection.send(Message::Ping(payload[..].into())).await?;
+    loop {
+        match connection.recv().await {
+            Some(Ok(Message::Pong(data))) if data == payload[..] => break Ok(sent.elapsed()),
+            Some(Ok(Message::Pong(_))) => {}
+            Some(Ok(_)) => {
+                break Err(anyhow::anyhow!(
+                    "Expected pong during latency check, got different message"
+                ));
+            }
+            Some(Err(e)) => break Err(anyhow::anyhow!("WebSocket error during setup: {}", e)),
+            None => break Err(anyhow::anyhow!("Connection closed during setup")),
+        }
+    }
+}
+
+/// Something [`matchmaking_loop`] was waiting for.
+enum Event {
+    /// A new connection, yet to set up.
+    Connected(Box<WebSocket>, Option<IpAddr>),
+    /// A player done setting up, looking for an opponent.
+    Ready(ConnectedPlayer),
+    /// A request from the waiting player at this place in the queue, or why there won't be one.
+    Request(Place, anyhow::Result<ClientRequest>),
+    /// The same from the lobby member at this index.
+    LobbyRequest(usize, anyhow::Result<ClientRequest>),
+    /// Time to tell the waiting players how many are waiting.
+    StatusDue,
+    /// The player who's waited longest has waited long enough, and gets a bot at this difficulty.
+    GiveUp(Difficulty),
+    /// The server is shutting down.
+    ShuttingDown,
+}
+
+/// Matchmaking loop that pairs up players. When a player opens a connection to the server, it gets
+/// tossed into the channel sender. Each one finishes setting up on its own task and comes back to
+/// wait for an opponent in the [`Queue`]: whoever's rated closest to them among the waiting players
+/// who want the same kind of game, or a bot if they asked for one. With a bot timeout configured, a
+/// player left waiting that long gets a bot instead. Players who asked for a lobby go there to
+/// choose their own opponent. Waiting players are listened to, so those who cancel or whose
+/// connection fails leave the queue, and are kept up to date with how many are waiting. It ends
+/// when the server shuts down, turning away everyone still waiting.
+pub(super) async fn matchmaking_loop(
+    mut matchmaking_rx: mpsc::UnboundedReceiver<(WebSocket, Option<IpAddr>)>,
+    config: ServerConfig,
+    ratings: SharedRatings,
+    in_use: AccountsInUse,
+    store: Option<Arc<dyn GameStore>>,
+    metrics: Arc<Metrics>,
+    mut shutdown: Shutdown,
+) {
+    info!("Matchmaking loop started");
+
+    let games = Games {
+        next_id: Arc::new(AtomicU64::new(1)),
+        metrics: metrics.clone(),
+        default_time_control: config.default_time_control,
+        reconnect_grace: config.reconnect_grace,
+        resumable: Resumable::default(),
+        ratings,
+        in_use,
+        correspondence: store
+            .clone()
+            .map(|store| Correspondence::new(store, metrics, shutdown.clone())),
+        store,
+        shutdown: shutdown.clone(),
+    };
+    let bot_timeout = config
+        .bot_timeout
+        .map(|timeout| (timeout, config.bot_difficulty));
+
+    let (ready_tx, mut ready_rx) = mpsc::unbounded_channel::<ConnectedPlayer>();
+    let mut queue = Queue::default();
+    let mut lobbies = Lobbies::default();
+    // Players are told as soon as they join, so the first status is due an interval later
+    let mut queue_status = interval_at(
+        (Instant::now() + QUEUE_STATUS_INTERVAL).into(),
+        QUEUE_STATUS_INTERVAL,
+    );
+    loop {
+        let give_up = match (queue.longest_wait(), bot_timeout) {
+            (Some(since), Some((timeout, difficulty))) => Some((since + timeout, difficulty)),
+            _ => None,
+        };
+        let timeout = async {
+            match give_up {
+                Some((deadline, difficulty)) => {
+                    sleep_until(deadline.into()).await;
+                    difficulty
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let event = tokio::select! {
+            connection = matchmaking_rx.recv() => match connection {
+                Some((conn, addr)) => Event::Connected(Box::new(conn), addr),
+                None => {
+                    warn!("Matchmaking channel closed");
+                    break;
+                }
+            },
+            Some(player) = ready_rx.recv() => Event::Ready(player),
+            (place, request) = queue.next_request() => Event::Request(place, request),
+            (index, request) = lobbies.next_request() => Event::LobbyRequest(index, request),
+            _ = queue_status.tick(), if !queue.is_empty() => Event::StatusDue,
+            difficulty = timeout => Event::GiveUp(difficulty),
+            () = shutdown.requested() => Event::ShuttingDown,
+        };
+        match event {
+            Event::Connected(conn, addr) => {
+                info!("Player connected, awaiting setup");
+                let ready_tx = ready_tx.clone();
+                let games = games.clone();
+                let (heartbeat_interval, setup_timeout) =
+                    (config.heartbeat_interval, config.setup_timeout);
+                let open = games.metrics.socket_opened();
+                tokio::spawn(async move {
+                    let connecting = connect_player(
+                        *conn,
+                        open,
+                        addr,
+                        &games,
+                        heartbeat_interval,
+                        setup_timeout,
+                    );
+                    match connecting.await {
+                        Ok(Some(player)) => {
+                            // Matchmaking has stopped if the server is shutting down
+                            if let Err(SendError(mut player)) = ready_tx.send(player) {
+                                player.connection.close().await;
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => info!("Player setup failed: {}", e),
+                    }
+                });
+            }
+            Event::Ready(mut player) => {
+                if let Some(max_games) = config.max_games
+                    && games.metrics.active_games() >= max_games
+                {
+                    info!("Turning {} away, the server is full", player.name);
+                    let reason = "The server is full. Please try again later".to_string();
+                    let _ = player
+                        .connection
+                        .send(&ServerMessage::RequestFailed { reason })
+                        .await;
+                    player.connection.close().await;
+                    continue;
+                }
+                player.rating = player
+                    .account
+                    .as_ref()
+                    .map(|account| games.ratings.lock().unwrap().get(account));
+                if let Some(difficulty) = player.wants_bot {
+                    info!("{} asked to play the {} bot", player.name, difficulty);
+                    let bot = ConnectedPlayer::bot(difficulty, player.rules.clone());
+                    tokio::spawn(start_game([player, bot], games.clone()));
+                    continue;
+                }
+                if let Some(lobby) = player.lobby.take() {
+                    lobbies.join(player, lobby).await;
+                    continue;
+                }
+                if let Some(players) = queue.join(player).await {
+                    tokio::spawn(start_game(players, games.clone()));
+                }
+            }
+            Event::Request(place, request) => queue.handle(place, request).await,
+            Event::LobbyRequest(index, request) => {
+                if let Some(players) = lobbies.handle(index, request).await {
+                    tokio::spawn(start_game(players, games.clone()));
+                }
+            }
+            Event::StatusDue => queue.send_status().await,
+            Event::GiveUp(difficulty) => {
+                // The timeout only finishes while someone is waiting
+                let player = queue.take_longest().await.unwrap();
+                info!(
+                    "No opponent for {}, pairing them with the {} bot",
+                    player.name, difficulty
+                );
+                let bot = ConnectedPlayer::bot(difficulty, player.rules.clone());
+                tokio::spawn(start_game([player, bot], games.clone()));
+            }
+            Event::ShuttingDown => {
+                info!("Shutting down, turning away everyone waiting for a game");
+                let reason = "The server is shutting down".to_string();
+                let waiting: Vec<_> = queue.drain().chain(lobbies.drain()).collect();
+                for mut player in waiting {
+                    let _ = player
+                        .connection
+                        .send(&ServerMessage::RequestFailed {
+                            reason: reason.clone(),
+                        })
+                        .await;
+                    player.connection.close().await;
+                }
+                break;
+            }
+        }
+    }
+
+    info!("Matchmaking loop ended");
+}
+
+/// Gives a game between two players its id and plays it, with everything it logs in a `game`
+/// span carrying the id and the players' names, so one game's story can be picked out of the
+/// logs.
+async fn start_game(players: [ConnectedPlayer; 2], games: Games) -> anyhow::Result<()> {
+    let [player1, player2] = seat(players);
+    let game_id = games.next_id.fetch_add(1, Ordering::Relaxed);
+    let span = info_span!(
+        "game",
+        id = game_id,
+        player1 = %player1.name,
+        player2 = %player2.name
+    );
+    play_game(game_id, [player1, player2], games)
+        .instrument(span)
+        .await
+}
+
+/// Puts two players about to play in the order they'll play in. Handicap setups take pieces off
+/// player 1, so in a handicap game the higher-rated player goes first, whoever asked for it.
+fn seat([player1, player2]: [ConnectedPlayer; 2]) -> [ConnectedPlayer; 2] {
+    let handicap = matches!(player1.setup_with(&player2), SetupKind::Handicap { .. });
+    if handicap && player2.rating > player1.rating {
+        [player2, player1]
+    } else {
+        [player1, player2]
+    }
+}
+
+/// Plays game `game_id` between two players, updating their ratings afterwards if both logged in
+/// and asked for a rated game, and keeping it if there's a store. With a reconnect grace period,
+/// players connected over WebSockets get a token to resume the game with if they lose their
+/// connection. If the server shuts down first, a game between two players is kept as a
+/// correspondence game to finish later, if there's a store, and the players are told which.
+async fn play_game(
+    game_id: u64,
+    [mut player1, mut player2]: [ConnectedPlayer; 2],
+    games: Games,
+) -> anyhow::Result<()> {
+    let _active = games.metrics.game_started();
+    // Given up once the game's kept, with the ratings it changed
+    let _in_use = [player1.in_use.take(), player2.in_use.take()];
+    for player in [&player1, &player2] {
+        if matches!(player.connection, Connection::Socket(_)) {
+            games.metrics.waited(player.ready_since.elapsed());
+        }
+    }
+    info!(
+        "Starting new game {} between {} ({}) and {} ({})",
+        game_id,
+        player1.name,
+        fmt_addr(player1.addr),
+        player2.name,
+        fmt_addr(player2.addr)
+    );
+
+    let setup = player1.setup_with(&player2);
+    // Players are only paired if their game works, but if it doesn't, let them know rather than
+    // leave them waiting on a game that never starts
+    if let Err(e) = player1.can_play(&player2) {
+        error!("Refusing to start a game from the {} setup: {}", setup, e);
+        let reason = format!("Your game couldn't start: {e}");
+        for mut player in [player1, player2] {
+            let failed = ServerMessage::RequestFailed {
+                reason: reason.clone(),
+            };
+            let _ = player.connection.send(&failed).await;
+            player.connection.close().await;
+        }
+        return Err(e.into());
+    }
+    info!("Playing the {} setup", setup);
+    // Players are only paired if they agree, so whoever asked for a time control gets it
+    let time_control = player1
+        .time_control
+        .or(player2.time_control)
+        .or(games.default_time_control);
+    let rules = player1.rules.clone();
+    if let Some(control) = time_control {
+        info!("Playing with a {} time control", control);
+    }
+    // Ratings belong to accounts, which bots don't have
+    let accounts = match (&player1.account, &player2.account) {
+        (Some(account1), Some(account2)) if player1.rated && player2.rated => {
+            Some([account1.clone(), account2.clone()])
+        }
+        _ => None,
+    };
+    let rated = accounts.is_some();
+    if rated {
+        info!("Playing a rated game");
+    }
+    // Bots can't log in to play on by correspondence
+    let keepable = [&player1, &player2]
+        .iter()
+        .all(|player| matches!(player.connection, Connection::Socket(_)));
+    let (reconnect_tx, reconnect_rx) = mpsc::unbounded_channel();
+    let handle = |mut player: ConnectedPlayer, order: Player| {
+        player.connection.join(game_id);
+        let resumes =
+            games.reconnect_grace.is_some() && matches!(player.connection, Connection::Socket(_));
+        let session_token = resumes.then(|| {
+            let token = session_token();
+            games
+                .resumable
+                .lock()
+                .unwrap()
+                .insert(token.clone(), (game_id, order, reconnect_tx.clone()));
+            token
+        });
+        PlayerHandle {
+            name: player.name,
+            latency: player.connection.latency(),
+            rating: player.rating,
+            connection: player.connection,
+            session_token,
+        }
+    };
+    let players = [
+        handle(player1, Player::Player1),
+        handle(player2, Player::Player2),
+    ];
+    let tokens: Vec<String> = players
+        .iter()
+        .filter_map(|player| player.session_token.clone())
+        .collect();
+    // Don't start a game nobody can finish
+    let session = GameSession::new(setup, rules, players);
+    let result = match session {
+        Ok(session) => {
+            let session = match time_control {
+                Some(control) => session.time_control(control),
+                None => session,
+            };
+            let session = match games.reconnect_grace {
+                Some(grace) => session.reconnections(grace, reconnect_rx),
+                None => session,
+            };
+            let session = if rated { session.rated() } else { session };
+            let mut shutdown = games.shutdown.clone();
+            let mut session = session
+                .metrics(games.metrics.clone())
+                .until(async move { shutdown.requested().await });
+            let started_at = SystemTime::now();
+            let started = Instant::now();
+            match session.run().await {
+                Ok(None) => {
+                    let correspondence_id = match &games.store {
+                        Some(store) if keepable => {
+                            let game = OngoingGame {
+                                record: session.record(),
+                                started_at,
+                                conditional_moves: Vec::new(),
+                                days_per_move: None,
+                                series: None,
+                            };
+                            keep_unfinished(store.clone(), game).await
+                        }
+                        _ => None,
+                    };
+                    session
+                        .close_with(&ServerMessage::ShuttingDown { correspondence_id })
+                        .await;
+                    Ok(())
+                }
+                Ok(Some(result)) => {
+                    games.metrics.game_lasted(started.elapsed());
+                    let record = session.record();
+                    let ratings = accounts.as_ref().map(|[account1, account2]| {
+                        games
+                            .ratings
+                            .lock()
+                            .unwrap()
+                            .record([account1, account2], &result)
+                    });
+                    if let Some(store) = games.store {
+                        let game = FinishedGame {
+                            record,
+                            rated,
+                            ratings,
+                            started_at,
+                            ended_at: SystemTime::now(),
+                        };
+                        save_game(store, game).await;
+                    }
+                    Ok(())
+                }
+                Err(e) => {
+                    info!("Game abandoned: {}", e);
+                    Err(e)
+                }
+            }
+        }
+        Err(e) => {
+            error!("Refusing to start a game from the {} setup: {}", setup, e);
+            Err(e.into())
+        }
+    };
+    let mut resumable = games.resumable.lock().unwrap();
+    for token in tokens {
+        resumable.remove(&token);
+    }
+    result
+}
+
+/// Keeps `game`, stopped unfinished, in `store` as a correspondence game, returning its id. A game
+/// that can't be kept is logged and lost.
+async fn keep_unfinished(store: Arc<dyn GameStore>, game: OngoingGame) -> Option<u64> {
+    match storage::blocking(&store, move |store| store.start_ongoing(&game)).await {
+        Ok(id) => {
+            info!("Kept the unfinished game as correspondence game {}", id);
+            Some(id)
+        }
+        Err(e) => {
+            error!("Failed to keep the unfinished game: {}", e);
+            None
+        }
+    }
+}
+
+/// Keeps `game` in `store`. A game that can't be kept is logged and lost.
+async fn save_game(store: Arc<dyn GameStore>, game: FinishedGame) {
+    match storage::blocking(&store, move |store| store.save(&game)).await {
+        Ok(id) => info!("Saved the game as game {}", id),
+        Err(e) => error!("Failed to save the game: {}", e),
+    }
+}
+
+/// A random 128-bit token, straight from the OS's randomness so nobody can guess it.
+pub(super) fn session_token() -> String {
+    let mut bytes = [0; 16];
+    // Without randomness to draw on there's no safe token to hand out
+    getrandom::fill(&mut bytes).expect("the OS should have randomness to give");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Whether `a` and `b` are the same token. Every byte is compared however early they differ, so
+/// how long a wrong guess takes to turn down gives nothing away about the right one.
+pub(super) fn tokens_match(a: &str, b: &str) -> bool {
+    let difference = a
+        .bytes()
+        .zip(b.bytes())
+        .fold(0, |difference, (a, b)| black_box(difference | (a ^ b)));
+    a.len() == b.len() && difference == 0
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use axum::{Router, extract::ws::WebSocketUpgrade, routing::get};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::{net::TcpStream, sync::mpsc};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite};
+
+    use super::{ConnectedPlayer, Connection, Metrics, Socket, seat, session_token, tokens_match};
+    use crate::{
+        ClientRequest, Envelope, ServerMessage,
+        logic::{RulesConfig, SetupKind},
+    };
+
+    /// The client's end of a test player's WebSocket.
+    pub(in crate::server) type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// A player done setting up under `name` and looking for a casual game of anything, connected
+    /// over a WebSocket on loopback that matchmaking takes to be `latency` away.
+    pub(in crate::server) async fn player(
+        name: &str,
+        latency: Duration,
+    ) -> (ConnectedPlayer, Client) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sockets, mut accepted) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/",
+            get(move |upgrade: WebSocketUpgrade| async move {
+                upgrade.on_upgrade(move |socket| async move {
+                    let _ = sockets.send(socket);
+                })
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+            .await
+            .unwrap();
+        let socket = accepted.recv().await.unwrap();
+        let open = Arc::new(Metrics::default()).socket_opened();
+        let player = ConnectedPlayer {
+            connection: Connection::Socket(Box::new(Socket::new(socket, None, latency, open))),
+            name: name.to_string(),
+            account: None,
+            in_use: None,
+            addr: None,
+            preferred_setup: None,
+            time_control: None,
+            rules: RulesConfig::default(),
+            rated: false,
+            rating: None,
+            ready_since: Instant::now(),
+            wants_bot: None,
+            lobby: None,
+        };
+        (player, client)
+    }
+
+    /// The next message the server sent `client`, skipping pings.
+    pub(in crate::server) async fn message(client: &mut Client) -> ServerMessage {
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                tungstenite::Message::Text(text) => {
+                    let envelope: Envelope<ServerMessage> = serde_json::from_str(&text).unwrap();
+                    break envelope.message;
+                }
+                tungstenite::Message::Ping(_) => {}
+                other => panic!("Expected a message, got {other:?}"),
+            }
+        }
+    }
+
+    /// Sends `request` from `client`, about no game in particular.
+    pub(in crate::server) async fn request(client: &mut Client, request: ClientRequest) {
+        let text = serde_json::to_string(&Envelope::new(None, request)).unwrap();
+        client.send(tungstenite::Message::text(text)).await.unwrap();
+    }
+
+    #[test]
+    fn session_tokens_are_random_128_bit_hex() {
+        let (a, b) = (session_token(), session_token());
+        assert_eq!(a.len(), 32);
+        assert!(a.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn tokens_match_only_themselves() {
+        let token = session_token();
+        assert!(tokens_match(&token, &token.clone()));
+        assert!(!tokens_match(&token, &session_token()));
+        assert!(!tokens_match(&token, &token[..31]));
+        assert!(!tokens_match(&token, &format!("{token}0")));
+        assert!(!tokens_match(&token, ""));
+    }
+
+    /// Two players rated `ratings`, the first asking for a handicap and the second not minding
+    /// the setup.
+    async fn handicap_pair(ratings: [Option<i32>; 2]) -> [ConnectedPlayer; 2] {
+        let (mut asker, _) = player("asker", Duration::ZERO).await;
+        let (mut opponent, _) = player("opponent", Duration::ZERO).await;
+        asker.preferred_setup = Some(SetupKind::Handicap { pieces_removed: 2 });
+        [asker.rating, opponent.rating] = ratings;
+        [asker, opponent]
+    }
+
+    #[tokio::test]
+    async fn the_higher_rated_player_gives_the_odds() {
+        for players in [
+            handicap_pair([Some(1400), Some(1600)]).await,
+            handicap_pair([Some(1600), Some(1400)]).await,
+        ] {
+            let [player1, player2] = seat(players);
+            assert_eq!(player1.rating, Some(1600));
+            assert_eq!(
+                player1.setup_with(&player2),
+                SetupKind::Handicap { pieces_removed: 2 }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handicaps_need_both_ratings() {
+        for ratings in [[Some(1400), None], [None, Some(1400)]] {
+            let [player1, player2] = seat(handicap_pair(ratings).await);
+            assert_eq!(player1.name, "asker");
+            assert_eq!(player1.setup_with(&player2), SetupKind::Classic);
+        }
+    }
+}
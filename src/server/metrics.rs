@@ -0,0 +1,281 @@
+//! Counts of what the server is doing, served at `/metrics` in Prometheus's text format for
+//! operators to scrape.
+
+use std::{
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+
+/// Everything the server counts. Gauges go up and down, counters only up, and histograms count
+/// how many of what they measure fell in each bucket.
+pub(super) struct Metrics {
+    active_games: AtomicUsize,
+    connected_sockets: AtomicUsize,
+    moves: AtomicU64,
+    invalid_moves: AtomicU64,
+    matchmaking_wait: Histogram,
+    game_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            active_games: AtomicUsize::new(0),
+            connected_sockets: AtomicUsize::new(0),
+            moves: AtomicU64::new(0),
+            invalid_moves: AtomicU64::new(0),
+            matchmaking_wait: Histogram::new(&[1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+            game_duration: Histogram::new(&[60.0, 300.0, 600.0, 900.0, 1800.0, 3600.0, 7200.0]),
+        }
+    }
+}
+
+impl Metrics {
+    /// How many live games are being played.
+    pub(super) fn active_games(&self) -> usize {
+        self.active_games.load(Ordering::Relaxed)
+    }
+
+    /// Counts a live game as being played for as long as the guard is kept.
+    pub(super) fn game_started(self: &Arc<Self>) -> Gauged {
+        Gauged::new(self, |metrics| &metrics.active_games)
+    }
+
+    /// Counts a WebSocket as connected for as long as the guard is kept.
+    pub(super) fn socket_opened(self: &Arc<Self>) -> Gauged {
+        Gauged::new(self, |metrics| &metrics.connected_sockets)
+    }
+
+    pub(super) fn move_played(&self) {
+        self.moves.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn move_rejected(&self) {
+        self.invalid_moves.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that a player waited `wait` between finishing setting up and their game starting.
+    pub(super) fn waited(&self, wait: Duration) {
+        self.matchmaking_wait.observe(wait);
+    }
+
+    /// Records that a live game lasted `duration`.
+    pub(super) fn game_lasted(&self, duration: Duration) {
+        self.game_duration.observe(duration);
+    }
+
+    /// Everything, in Prometheus's text format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        metric(
+            "laser_chess_active_games",
+            "gauge",
+            "Live games being played.",
+            self.active_games() as u64,
+        );
+        metric(
+            "laser_chess_connected_sockets",
+            "gauge",
+            "WebSocket connections open.",
+            self.connected_sockets.load(Ordering::Relaxed) as u64,
+        );
+        metric(
+            "laser_chess_moves_total",
+            "counter",
+            "Moves played, live and by correspondence.",
+            self.moves.load(Ordering::Relaxed),
+        );
+        metric(
+            "laser_chess_invalid_moves_total",
+            "counter",
+            "Moves rejected as illegal or out of turn.",
+            self.invalid_moves.load(Ordering::Relaxed),
+        );
+        self.matchmaking_wait.render(
+            &mut out,
+            "laser_chess_matchmaking_wait_seconds",
+            "Time players waited between setting up and their game starting.",
+        );
+        self.game_duration.render(
+            &mut out,
+            "laser_chess_game_duration_seconds",
+            "How long finished live games lasted.",
+        );
+        out
+    }
+}
+
+/// Serves `metrics`.
+pub(super) async fn serve(State(metrics): State<Arc<Metrics>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+        .into_response()
+}
+
+/// Counts something in one of the gauges for as long as it's kept.
+pub(super) struct Gauged {
+    metrics: Arc<Metrics>,
+    gauge: fn(&Metrics) -> &AtomicUsize,
+}
+
+impl Gauged {
+    fn new(metrics: &Arc<Metrics>, gauge: fn(&Metrics) -> &AtomicUsize) -> Self {
+        gauge(metrics).fetch_add(1, Ordering::Relaxed);
+        Self {
+            metrics: metrics.clone(),
+            gauge,
+        }
+    }
+}
+
+impl Drop for Gauged {
+    fn drop(&mut self) {
+        (self.gauge)(&self.metrics).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Durations, counted into buckets by their upper bounds in seconds.
+struct Histogram {
+    bounds: &'static [f64],
+    observations: Mutex<Observations>,
+}
+
+#[derive(Default)]
+struct Observations {
+    /// How many were within each bound, so every observation is counted in all the buckets it
+    /// fits in, as Prometheus expects.
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            observations: Mutex::new(Observations {
+                buckets: vec![0; bounds.len()],
+                ..Observations::default()
+            }),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut observations = self.observations.lock().unwrap();
+        for (bound, bucket) in self.bounds.iter().zip(&mut observations.buckets) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        observations.count += 1;
+        observations.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let observations = self.observations.lock().unwrap();
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bound, bucket) in self.bounds.iter().zip(&observations.buckets) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {bucket}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", observations.count);
+        let _ = writeln!(out, "{name}_sum {}", observations.sum);
+        let _ = writeln!(out, "{name}_count {}", observations.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::Metrics;
+
+    /// The value of the sample `name` in `rendered`.
+    fn sample(rendered: &str, name: &str) -> String {
+        rendered
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("No {name} in {rendered}"))
+            .to_string()
+    }
+
+    #[test]
+    fn gauges_follow_their_guards() {
+        let metrics = Arc::new(Metrics::default());
+        let game = metrics.game_started();
+        let sockets = [metrics.socket_opened(), metrics.socket_opened()];
+        assert_eq!(metrics.active_games(), 1);
+        let rendered = metrics.render();
+        assert_eq!(sample(&rendered, "laser_chess_active_games"), "1");
+        assert_eq!(sample(&rendered, "laser_chess_connected_sockets"), "2");
+        assert!(rendered.contains("# TYPE laser_chess_active_games gauge"));
+
+        drop(game);
+        drop(sockets);
+        assert_eq!(metrics.active_games(), 0);
+        assert_eq!(
+            sample(&metrics.render(), "laser_chess_connected_sockets"),
+            "0"
+        );
+    }
+
+    #[test]
+    fn counts_moves() {
+        let metrics = Metrics::default();
+        metrics.move_played();
+        metrics.move_played();
+        metrics.move_rejected();
+        let rendered = metrics.render();
+        assert_eq!(sample(&rendered, "laser_chess_moves_total"), "2");
+        assert_eq!(sample(&rendered, "laser_chess_invalid_moves_total"), "1");
+        assert!(rendered.contains("# TYPE laser_chess_moves_total counter"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        metrics.waited(Duration::from_millis(500));
+        metrics.waited(Duration::from_secs(20));
+        metrics.waited(Duration::from_secs(1_000));
+        let rendered = metrics.render();
+        let bucket = |le| {
+            let name = format!("laser_chess_matchmaking_wait_seconds_bucket{{le=\"{le}\"}}");
+            sample(&rendered, &name)
+        };
+        assert_eq!(bucket("1"), "1");
+        assert_eq!(bucket("15"), "1");
+        assert_eq!(bucket("30"), "2");
+        assert_eq!(bucket("600"), "2");
+        assert_eq!(bucket("+Inf"), "3");
+        assert_eq!(
+            sample(&rendered, "laser_chess_matchmaking_wait_seconds_count"),
+            "3"
+        );
+        assert_eq!(
+            sample(&rendered, "laser_chess_matchmaking_wait_seconds_sum"),
+            "1020.5"
+        );
+        assert_eq!(
+            sample(&rendered, "laser_chess_game_duration_seconds_count"),
+            "0"
+        );
+    }
+}
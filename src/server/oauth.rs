@@ -0,0 +1,550 @@
+//! Logging in with an account elsewhere over OAuth 2.0, so web players don't need to keep a
+//! token of ours safe. A player is sent to the provider to log in, comes back with a code, and
+//! leaves with the same kind of token correspondence players are given, for an account of ours
+//! tied to theirs at the provider.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use super::{
+    GameStore, correspondence::hash_token, guests::is_guest, matchmaking::session_token, storage,
+};
+
+/// How long a player has to log in at the provider and come back.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How many numbers are tried after a name that's taken before giving up on it.
+const MAX_NAME_SUFFIX: u32 = 100;
+
+/// A site players can log in with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OAuthProvider {
+    GitHub,
+    Google,
+}
+
+/// An app registered with an [`OAuthProvider`] for the server, which players log in through.
+#[derive(Clone, Debug)]
+pub struct OAuthClient {
+    pub provider: OAuthProvider,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// Where a provider's steps of logging in happen.
+#[derive(Clone, Debug)]
+pub(super) struct Endpoints {
+    /// Where players are sent to log in.
+    pub(super) authorize: String,
+    /// Where the code they come back with is traded for an access token.
+    pub(super) token: String,
+    /// Who the access token belongs to.
+    pub(super) user: String,
+    pub(super) scope: &'static str,
+}
+
+/// Who a player is at a provider.
+#[derive(Debug, PartialEq, Eq)]
+struct Identity {
+    /// What never changes about their account there.
+    id: String,
+    /// The name to give them here, if it's free.
+    name: String,
+}
+
+/// What a player who's logged in is given, as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct LoggedIn {
+    pub(super) player_name: String,
+    /// Log in with it as with a correspondence token. It replaces any given out before.
+    pub(super) token: String,
+}
+
+impl OAuthProvider {
+    /// How the provider is named in the routes: `github` or `google`.
+    fn slug(self) -> &'static str {
+        match self {
+            OAuthProvider::GitHub => "github",
+            OAuthProvider::Google => "google",
+        }
+    }
+
+    fn endpoints(self) -> Endpoints {
+        match self {
+            OAuthProvider::GitHub => Endpoints {
+                authorize: "https://github.com/login/oauth/authorize".into(),
+                token: "https://github.com/login/oauth/access_token".into(),
+                user: "https://api.github.com/user".into(),
+                scope: "read:user",
+            },
+            OAuthProvider::Google => Endpoints {
+                authorize: "https://accounts.google.com/o/oauth2/v2/auth".into(),
+                token: "https://oauth2.googleapis.com/token".into(),
+                user: "https://openidconnect.googleapis.com/v1/userinfo".into(),
+                scope: "openid email",
+            },
+        }
+    }
+
+    /// Who the user the provider described as `user` is.
+    fn identity(self, user: &serde_json::Value) -> Option<Identity> {
+        match self {
+            OAuthProvider::GitHub => Some(Identity {
+                id: user["id"].as_u64()?.to_string(),
+                name: user["login"].as_str()?.to_string(),
+            }),
+            OAuthProvider::Google => {
+                let email = user["email"].as_str().unwrap_or_default();
+                let name = email.split('@').next().filter(|name| !name.is_empty());
+                Some(Identity {
+                    id: user["sub"].as_str()?.to_string(),
+                    name: name.unwrap_or("player").to_string(),
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for OAuthProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OAuthProvider::GitHub => write!(f, "GitHub"),
+            OAuthProvider::Google => write!(f, "Google"),
+        }
+    }
+}
+
+impl FromStr for OAuthProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [OAuthProvider::GitHub, OAuthProvider::Google]
+            .into_iter()
+            .find(|provider| provider.slug().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown OAuth provider '{s}' (expected github or google)"))
+    }
+}
+
+/// Logging in through providers, shared by every request.
+#[derive(Clone)]
+pub(super) struct OAuth {
+    clients: Arc<HashMap<OAuthProvider, (OAuthClient, Endpoints)>>,
+    /// Where the server is reached, without a trailing slash.
+    public_url: Arc<str>,
+    store: Arc<dyn GameStore>,
+    /// Logins players have been sent off to finish, by the state they'll come back with.
+    pending: Arc<Mutex<HashMap<String, (OAuthProvider, Instant)>>>,
+    http: reqwest::Client,
+}
+
+impl OAuth {
+    pub(super) fn new(
+        clients: Vec<OAuthClient>,
+        public_url: &str,
+        store: Arc<dyn GameStore>,
+    ) -> anyhow::Result<Self> {
+        let clients = clients
+            .into_iter()
+            .map(|client| {
+                let endpoints = client.provider.endpoints();
+                (client, endpoints)
+            })
+            .collect();
+        Self::with_endpoints(clients, public_url, store)
+    }
+
+    pub(super) fn with_endpoints(
+        clients: Vec<(OAuthClient, Endpoints)>,
+        public_url: &str,
+        store: Arc<dyn GameStore>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            clients: Arc::new(
+                clients
+                    .into_iter()
+                    .map(|(client, endpoints)| (client.provider, (client, endpoints)))
+                    .collect(),
+            ),
+            public_url: public_url.trim_end_matches('/').into(),
+            store,
+            pending: Arc::default(),
+            // GitHub turns away requests that don't say what's making them
+            http: reqwest::Client::builder()
+                .user_agent(concat!("laser-chess/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+        })
+    }
+
+    pub(super) fn routes(self) -> Router {
+        Router::new()
+            .route("/auth/{provider}", get(start))
+            .route("/auth/{provider}/callback", get(callback))
+            .with_state(self)
+    }
+
+    fn redirect_uri(&self, provider: OAuthProvider) -> String {
+        format!("{}/auth/{}/callback", self.public_url, provider.slug())
+    }
+
+    /// Trades `code` for an access token and finds out who it belongs to.
+    async fn identify(&self, provider: OAuthProvider, code: &str) -> anyhow::Result<Identity> {
+        let (client, endpoints) = &self.clients[&provider];
+        let redirect_uri = self.redirect_uri(provider);
+        let token: serde_json::Value = self
+            .http
+            .post(&endpoints.token)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&[
+                ("client_id", client.client_id.as_str()),
+                ("client_secret", client.client_secret.as_str()),
+                ("code", code),
+                ("redirect_uri", &redirect_uri),
+                ("grant_type", "authorization_code"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let Some(access_token) = token["access_token"].as_str() else {
+            anyhow::bail!("No access token in {}", token);
+        };
+        let user = self
+            .http
+            .get(&endpoints.user)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        provider
+            .identity(&user)
+            .ok_or_else(|| anyhow::anyhow!("Couldn't tell who {} is", user))
+    }
+}
+
+/// Sends the player to `provider` to log in.
+async fn start(State(oauth): State<OAuth>, Path(provider): Path<String>) -> Response {
+    let Some(provider) = provider
+        .parse()
+        .ok()
+        .filter(|provider| oauth.clients.contains_key(provider))
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Can't log in with {provider}"),
+        )
+            .into_response();
+    };
+    let (client, endpoints) = &oauth.clients[&provider];
+    let state = session_token();
+    {
+        let mut pending = oauth.pending.lock().unwrap();
+        pending.retain(|_, (_, started)| started.elapsed() < LOGIN_TIMEOUT);
+        pending.insert(state.clone(), (provider, Instant::now()));
+    }
+    let url = reqwest::Url::parse_with_params(
+        &endpoints.authorize,
+        [
+            ("client_id", client.client_id.as_str()),
+            ("redirect_uri", &oauth.redirect_uri(provider)),
+            ("response_type", "code"),
+            ("scope", endpoints.scope),
+            ("state", &state),
+        ],
+    );
+    match url {
+        Ok(url) => Redirect::to(url.as_str()).into_response(),
+        Err(e) => {
+            error!("Invalid {} authorization URL: {}", provider, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CallbackParams {
+    code: String,
+    state: String,
+}
+
+/// Logs in the player `provider` sent back, giving them an account here the first time.
+async fn callback(
+    State(oauth): State<OAuth>,
+    Path(provider): Path<String>,
+    Query(params): Query<CallbackParams>,
+) -> Response {
+    let started = oauth.pending.lock().unwrap().remove(&params.state);
+    let provider = match (started, provider.parse::<OAuthProvider>()) {
+        (Some((started, when)), Ok(provider))
+            if started == provider && when.elapsed() < LOGIN_TIMEOUT =>
+        {
+            provider
+        }
+        _ => {
+            let reason = "This login has expired or didn't start here. Please try again";
+            return (StatusCode::BAD_REQUEST, reason).into_response();
+        }
+    };
+    let identity = match oauth.identify(provider, &params.code).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            info!("Logging in with {} failed: {}", provider, e);
+            let reason = format!("Couldn't log in with {provider}");
+            return (StatusCode::BAD_GATEWAY, reason).into_response();
+        }
+    };
+    let token = session_token();
+    let hash = hash_token(&token);
+    let login = storage::blocking(&oauth.store, move |store| {
+        login(store, provider, identity, &hash)
+    });
+    match login.await {
+        Ok(Some(player_name)) => {
+            info!("{} logged in with {}", player_name, provider);
+            Json(LoggedIn { player_name, token }).into_response()
+        }
+        Ok(None) => {
+            let reason = "Couldn't find a free name for you. Please try again later";
+            (StatusCode::CONFLICT, reason).into_response()
+        }
+        Err(e) => {
+            error!("Failed to log in with {}: {}", provider, e);
+            let reason = "Something went wrong on the server. Please try again later";
+            (StatusCode::INTERNAL_SERVER_ERROR, reason).into_response()
+        }
+    }
+}
+
+/// Logs in whoever `identity` is at `provider` with the token hashing to `token_hash`, returning
+/// their name. Newcomers get an account named after theirs at the provider, numbered if that's
+/// taken, or `None` if no number up to [`MAX_NAME_SUFFIX`] is free.
+fn login(
+    store: &dyn GameStore,
+    provider: OAuthProvider,
+    identity: Identity,
+    token_hash: &str,
+) -> anyhow::Result<Option<String>> {
+    let provider = provider.slug();
+    // Names that look like guests' are kept for guests
+    let base_name = match is_guest(&identity.name) {
+        true => "player",
+        false => &identity.name,
+    };
+    if let Some(player) = store.oauth_account(provider, &identity.id)? {
+        store.set_token_hash(&player, token_hash)?;
+        return Ok(Some(player));
+    }
+    for suffix in 1..=MAX_NAME_SUFFIX {
+        let name = match suffix {
+            1 => base_name.to_string(),
+            suffix => format!("{base_name}-{suffix}"),
+        };
+        if store.register_oauth(provider, &identity.id, &name, token_hash)? {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{
+        Form, Json, Router,
+        body::{self, Body},
+        http::{HeaderMap, Request, StatusCode, header},
+        routing::{get, post},
+    };
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    use super::{
+        super::{GameStore, correspondence::hash_token, storage::tests::MemoryStore},
+        Endpoints, Identity, LoggedIn, OAuth, OAuthClient, OAuthProvider,
+    };
+
+    /// Serves a provider like GitHub on a port of its own, returning where. The access token for
+    /// a code is the code itself, and the code `name:id` logs in the user `name` with ID `id`.
+    /// Any other code is turned away.
+    async fn provider() -> String {
+        let token = |Form(form): Form<HashMap<String, String>>| async move {
+            match form["code"].contains(':') {
+                true => Ok(Json(json!({ "access_token": form["code"] }))),
+                false => Err(StatusCode::UNAUTHORIZED),
+            }
+        };
+        let user = |headers: HeaderMap| async move {
+            let token = headers[header::AUTHORIZATION].to_str().unwrap();
+            let (login, id) = token
+                .strip_prefix("Bearer ")
+                .unwrap()
+                .split_once(':')
+                .unwrap();
+            Json(json!({ "id": id.parse::<u64>().unwrap(), "login": login }))
+        };
+        let app = Router::new()
+            .route("/token", post(token))
+            .route("/user", get(user));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{addr}")
+    }
+
+    async fn oauth(store: Arc<dyn GameStore>) -> OAuth {
+        let provider = provider().await;
+        let client = OAuthClient {
+            provider: OAuthProvider::GitHub,
+            client_id: "client".into(),
+            client_secret: "secret".into(),
+        };
+        let endpoints = Endpoints {
+            authorize: format!("{provider}/authorize"),
+            token: format!("{provider}/token"),
+            user: format!("{provider}/user"),
+            scope: "read:user",
+        };
+        OAuth::with_endpoints(vec![(client, endpoints)], "https://lasers.test/", store).unwrap()
+    }
+
+    /// Fetches `uri` from `oauth`'s routes, returning the status, any redirect and the body.
+    async fn get_uri(oauth: &OAuth, uri: &str) -> (StatusCode, Option<String>, String) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = oauth.clone().routes().oneshot(request).await.unwrap();
+        let status = response.status();
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .map(|location| location.to_str().unwrap().to_string());
+        let body = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, location, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Starts logging in with GitHub, returning the state the player is sent off with.
+    async fn start(oauth: &OAuth) -> String {
+        let (status, location, _) = get_uri(oauth, "/auth/github").await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        let location = reqwest::Url::parse(&location.unwrap()).unwrap();
+        let params: HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(params["client_id"], "client");
+        assert_eq!(
+            params["redirect_uri"],
+            "https://lasers.test/auth/github/callback"
+        );
+        params["state"].clone()
+    }
+
+    /// Logs in as the user the provider knows by `code`.
+    async fn log_in(oauth: &OAuth, code: &str) -> LoggedIn {
+        let state = start(oauth).await;
+        let uri = format!("/auth/github/callback?code={code}&state={state}");
+        let (status, _, body) = get_uri(oauth, &uri).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        serde_json::from_str(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn logging_in_makes_an_account_the_first_time() {
+        let store: Arc<dyn GameStore> = Arc::new(MemoryStore::default());
+        let oauth = oauth(store.clone()).await;
+
+        let first = log_in(&oauth, "alice:1").await;
+        assert_eq!(first.player_name, "alice");
+        let token_hash = store.token_hash("alice").unwrap();
+        assert_eq!(token_hash, Some(hash_token(&first.token)));
+
+        // The same user comes back to the same account, with a new token
+        let again = log_in(&oauth, "alice:1").await;
+        assert_eq!(again.player_name, "alice");
+        assert_ne!(again.token, first.token);
+        let token_hash = store.token_hash("alice").unwrap();
+        assert_eq!(token_hash, Some(hash_token(&again.token)));
+
+        // Someone else with the same name is numbered
+        assert_eq!(log_in(&oauth, "alice:2").await.player_name, "alice-2");
+        store.register("bob", "hash").unwrap();
+        assert_eq!(log_in(&oauth, "bob:3").await.player_name, "bob-2");
+        assert_eq!(store.token_hash("bob").unwrap().as_deref(), Some("hash"));
+    }
+
+    #[tokio::test]
+    async fn logins_need_a_state_from_here() {
+        let oauth = oauth(Arc::new(MemoryStore::default())).await;
+        let uri = "/auth/github/callback?code=alice:1&state=made-up";
+        assert_eq!(get_uri(&oauth, uri).await.0, StatusCode::BAD_REQUEST);
+
+        // A state only works once, and only for the provider it was made for
+        let state = start(&oauth).await;
+        let uri = format!("/auth/google/callback?code=alice:1&state={state}");
+        assert_eq!(get_uri(&oauth, &uri).await.0, StatusCode::BAD_REQUEST);
+        let uri = format!("/auth/github/callback?code=alice:1&state={state}");
+        assert_eq!(get_uri(&oauth, &uri).await.0, StatusCode::BAD_REQUEST);
+
+        let state = start(&oauth).await;
+        let uri = format!("/auth/github/callback?code=refused&state={state}");
+        assert_eq!(get_uri(&oauth, &uri).await.0, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn only_configured_providers_can_be_used() {
+        let oauth = oauth(Arc::new(MemoryStore::default())).await;
+        assert_eq!(
+            get_uri(&oauth, "/auth/google").await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get_uri(&oauth, "/auth/gitlab").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn providers_parse_and_identify_users() {
+        assert_eq!("GitHub".parse(), Ok(OAuthProvider::GitHub));
+        assert_eq!("google".parse(), Ok(OAuthProvider::Google));
+        assert!("gitlab".parse::<OAuthProvider>().is_err());
+
+        let github = json!({ "id": 42, "login": "alice" });
+        assert_eq!(
+            OAuthProvider::GitHub.identity(&github),
+            Some(Identity {
+                id: "42".into(),
+                name: "alice".into()
+            })
+        );
+        assert_eq!(OAuthProvider::GitHub.identity(&json!({ "id": 42 })), None);
+
+        let google = json!({ "sub": "1234", "email": "bob@example.com" });
+        assert_eq!(
+            OAuthProvider::Google.identity(&google),
+            Some(Identity {
+                id: "1234".into(),
+                name: "bob".into()
+            })
+        );
+        let without_email = json!({ "sub": "1234" });
+        let name = OAuthProvider::Google.identity(&without_email).unwrap().name;
+        assert_eq!(name, "player");
+    }
+}
@@ -0,0 +1,244 @@
+//! Working out a client's real address behind reverse proxies.
+
+use std::{net::IpAddr, str::FromStr};
+
+use axum::{extract::connect_info::Connected, http::HeaderMap, serve::IncomingStream};
+use tokio::net::{TcpListener, UnixListener};
+
+/// The address of whoever opened the socket, as connect info: an IP for TCP listeners, or `None`
+/// for Unix sockets, which only a reverse proxy on the same host can reach.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub Option<IpAddr>);
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(Some(stream.remote_addr().ip()))
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+    fn connect_info(_: IncomingStream<'_, UnixListener>) -> Self {
+        Self(None)
+    }
+}
+
+/// An IP address range in CIDR notation (a bare address is a range of one).
+#[derive(Clone, Copy, Debug)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr.parse::<IpAddr>()?, Some(prefix_len.parse()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            anyhow::bail!("Invalid prefix length in {}", s);
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Works out the real client address for a request. Forwarding headers are only believed when
+/// the socket peer is a trusted proxy: we walk the chain of forwarded addresses from the nearest
+/// hop outwards, skipping trusted proxies, and stop at the first address we don't trust.
+pub(super) fn client_addr(
+    peer: PeerAddr,
+    headers: &HeaderMap,
+    trusted: &[IpNetwork],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if peer.0.is_some_and(|ip| !is_trusted(ip)) {
+        return peer.0;
+    }
+    let mut client = peer.0;
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        // An unparseable hop ("unknown" or an obfuscated identifier) ends what we can trust
+        let Some(ip) = hop else {
+            break;
+        };
+        client = Some(ip);
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Collects the client chain from the standard `Forwarded` header, falling back to
+/// `X-Forwarded-For`, ordered from the original client to the nearest proxy.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| parse_forwarded_node(value.trim_matches('"')))
+            })
+        })
+        .collect::<Vec<_>>();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|node| parse_forwarded_node(node.trim()))
+        .collect()
+}
+
+/// Parses an address as it appears in forwarding headers: `1.2.3.4`, `1.2.3.4:80`, `::1` or
+/// `[::1]:80`.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.rsplit_once(':')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::HeaderMap;
+
+    use super::{IpNetwork, PeerAddr, client_addr, parse_forwarded_node};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn networks_parse_and_contain() {
+        let net: IpNetwork = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.255.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(!net.contains(ip("::ffff:10.1.0.1")));
+        let single: IpNetwork = "192.168.0.7".parse().unwrap();
+        assert!(single.contains(ip("192.168.0.7")));
+        assert!(!single.contains(ip("192.168.0.8")));
+        let everything: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("8.8.8.8")));
+        let v6: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/x", "proxy"] {
+            assert!(invalid.parse::<IpNetwork>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn forwarded_nodes_parse_with_or_without_ports() {
+        assert_eq!(parse_forwarded_node("1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_forwarded_node("1.2.3.4:80"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_forwarded_node("::1"), Some(ip("::1")));
+        assert_eq!(parse_forwarded_node("[::1]:80"), Some(ip("::1")));
+        assert_eq!(parse_forwarded_node("[::1]"), Some(ip("::1")));
+        assert_eq!(parse_forwarded_node("unknown"), None);
+        assert_eq!(parse_forwarded_node("_hidden"), None);
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let forged = headers(&[("x-forwarded-for", "1.1.1.1")]);
+        let peer = PeerAddr(Some(ip("203.0.113.9")));
+        assert_eq!(client_addr(peer, &forged, &trusted), peer.0);
+        assert_eq!(client_addr(peer, &forged, &[]), peer.0);
+    }
+
+    #[test]
+    fn trusted_proxies_are_skipped() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let peer = PeerAddr(Some(ip("10.0.0.1")));
+        // The client claims to be 1.1.1.1, but only the hop the proxies saw can be believed
+        let chain = headers(&[("x-forwarded-for", "1.1.1.1, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(client_addr(peer, &chain, &trusted), Some(ip("203.0.113.9")));
+        // Headers sent more than once are one chain
+        let split = headers(&[
+            ("x-forwarded-for", "1.1.1.1, 203.0.113.9"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(client_addr(peer, &split, &trusted), Some(ip("203.0.113.9")));
+        // Only proxies all the way, so the furthest is as close to the client as we get
+        let proxies = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(client_addr(peer, &proxies, &trusted), Some(ip("10.0.0.3")));
+        assert_eq!(client_addr(peer, &HeaderMap::new(), &trusted), peer.0);
+        // A hop that isn't an address ends what can be believed
+        let hidden = headers(&[("x-forwarded-for", "203.0.113.9, unknown, 10.0.0.2")]);
+        assert_eq!(client_addr(peer, &hidden, &trusted), Some(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn forwarded_is_preferred_over_x_forwarded_for() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let peer = PeerAddr(Some(ip("10.0.0.1")));
+        let both = headers(&[
+            (
+                "forwarded",
+                "for=203.0.113.9;proto=https, For=\"[2001:db8::1]:443\"",
+            ),
+            ("x-forwarded-for", "1.1.1.1"),
+        ]);
+        assert_eq!(client_addr(peer, &both, &trusted), Some(ip("2001:db8::1")));
+        let without_for = headers(&[
+            ("forwarded", "proto=https"),
+            ("x-forwarded-for", "203.0.113.9"),
+        ]);
+        assert_eq!(
+            client_addr(peer, &without_for, &trusted),
+            Some(ip("203.0.113.9"))
+        );
+    }
+
+    #[test]
+    fn unix_sockets_trust_their_proxy() {
+        let chain = headers(&[("x-forwarded-for", "203.0.113.9")]);
+        assert_eq!(
+            client_addr(PeerAddr(None), &chain, &[]),
+            Some(ip("203.0.113.9"))
+        );
+        assert_eq!(client_addr(PeerAddr(None), &HeaderMap::new(), &[]), None);
+    }
+}
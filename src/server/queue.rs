@@ -0,0 +1,348 @@
+//! The queue players wait in for an opponent, split into pools by the kind of game they want, so
+//! nobody is paired into a game they didn't ask for.
+
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use futures_util::{FutureExt, future::select_all};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::{
+    ClientRequest, ServerMessage,
+    logic::{RulesConfig, TimeControl},
+};
+
+use super::{PlayerConnection, matchmaking::ConnectedPlayer};
+
+/// How much difference in round-trip time counts the same as a point of rating difference when
+/// pairing players for a fast game, so players with similar connections are preferred without
+/// holding out for them forever.
+const LATENCY_PER_RATING_POINT: Duration = Duration::from_millis(5);
+
+/// How long a waiting player's connection has to take a message before they're dropped, so one
+/// slow connection can't hold up matchmaking for everyone.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Games starting with at most this much on the clock are fast enough for lag to matter when
+/// pairing.
+const FAST_GAME: Duration = Duration::from_secs(10 * 60);
+
+/// The kind of game a pool's players want.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PoolKey {
+    /// `None` for players who don't mind, who can be paired from any pool that agrees otherwise.
+    time_control: Option<TimeControl>,
+    rules: RulesConfig,
+    rated: bool,
+}
+
+impl PoolKey {
+    fn of(player: &ConnectedPlayer) -> Self {
+        Self {
+            time_control: player.time_control,
+            rules: player.rules.clone(),
+            rated: player.rated,
+        }
+    }
+
+    /// Whether players from this pool and `other`'s can be paired: the same rules, both rated or
+    /// both casual, and the same time control unless one of them doesn't mind.
+    fn agrees_with(&self, other: &PoolKey) -> bool {
+        let same_time = match (self.time_control, other.time_control) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        same_time && self.rules == other.rules && self.rated == other.rated
+    }
+}
+
+/// A player waiting for an opponent, with when they started waiting.
+struct Waiting {
+    player: ConnectedPlayer,
+    since: Instant,
+}
+
+/// Where a waiting player is in the [`Queue`]: their pool, and their place in it.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Place {
+    pool: usize,
+    index: usize,
+}
+
+/// Everyone waiting for an opponent, in pools by the kind of game they want. Each pool is in the
+/// order its players joined, and empty pools are dropped.
+#[derive(Default)]
+pub(super) struct Queue {
+    /// There are only ever a few pools, and rules can't be hashed, so pools are found by looking
+    /// through them.
+    pools: Vec<(PoolKey, Vec<Waiting>)>,
+}
+
+impl Queue {
+    pub(super) fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+
+    /// Pairs `player` with whoever's the best match for them among the players waiting for a game
+    /// they agree on, and returns the two of them, the one who was waiting first. The best match
+    /// is rated closest, with any difference in their latency counted against them too if they'd
+    /// play a fast game, and whoever's waited longest if that's a tie. If there's nobody, `player`
+    /// joins the queue and everyone waiting is told how many are.
+    ///
+    /// A player logged in to an account that's already waiting, say after connecting twice, has
+    /// the old connection closed and takes its place instead of being paired with it. Anyone else
+    /// is only known by their connection.
+    pub(super) async fn join(&mut self, player: ConnectedPlayer) -> Option<[ConnectedPlayer; 2]> {
+        let mut since = Instant::now();
+        let duplicate = self.places().find(|&place| {
+            let waiting = &self.waiting(place).player;
+            player.account.is_some() && waiting.account == player.account
+        });
+        if let Some(place) = duplicate {
+            info!("{} connected again while waiting", player.name);
+            let mut old = self.remove(place);
+            since = old.since;
+            let reason = "You connected again from somewhere else".to_string();
+            let _ = send(&mut old.player, &ServerMessage::RequestFailed { reason }).await;
+            close(&mut old.player).await;
+        }
+        while let Some(place) = self.opponent_for(&player) {
+            // The select loop may not have got round to noticing the opponent has left, so have
+            // a last look at what they've sent rather than pair them into a dead game
+            let opponent = &mut self.pools[place.pool].1[place.index].player;
+            match opponent.connection.recv().now_or_never() {
+                None => return Some([self.remove(place).player, player]),
+                Some(request) => self.handle(place, request).await,
+            }
+        }
+        let key = PoolKey::of(&player);
+        let waiting = Waiting { player, since };
+        match self.pools.iter_mut().find(|(pool, _)| *pool == key) {
+            Some((_, players)) => players.push(waiting),
+            None => self.pools.push((key, vec![waiting])),
+        }
+        self.send_status().await;
+        None
+    }
+
+    /// Waits for a request from any waiting player, returning where they are. Never finishes if
+    /// nobody's waiting.
+    pub(super) async fn next_request(&mut self) -> (Place, anyhow::Result<ClientRequest>) {
+        if self.pools.is_empty() {
+            return std::future::pending().await;
+        }
+        let places: Vec<_> = self.places().collect();
+        let requests = self.pools.iter_mut().flat_map(|(_, players)| {
+            players
+                .iter_mut()
+                .map(|waiting| Box::pin(waiting.player.connection.recv()))
+        });
+        let (request, index, _) = select_all(requests).await;
+        (places[index], request)
+    }
+
+    /// Acts on a request from the player at `place`, taking them out of the queue if they cancel
+    /// or their connection failed, and telling everyone left how many are waiting.
+    pub(super) async fn handle(&mut self, place: Place, request: anyhow::Result<ClientRequest>) {
+        let name = self.waiting(place).player.name.clone();
+        match request {
+            Ok(ClientRequest::CancelMatchmaking) => {
+                info!("{} left the queue", name);
+                close(&mut self.remove(place).player).await;
+            }
+            Ok(_) => {
+                warn!(
+                    "Unexpected request from {} while waiting for an opponent",
+                    name
+                );
+                return;
+            }
+            Err(e) => {
+                info!("{} left the queue: {}", name, e);
+                self.remove(place);
+            }
+        }
+        self.send_status().await;
+    }
+
+    /// When the player who's waited longest started waiting, if anyone is.
+    pub(super) fn longest_wait(&self) -> Option<Instant> {
+        self.places().map(|place| self.waiting(place).since).min()
+    }
+
+    /// Takes the player who's waited longest out of the queue, if anyone is waiting.
+    pub(super) async fn take_longest(&mut self) -> Option<ConnectedPlayer> {
+        let place = self
+            .places()
+            .min_by_key(|&place| self.waiting(place).since)?;
+        let waiting = self.remove(place);
+        self.send_status().await;
+        Some(waiting.player)
+    }
+
+    /// Takes everyone out of the queue.
+    pub(super) fn drain(&mut self) -> impl Iterator<Item = ConnectedPlayer> + '_ {
+        self.pools
+            .drain(..)
+            .flat_map(|(_, players)| players)
+            .map(|waiting| waiting.player)
+    }
+
+    /// Tells everyone waiting how many players are waiting for a game they agree on, them
+    /// included, dropping any who can't be reached.
+    pub(super) async fn send_status(&mut self) {
+        let counts: Vec<usize> = self
+            .pools
+            .iter()
+            .map(|(key, _)| {
+                self.pools
+                    .iter()
+                    .filter(|(other, _)| key.agrees_with(other))
+                    .map(|(_, players)| players.len())
+                    .sum()
+            })
+            .collect();
+        for ((_, players), waiting_players) in self.pools.iter_mut().zip(counts) {
+            let status = ServerMessage::QueueStatus { waiting_players };
+            let mut reached = Vec::with_capacity(players.len());
+            for mut waiting in players.drain(..) {
+                match send(&mut waiting.player, &status).await {
+                    Ok(()) => reached.push(waiting),
+                    Err(e) => info!("{} left the queue: {}", waiting.player.name, e),
+                }
+            }
+            *players = reached;
+        }
+        self.pools.retain(|(_, players)| !players.is_empty());
+    }
+
+    /// Where the best opponent for `player` is waiting, if anyone they agree with and could start
+    /// a game with is.
+    fn opponent_for(&self, player: &ConnectedPlayer) -> Option<Place> {
+        let key = PoolKey::of(player);
+        let score = |opponent: &ConnectedPlayer| {
+            let gap = opponent
+                .rating
+                .zip(player.rating)
+                .map_or(0, |(a, b)| a.abs_diff(b));
+            // A fast game is decided by the time control either of them asked for
+            let fast = [opponent.time_control, player.time_control]
+                .into_iter()
+                .flatten()
+                .any(|control| control.initial <= FAST_GAME);
+            let lag = if fast {
+                let lag = opponent
+                    .connection
+                    .latency()
+                    .abs_diff(player.connection.latency());
+                lag.as_millis() / LATENCY_PER_RATING_POINT.as_millis()
+            } else {
+                0
+            };
+            u128::from(gap) + lag
+        };
+        self.places()
+            .filter(|&place| self.pools[place.pool].0.agrees_with(&key))
+            // The one who was waiting plays player 1
+            .filter(|&place| self.waiting(place).player.can_play(player).is_ok())
+            .min_by_key(|&place| {
+                let waiting = self.waiting(place);
+                (score(&waiting.player), waiting.since)
+            })
+    }
+
+    /// Where every waiting player is, pool by pool.
+    fn places(&self) -> impl Iterator<Item = Place> + '_ {
+        self.pools
+            .iter()
+            .enumerate()
+            .flat_map(|(pool, (_, players))| {
+                (0..players.len()).map(move |index| Place { pool, index })
+            })
+    }
+
+    fn waiting(&self, place: Place) -> &Waiting {
+        &self.pools[place.pool].1[place.index]
+    }
+
+    /// Takes the player at `place` out of the queue, dropping their pool if it's left empty.
+    fn remove(&mut self, place: Place) -> Waiting {
+        let players = &mut self.pools[place.pool].1;
+        let waiting = players.remove(place.index);
+        if players.is_empty() {
+            self.pools.remove(place.pool);
+        }
+        waiting
+    }
+}
+
+/// Sends `message` to `player`, giving up on them if it takes longer than [`SEND_TIMEOUT`].
+async fn send(player: &mut ConnectedPlayer, message: &ServerMessage) -> anyhow::Result<()> {
+    timeout(SEND_TIMEOUT, player.connection.send(message))
+        .await
+        .map_err(|_| anyhow!("Too slow to take messages"))?
+}
+
+/// Says goodbye to `player`, unless their connection is too slow to.
+async fn close(player: &mut ConnectedPlayer) {
+    let _ = timeout(SEND_TIMEOUT, player.connection.close()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::logic::TimeControl;
+
+    use super::{super::matchmaking::tests::player, Queue};
+
+    /// Has the players in `waiting` join the queue one after another, each `(latency, time
+    /// control)`, then someone who doesn't mind the time control `latency` away. Returns the name
+    /// of who they're paired with.
+    async fn pairing(waiting: [(u64, u64); 2], latency: u64) -> String {
+        let mut queue = Queue::default();
+        let mut clients = Vec::new();
+        for (index, (latency, minutes)) in waiting.into_iter().enumerate() {
+            let (mut waiting, client) =
+                player(&format!("player {index}"), Duration::from_millis(latency)).await;
+            waiting.time_control = Some(TimeControl::new(minutes, 0));
+            clients.push(client);
+            assert!(queue.join(waiting).await.is_none());
+        }
+        let (newcomer, _client) = player("newcomer", Duration::from_millis(latency)).await;
+        let [opponent, newcomer] = queue.join(newcomer).await.unwrap();
+        assert_eq!(newcomer.name, "newcomer");
+        opponent.name
+    }
+
+    #[tokio::test]
+    async fn fast_games_pair_players_with_similar_latency() {
+        // The one who waited longest is as far off as they could be
+        assert_eq!(pairing([(30, 3), (300, 5)], 300).await, "player 1");
+        assert_eq!(pairing([(300, 3), (30, 5)], 30).await, "player 1");
+    }
+
+    #[tokio::test]
+    async fn slow_games_ignore_latency() {
+        assert_eq!(pairing([(30, 60), (300, 90)], 300).await, "player 0");
+    }
+
+    #[tokio::test]
+    async fn players_are_paired_by_rating() {
+        let mut queue = Queue::default();
+        let mut clients = Vec::new();
+        // Wanting different time controls keeps them from being paired with each other
+        for (name, rating, minutes) in [("far", 1200, 60), ("close", 1650, 90)] {
+            let (mut waiting, client) = player(name, Duration::ZERO).await;
+            waiting.rating = Some(rating);
+            waiting.time_control = Some(TimeControl::new(minutes, 0));
+            clients.push(client);
+            assert!(queue.join(waiting).await.is_none());
+        }
+        let (mut newcomer, _client) = player("newcomer", Duration::ZERO).await;
+        newcomer.rating = Some(1700);
+        let [opponent, _] = queue.join(newcomer).await.unwrap();
+        assert_eq!(opponent.name, "close");
+    }
+}
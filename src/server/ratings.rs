@@ -0,0 +1,201 @@
+//! Elo ratings, kept by account and saved to a file after every rated game so they survive
+//! restarts.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use tracing::{error, info};
+
+use crate::logic::{GameResult, Player};
+
+use super::guests::is_guest;
+
+/// The rating a player starts with.
+pub(super) const INITIAL_RATING: i32 = 1500;
+
+/// What a player who asks for a rated game without logging in is told.
+pub(super) const NOT_LOGGED_IN: &str = "Log in with your token to play rated games";
+
+/// What a player who asks for a handicap game without logging in is told. Whoever's rated higher
+/// gives the odds, so both players need a rating.
+pub(super) const HANDICAP_NOT_LOGGED_IN: &str =
+    "Log in with your token to play handicap games, so your rating can say who gives odds";
+
+/// The most a rating moves after one game: what a player gains for beating an opponent they were
+/// certain to lose to.
+const K_FACTOR: f64 = 32.0;
+
+/// Everyone's ratings, and where they're kept. They're kept by account, the name a player logged
+/// in to with its token, so nobody can play under someone else's rating. Guests' ratings are only
+/// kept in memory, and start afresh when the server does unless the guest keeps their account.
+pub(super) struct Ratings {
+    ratings: BTreeMap<String, i32>,
+    /// The file the ratings are saved to. `None` keeps them in memory.
+    path: Option<PathBuf>,
+}
+
+impl Ratings {
+    /// The ratings saved at `path`, or none if nothing's been saved there yet.
+    pub(super) fn load(path: Option<PathBuf>) -> io::Result<Self> {
+        let ratings = match &path {
+            Some(path) => read(path)?,
+            None => BTreeMap::new(),
+        };
+        Ok(Self { ratings, path })
+    }
+
+    /// `account`'s rating, which is [`INITIAL_RATING`] until it's played a rated game.
+    pub(super) fn get(&self, account: &str) -> i32 {
+        self.ratings.get(account).copied().unwrap_or(INITIAL_RATING)
+    }
+
+    /// Updates the ratings of `players`, player 1 first, after a game that ended in `result`,
+    /// and saves them. Returns their new ratings.
+    pub(super) fn record(&mut self, players: [&str; 2], result: &GameResult) -> [i32; 2] {
+        let score = match result {
+            GameResult::Win {
+                winner: Player::Player1,
+                ..
+            } => 1.0,
+            GameResult::Win { .. } => 0.0,
+            GameResult::Draw { .. } => 0.5,
+        };
+        let [rating1, rating2] = players.map(|name| self.get(name));
+        let expected = 1.0 / (1.0 + 10f64.powf(f64::from(rating2 - rating1) / 400.0));
+        // What one player gains the other loses, so ratings don't drift
+        let change = (K_FACTOR * (score - expected)).round() as i32;
+        self.ratings
+            .insert(players[0].to_string(), rating1 + change);
+        // Looked up again in case both players are the same account
+        let rating2 = self.get(players[1]);
+        self.ratings
+            .insert(players[1].to_string(), rating2 - change);
+        info!(
+            "{} is now rated {} and {} {}",
+            players[0],
+            self.get(players[0]),
+            players[1],
+            self.get(players[1])
+        );
+        self.save();
+        players.map(|name| self.get(name))
+    }
+
+    /// Moves the rating of the account `from` to `to`, which it's been renamed to, and saves it.
+    pub(super) fn rename(&mut self, from: &str, to: &str) {
+        if let Some(rating) = self.ratings.remove(from) {
+            self.ratings.insert(to.to_string(), rating);
+            self.save();
+        }
+    }
+
+    /// Saves everyone's ratings but guests', if they're kept in a file.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let kept = self
+            .ratings
+            .iter()
+            .filter(|(name, _)| !is_guest(name))
+            .collect();
+        if let Err(e) = write(path, &kept) {
+            error!("Failed to save ratings to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn read(path: &Path) -> io::Result<BTreeMap<String, i32>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Saves `ratings` to `path` through a temporary file, so a crash halfway leaves the old ones.
+fn write(path: &Path, ratings: &BTreeMap<&String, &i32>) -> io::Result<()> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, serde_json::to_vec_pretty(ratings)?)?;
+    fs::rename(temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use crate::logic::{DrawReason, GameResult, Player, WinReason};
+
+    use super::{INITIAL_RATING, Ratings};
+
+    fn win(winner: Player) -> GameResult {
+        GameResult::Win {
+            winner,
+            reason: WinReason::KingDestroyed,
+        }
+    }
+
+    #[test]
+    fn ratings_move_by_how_surprising_the_result_was() {
+        let mut ratings = Ratings::load(None).unwrap();
+        assert_eq!(ratings.get("alice"), INITIAL_RATING);
+
+        ratings.record(["alice", "bob"], &win(Player::Player1));
+        assert_eq!(ratings.get("alice"), INITIAL_RATING + 16);
+        assert_eq!(ratings.get("bob"), INITIAL_RATING - 16);
+
+        // The favourite winning again gains less, and the underdog winning gains more
+        ratings.record(["bob", "alice"], &win(Player::Player2));
+        let favourite_gain = ratings.get("alice") - (INITIAL_RATING + 16);
+        assert!(0 < favourite_gain && favourite_gain < 16);
+        let before = ratings.get("bob");
+        ratings.record(["alice", "bob"], &win(Player::Player2));
+        assert!(ratings.get("bob") - before > 16);
+
+        // A draw between equals changes nothing, and ratings never drift overall
+        let draw = GameResult::Draw {
+            reason: DrawReason::Agreement,
+        };
+        ratings.record(["carol", "dave"], &draw);
+        assert_eq!(ratings.get("carol"), INITIAL_RATING);
+        let total: i32 = ["alice", "bob", "carol", "dave"]
+            .iter()
+            .map(|name| ratings.get(name))
+            .sum();
+        assert_eq!(total, 4 * INITIAL_RATING);
+    }
+
+    #[test]
+    fn ratings_are_saved_and_loaded() {
+        let path = env::temp_dir().join(format!("laser-chess-ratings-{}.json", process::id()));
+        let mut ratings = Ratings::load(Some(path.clone())).unwrap();
+        ratings.record(["alice", "bob"], &win(Player::Player2));
+        let loaded = Ratings::load(Some(path.clone())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get("alice"), INITIAL_RATING - 16);
+        assert_eq!(loaded.get("bob"), INITIAL_RATING + 16);
+        assert_eq!(loaded.get("carol"), INITIAL_RATING);
+    }
+
+    #[test]
+    fn guest_ratings_are_only_saved_once_kept() {
+        let path = env::temp_dir().join(format!("laser-chess-guests-{}.json", process::id()));
+        let mut ratings = Ratings::load(Some(path.clone())).unwrap();
+        ratings.record(["alice", "guest-1"], &win(Player::Player2));
+        ratings.record(["bob", "guest-2"], &win(Player::Player2));
+        assert_eq!(ratings.get("guest-1"), INITIAL_RATING + 16);
+        let loaded = Ratings::load(Some(path.clone())).unwrap();
+        assert_eq!(loaded.get("alice"), INITIAL_RATING - 16);
+        assert_eq!(loaded.get("guest-1"), INITIAL_RATING);
+
+        ratings.rename("guest-1", "carol");
+        assert_eq!(ratings.get("guest-1"), INITIAL_RATING);
+        let loaded = Ratings::load(Some(path.clone())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get("carol"), INITIAL_RATING + 16);
+        assert_eq!(loaded.get("guest-2"), INITIAL_RATING);
+    }
+}
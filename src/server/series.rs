@@ -0,0 +1,204 @@
+//! Series of correspondence games between the same two players. Each game of a series carries
+//! where the series stands, and the next is started as soon as it's over, with the players
+//! swapping sides, until one of them can't be caught. A series that ends level can be settled by
+//! an Armageddon game, whose draws the server scores as wins for player 2.
+
+use crate::{
+    SeriesConfig, SeriesStanding,
+    logic::{GameResult, Player, WinReason},
+};
+
+use super::{OngoingGame, storage::Series, vacation::MAX_DAYS_PER_MOVE};
+
+/// The most games a series can be played over.
+pub const MAX_SERIES_GAMES: u32 = 15;
+
+/// Checks `config` is a series that can be played. Refusals come with the reason to give the
+/// player.
+pub(super) fn check(config: &SeriesConfig) -> Result<(), String> {
+    if !(1..=MAX_SERIES_GAMES).contains(&config.games) {
+        return Err(format!(
+            "A series is played over 1 to {MAX_SERIES_GAMES} games"
+        ));
+    }
+    if let Some(odds) = config.armageddon {
+        let days = [odds.must_win_days, odds.draw_odds_days];
+        if days
+            .iter()
+            .any(|days| !(1..=MAX_DAYS_PER_MOVE).contains(days))
+        {
+            return Err(format!(
+                "Give each move of an Armageddon game between 1 and {MAX_DAYS_PER_MOVE} days"
+            ));
+        }
+        if odds.must_win_days <= odds.draw_odds_days {
+            return Err(
+                "Give the player who has to win more days per move than the one with draw odds"
+                    .into(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The first game of a series played by `config`.
+pub(super) fn start(config: SeriesConfig) -> Series {
+    Series {
+        config,
+        half_points: [0; 2],
+        played: 0,
+    }
+}
+
+/// Whether the game `series` is at is the Armageddon game, after all the others.
+pub(super) fn is_armageddon(series: &Series) -> bool {
+    series.played >= series.config.games
+}
+
+/// How many days `player` has for each move in `game`, if it's timed. In an Armageddon game
+/// that's by the odds, and otherwise the same for both players.
+pub(super) fn days_per_move(game: &OngoingGame, player: Player) -> Option<u32> {
+    let odds = game
+        .series
+        .filter(is_armageddon)
+        .and_then(|series| series.config.armageddon);
+    match odds {
+        Some(odds) if player == Player::Player1 => Some(odds.must_win_days),
+        Some(odds) => Some(odds.draw_odds_days),
+        None => game.days_per_move,
+    }
+}
+
+/// How a game at `series` counts: as it ended, except that a draw in an Armageddon game is a win
+/// for player 2.
+pub(super) fn adjudicate(series: Option<&Series>, result: GameResult) -> GameResult {
+    match result {
+        GameResult::Draw { .. } if series.is_some_and(is_armageddon) => GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::DrawOdds,
+        },
+        result => result,
+    }
+}
+
+/// Where `series` stands for the next game once the game it's at ended in `result`, as counted
+/// by [`adjudicate`], or `None` if that was the last. The players swap sides for the next game,
+/// and so do their scores.
+pub(super) fn next(series: &Series, result: GameResult) -> Option<Series> {
+    if is_armageddon(series) {
+        return None;
+    }
+    let mut half_points = series.half_points;
+    match result {
+        GameResult::Win { winner, .. } => half_points[winner.index()] += 2,
+        GameResult::Draw { .. } => half_points.iter_mut().for_each(|points| *points += 1),
+    }
+    let played = series.played + 1;
+    let games = series.config.games;
+    // More than half the points there are to score can't be caught
+    if half_points.iter().any(|&points| points > games) {
+        return None;
+    }
+    let level = half_points[0] == half_points[1];
+    if played == games && !(level && series.config.armageddon.is_some()) {
+        return None;
+    }
+    half_points.reverse();
+    Some(Series {
+        config: series.config,
+        half_points,
+        played,
+    })
+}
+
+/// Where `series` stands for `side`.
+pub(super) fn standing(series: &Series, side: Player) -> SeriesStanding {
+    SeriesStanding {
+        game: series.played + 1,
+        config: series.config,
+        your_half_points: series.half_points[side.index()],
+        opponent_half_points: series.half_points[side.opponent().index()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ArmageddonOdds, SeriesConfig,
+        logic::{DrawReason, GameResult, Player, WinReason},
+    };
+
+    use super::{adjudicate, check, is_armageddon, next, start};
+
+    fn config(games: u32, armageddon: bool) -> SeriesConfig {
+        SeriesConfig {
+            games,
+            armageddon: armageddon.then_some(ArmageddonOdds {
+                must_win_days: 3,
+                draw_odds_days: 2,
+            }),
+        }
+    }
+
+    fn win(winner: Player) -> GameResult {
+        GameResult::Win {
+            winner,
+            reason: WinReason::KingDestroyed,
+        }
+    }
+
+    const DRAW: GameResult = GameResult::Draw {
+        reason: DrawReason::Repetition,
+    };
+
+    #[test]
+    fn series_end_once_someone_cant_be_caught() {
+        let series = start(config(3, true));
+        // Player 1 of the first game wins it, then plays the second as player 2
+        let second = next(&series, win(Player::Player1)).unwrap();
+        assert_eq!((second.played, second.half_points), (1, [0, 2]));
+        let third = next(&second, DRAW).unwrap();
+        // Back on the side they started, the first game's winner is a point and a half up
+        assert_eq!(third.half_points, [3, 1]);
+        // A win or a draw for the leader settles it
+        assert_eq!(next(&second, win(Player::Player2)), None);
+        assert_eq!(next(&third, DRAW), None);
+        assert_eq!(next(&third, win(Player::Player1)), None);
+    }
+
+    #[test]
+    fn level_series_go_to_armageddon() {
+        let series = start(config(2, true));
+        let second = next(&series, win(Player::Player1)).unwrap();
+        let armageddon = next(&second, win(Player::Player1)).unwrap();
+        assert!(is_armageddon(&armageddon));
+        assert_eq!(armageddon.half_points, [2, 2]);
+        // Where a draw is a win for player 2, and the series is over whatever happens
+        assert_eq!(
+            adjudicate(Some(&armageddon), DRAW),
+            GameResult::Win {
+                winner: Player::Player2,
+                reason: WinReason::DrawOdds,
+            }
+        );
+        assert_eq!(adjudicate(Some(&second), DRAW), DRAW);
+        assert_eq!(next(&armageddon, win(Player::Player1)), None);
+
+        // Without Armageddon, a level series is drawn
+        let second = next(&start(config(2, false)), DRAW).unwrap();
+        assert_eq!(next(&second, DRAW), None);
+    }
+
+    #[test]
+    fn series_need_odds_that_favour_the_player_who_has_to_win() {
+        assert!(check(&config(3, true)).is_ok());
+        assert!(check(&config(0, false)).is_err());
+        assert!(check(&config(16, false)).is_err());
+        let mut even = config(3, true);
+        even.armageddon.as_mut().unwrap().draw_odds_days = 3;
+        assert_eq!(
+            check(&even).unwrap_err(),
+            "Give the player who has to win more days per move than the one with draw odds"
+        );
+    }
+}
@@ -0,0 +1,882 @@
+//! [`GameSession`], one game between two players, whatever they're connected by.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::mpsc::UnboundedReceiver, time::sleep_until};
+use tracing::{info, warn};
+
+use super::metrics::Metrics;
+use crate::{
+    ClientRequest, ServerMessage,
+    logic::{
+        Annotation, Board, Clock, DrawReason, GameRecord, GameResult, GameState, Move, Player,
+        RulesConfig, SetupError, SetupKind, TimeControl, TimedMove, WinReason, format_coord,
+    },
+};
+
+/// The longest chat message, in characters, that's passed on.
+pub const MAX_CHAT_LENGTH: usize = 500;
+
+/// How many chat messages a player can send in [`CHAT_WINDOW`] before the rest are dropped.
+const CHAT_LIMIT: usize = 5;
+
+const CHAT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Something that can play one side of a game: it's sent what a client would be sent and answers
+/// with requests.
+pub trait PlayerConnection: Send {
+    fn send(&mut self, message: &ServerMessage) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Waits for the next request. It must be safe to cancel, since the game waits on both
+    /// players at once and drops whichever doesn't answer first.
+    fn recv(&mut self) -> impl Future<Output = anyhow::Result<ClientRequest>> + Send;
+
+    /// Says goodbye once the game is over. Does nothing unless the connection has a way to.
+    fn close(&mut self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// A player in a [`GameSession`].
+pub struct PlayerHandle<C> {
+    pub name: String,
+    /// Round-trip time to the server, as told to both players at the start.
+    pub latency: Duration,
+    /// Rating going into the game, as told to both players at the start. `None` for players
+    /// without one, like bots.
+    pub rating: Option<i32>,
+    pub connection: C,
+    /// The token this player can resume the game with, sent to them at the start. Only useful
+    /// if the session [takes reconnections](GameSession::reconnections).
+    pub session_token: Option<String>,
+}
+
+/// One game between two players. Player 1 is the first handle and moves first.
+pub struct GameSession<C> {
+    game: GameState,
+    setup: SetupKind,
+    players: [PlayerHandle<C>; 2],
+    /// Time each player has spent thinking, from when the previous move was played to when theirs
+    /// arrived.
+    thinking: [Duration; 2],
+    /// The chess clock, in a timed game.
+    clock: Option<Clock>,
+    /// When the game started, once it's [running](GameSession::run).
+    started: Instant,
+    /// Every move played, with when it was played and the mover's time left after it.
+    timed_moves: Vec<TimedMove>,
+    /// Whether the game counts towards the players' ratings, as told to them at the start.
+    rated: bool,
+    /// Connections watching the game. They're sent the setup as player 1 sees it, every move as
+    /// [`ServerMessage::OpponentMoved`] and the result.
+    spectators: Vec<C>,
+    /// How long a player who lost their connection has to come back, and where their new
+    /// connections arrive.
+    reconnections: Option<(Duration, UnboundedReceiver<(Player, C)>)>,
+    /// For each player whose connection was lost, when they lose the game unless they're back.
+    deadlines: [Option<Instant>; 2],
+    /// The player whose draw offer is waiting for an answer.
+    draw_offer: Option<Player>,
+    /// When each player sent the chat messages they've sent in the last [`CHAT_WINDOW`].
+    recent_chat: [VecDeque<Instant>; 2],
+    /// Where moves played and rejected are counted, for games the server hosts.
+    metrics: Option<Arc<Metrics>>,
+    /// Finishes when the game is to stop, finished or not.
+    stop: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+/// Something [`GameSession::run`] was waiting for.
+enum Event<C> {
+    Request(Player, anyhow::Result<ClientRequest>),
+    Resumed(Player, C),
+    GaveUp(Player),
+    /// The player to move ran out of time.
+    Flagged,
+    /// The game is to stop before it's finished.
+    Stopped,
+}
+
+impl<C: PlayerConnection> GameSession<C> {
+    /// A game from `setup` under `rules`, or why nobody could finish it.
+    pub fn new(
+        setup: SetupKind,
+        rules: RulesConfig,
+        players: [PlayerHandle<C>; 2],
+    ) -> Result<Self, SetupError> {
+        let board = Board::from_setup(setup, &rules);
+        board.validate(&rules)?;
+        Ok(Self {
+            game: GameState::new(board).with_rules(rules),
+            setup,
+            players,
+            thinking: [Duration::ZERO; 2],
+            clock: None,
+            started: Instant::now(),
+            timed_moves: Vec::new(),
+            rated: false,
+            spectators: Vec::new(),
+            reconnections: None,
+            deadlines: [None; 2],
+            draw_offer: None,
+            recent_chat: Default::default(),
+            metrics: None,
+            stop: None,
+        })
+    }
+
+    /// Keeps the game going for `grace` when a player's connection is lost, so they can come back
+    /// on a new connection sent to `connections`. They're sent a [`ServerMessage::StateSync`] in
+    /// place of the moves they missed. Without this, a player who loses their connection loses
+    /// the game straight away.
+    pub fn reconnections(
+        mut self,
+        grace: Duration,
+        connections: UnboundedReceiver<(Player, C)>,
+    ) -> Self {
+        self.reconnections = Some((grace, connections));
+        self
+    }
+
+    /// Plays the game on a clock. A player who runs out of time loses by
+    /// [`WinReason::Timeout`].
+    pub fn time_control(mut self, control: TimeControl) -> Self {
+        self.clock = Some(Clock::new(control));
+        self
+    }
+
+    /// Tells the players the game counts towards their ratings. Updating them is up to whoever
+    /// [runs](GameSession::run) the game.
+    pub fn rated(mut self) -> Self {
+        self.rated = true;
+        self
+    }
+
+    /// Stops the game unfinished once `stop` finishes, say because the server is shutting down.
+    /// [`run`](GameSession::run) returns without a result and without telling anyone, leaving the
+    /// connections open for whoever runs the game to [close](GameSession::close_with) once they've
+    /// dealt with it.
+    pub fn until(mut self, stop: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.stop = Some(Box::pin(stop));
+        self
+    }
+
+    /// Counts the moves played and rejected in `metrics`.
+    pub(super) fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Lets `connection` watch the game from the start.
+    pub fn add_spectator(&mut self, connection: C) {
+        self.spectators.push(connection);
+    }
+
+    pub fn game(&self) -> &GameState {
+        &self.game
+    }
+
+    pub fn players(&self) -> &[PlayerHandle<C>; 2] {
+        &self.players
+    }
+
+    /// Time `player` has spent thinking so far.
+    pub fn thinking_time(&self, player: Player) -> Duration {
+        self.thinking[player.index()]
+    }
+
+    /// The chess clock, if the game is timed.
+    pub fn clock(&self) -> Option<&Clock> {
+        self.clock.as_ref()
+    }
+
+    /// The game so far, with the players' names and how long into the game each move was played.
+    pub fn record(&self) -> GameRecord {
+        let names = self.players.each_ref().map(|player| player.name.clone());
+        let mut record = GameRecord::new(
+            names,
+            self.game.history().initial().clone(),
+            self.game.rules().clone(),
+        );
+        record.moves = self.timed_moves.clone();
+        record.result = self.game.result();
+        record
+    }
+
+    /// Plays the game to the end, tells everyone the result and closes their connections.
+    /// Invalid and out-of-turn moves are rejected. Either player can resign or offer a draw at any
+    /// time. A player whose connection fails and who doesn't come back in time loses by
+    /// [`WinReason::Disconnect`], and in a timed game one whose time runs out by
+    /// [`WinReason::Timeout`]. Returns `None` if the game [was stopped](GameSession::until) first.
+    pub async fn run(&mut self) -> anyhow::Result<Option<GameResult>> {
+        for player in [Player::Player1, Player::Player2] {
+            let setup = self.initial_setup(player.index());
+            self.send(player, &setup).await?;
+        }
+        self.broadcast_spectators(&self.initial_setup(0)).await;
+
+        self.started = Instant::now();
+        let mut turn_start = self.started;
+        while self.game.result().is_none() {
+            let deadline = self.deadlines.iter().flatten().min().copied();
+            // A flag fall too far off to represent never comes
+            let flag_fall = self
+                .clock
+                .and_then(|clock| turn_start.checked_add(clock.time_left(Duration::ZERO)));
+            let [first_deadline, second_deadline] = self.deadlines;
+            let give_up = deadline.unwrap_or_else(Instant::now);
+            let flag = flag_fall.unwrap_or_else(Instant::now);
+            let [first, second] = &mut self.players;
+            let reconnections = &mut self.reconnections;
+            let stop = &mut self.stop;
+            // Listen to both players, so moves sent out of turn are rejected instead of being
+            // picked up as that player's next move
+            let event = tokio::select! {
+                request = first.connection.recv(), if first_deadline.is_none() => {
+                    Event::Request(Player::Player1, request)
+                }
+                request = second.connection.recv(), if second_deadline.is_none() => {
+                    Event::Request(Player::Player2, request)
+                }
+                Some((player, connection)) = async {
+                    match reconnections {
+                        Some((_, connections)) => connections.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => Event::Resumed(player, connection),
+                _ = sleep_until(give_up.into()), if deadline.is_some() => {
+                    let player = if first_deadline == deadline {
+                        Player::Player1
+                    } else {
+                        Player::Player2
+                    };
+                    Event::GaveUp(player)
+                }
+                _ = sleep_until(flag.into()), if flag_fall.is_some() => {
+                    Event::Flagged
+                }
+                () = async {
+                    match stop {
+                        Some(stop) => stop.await,
+                        None => std::future::pending().await,
+                    }
+                } => Event::Stopped,
+            };
+            match event {
+                Event::Request(player, Err(e)) => self.disconnected(player, e).await?,
+                Event::Request(player, Ok(ClientRequest::Move(player_move))) => {
+                    let thinking = turn_start.elapsed();
+                    // A move that arrives after the flag fell is too late to count
+                    if player == self.game.to_move() && self.flag_fell(thinking) {
+                        continue;
+                    }
+                    if self.play(player, player_move, thinking).await? {
+                        self.thinking[player.index()] += thinking;
+                        turn_start = Instant::now();
+                        // Moving instead of answering a draw offer turns it down
+                        if self.draw_offer == Some(player.opponent()) {
+                            self.decline_draw(player).await?;
+                        }
+                    }
+                }
+                Event::Request(player, Ok(ClientRequest::Resign)) => {
+                    info!("{} resigned", self.players[player.index()].name);
+                    self.game.end(GameResult::Win {
+                        winner: player.opponent(),
+                        reason: WinReason::Resignation,
+                    });
+                }
+                Event::Request(player, Ok(ClientRequest::OfferDraw)) => {
+                    self.offer_draw(player).await?
+                }
+                Event::Request(player, Ok(ClientRequest::AcceptDraw)) => self.accept_draw(player),
+                Event::Request(player, Ok(ClientRequest::DeclineDraw)) => {
+                    self.decline_draw(player).await?
+                }
+                Event::Request(player, Ok(ClientRequest::Chat(text))) => {
+                    self.chat(player, text).await?
+                }
+                Event::Request(player, Ok(_)) => warn!(
+                    "Unexpected request from {} during the game",
+                    self.players[player.index()].name
+                ),
+                Event::Resumed(player, connection) => self.resume(player, connection).await,
+                Event::GaveUp(player) => {
+                    info!(
+                        "{} didn't come back in time",
+                        self.players[player.index()].name
+                    );
+                    self.forfeit(player);
+                }
+                Event::Flagged => {
+                    self.flag_fell(turn_start.elapsed());
+                }
+                Event::Stopped => {
+                    info!(
+                        moves = self.timed_moves.len(),
+                        position = %self.game,
+                        "Game stopped unfinished"
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+
+        let result = self.game.result().unwrap(); // The loop only ends once there's a result
+        info!(
+            ?result,
+            moves = self.timed_moves.len(),
+            position = %self.game,
+            "Game over"
+        );
+        let game_over = ServerMessage::GameOver(result);
+        for player in [Player::Player1, Player::Player2] {
+            self.send(player, &game_over).await?;
+        }
+        self.broadcast_spectators(&game_over).await;
+        for (handle, deadline) in self.players.iter_mut().zip(self.deadlines) {
+            if deadline.is_none() {
+                handle.connection.close().await;
+            }
+        }
+        for spectator in &mut self.spectators {
+            spectator.close().await;
+        }
+        Ok(Some(result))
+    }
+
+    /// Tells everyone still connected `message` and closes their connections, for a game that
+    /// [was stopped](GameSession::until) before it finished.
+    pub async fn close_with(&mut self, message: &ServerMessage) {
+        for (handle, deadline) in self.players.iter_mut().zip(self.deadlines) {
+            if deadline.is_none() {
+                let _ = handle.connection.send(message).await;
+                handle.connection.close().await;
+            }
+        }
+        self.broadcast_spectators(message).await;
+        for spectator in &mut self.spectators {
+            spectator.close().await;
+        }
+    }
+
+    /// What player `order` is told about the game before it starts.
+    fn initial_setup(&self, order: usize) -> ServerMessage {
+        let (player, opponent) = (&self.players[order], &self.players[1 - order]);
+        ServerMessage::InitialSetup {
+            board: self.game.board().clone(),
+            setup: self.setup,
+            rules: self.game.rules().clone(),
+            time_control: self.clock.map(|clock| *clock.control()),
+            player_order: order,
+            opponent_name: opponent.name.clone(),
+            latency_ms: player.latency.as_millis() as u64,
+            opponent_latency_ms: opponent.latency.as_millis() as u64,
+            session_token: player.session_token.clone(),
+            rated: self.rated,
+            rating: player.rating,
+            opponent_rating: opponent.rating,
+        }
+    }
+
+    /// Plays `player_move` for `player` after `thinking` about it, tells them whether it was legal,
+    /// and relays it to their opponent and the spectators if so, with the time they have left.
+    /// Returns whether it was legal.
+    async fn play(
+        &mut self,
+        player: Player,
+        player_move: Move,
+        thinking: Duration,
+    ) -> anyhow::Result<bool> {
+        let name = &self.players[player.index()].name;
+        let outcome = match self.game.apply_as(player, &player_move) {
+            Ok(outcome) => outcome,
+            Err(reason) => {
+                warn!(player = %name, %player_move, %reason, "Move rejected");
+                if let Some(metrics) = &self.metrics {
+                    metrics.move_rejected();
+                }
+                self.send(player, &ServerMessage::MoveRejected { reason })
+                    .await?;
+                return Ok(false);
+            }
+        };
+        info!(
+            player = %name,
+            %player_move,
+            number = self.timed_moves.len() + 1,
+            thinking_ms = thinking.as_millis() as u64,
+            "Move played"
+        );
+        for capture in &outcome.captures {
+            info!(
+                player = %name,
+                piece = capture.piece.kind.name(),
+                at = %format_coord(capture.position),
+                "Piece hit"
+            );
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.move_played();
+        }
+        if let Some(clock) = &mut self.clock
+            && let Some(result) = clock.apply_move(thinking)
+        {
+            self.game.end(result);
+        }
+        let clock = self.clock;
+        self.timed_moves.push(TimedMove {
+            player_move,
+            elapsed: self.started.elapsed(),
+            annotation: Annotation {
+                clock: clock.map(|clock| clock.remaining(player)),
+                ..Annotation::default()
+            },
+        });
+        self.send(player, &ServerMessage::MoveAccepted { clock })
+            .await?;
+        let moved = ServerMessage::OpponentMoved { player_move, clock };
+        self.send(player.opponent(), &moved).await?;
+        self.broadcast_spectators(&moved).await;
+        Ok(true)
+    }
+
+    /// Ends the game on time if the player to move has used up their time after `thinking` for
+    /// so long. Returns whether they had.
+    fn flag_fell(&mut self, thinking: Duration) -> bool {
+        let Some(clock) = &mut self.clock else {
+            return false;
+        };
+        if !clock.time_left(thinking).is_zero() {
+            return false;
+        }
+        let name = &self.players[clock.to_move().index()].name;
+        info!("{} ran out of time", name);
+        // Charging the time it's had stops the clock and hands back the loss
+        if let Some(result) = clock.apply_move(thinking) {
+            self.game.end(result);
+        }
+        true
+    }
+
+    /// Passes `player`'s draw offer on to their opponent, or agrees to a draw if the opponent has
+    /// already offered one.
+    async fn offer_draw(&mut self, player: Player) -> anyhow::Result<()> {
+        match self.draw_offer {
+            Some(offerer) if offerer != player => self.accept_draw(player),
+            Some(_) => {}
+            None => {
+                info!("{} offered a draw", self.players[player.index()].name);
+                self.draw_offer = Some(player);
+                self.send(player.opponent(), &ServerMessage::DrawOffered)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the game in a draw if `player`'s opponent offered one.
+    fn accept_draw(&mut self, player: Player) {
+        let name = &self.players[player.index()].name;
+        if self.draw_offer != Some(player.opponent()) {
+            warn!("{} accepted a draw that wasn't offered", name);
+            return;
+        }
+        info!("{} accepted the draw", name);
+        self.game.end(GameResult::Draw {
+            reason: DrawReason::Agreement,
+        });
+    }
+
+    /// Turns down the draw `player`'s opponent offered and lets them know.
+    async fn decline_draw(&mut self, player: Player) -> anyhow::Result<()> {
+        let name = &self.players[player.index()].name;
+        if self.draw_offer != Some(player.opponent()) {
+            warn!("{} declined a draw that wasn't offered", name);
+            return Ok(());
+        }
+        info!("{} declined the draw", name);
+        self.draw_offer = None;
+        self.send(player.opponent(), &ServerMessage::DrawDeclined)
+            .await
+    }
+
+    /// Passes `player`'s chat message on to their opponent and the spectators, unless it's too long
+    /// or they've been sending too many.
+    async fn chat(&mut self, player: Player, text: String) -> anyhow::Result<()> {
+        let name = &self.players[player.index()].name;
+        let length = text.chars().count();
+        if length > MAX_CHAT_LENGTH {
+            warn!("Dropping a {}-character chat message from {}", length, name);
+            return Ok(());
+        }
+        let now = Instant::now();
+        let recent = &mut self.recent_chat[player.index()];
+        while recent.front().is_some_and(|&sent| now - sent > CHAT_WINDOW) {
+            recent.pop_front();
+        }
+        if recent.len() >= CHAT_LIMIT {
+            warn!(
+                "Dropping a chat message from {}, who's sending too many",
+                name
+            );
+            return Ok(());
+        }
+        recent.push_back(now);
+        let message = ServerMessage::Chat {
+            from: name.clone(),
+            text,
+        };
+        self.send(player.opponent(), &message).await?;
+        self.broadcast_spectators(&message).await;
+        Ok(())
+    }
+
+    /// Sends `message` to `player`, unless they're away, in which case they'll catch up when they
+    /// come back.
+    async fn send(&mut self, player: Player, message: &ServerMessage) -> anyhow::Result<()> {
+        if self.deadlines[player.index()].is_some() {
+            return Ok(());
+        }
+        if let Err(e) = self.players[player.index()].connection.send(message).await {
+            self.disconnected(player, e).await?;
+        }
+        Ok(())
+    }
+
+    /// Starts the clock on `player` coming back after their connection failed with `error`, or
+    /// gives the game to their opponent if they can't.
+    async fn disconnected(&mut self, player: Player, error: anyhow::Error) -> anyhow::Result<()> {
+        let handle = &mut self.players[player.index()];
+        // A connection that went quiet may still be there, so tell the client to come back on a
+        // new one rather than leave it waiting on a game that's stopped listening
+        handle.connection.close().await;
+        let name = &handle.name;
+        let now = Instant::now();
+        match &self.reconnections {
+            Some((grace, _)) if self.game.result().is_none() => {
+                info!("Lost {}: {}, holding the game for {:?}", name, error, grace);
+                let grace = *grace;
+                self.deadlines[player.index()] = Some(now + grace);
+                let notice = ServerMessage::OpponentDisconnected {
+                    grace_seconds: grace.as_secs(),
+                };
+                self.notify_opponent(player, &notice).await;
+            }
+            _ => {
+                info!("Lost {}: {}", name, error);
+                self.deadlines[player.index()] = Some(now);
+                self.forfeit(player);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the game with a win for `player`'s opponent, unless it's already over.
+    fn forfeit(&mut self, player: Player) {
+        self.game.end(GameResult::Win {
+            winner: player.opponent(),
+            reason: WinReason::Disconnect,
+        });
+    }
+
+    /// Puts `player` back in the game on `connection`, after bringing it up to date.
+    async fn resume(&mut self, player: Player, mut connection: C) {
+        let opponent = &self.players[player.opponent().index()];
+        let sync = ServerMessage::StateSync {
+            state: self.game.clone(),
+            player_order: player.index(),
+            opponent_name: opponent.name.clone(),
+            clock: self.clock,
+        };
+        let handle = &mut self.players[player.index()];
+        if let Err(e) = connection.send(&sync).await {
+            info!("{} couldn't resume: {}", handle.name, e);
+            return;
+        }
+        info!("{} resumed the game", handle.name);
+        handle.connection = connection;
+        self.deadlines[player.index()] = None;
+        self.notify_opponent(player, &ServerMessage::OpponentReconnected)
+            .await;
+    }
+
+    /// Tells `player`'s opponent about `player`'s connection. Sending it can't go through
+    /// [`Self::send`], which would need this to find out the opponent's connection failed too;
+    /// if it has, they'll be found out when the game next listens to them.
+    async fn notify_opponent(&mut self, player: Player, message: &ServerMessage) {
+        let opponent = player.opponent().index();
+        if self.deadlines[opponent].is_none() {
+            let _ = self.players[opponent].connection.send(message).await;
+        }
+    }
+
+    /// Sends `message` to every spectator, dropping any that can't be reached rather than
+    /// holding up the game.
+    async fn broadcast_spectators(&mut self, message: &ServerMessage) {
+        let mut spectators = Vec::with_capacity(self.spectators.len());
+        for mut spectator in self.spectators.drain(..) {
+            match spectator.send(message).await {
+                Ok(()) => spectators.push(spectator),
+                Err(e) => info!("Dropping spectator: {}", e),
+            }
+        }
+        self.spectators = spectators;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use tokio::{
+        sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        task::JoinHandle,
+        time::timeout,
+    };
+
+    use super::{GameSession, PlayerConnection, PlayerHandle};
+    use crate::{
+        ClientRequest, ServerMessage,
+        logic::{DrawReason, GameResult, Player, RulesConfig, SetupKind, TimeControl, WinReason},
+    };
+
+    /// The server's end of a connection made of channels. Messages go through JSON on the way, as
+    /// they would over a socket.
+    struct ChannelConnection {
+        messages: UnboundedSender<ServerMessage>,
+        requests: UnboundedReceiver<ClientRequest>,
+    }
+
+    impl PlayerConnection for ChannelConnection {
+        async fn send(&mut self, message: &ServerMessage) -> anyhow::Result<()> {
+            let message = serde_json::from_str(&serde_json::to_string(message)?)?;
+            self.messages
+                .send(message)
+                .map_err(|_| anyhow!("client went away"))
+        }
+
+        async fn recv(&mut self) -> anyhow::Result<ClientRequest> {
+            self.requests
+                .recv()
+                .await
+                .ok_or_else(|| anyhow!("client went away"))
+        }
+    }
+
+    /// The client's end. Dropping it loses the connection.
+    struct Client {
+        messages: UnboundedReceiver<ServerMessage>,
+        requests: UnboundedSender<ClientRequest>,
+    }
+
+    impl Client {
+        fn send(&self, request: ClientRequest) {
+            self.requests.send(request).unwrap();
+        }
+
+        /// The next message, failing the test if it takes too long to come.
+        async fn next(&mut self) -> ServerMessage {
+            timeout(Duration::from_secs(5), self.messages.recv())
+                .await
+                .expect("no message from the server")
+                .expect("server hung up")
+        }
+    }
+
+    fn connect() -> (ChannelConnection, Client) {
+        let (messages, client_messages) = unbounded_channel();
+        let (client_requests, requests) = unbounded_channel();
+        let connection = ChannelConnection { messages, requests };
+        let client = Client {
+            messages: client_messages,
+            requests: client_requests,
+        };
+        (connection, client)
+    }
+
+    fn handle(name: &str, connection: ChannelConnection) -> PlayerHandle<ChannelConnection> {
+        PlayerHandle {
+            name: name.to_string(),
+            latency: Duration::ZERO,
+            rating: None,
+            connection,
+            session_token: None,
+        }
+    }
+
+    /// A classic game between two clients, not started yet.
+    fn session() -> (GameSession<ChannelConnection>, [Client; 2]) {
+        let (alice, alice_client) = connect();
+        let (bob, bob_client) = connect();
+        let players = [handle("alice", alice), handle("bob", bob)];
+        let session = GameSession::new(SetupKind::Classic, RulesConfig::default(), players);
+        (session.unwrap(), [alice_client, bob_client])
+    }
+
+    /// Runs `session` in the background, once both clients have seen the game start.
+    async fn start(
+        mut session: GameSession<ChannelConnection>,
+        clients: &mut [Client; 2],
+    ) -> JoinHandle<Option<GameResult>> {
+        let game = tokio::spawn(async move { session.run().await.unwrap() });
+        for (order, client) in clients.iter_mut().enumerate() {
+            let ServerMessage::InitialSetup { player_order, .. } = client.next().await else {
+                panic!("game didn't start with the setup");
+            };
+            assert_eq!(player_order, order);
+        }
+        game
+    }
+
+    /// Waits for `game` to finish and checks both clients still connected heard how.
+    async fn finish(
+        game: JoinHandle<Option<GameResult>>,
+        clients: &mut [Client],
+        expected: GameResult,
+    ) {
+        let result = timeout(Duration::from_secs(5), game)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Some(expected));
+        for client in clients {
+            assert!(
+                matches!(client.next().await, ServerMessage::GameOver(result) if result == expected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn move_is_relayed() {
+        let (session, mut clients) = session();
+        let first_move = session
+            .game()
+            .board()
+            .legal_moves(Player::Player1, &RulesConfig::default())[0];
+        let game = start(session, &mut clients).await;
+        let [alice, bob] = &mut clients;
+
+        alice.send(ClientRequest::Move(first_move));
+        assert!(matches!(
+            alice.next().await,
+            ServerMessage::MoveAccepted { .. }
+        ));
+        let ServerMessage::OpponentMoved { player_move, .. } = bob.next().await else {
+            panic!("bob wasn't sent alice's move");
+        };
+        assert_eq!(player_move, first_move);
+
+        // It's not alice's turn any more
+        alice.send(ClientRequest::Move(first_move));
+        assert!(matches!(
+            alice.next().await,
+            ServerMessage::MoveRejected { .. }
+        ));
+
+        alice.send(ClientRequest::Resign);
+        let bob_wins = GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::Resignation,
+        };
+        finish(game, &mut clients, bob_wins).await;
+    }
+
+    #[tokio::test]
+    async fn resignation_ends_the_game() {
+        let (session, mut clients) = session();
+        let game = start(session, &mut clients).await;
+
+        // Resigning out of turn is fine
+        clients[1].send(ClientRequest::Resign);
+        let alice_wins = GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        };
+        finish(game, &mut clients, alice_wins).await;
+    }
+
+    #[tokio::test]
+    async fn accepted_draw_offer_ends_the_game() {
+        let (session, mut clients) = session();
+        let game = start(session, &mut clients).await;
+        let [alice, bob] = &mut clients;
+
+        alice.send(ClientRequest::OfferDraw);
+        assert!(matches!(bob.next().await, ServerMessage::DrawOffered));
+        bob.send(ClientRequest::AcceptDraw);
+        let draw = GameResult::Draw {
+            reason: DrawReason::Agreement,
+        };
+        finish(game, &mut clients, draw).await;
+    }
+
+    #[tokio::test]
+    async fn disconnected_player_can_resume() {
+        let (session, mut clients) = session();
+        let (resume, connections) = unbounded_channel();
+        let session = session.reconnections(Duration::from_secs(60), connections);
+        let game = start(session, &mut clients).await;
+        let [mut alice, bob] = clients;
+
+        drop(bob);
+        let ServerMessage::OpponentDisconnected { grace_seconds } = alice.next().await else {
+            panic!("alice wasn't told bob left");
+        };
+        assert_eq!(grace_seconds, 60);
+
+        let (connection, mut bob) = connect();
+        resume.send((Player::Player2, connection)).unwrap();
+        let ServerMessage::StateSync { player_order, .. } = bob.next().await else {
+            panic!("bob wasn't brought up to date");
+        };
+        assert_eq!(player_order, 1);
+        assert!(matches!(
+            alice.next().await,
+            ServerMessage::OpponentReconnected
+        ));
+
+        bob.send(ClientRequest::Resign);
+        let alice_wins = GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        };
+        finish(game, &mut [alice, bob], alice_wins).await;
+    }
+
+    #[test]
+    fn thinking_for_all_the_time_left_loses() {
+        let (session, _clients) = session();
+        let mut session = session.time_control(TimeControl::new(1, 0));
+
+        assert!(session.flag_fell(Duration::from_secs(60)));
+        let bob_wins = GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::Timeout,
+        };
+        assert_eq!(session.game().result(), Some(bob_wins));
+        let clock = session.clock().unwrap();
+        assert_eq!(clock.flagged(), Some(Player::Player1));
+        assert_eq!(clock.to_move(), Player::Player1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_loses_without_reconnections() {
+        let (session, mut clients) = session();
+        let game = start(session, &mut clients).await;
+        let [alice, bob] = clients;
+
+        drop(alice);
+        let bob_wins = GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::Disconnect,
+        };
+        finish(game, &mut [bob], bob_wins).await;
+    }
+}
@@ -0,0 +1,34 @@
+//! Shutting the server down without losing the games being played. Everything that has to wrap
+//! up first holds a [`Shutdown`], and the server's done once they've all been dropped.
+
+use tokio::sync::watch;
+
+/// Watches for the server starting to shut down.
+#[derive(Clone)]
+pub(super) struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// A way to start shutting down, and something to watch for it with.
+    pub(super) fn new() -> (watch::Sender<bool>, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (sender, Self(receiver))
+    }
+
+    /// Something else to watch `sender` with.
+    pub(super) fn watching(sender: &watch::Sender<bool>) -> Self {
+        Self(sender.subscribe())
+    }
+
+    /// Finishes once the server starts shutting down, straight away if it already has. Never
+    /// finishes if the server's dropped without shutting down.
+    pub(super) async fn requested(&mut self) {
+        if self
+            .0
+            .wait_for(|&shutting_down| shutting_down)
+            .await
+            .is_err()
+        {
+            std::future::pending().await
+        }
+    }
+}
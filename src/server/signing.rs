@@ -0,0 +1,112 @@
+//! Signatures on finished games, so whoever a game is shared with can check it's as the server
+//! recorded it. A game is signed with ed25519 over its record in [`GameRecord`]'s text notation,
+//! exactly as the server writes it, and the key to check signatures with is served at
+//! `GET /signing-key`.
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier, VerifyingKey};
+use serde::Serialize;
+
+use crate::logic::GameRecord;
+
+/// Signs games with the server's key.
+#[derive(Clone)]
+pub(super) struct Signer(SigningKey);
+
+/// The key to check the server's signatures with, as `GET /signing-key` serves it.
+#[derive(Serialize)]
+pub(super) struct PublicKey {
+    algorithm: &'static str,
+    /// In base64.
+    public_key: String,
+}
+
+impl Signer {
+    /// Signs with the key `seed` makes, or with a new one if there's no seed, which nobody can
+    /// check signatures against once the server restarts.
+    pub(super) fn new(seed: Option<[u8; 32]>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            let mut seed = [0; 32];
+            getrandom::fill(&mut seed).expect("the OS should have randomness to give");
+            seed
+        });
+        Self(SigningKey::from_bytes(&seed))
+    }
+
+    pub(super) fn public_key(&self) -> PublicKey {
+        PublicKey {
+            algorithm: "ed25519",
+            public_key: BASE64_STANDARD.encode(self.0.verifying_key().as_bytes()),
+        }
+    }
+
+    /// The signature on `record`, in base64.
+    pub(super) fn sign(&self, record: &GameRecord) -> String {
+        let signature = self.0.sign(record.to_string().as_bytes());
+        BASE64_STANDARD.encode(signature.to_bytes())
+    }
+
+    /// `record` in its text notation, with its signature in a `Signature` tag before the rest.
+    /// Taking that line out leaves exactly what was signed.
+    pub(super) fn signed_notation(&self, record: &GameRecord) -> String {
+        format!("[Signature \"{}\"]\n{record}", self.sign(record))
+    }
+}
+
+/// Whether `signature` is the signature of the server with `public_key` on `record`, both in
+/// base64 as the server serves them.
+pub fn verify_record(record: &GameRecord, signature: &str, public_key: &str) -> bool {
+    let key = BASE64_STANDARD
+        .decode(public_key)
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .and_then(|key| VerifyingKey::from_bytes(&key).ok());
+    let signature = BASE64_STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|signature| Signature::from_slice(&signature).ok());
+    match (key, signature) {
+        (Some(key), Some(signature)) => key
+            .verify(record.to_string().as_bytes(), &signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logic::{GameResult, Player, WinReason};
+
+    use super::{super::storage::tests::finished, Signer, verify_record};
+
+    #[test]
+    fn altered_records_fail_to_verify() {
+        let signer = Signer::new(Some([7; 32]));
+        let key = signer.public_key().public_key;
+        let mut record = finished(["alice", "bob"], 20).record;
+        let signature = signer.sign(&record);
+        assert!(verify_record(&record, &signature, &key));
+        // The same key always makes the same signature
+        assert_eq!(Signer::new(Some([7; 32])).sign(&record), signature);
+
+        let other = Signer::new(None).public_key().public_key;
+        assert!(!verify_record(&record, &signature, &other));
+        assert!(!verify_record(&record, "not base64!", &key));
+        record.result = Some(GameResult::Win {
+            winner: Player::Player2,
+            reason: WinReason::Resignation,
+        });
+        assert!(!verify_record(&record, &signature, &key));
+    }
+
+    #[test]
+    fn signed_notation_still_reads_as_the_record() {
+        let signer = Signer::new(None);
+        let record = finished(["alice", "bob"], 20).record;
+        let signed = signer.signed_notation(&record);
+        assert_eq!(signed.parse(), Ok(record.clone()));
+        let (tag, signed_text) = signed.split_once('\n').unwrap();
+        assert_eq!(tag, format!("[Signature \"{}\"]", signer.sign(&record)));
+        assert_eq!(signed_text, record.to_string());
+    }
+}
@@ -0,0 +1,572 @@
+//! Keeping finished games, so they outlive the server and can be looked up afterwards for a
+//! player's history, to replay them, or to work ratings out again. Correspondence games are kept
+//! here while they're played too, along with the accounts of the players playing them.
+
+use std::{sync::Arc, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::{
+    SeriesConfig,
+    logic::{GameRecord, Move},
+};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+/// A game as it's kept once it's over.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FinishedGame {
+    /// The players, the moves and the result.
+    pub record: GameRecord,
+    /// Whether the game counted towards the players' ratings.
+    pub rated: bool,
+    /// The players' ratings once the game was over, player 1's first, if it was rated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ratings: Option<[i32; 2]>,
+    /// Serialized as milliseconds since the Unix epoch, as is `ended_at`.
+    #[serde(with = "unix_millis")]
+    pub started_at: SystemTime,
+    #[serde(with = "unix_millis")]
+    pub ended_at: SystemTime,
+}
+
+/// A correspondence game still being played.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OngoingGame {
+    /// The players and the moves so far, each timed from `started_at`.
+    pub record: GameRecord,
+    #[serde(with = "unix_millis")]
+    pub started_at: SystemTime,
+    /// Moves the player waiting for their opponent to move has left to be played for them: lines
+    /// of the opponent's move then their reply, as many times over as they like. Whichever line
+    /// the opponent's move starts first is played on, and lines the game leaves are dropped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditional_moves: Vec<Vec<Move>>,
+    /// How many days each player has for each move, not counting time on vacation, or `None` if
+    /// they can take as long as they like.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub days_per_move: Option<u32>,
+    /// Where the series the game is part of stands, if it's part of one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series: Option<Series>,
+}
+
+/// Where a series of correspondence games stands as of one of its games.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Series {
+    pub config: SeriesConfig,
+    /// The players' scores from the games before this one, in half points, in the order they
+    /// play this one.
+    pub half_points: [u32; 2],
+    /// How many games were played before this one.
+    pub played: u32,
+}
+
+/// A player's time away from their correspondence games.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vacation {
+    pub started_at: SystemTime,
+    /// When they came back, or `None` if they haven't yet.
+    pub ended_at: Option<SystemTime>,
+}
+
+/// Somewhere finished games are kept. Calls may block, so they're made off the async runtime.
+pub trait GameStore: Send + Sync {
+    /// Keeps `game`, returning the id to look it up by.
+    fn save(&self, game: &FinishedGame) -> anyhow::Result<u64>;
+
+    /// The game kept under `id`, if there is one.
+    fn game(&self, id: u64) -> anyhow::Result<Option<FinishedGame>>;
+
+    /// Up to `limit` of the games that ended last, with their ids, the most recent first.
+    fn recent_games(&self, limit: usize) -> anyhow::Result<Vec<(u64, FinishedGame)>>;
+
+    /// Up to `limit` of the games `player` played, with their ids, the most recent first.
+    fn games_of(&self, player: &str, limit: usize) -> anyhow::Result<Vec<(u64, FinishedGame)>>;
+
+    /// Gives `player` an account, logged into with the token that hashes to `token_hash`. Returns
+    /// whether it did, which it doesn't if they already have one.
+    fn register(&self, player: &str, token_hash: &str) -> anyhow::Result<bool>;
+
+    /// The hash of the token `player` logs in with, if they have an account.
+    fn token_hash(&self, player: &str) -> anyhow::Result<Option<String>>;
+
+    /// Has `player` log in with the token that hashes to `token_hash` from now on.
+    fn set_token_hash(&self, player: &str, token_hash: &str) -> anyhow::Result<()>;
+
+    /// The account tied to the user with `id` at OAuth provider `provider`, if there is one.
+    fn oauth_account(&self, provider: &str, id: &str) -> anyhow::Result<Option<String>>;
+
+    /// Gives `player` an account tied to the user with `id` at OAuth provider `provider`, as
+    /// [`register`](GameStore::register) does. Returns whether it did, which it doesn't if
+    /// `player` already has one.
+    fn register_oauth(
+        &self,
+        provider: &str,
+        id: &str,
+        player: &str,
+        token_hash: &str,
+    ) -> anyhow::Result<bool>;
+
+    /// Renames the account `from` to `to`, along with the games it's played and is playing and
+    /// any OAuth logins and vacations tied to it. Returns whether it did, which it doesn't if `to` already has an
+    /// account. Fails if `from` doesn't have one.
+    fn rename_account(&self, from: &str, to: &str) -> anyhow::Result<bool>;
+
+    /// The vacations `player` has taken and is taking, oldest first.
+    fn vacations(&self, player: &str) -> anyhow::Result<Vec<Vacation>>;
+
+    /// Replaces `player`'s vacations with `vacations`, oldest first.
+    fn set_vacations(&self, player: &str, vacations: &[Vacation]) -> anyhow::Result<()>;
+
+    /// Keeps a new correspondence game, returning the id to look it up by.
+    fn start_ongoing(&self, game: &OngoingGame) -> anyhow::Result<u64>;
+
+    /// Replaces the correspondence game kept under `id` with `game`, after a move.
+    fn update_ongoing(&self, id: u64, game: &OngoingGame) -> anyhow::Result<()>;
+
+    /// The correspondence game kept under `id`, if it's still being played.
+    fn ongoing(&self, id: u64) -> anyhow::Result<Option<OngoingGame>>;
+
+    /// The correspondence games `player` is playing, with their ids, oldest first.
+    fn ongoing_games_of(&self, player: &str) -> anyhow::Result<Vec<(u64, OngoingGame)>>;
+
+    /// Moves the correspondence game kept under `id` to the finished games as `game`, returning
+    /// its id there.
+    fn finish_ongoing(&self, id: u64, game: &FinishedGame) -> anyhow::Result<u64>;
+}
+
+/// Runs `f` against `store` on a thread that's allowed to block.
+pub(super) async fn blocking<T: Send + 'static>(
+    store: &Arc<dyn GameStore>,
+    f: impl FnOnce(&dyn GameStore) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let store = store.clone();
+    task::spawn_blocking(move || f(&*store)).await?
+}
+
+/// `SystemTime` as milliseconds since the Unix epoch, for `#[serde(with)]`.
+pub(super) mod unix_millis {
+    use std::time::{Duration, SystemTime};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(in crate::server) fn serialize<S: Serializer>(
+        time: &SystemTime,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let since_epoch = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_u64(since_epoch.as_millis() as u64)
+    }
+
+    pub(in crate::server) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SystemTime, D::Error> {
+        let millis = u64::deserialize(deserializer)?;
+        Ok(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        SeriesConfig,
+        logic::{Board, GameRecord, GameResult, GameState, Player, RulesConfig, WinReason},
+    };
+
+    use super::{FinishedGame, GameStore, OngoingGame, Series, Vacation};
+
+    /// A store that keeps everything in memory, for testing what's built on stores.
+    #[derive(Default)]
+    pub(in crate::server) struct MemoryStore {
+        games: Mutex<BTreeMap<u64, FinishedGame>>,
+        accounts: Mutex<HashMap<String, String>>,
+        oauth_accounts: Mutex<HashMap<(String, String), String>>,
+        ongoing: Mutex<BTreeMap<u64, OngoingGame>>,
+        vacations: Mutex<HashMap<String, Vec<Vacation>>>,
+        next_id: Mutex<u64>,
+    }
+
+    impl MemoryStore {
+        fn next_id(&self) -> u64 {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        }
+
+        /// Up to `limit` games matching `filter`, the one that ended last first.
+        fn latest(
+            &self,
+            limit: usize,
+            filter: impl Fn(&FinishedGame) -> bool,
+        ) -> Vec<(u64, FinishedGame)> {
+            let mut games: Vec<_> = self
+                .games
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, game)| filter(game))
+                .map(|(&id, game)| (id, game.clone()))
+                .collect();
+            games.sort_by_key(|(id, game)| std::cmp::Reverse((game.ended_at, *id)));
+            games.truncate(limit);
+            games
+        }
+    }
+
+    impl GameStore for MemoryStore {
+        fn save(&self, game: &FinishedGame) -> anyhow::Result<u64> {
+            let id = self.next_id();
+            self.games.lock().unwrap().insert(id, game.clone());
+            Ok(id)
+        }
+
+        fn game(&self, id: u64) -> anyhow::Result<Option<FinishedGame>> {
+            Ok(self.games.lock().unwrap().get(&id).cloned())
+        }
+
+        fn recent_games(&self, limit: usize) -> anyhow::Result<Vec<(u64, FinishedGame)>> {
+            Ok(self.latest(limit, |_| true))
+        }
+
+        fn games_of(&self, player: &str, limit: usize) -> anyhow::Result<Vec<(u64, FinishedGame)>> {
+            Ok(self.latest(limit, |game| {
+                game.record.players.iter().any(|p| p == player)
+            }))
+        }
+
+        fn register(&self, player: &str, token_hash: &str) -> anyhow::Result<bool> {
+            let mut accounts = self.accounts.lock().unwrap();
+            if accounts.contains_key(player) {
+                return Ok(false);
+            }
+            accounts.insert(player.to_string(), token_hash.to_string());
+            Ok(true)
+        }
+
+        fn token_hash(&self, player: &str) -> anyhow::Result<Option<String>> {
+            Ok(self.accounts.lock().unwrap().get(player).cloned())
+        }
+
+        fn set_token_hash(&self, player: &str, token_hash: &str) -> anyhow::Result<()> {
+            let mut accounts = self.accounts.lock().unwrap();
+            let kept = accounts
+                .get_mut(player)
+                .ok_or_else(|| anyhow::anyhow!("No account {}", player))?;
+            *kept = token_hash.to_string();
+            Ok(())
+        }
+
+        fn oauth_account(&self, provider: &str, id: &str) -> anyhow::Result<Option<String>> {
+            let key = (provider.to_string(), id.to_string());
+            Ok(self.oauth_accounts.lock().unwrap().get(&key).cloned())
+        }
+
+        fn register_oauth(
+            &self,
+            provider: &str,
+            id: &str,
+            player: &str,
+            token_hash: &str,
+        ) -> anyhow::Result<bool> {
+            if !self.register(player, token_hash)? {
+                return Ok(false);
+            }
+            let key = (provider.to_string(), id.to_string());
+            self.oauth_accounts
+                .lock()
+                .unwrap()
+                .insert(key, player.to_string());
+            Ok(true)
+        }
+
+        fn rename_account(&self, from: &str, to: &str) -> anyhow::Result<bool> {
+            let mut accounts = self.accounts.lock().unwrap();
+            if accounts.contains_key(to) {
+                return Ok(false);
+            }
+            let token_hash = accounts
+                .remove(from)
+                .ok_or_else(|| anyhow::anyhow!("No account {}", from))?;
+            accounts.insert(to.to_string(), token_hash);
+            for name in self.oauth_accounts.lock().unwrap().values_mut() {
+                if name == from {
+                    *name = to.to_string();
+                }
+            }
+            let mut vacations = self.vacations.lock().unwrap();
+            if let Some(taken) = vacations.remove(from) {
+                vacations.insert(to.to_string(), taken);
+            }
+            let mut games = self.games.lock().unwrap();
+            let mut ongoing = self.ongoing.lock().unwrap();
+            let records = games
+                .values_mut()
+                .map(|game| &mut game.record)
+                .chain(ongoing.values_mut().map(|game| &mut game.record));
+            for record in records {
+                for player in &mut record.players {
+                    if player == from {
+                        *player = to.to_string();
+                    }
+                }
+            }
+            Ok(true)
+        }
+
+        fn vacations(&self, player: &str) -> anyhow::Result<Vec<Vacation>> {
+            let vacations = self.vacations.lock().unwrap();
+            Ok(vacations.get(player).cloned().unwrap_or_default())
+        }
+
+        fn set_vacations(&self, player: &str, vacations: &[Vacation]) -> anyhow::Result<()> {
+            self.vacations
+                .lock()
+                .unwrap()
+                .insert(player.to_string(), vacations.to_vec());
+            Ok(())
+        }
+
+        fn start_ongoing(&self, game: &OngoingGame) -> anyhow::Result<u64> {
+            let id = self.next_id();
+            self.ongoing.lock().unwrap().insert(id, game.clone());
+            Ok(id)
+        }
+
+        fn update_ongoing(&self, id: u64, game: &OngoingGame) -> anyhow::Result<()> {
+            let mut ongoing = self.ongoing.lock().unwrap();
+            let kept = ongoing
+                .get_mut(&id)
+                .ok_or_else(|| anyhow::anyhow!("No ongoing game {}", id))?;
+            kept.record = game.record.clone();
+            kept.conditional_moves = game.conditional_moves.clone();
+            Ok(())
+        }
+
+        fn ongoing(&self, id: u64) -> anyhow::Result<Option<OngoingGame>> {
+            Ok(self.ongoing.lock().unwrap().get(&id).cloned())
+        }
+
+        fn ongoing_games_of(&self, player: &str) -> anyhow::Result<Vec<(u64, OngoingGame)>> {
+            Ok(self
+                .ongoing
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, game)| game.record.players.iter().any(|p| p == player))
+                .map(|(&id, game)| (id, game.clone()))
+                .collect())
+        }
+
+        fn finish_ongoing(&self, id: u64, game: &FinishedGame) -> anyhow::Result<u64> {
+            self.ongoing.lock().unwrap().remove(&id);
+            self.save(game)
+        }
+    }
+
+    /// A game between `players` that ended `ended` seconds after the epoch, won by player 1.
+    pub(in crate::server) fn finished(players: [&str; 2], ended: u64) -> FinishedGame {
+        let mut record = GameRecord::new(
+            players.map(String::from),
+            Board::classic_setup(),
+            RulesConfig::default(),
+        );
+        record.result = Some(GameResult::Win {
+            winner: Player::Player1,
+            reason: WinReason::Resignation,
+        });
+        let rated = ended.is_multiple_of(2);
+        FinishedGame {
+            record,
+            rated,
+            ratings: rated.then_some([1516, 1484]),
+            started_at: SystemTime::UNIX_EPOCH + Duration::from_millis(1_500),
+            ended_at: SystemTime::UNIX_EPOCH + Duration::from_secs(ended),
+        }
+    }
+
+    /// Checks `store` keeps to what [`GameStore`] promises.
+    pub(super) fn check_store(store: &dyn GameStore) {
+        // Saved out of order, so ending last isn't the same as saved last
+        let alice_bob = store.save(&finished(["alice", "bob"], 20)).unwrap();
+        let bob_carol = store.save(&finished(["bob", "carol"], 30)).unwrap();
+        let carol_alice = store.save(&finished(["carol", "alice"], 10)).unwrap();
+        assert_eq!(
+            store.game(alice_bob).unwrap(),
+            Some(finished(["alice", "bob"], 20))
+        );
+        assert_eq!(store.game(carol_alice + 100).unwrap(), None);
+        let ids = |games: Vec<(u64, FinishedGame)>| -> Vec<u64> {
+            games.into_iter().map(|(id, _)| id).collect()
+        };
+        assert_eq!(
+            ids(store.recent_games(10).unwrap()),
+            [bob_carol, alice_bob, carol_alice]
+        );
+        assert_eq!(ids(store.recent_games(1).unwrap()), [bob_carol]);
+        assert_eq!(
+            ids(store.games_of("alice", 10).unwrap()),
+            [alice_bob, carol_alice]
+        );
+        assert!(store.games_of("dave", 10).unwrap().is_empty());
+
+        assert!(store.register("alice", "hash").unwrap());
+        assert!(!store.register("alice", "other hash").unwrap());
+        assert_eq!(store.token_hash("alice").unwrap().as_deref(), Some("hash"));
+        assert_eq!(store.token_hash("bob").unwrap(), None);
+        store.set_token_hash("alice", "new hash").unwrap();
+        assert_eq!(
+            store.token_hash("alice").unwrap().as_deref(),
+            Some("new hash")
+        );
+        assert!(store.set_token_hash("bob", "hash").is_err());
+
+        assert_eq!(store.oauth_account("github", "1").unwrap(), None);
+        assert!(
+            !store
+                .register_oauth("github", "1", "alice", "hash")
+                .unwrap()
+        );
+        assert_eq!(store.oauth_account("github", "1").unwrap(), None);
+        assert!(
+            store
+                .register_oauth("github", "1", "bob", "bob's hash")
+                .unwrap()
+        );
+        assert_eq!(
+            store.oauth_account("github", "1").unwrap().as_deref(),
+            Some("bob")
+        );
+        assert_eq!(store.oauth_account("google", "1").unwrap(), None);
+        assert_eq!(
+            store.token_hash("bob").unwrap().as_deref(),
+            Some("bob's hash")
+        );
+
+        let mut ongoing = OngoingGame {
+            record: finished(["alice", "dave"], 0).record,
+            started_at: SystemTime::UNIX_EPOCH + Duration::from_millis(2_500),
+            conditional_moves: Vec::new(),
+            days_per_move: Some(3),
+            series: Some(Series {
+                config: SeriesConfig {
+                    games: 3,
+                    armageddon: None,
+                },
+                half_points: [2, 0],
+                played: 1,
+            }),
+        };
+        ongoing.record.result = None;
+        let id = store.start_ongoing(&ongoing).unwrap();
+        ongoing.record.players[1] = "renamed".into();
+        let some_move = GameState::new(Board::classic_setup()).legal_moves()[0];
+        ongoing.conditional_moves = vec![vec![some_move, some_move]];
+        store.update_ongoing(id, &ongoing).unwrap();
+        assert_eq!(store.ongoing(id).unwrap(), Some(ongoing.clone()));
+        assert_eq!(store.ongoing_games_of("alice").unwrap(), [(id, ongoing)]);
+        assert!(store.ongoing_games_of("bob").unwrap().is_empty());
+        assert!(store.update_ongoing(id + 100, &finished_ongoing()).is_err());
+
+        let done = finished(["alice", "dave"], 40);
+        let finished_id = store.finish_ongoing(id, &done).unwrap();
+        assert_eq!(store.ongoing(id).unwrap(), None);
+        assert!(store.ongoing_games_of("alice").unwrap().is_empty());
+        assert_eq!(store.game(finished_id).unwrap(), Some(done));
+        assert_eq!(ids(store.recent_games(1).unwrap()), [finished_id]);
+
+        let ongoing = OngoingGame {
+            record: finished(["bob", "alice"], 0).record,
+            started_at: SystemTime::UNIX_EPOCH,
+            conditional_moves: Vec::new(),
+            days_per_move: None,
+            series: None,
+        };
+        let ongoing_id = store.start_ongoing(&ongoing).unwrap();
+        let away = |days: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(days * 24 * 60 * 60);
+        let vacations = [
+            Vacation {
+                started_at: away(1),
+                ended_at: Some(away(3)),
+            },
+            Vacation {
+                started_at: away(10),
+                ended_at: None,
+            },
+        ];
+        assert!(store.vacations("alice").unwrap().is_empty());
+        store.set_vacations("alice", &vacations).unwrap();
+        store.set_vacations("bob", &vacations[1..]).unwrap();
+        assert_eq!(store.vacations("alice").unwrap(), vacations);
+        store.set_vacations("bob", &vacations[..1]).unwrap();
+        assert_eq!(store.vacations("bob").unwrap(), vacations[..1]);
+        assert!(!store.rename_account("alice", "bob").unwrap());
+        assert!(store.rename_account("dave", "erin").is_err());
+        assert!(store.rename_account("alice", "alicia").unwrap());
+        assert_eq!(store.token_hash("alice").unwrap(), None);
+        assert_eq!(
+            store.token_hash("alicia").unwrap().as_deref(),
+            Some("new hash")
+        );
+        assert!(store.games_of("alice", 10).unwrap().is_empty());
+        assert_eq!(
+            ids(store.games_of("alicia", 10).unwrap()),
+            [finished_id, alice_bob, carol_alice]
+        );
+        assert_eq!(
+            store.game(carol_alice).unwrap().unwrap().record.players,
+            ["carol", "alicia"]
+        );
+        assert_eq!(
+            store.game(bob_carol).unwrap(),
+            Some(finished(["bob", "carol"], 30))
+        );
+        let renamed = store.ongoing_games_of("alicia").unwrap();
+        assert_eq!(renamed[0].0, ongoing_id);
+        assert_eq!(renamed[0].1.record.players, ["bob", "alicia"]);
+        assert!(store.vacations("alice").unwrap().is_empty());
+        assert_eq!(store.vacations("alicia").unwrap(), vacations);
+        assert!(store.rename_account("bob", "robert").unwrap());
+        assert_eq!(
+            store.oauth_account("github", "1").unwrap().as_deref(),
+            Some("robert")
+        );
+    }
+
+    fn finished_ongoing() -> OngoingGame {
+        OngoingGame {
+            record: finished(["x", "y"], 0).record,
+            started_at: SystemTime::UNIX_EPOCH,
+            conditional_moves: Vec::new(),
+            days_per_move: None,
+            series: None,
+        }
+    }
+
+    #[test]
+    fn memory_store_keeps_its_promises() {
+        check_store(&MemoryStore::default());
+    }
+
+    #[test]
+    fn games_serialize_times_as_unix_millis() {
+        let game = finished(["alice", "bob"], 20);
+        let json = serde_json::to_value(&game).unwrap();
+        assert_eq!(json["started_at"], 1_500);
+        assert_eq!(json["ended_at"], 20_000);
+        assert_eq!(serde_json::from_value::<FinishedGame>(json).unwrap(), game);
+    }
+}
@@ -0,0 +1,541 @@
+//! [`SqliteStore`], keeping games in a SQLite database.
+
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use rusqlite::{Connection, OptionalExtension, Row, params};
+
+use crate::logic::GameRecord;
+
+use super::{FinishedGame, GameStore, OngoingGame, Vacation};
+
+/// Keeps games in a SQLite database, one row each. The players, result and times have columns of
+/// their own to query by, and the whole [`GameRecord`](crate::logic::GameRecord) is kept as JSON
+/// to replay the game from.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS games (
+                id INTEGER PRIMARY KEY,
+                player1 TEXT NOT NULL,
+                player2 TEXT NOT NULL,
+                result TEXT,
+                rated INTEGER NOT NULL,
+                started_at INTEGER NOT NULL,
+                ended_at INTEGER NOT NULL,
+                record TEXT NOT NULL,
+                rating1 INTEGER,
+                rating2 INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS games_ended_at ON games (ended_at);
+            CREATE INDEX IF NOT EXISTS games_player1 ON games (player1, ended_at);
+            CREATE INDEX IF NOT EXISTS games_player2 ON games (player2, ended_at);
+            CREATE TABLE IF NOT EXISTS accounts (
+                name TEXT PRIMARY KEY,
+                token_hash TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS oauth_accounts (
+                provider TEXT NOT NULL,
+                id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                PRIMARY KEY (provider, id)
+            );
+            CREATE TABLE IF NOT EXISTS ongoing_games (
+                id INTEGER PRIMARY KEY,
+                player1 TEXT NOT NULL,
+                player2 TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                record TEXT NOT NULL,
+                conditional_moves TEXT NOT NULL DEFAULT '[]',
+                days_per_move INTEGER,
+                series TEXT
+            );
+            CREATE INDEX IF NOT EXISTS ongoing_games_player1 ON ongoing_games (player1);
+            CREATE INDEX IF NOT EXISTS ongoing_games_player2 ON ongoing_games (player2);
+            CREATE TABLE IF NOT EXISTS vacations (
+                name TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                ended_at INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS vacations_name ON vacations (name, started_at);",
+        )?;
+        // Databases from before games kept the ratings they left the players on
+        if !has_column(&connection, "games", "rating1")? {
+            connection.execute_batch(
+                "ALTER TABLE games ADD COLUMN rating1 INTEGER;
+                ALTER TABLE games ADD COLUMN rating2 INTEGER;",
+            )?;
+        }
+        // And from before correspondence games could have moves left to be played
+        if !has_column(&connection, "ongoing_games", "conditional_moves")? {
+            connection.execute_batch(
+                "ALTER TABLE ongoing_games
+                ADD COLUMN conditional_moves TEXT NOT NULL DEFAULT '[]';",
+            )?;
+        }
+        // And from before they could be timed
+        if !has_column(&connection, "ongoing_games", "days_per_move")? {
+            connection
+                .execute_batch("ALTER TABLE ongoing_games ADD COLUMN days_per_move INTEGER;")?;
+        }
+        // And from before they could be part of a series
+        if !has_column(&connection, "ongoing_games", "series")? {
+            connection.execute_batch("ALTER TABLE ongoing_games ADD COLUMN series TEXT;")?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl GameStore for SqliteStore {
+    fn save(&self, game: &FinishedGame) -> anyhow::Result<u64> {
+        insert_game(&self.connection.lock().unwrap(), game)
+    }
+
+    fn game(&self, id: u64) -> anyhow::Result<Option<FinishedGame>> {
+        let connection = self.connection.lock().unwrap();
+        let row = connection
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM games WHERE id = ?1"),
+                params![i64::try_from(id)?],
+                read_row,
+            )
+            .optional()?;
+        Ok(row.map(finished_game).transpose()?.map(|(_, game)| game))
+    }
+
+    fn recent_games(&self, limit: usize) -> anyhow::Result<Vec<(u64, FinishedGame)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM games ORDER BY ended_at DESC, id DESC LIMIT ?1"
+        ))?;
+        let rows = statement.query_map(params![i64::try_from(limit)?], read_row)?;
+        rows.map(|row| finished_game(row?)).collect()
+    }
+
+    fn games_of(&self, player: &str, limit: usize) -> anyhow::Result<Vec<(u64, FinishedGame)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM games
+            WHERE player1 = ?1 OR player2 = ?1
+            ORDER BY ended_at DESC, id DESC
+            LIMIT ?2"
+        ))?;
+        let rows = statement.query_map(params![player, i64::try_from(limit)?], read_row)?;
+        rows.map(|row| finished_game(row?)).collect()
+    }
+
+    fn register(&self, player: &str, token_hash: &str) -> anyhow::Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        let added = connection.execute(
+            "INSERT OR IGNORE INTO accounts (name, token_hash) VALUES (?1, ?2)",
+            params![player, token_hash],
+        )?;
+        Ok(added == 1)
+    }
+
+    fn token_hash(&self, player: &str) -> anyhow::Result<Option<String>> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection
+            .query_row(
+                "SELECT token_hash FROM accounts WHERE name = ?1",
+                params![player],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_token_hash(&self, player: &str, token_hash: &str) -> anyhow::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE accounts SET token_hash = ?2 WHERE name = ?1",
+            params![player, token_hash],
+        )?;
+        anyhow::ensure!(updated == 1, "No account {}", player);
+        Ok(())
+    }
+
+    fn oauth_account(&self, provider: &str, id: &str) -> anyhow::Result<Option<String>> {
+        let connection = self.connection.lock().unwrap();
+        Ok(connection
+            .query_row(
+                "SELECT name FROM oauth_accounts WHERE provider = ?1 AND id = ?2",
+                params![provider, id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn register_oauth(
+        &self,
+        provider: &str,
+        id: &str,
+        player: &str,
+        token_hash: &str,
+    ) -> anyhow::Result<bool> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let added = transaction.execute(
+            "INSERT OR IGNORE INTO accounts (name, token_hash) VALUES (?1, ?2)",
+            params![player, token_hash],
+        )?;
+        if added == 1 {
+            transaction.execute(
+                "INSERT INTO oauth_accounts (provider, id, name) VALUES (?1, ?2, ?3)",
+                params![provider, id, player],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(added == 1)
+    }
+
+    fn rename_account(&self, from: &str, to: &str) -> anyhow::Result<bool> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let taken = transaction
+            .prepare("SELECT 1 FROM accounts WHERE name = ?1")?
+            .exists(params![to])?;
+        if taken {
+            return Ok(false);
+        }
+        let renamed = transaction.execute(
+            "UPDATE accounts SET name = ?2 WHERE name = ?1",
+            params![from, to],
+        )?;
+        anyhow::ensure!(renamed == 1, "No account {}", from);
+        for table in ["oauth_accounts", "vacations"] {
+            transaction.execute(
+                &format!("UPDATE {table} SET name = ?2 WHERE name = ?1"),
+                params![from, to],
+            )?;
+        }
+        for table in ["games", "ongoing_games"] {
+            rename_player(&transaction, table, from, to)?;
+        }
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    fn vacations(&self, player: &str) -> anyhow::Result<Vec<Vacation>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT started_at, ended_at FROM vacations WHERE name = ?1 ORDER BY started_at",
+        )?;
+        let rows = statement.query_map(params![player], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?))
+        })?;
+        rows.map(|row| {
+            let (started_at, ended_at) = row?;
+            Ok(Vacation {
+                started_at: from_millis(started_at)?,
+                ended_at: ended_at.map(from_millis).transpose()?,
+            })
+        })
+        .collect()
+    }
+
+    fn set_vacations(&self, player: &str, vacations: &[Vacation]) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute("DELETE FROM vacations WHERE name = ?1", params![player])?;
+        for vacation in vacations {
+            transaction.execute(
+                "INSERT INTO vacations (name, started_at, ended_at) VALUES (?1, ?2, ?3)",
+                params![
+                    player,
+                    millis(vacation.started_at)?,
+                    vacation.ended_at.map(millis).transpose()?,
+                ],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn start_ongoing(&self, game: &OngoingGame) -> anyhow::Result<u64> {
+        let connection = self.connection.lock().unwrap();
+        let [player1, player2] = &game.record.players;
+        connection.execute(
+            "INSERT INTO ongoing_games (
+                player1, player2, started_at, record, conditional_moves, days_per_move, series
+            )
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                player1,
+                player2,
+                millis(game.started_at)?,
+                serde_json::to_string(&game.record)?,
+                serde_json::to_string(&game.conditional_moves)?,
+                game.days_per_move,
+                game.series
+                    .map(|series| serde_json::to_string(&series))
+                    .transpose()?,
+            ],
+        )?;
+        Ok(connection.last_insert_rowid().try_into()?)
+    }
+
+    fn update_ongoing(&self, id: u64, game: &OngoingGame) -> anyhow::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE ongoing_games SET record = ?2, conditional_moves = ?3 WHERE id = ?1",
+            params![
+                i64::try_from(id)?,
+                serde_json::to_string(&game.record)?,
+                serde_json::to_string(&game.conditional_moves)?,
+            ],
+        )?;
+        anyhow::ensure!(updated == 1, "No ongoing game {}", id);
+        Ok(())
+    }
+
+    fn ongoing(&self, id: u64) -> anyhow::Result<Option<OngoingGame>> {
+        let connection = self.connection.lock().unwrap();
+        let row = connection
+            .query_row(
+                &format!("SELECT {ONGOING_COLUMNS} FROM ongoing_games WHERE id = ?1"),
+                params![i64::try_from(id)?],
+                read_ongoing_row,
+            )
+            .optional()?;
+        Ok(row.map(ongoing_game).transpose()?.map(|(_, game)| game))
+    }
+
+    fn ongoing_games_of(&self, player: &str) -> anyhow::Result<Vec<(u64, OngoingGame)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {ONGOING_COLUMNS} FROM ongoing_games
+            WHERE player1 = ?1 OR player2 = ?1
+            ORDER BY id"
+        ))?;
+        let rows = statement.query_map(params![player], read_ongoing_row)?;
+        rows.map(|row| ongoing_game(row?)).collect()
+    }
+
+    fn finish_ongoing(&self, id: u64, game: &FinishedGame) -> anyhow::Result<u64> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "DELETE FROM ongoing_games WHERE id = ?1",
+            params![i64::try_from(id)?],
+        )?;
+        let id = insert_game(&transaction, game)?;
+        transaction.commit()?;
+        Ok(id)
+    }
+}
+
+fn insert_game(connection: &Connection, game: &FinishedGame) -> anyhow::Result<u64> {
+    let [player1, player2] = &game.record.players;
+    connection.execute(
+        "INSERT INTO games (
+            player1, player2, result, rated, started_at, ended_at, record, rating1, rating2
+        )
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            player1,
+            player2,
+            game.record
+                .result
+                .map(|result| serde_json::to_string(&result))
+                .transpose()?,
+            game.rated,
+            millis(game.started_at)?,
+            millis(game.ended_at)?,
+            serde_json::to_string(&game.record)?,
+            game.ratings.map(|[rating1, _]| rating1),
+            game.ratings.map(|[_, rating2]| rating2),
+        ],
+    )?;
+    Ok(connection.last_insert_rowid().try_into()?)
+}
+
+/// Renames `from` to `to` in every game in `table`, in both the columns and the records.
+fn rename_player(connection: &Connection, table: &str, from: &str, to: &str) -> anyhow::Result<()> {
+    let mut select = connection.prepare(&format!(
+        "SELECT id, record FROM {table} WHERE player1 = ?1 OR player2 = ?1"
+    ))?;
+    let rows = select
+        .query_map(params![from], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+    let mut update = connection.prepare(&format!(
+        "UPDATE {table} SET player1 = ?2, player2 = ?3, record = ?4 WHERE id = ?1"
+    ))?;
+    for (id, record) in rows {
+        let mut record: GameRecord = serde_json::from_str(&record)?;
+        for player in &mut record.players {
+            if player == from {
+                *player = to.to_string();
+            }
+        }
+        let [player1, player2] = &record.players;
+        update.execute(params![
+            id,
+            player1,
+            player2,
+            serde_json::to_string(&record)?
+        ])?;
+    }
+    Ok(())
+}
+
+/// The columns a [`FinishedGame`] is read back from, with its id.
+const COLUMNS: &str = "id, rated, started_at, ended_at, record, rating1, rating2";
+
+type Columns = (i64, bool, i64, i64, String, Option<i32>, Option<i32>);
+
+fn read_row(row: &Row) -> rusqlite::Result<Columns> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+    ))
+}
+
+fn finished_game(
+    (id, rated, started_at, ended_at, record, rating1, rating2): Columns,
+) -> anyhow::Result<(u64, FinishedGame)> {
+    let game = FinishedGame {
+        record: serde_json::from_str(&record)?,
+        rated,
+        ratings: rating1
+            .zip(rating2)
+            .map(|(rating1, rating2)| [rating1, rating2]),
+        started_at: from_millis(started_at)?,
+        ended_at: from_millis(ended_at)?,
+    };
+    Ok((id.try_into()?, game))
+}
+
+/// The columns an [`OngoingGame`] is read back from, with its id.
+const ONGOING_COLUMNS: &str = "id, started_at, record, conditional_moves, days_per_move, series";
+
+type OngoingColumns = (i64, i64, String, String, Option<u32>, Option<String>);
+
+fn read_ongoing_row(row: &Row) -> rusqlite::Result<OngoingColumns> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+    ))
+}
+
+fn ongoing_game(
+    (id, started_at, record, conditional_moves, days_per_move, series): OngoingColumns,
+) -> anyhow::Result<(u64, OngoingGame)> {
+    let game = OngoingGame {
+        record: serde_json::from_str(&record)?,
+        started_at: from_millis(started_at)?,
+        conditional_moves: serde_json::from_str(&conditional_moves)?,
+        days_per_move,
+        series: series
+            .map(|series| serde_json::from_str(&series))
+            .transpose()?,
+    };
+    Ok((id.try_into()?, game))
+}
+
+/// Whether `table` has a column called `column`.
+fn has_column(connection: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    connection
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists(params![table, column])
+}
+
+/// `time` as milliseconds since the Unix epoch, which is how it's stored.
+fn millis(time: SystemTime) -> anyhow::Result<i64> {
+    Ok(time
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis()
+        .try_into()?)
+}
+
+fn from_millis(millis: i64) -> anyhow::Result<SystemTime> {
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_millis(millis.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, time::SystemTime};
+
+    use rusqlite::Connection;
+
+    use super::{
+        super::{
+            GameStore, OngoingGame,
+            tests::{check_store, finished},
+        },
+        SqliteStore,
+    };
+
+    #[test]
+    fn sqlite_store_keeps_its_promises() {
+        check_store(&SqliteStore::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn old_databases_are_upgraded() {
+        let path = env::temp_dir().join(format!("laser-chess-games-{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE games (
+                    id INTEGER PRIMARY KEY,
+                    player1 TEXT NOT NULL,
+                    player2 TEXT NOT NULL,
+                    result TEXT,
+                    rated INTEGER NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    ended_at INTEGER NOT NULL,
+                    record TEXT NOT NULL
+                );
+                CREATE TABLE ongoing_games (
+                    id INTEGER PRIMARY KEY,
+                    player1 TEXT NOT NULL,
+                    player2 TEXT NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    record TEXT NOT NULL
+                );
+                INSERT INTO ongoing_games (player1, player2, started_at, record)
+                VALUES ('alice', 'bob', 0, '{}');",
+            )
+            .unwrap();
+        let store = SqliteStore::open(&path).unwrap();
+        let game = finished(["alice", "bob"], 20);
+        let id = store.save(&game).unwrap();
+        assert_eq!(store.game(id).unwrap(), Some(game));
+        let mut ongoing = OngoingGame {
+            record: finished(["alice", "bob"], 0).record,
+            started_at: SystemTime::UNIX_EPOCH,
+            conditional_moves: Vec::new(),
+            days_per_move: None,
+            series: None,
+        };
+        ongoing.record.result = None;
+        // Games kept before have no moves left to be played, aren't timed and aren't in a series
+        store.update_ongoing(1, &ongoing).unwrap();
+        assert_eq!(store.ongoing(1).unwrap(), Some(ongoing));
+        drop(store);
+        // And opening it again leaves it be
+        SqliteStore::open(&path).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,199 @@
+//! Vacations from correspondence play. A player can go on vacation to stop the clocks of their
+//! timed correspondence games, so a trip doesn't lose them on time, and their opponents are shown
+//! they're away. Each player has [`VACATION_BUDGET`] of it a year, after which their clocks run
+//! again whether they're back or not.
+
+use std::time::{Duration, SystemTime};
+
+use super::storage::Vacation;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long a player can be on vacation in any year.
+pub(super) const VACATION_BUDGET: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How long a vacation counts against the budget for.
+const BUDGET_PERIOD: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// The most days a correspondence game can give each move. Vacations are kept for a year, which
+/// has to cover any move's time with a whole budget of vacation on top.
+pub const MAX_DAYS_PER_MOVE: u32 = 14;
+
+/// When each of `vacations` started and ended: when the player came back, or when they ran out
+/// of budget, which may not have happened yet for one that's still going.
+fn periods(vacations: &[Vacation]) -> Vec<(SystemTime, SystemTime)> {
+    let mut periods: Vec<(SystemTime, SystemTime)> = Vec::with_capacity(vacations.len());
+    for vacation in vacations {
+        let start = vacation.started_at;
+        let used: Duration = periods
+            .iter()
+            .filter(|(earlier, _)| within_period(*earlier, start))
+            .map(|(earlier, end)| end.duration_since(*earlier).unwrap_or_default())
+            .sum();
+        let out_of_budget = start + VACATION_BUDGET.saturating_sub(used);
+        let end = match vacation.ended_at {
+            Some(ended_at) => ended_at.clamp(start, out_of_budget),
+            None => out_of_budget,
+        };
+        periods.push((start, end));
+    }
+    periods
+}
+
+/// Whether a vacation that started at `start` still counts against the budget at `now`.
+fn within_period(start: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(start)
+        .is_ok_and(|since| since < BUDGET_PERIOD)
+}
+
+/// Whether the player with `vacations` is on one at `now`.
+pub(super) fn on_vacation(vacations: &[Vacation], now: SystemTime) -> bool {
+    let last = vacations.last().filter(|last| last.ended_at.is_none());
+    last.is_some()
+        && periods(vacations)
+            .last()
+            .is_some_and(|(start, end)| *start <= now && now < *end)
+}
+
+/// How much vacation the player with `vacations` has left at `now`.
+pub(super) fn budget_left(vacations: &[Vacation], now: SystemTime) -> Duration {
+    let used: Duration = periods(vacations)
+        .iter()
+        .filter(|(start, _)| within_period(*start, now))
+        .map(|(start, end)| paused((*start, *end), *start, now))
+        .sum();
+    VACATION_BUDGET.saturating_sub(used)
+}
+
+/// How much of the time from `from` to `to` the player with `vacations` was on one.
+pub(super) fn paused_between(vacations: &[Vacation], from: SystemTime, to: SystemTime) -> Duration {
+    periods(vacations)
+        .into_iter()
+        .map(|period| paused(period, from, to))
+        .sum()
+}
+
+/// How much of the time from `from` to `to` falls in `(start, end)`.
+fn paused((start, end): (SystemTime, SystemTime), from: SystemTime, to: SystemTime) -> Duration {
+    end.min(to)
+        .duration_since(start.max(from))
+        .unwrap_or_default()
+}
+
+/// How long a player given `days_per_move` for a move they've been on since `since` has left at
+/// `now`, not counting time on `vacations`. Zero once they've run out.
+pub(super) fn time_left(
+    days_per_move: u32,
+    since: SystemTime,
+    vacations: &[Vacation],
+    now: SystemTime,
+) -> Duration {
+    let taken = now.duration_since(since).unwrap_or_default();
+    let taken = taken.saturating_sub(paused_between(vacations, since, now));
+    (DAY * days_per_move).saturating_sub(taken)
+}
+
+/// `vacations` after going on one or coming back at `now`, leaving out any too old to matter.
+/// Going on vacation while on one, or coming back while not, changes nothing.
+pub(super) fn set_vacation(vacations: &[Vacation], on: bool, now: SystemTime) -> Vec<Vacation> {
+    let mut vacations: Vec<Vacation> = vacations
+        .iter()
+        .zip(periods(vacations))
+        // One that ran out of budget ended then
+        .map(|(vacation, (_, end))| match vacation.ended_at {
+            None if end <= now => Vacation {
+                ended_at: Some(end),
+                ..*vacation
+            },
+            _ => *vacation,
+        })
+        .filter(|vacation| {
+            vacation
+                .ended_at
+                .is_none_or(|ended_at| within_period(ended_at, now))
+        })
+        .collect();
+    let going = vacations.last_mut().filter(|last| last.ended_at.is_none());
+    match (going, on) {
+        (Some(last), false) => last.ended_at = Some(now.max(last.started_at)),
+        (None, true) => vacations.push(Vacation {
+            started_at: now,
+            ended_at: None,
+        }),
+        _ => {}
+    }
+    vacations
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{
+        super::storage::Vacation, DAY, VACATION_BUDGET, budget_left, on_vacation, paused_between,
+        set_vacation, time_left,
+    };
+
+    fn day(days: u32) -> SystemTime {
+        SystemTime::UNIX_EPOCH + DAY * days
+    }
+
+    fn vacation(from: u32, to: Option<u32>) -> Vacation {
+        Vacation {
+            started_at: day(from),
+            ended_at: to.map(day),
+        }
+    }
+
+    #[test]
+    fn vacations_stop_the_clock() {
+        let away = [vacation(2, Some(5))];
+        assert_eq!(paused_between(&away, day(0), day(10)), DAY * 3);
+        assert_eq!(paused_between(&away, day(3), day(4)), DAY);
+        assert_eq!(paused_between(&away, day(6), day(10)), Duration::ZERO);
+        // Three days to move from day 1, three days away
+        assert_eq!(time_left(3, day(1), &away, day(6)), DAY);
+        assert_eq!(time_left(3, day(1), &away, day(7)), Duration::ZERO);
+        assert_eq!(time_left(3, day(1), &[], day(6)), Duration::ZERO);
+    }
+
+    #[test]
+    fn vacations_only_last_as_long_as_the_budget() {
+        let away = [vacation(10, None)];
+        assert!(on_vacation(&away, day(20)));
+        assert_eq!(budget_left(&away, day(20)), VACATION_BUDGET - DAY * 10);
+        assert!(!on_vacation(&away, day(40)));
+        assert_eq!(budget_left(&away, day(40)), Duration::ZERO);
+        assert_eq!(paused_between(&away, day(0), day(100)), VACATION_BUDGET);
+
+        // A year on, there's a new budget
+        let again = [vacation(10, Some(40)), vacation(380, None)];
+        assert!(on_vacation(&again, day(390)));
+        assert_eq!(paused_between(&again, day(380), day(500)), VACATION_BUDGET);
+        // But not before
+        let too_soon = [vacation(10, Some(30)), vacation(100, None)];
+        assert_eq!(paused_between(&too_soon, day(100), day(200)), DAY * 10);
+    }
+
+    #[test]
+    fn going_away_and_coming_back() {
+        let away = set_vacation(&[], true, day(400));
+        assert_eq!(away, [vacation(400, None)]);
+        assert_eq!(set_vacation(&away, true, day(401)), away);
+        let back = set_vacation(&away, false, day(402));
+        assert_eq!(back, [vacation(400, Some(402))]);
+        assert!(!on_vacation(&back, day(402)));
+        assert_eq!(set_vacation(&back, false, day(403)), back);
+
+        // Running out of budget is coming back, so there can be another vacation a year on
+        let forgotten = [vacation(10, None)];
+        let returned = set_vacation(&forgotten, true, day(400));
+        assert_eq!(returned, [vacation(10, Some(40)), vacation(400, None)]);
+        assert!(on_vacation(&returned, day(401)));
+
+        // Vacations more than a year over are forgotten
+        let old = [vacation(1, Some(2)), vacation(300, Some(302))];
+        assert_eq!(set_vacation(&old, true, day(500)).len(), 2);
+        assert_eq!(set_vacation(&old, true, day(360)).len(), 3);
+    }
+}